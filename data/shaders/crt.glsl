@@ -0,0 +1,48 @@
+// The shader fancy-nes draws the picture with unless the config names another
+// (see src/post_process.rs): a CRT's curved glass, darkening towards its
+// corners, and the gaps between its scanlines. Copy it to start a shader of
+// your own.
+#version 110
+
+uniform sampler2D source;
+uniform vec2 source_origin;  // The visible lines' top left in source, in texture coordinates
+uniform vec2 source_extent;  // Their size, likewise
+uniform vec2 source_size;    // Their size in NES pixels
+uniform float curvature;     // 0 for a flat screen, up to 1
+uniform float scanlines;     // How much to darken between lines, from 0 to 1
+
+varying vec2 tex_coord;      // 0 to 1 across the picture, from its top left
+
+// How much of the lines from the top to y are the gaps beneath them
+float gaps(float y) {
+    return floor(y) * 0.5 + max(fract(y) - 0.5, 0.0);
+}
+
+// Where on the flat picture a point on the curved one shows
+vec2 bend(vec2 uv) {
+    vec2 centred = uv * 2.0 - 1.0;
+    centred *= 1.0 + curvature * 0.25 * centred.yx * centred.yx;
+    return centred * 0.5 + 0.5;
+}
+
+void main() {
+    vec2 uv = bend(tex_coord);
+    if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+        gl_FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec3 colour = texture2D(source, source_origin + uv * source_extent).rgb;
+
+    // The lower half of each of the NES's lines is the gap beneath a scanline.
+    // Averaged over the lines this pixel covers, curved ones don't alias.
+    float line = uv.y * source_size.y;
+    float span = max(fwidth(line), 0.001);
+    colour *= 1.0 - scanlines * (gaps(line + span * 0.5) - gaps(line - span * 0.5)) / span;
+
+    // The tube curves away from the viewer towards its corners
+    vec2 edge = uv * (1.0 - uv);
+    colour *= mix(1.0, clamp(pow(edge.x * edge.y * 16.0, 0.25), 0.0, 1.0), curvature);
+
+    gl_FragColor = vec4(colour, 1.0);
+}
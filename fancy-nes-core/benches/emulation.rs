@@ -0,0 +1,68 @@
+// Criterion benchmarks of the CPU and PPU, each on its own, with nestest.nes:
+//
+//     cargo bench -p fancy-nes-core
+//
+// For the speed of the whole machine on any ROM, see `fancy-nes bench`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use fancy_nes_core::Nes;
+
+/* nestest's automated mode, which runs through every instruction, official or not */
+const START: u16 = 0xC000;
+const INSTRUCTIONS: u64 = 5000;
+
+/* CPU cycles in an NTSC frame, which tick the PPU for its 89342 dots */
+const FRAME_CYCLES: u32 = 29781;
+
+fn nestest() -> Nes {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tools/roms/nestest.nes");
+    let rom = fs::read(path).expect("tools/roms/nestest.nes is missing");
+    Nes::from_rom(&rom).unwrap()
+}
+
+/* Decoding and executing instructions, a whole instruction at a time */
+fn cpu_instructions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.bench_function("nestest instructions", |b| b.iter_batched_ref(
+        || {
+            let mut nes = nestest();
+            nes.start_at(START);
+            nes
+        },
+        |nes| {
+            for _ in 0..INSTRUCTIONS {
+                nes.step().unwrap();
+            }
+        },
+        BatchSize::SmallInput,
+    ));
+    group.finish();
+}
+
+/* Rendering a frame of nestest's menu, with no CPU running alongside */
+fn ppu_frame(c: &mut Criterion) {
+    let mut nes = nestest();
+    for _ in 0..10 {
+        nes.run_frame().unwrap();  /* Until the menu is up, with rendering enabled */
+    }
+    c.bench_function("ppu frame", |b| b.iter(|| {
+        for _ in 0..FRAME_CYCLES {
+            nes.cpu_mut().bus.tick_ppu();
+        }
+    }));
+}
+
+/* The whole machine, as in `fancy-nes bench` */
+fn full_frame(c: &mut Criterion) {
+    let mut nes = nestest();
+    c.bench_function("full frame", |b| b.iter(|| {
+        nes.run_frame().unwrap();
+    }));
+}
+
+criterion_group!(benches, cpu_instructions, ppu_frame, full_frame);
+criterion_main!(benches);
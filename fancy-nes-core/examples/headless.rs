@@ -0,0 +1,45 @@
+// Run a ROM for a number of frames with no graphics or audio, and print
+// a checksum of the final frame. Useful for scripted regression checks:
+//
+//     cargo run -p fancy-nes-core --example headless -- game.nes 600
+
+use std::{env, fs, process};
+
+use fancy_nes_core::Nes;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <rom> [frames]", args[0]);
+        process::exit(2);
+    }
+
+    let rom = fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", args[1], e);
+        process::exit(1);
+    });
+    let frames: u32 = args.get(2).and_then(|f| f.parse().ok()).unwrap_or(60);
+
+    let mut nes = Nes::from_rom(&rom).unwrap_or_else(|e| {
+        eprintln!("Could not load ROM: {}", e);
+        process::exit(1);
+    });
+
+    let mut audio = [0f32; 4096];
+    let mut samples = 0;
+    for n in 0..frames {
+        if let Err(e) = nes.run_frame() {
+            eprintln!("Emulation stopped in frame {}: {}", n, e);
+            process::exit(1);
+        }
+        loop {
+            let count = nes.take_samples(&mut audio);
+            if count == 0 {
+                break;
+            }
+            samples += count;
+        }
+    }
+
+    println!("frames: {}, audio samples: {}, frame checksum: {:016x}", frames, samples, nes.frame_hash());
+}
@@ -0,0 +1,614 @@
+//! The APU (audio processing unit) lives inside the 2A03 alongside the CPU,
+//! and so is clocked directly from the CPU tick. It consists of five channels:
+//! two pulse waves, a triangle wave, a noise generator and a delta modulation
+//! channel (DMC) for sample playback. These are sequenced by a frame counter,
+//! and mixed (non-linearly) into a single output.
+//!
+//! Samples are generated at `sample_rate` and accumulate in a buffer which
+//! the frontend is expected to drain regularly (e.g. once per frame). Any
+//! expansion sound on the cartridge is mixed in as they are.
+//!
+//! How the channels are mixed can be changed (see `MixerSettings`): each one's
+//! volume, the master volume, and whether the output is filtered as the NES's
+//! is. Each channel's output can also be kept on its own, as a stem.
+//!
+//! For a debugger, each channel can be muted in the mix, its registers read
+//! back as a `ChannelState`, and its output over the last few samples seen
+//! (see `scope`).
+
+use crate::prelude::*;
+use crate::Region;
+use crate::state::{StateReader, StateWriter};
+
+use self::dmc::Dmc;
+use self::filter::{Filter, FilterKind};
+use self::noise::Noise;
+use self::pulse::Pulse;
+use self::triangle::Triangle;
+
+pub mod dmc;
+mod filter;
+pub mod noise;
+pub mod pulse;
+pub mod triangle;
+pub mod units;
+pub mod vrc6;
+
+/* Never buffer more than this many samples, in case nobody is listening */
+const MAX_BUFFERED_SAMPLES: usize = 48000;
+
+/// Samples of each channel's output kept for `scope`
+pub const SCOPE_SAMPLES: usize = 512;
+
+/* Frame counter step positions, in CPU cycles since the sequencer was reset: the
+   quarter frames, then the end of the 4-step sequence and of the 5-step one */
+const FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCounterMode {
+    FourStep,
+    FiveStep,
+}
+
+/// The sources the mixer adds together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,  /* The cartridge's own sound, if it has any */
+}
+
+pub const CHANNELS: usize = 6;
+
+impl Channel {
+    pub const ALL: [Channel; CHANNELS] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise,
+        Channel::Dmc, Channel::Expansion];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "Pulse 1",
+            Channel::Pulse2 => "Pulse 2",
+            Channel::Triangle => "Triangle",
+            Channel::Noise => "Noise",
+            Channel::Dmc => "DMC",
+            Channel::Expansion => "Expansion",
+        }
+    }
+
+    /// Its name in settings and file names, e.g. "pulse1"
+    pub fn key(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+/// How the channels are mixed into the output. The filters are those the NES
+/// has between its mixer and the audio out: two high-pass filters, which take
+/// out the mix's DC offset, and a low-pass filter which softens the pulses' edges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MixerSettings {
+    pub volume: f32,                /* Master, from 0 to 1 */
+    pub channels: [f32; CHANNELS],  /* Each channel's, from 0 to 1, by Channel */
+    pub high_pass: bool,            /* At 90Hz and 440Hz */
+    pub low_pass: bool,             /* At 14kHz */
+}
+
+impl Default for MixerSettings {
+    fn default() -> Self {
+        Self { volume: 1.0, channels: [1.0; CHANNELS], high_pass: false, low_pass: false }
+    }
+}
+
+/// A channel's registers and counters, as a debugger shows them (see NESApu::channel_state)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChannelState {
+    pub enabled: bool,          /* By $4015 */
+    pub period: u16,            /* The timer's, in CPU cycles for the noise and DMC, and timer ticks otherwise */
+    pub frequency: f32,         /* In Hz: of the note played, or for the noise and DMC, of their bits */
+    pub volume: u8,             /* 0-15 from the envelope, or the triangle's linear counter or the DMC's DAC level */
+    pub constant_volume: bool,  /* Rather than the envelope's decay */
+    pub looping: bool,          /* The envelope loops and the length counter is halted (the triangle's control flag) */
+    pub duty: Option<u8>,       /* For the pulses, 0-3 (12.5%, 25%, 50%, 75%) */
+    pub length: u16,            /* The length counter, or the DMC's bytes remaining */
+    pub output: u8,             /* Its level in the mix now */
+}
+
+pub struct NESApu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+
+    pub frame_mode: FrameCounterMode,
+    frame_steps: [u32; 5],  /* FRAME_STEPS_NTSC or FRAME_STEPS_PAL */
+    frame_cycle: u32,   /* CPU cycles into the current frame counter sequence */
+    frame_irq_inhibit: bool,
+    frame_irq: bool,    /* Raised at the end of each 4-step sequence, until $4015 is read */
+    frame_reset_delay: u8,  /* CPU cycles until a $4017 write restarts the sequence */
+    odd_cycle: bool,    /* pulse and noise timers tick at half the CPU rate */
+
+    /* Lookup tables for the non-linear mixer (see NESDEV "APU Mixer") */
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    muted: [bool; CHANNELS],
+    mixer: MixerSettings,
+    filters: [Filter; 3],  /* The 90Hz and 440Hz high-pass, then the 14kHz low-pass */
+
+    /* Each channel's output on its own, as samples and the sum of its mix towards the next, while kept */
+    stems: Option<[Vec<f32>; CHANNELS]>,
+    stem_accum: [f32; CHANNELS],
+
+    /* The output of the five channels at each of the last SCOPE_SAMPLES samples, in a ring from scope_pos */
+    scope: [[u8; SCOPE_SAMPLES]; 5],
+    scope_pos: usize,
+
+    /* Down-sampling from the CPU clock to the output rate (box filter) */
+    cpu_clock: f64,
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    sample_timer: f64,
+    sample_accum: f32,
+    sample_accum_count: u32,
+    samples: Vec<f32>,
+}
+
+impl NESApu {
+    pub fn new() -> Self {
+        let mut pulse_table = [0f32; 31];
+        for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+            *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+        }
+
+        let mut tnd_table = [0f32; 203];
+        for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+            *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+        }
+
+        let mut apu = Self {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_mode: FrameCounterMode::FourStep,
+            frame_steps: FRAME_STEPS_NTSC,
+            frame_cycle: 0,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_reset_delay: 0,
+            odd_cycle: false,
+            pulse_table,
+            tnd_table,
+            muted: [false; CHANNELS],
+            mixer: MixerSettings::default(),
+            filters: [
+                Filter::new(FilterKind::HighPass, 90.0),
+                Filter::new(FilterKind::HighPass, 440.0),
+                Filter::new(FilterKind::LowPass, 14000.0),
+            ],
+            stems: None,
+            stem_accum: [0.0; CHANNELS],
+            scope: [[0; SCOPE_SAMPLES]; 5],
+            scope_pos: 0,
+            cpu_clock: Region::NTSC.cpu_clock(),
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            sample_timer: 0.0,
+            sample_accum: 0.0,
+            sample_accum_count: 0,
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(44100);
+        apu
+    }
+
+    /// Set the rate at which samples are produced, typically that of the audio device.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.cycles_per_sample = self.cpu_clock / rate as f64;
+        for filter in &mut self.filters {
+            filter.set_sample_rate(rate);
+        }
+    }
+
+    /// Use the region's frame counter steps and noise and DMC periods, and
+    /// resample for its CPU clock. The Dendy's are the NTSC 2A03's.
+    pub fn set_region(&mut self, region: Region) {
+        self.frame_steps = match region {
+            Region::PAL => FRAME_STEPS_PAL,
+            Region::NTSC | Region::Dendy => FRAME_STEPS_NTSC,
+        };
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+        self.cpu_clock = region.cpu_clock();
+        self.set_sample_rate(self.sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Take all samples generated since the last drain, in the range [0.0, 1.0]
+    /// (a little beyond, with a loud cartridge mixed in), or around 0.0 when
+    /// high-pass filtered
+    pub fn drain_samples(&mut self) -> alloc::vec::Drain<'_, f32> {
+        self.samples.drain(..)
+    }
+
+    /// Move as many pending samples as will fit into `buf`, oldest first,
+    /// returning how many were written. Any remainder is kept for the next call.
+    pub fn take_samples(&mut self, buf: &mut [f32]) -> usize {
+        let count = buf.len().min(self.samples.len());
+        buf[..count].copy_from_slice(&self.samples[..count]);
+        self.samples.drain(..count);
+        count
+    }
+
+    /// The number of samples waiting to be taken
+    pub fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+
+    // Interpreted in terms of the CPU's address space
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0x01 > 0);
+                self.pulse2.length.set_enabled(data & 0x02 > 0);
+                self.triangle.length.set_enabled(data & 0x04 > 0);
+                self.noise.length.set_enabled(data & 0x08 > 0);
+                self.dmc.set_enabled(data & 0x10 > 0);
+                self.dmc.irq_flag = false;
+            }
+            0x4017 => {
+                self.frame_mode = if data & 0x80 > 0 { FrameCounterMode::FiveStep } else { FrameCounterMode::FourStep };
+                self.frame_irq_inhibit = data & 0x40 > 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                // The sequence restarts 3 or 4 cycles later, depending on whether the write
+                // lands between APU cycles (see clock_frame_counter)
+                self.frame_reset_delay = if self.odd_cycle { 4 } else { 3 };
+            }
+            _ => {}
+        }
+    }
+
+    /// $4015 (SND_CHN) reads report which channels are still active, and
+    /// which interrupts are pending. Reading it also acknowledges the frame
+    /// IRQ (see `acknowledge_frame_irq`), which this doesn't do.
+    pub fn read_status(&self) -> u8 {
+        (self.pulse1.length.active() as u8)
+            | (self.pulse2.length.active() as u8) << 1
+            | (self.triangle.length.active() as u8) << 2
+            | (self.noise.length.active() as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq_flag as u8) << 7
+    }
+
+    /// Clear the frame IRQ, as a CPU read of $4015 does
+    pub fn acknowledge_frame_irq(&mut self) {
+        self.frame_irq = false;
+    }
+
+    /// Whether the CPU is on an odd cycle, which delays the start of an OAM DMA by one
+    pub fn odd_cycle(&self) -> bool {
+        self.odd_cycle
+    }
+
+    /// The APU's IRQ output, from the frame counter or the DMC
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq_flag
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.length.clock();
+        self.pulse2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+
+                // Selecting the 5-step sequence immediately clocks all units
+                if self.frame_mode == FrameCounterMode::FiveStep {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+
+        /* The 4-step sequence raises its IRQ from the cycle before step 4 (hardware also
+           sets it on the cycle after, which only a $4015 read landing between would notice) */
+        if self.frame_mode == FrameCounterMode::FourStep && !self.frame_irq_inhibit
+            && (self.frame_steps[3] - 1..=self.frame_steps[3]).contains(&self.frame_cycle) {
+            if !self.frame_irq {
+                nes_log!(Debug, APU, "Frame counter IRQ raised");
+            }
+            self.frame_irq = true;
+        }
+
+        let [step_1, step_2, step_3, step_4, step_5] = self.frame_steps;
+        match (self.frame_cycle, self.frame_mode) {
+            (cycle, _) if cycle == step_1 || cycle == step_3 => {
+                self.clock_quarter_frame();
+            }
+            (cycle, _) if cycle == step_2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (cycle, FrameCounterMode::FourStep) if cycle == step_4 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            (cycle, FrameCounterMode::FiveStep) if cycle == step_5 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_cycle = 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// The resampler and any buffered samples belong to the host, so are not saved
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+
+        w.write_bool(self.frame_mode == FrameCounterMode::FiveStep);
+        w.write_u32(self.frame_cycle);
+        w.write_bool(self.frame_irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u8(self.frame_reset_delay);
+        w.write_bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+
+        self.frame_mode = if r.read_bool()? { FrameCounterMode::FiveStep } else { FrameCounterMode::FourStep };
+        self.frame_cycle = r.read_u32()?;
+        self.frame_irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_reset_delay = r.read_u8()?;
+        self.odd_cycle = r.read_bool()?;
+
+        /* Whatever was buffered belongs to the timeline we just left */
+        self.samples.clear();
+        if let Some(stems) = &mut self.stems {
+            stems.iter_mut().for_each(Vec::clear);
+        }
+        Ok(())
+    }
+
+    /// Leave a channel out of the mix, or put it back. It carries on playing, unheard.
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    /// Change the volumes and filters, the volumes being held between 0 and 1
+    pub fn set_mixer(&mut self, settings: MixerSettings) {
+        let mut settings = settings;
+        settings.volume = settings.volume.clamp(0.0, 1.0);
+        settings.channels = settings.channels.map(|volume| volume.clamp(0.0, 1.0));
+
+        /* Filters switched back in start afresh, rather than from where they were left */
+        if settings.high_pass && !self.mixer.high_pass {
+            self.filters[0].reset();
+            self.filters[1].reset();
+        }
+        if settings.low_pass && !self.mixer.low_pass {
+            self.filters[2].reset();
+        }
+        self.mixer = settings;
+    }
+
+    pub fn mixer(&self) -> MixerSettings {
+        self.mixer
+    }
+
+    /// Start or stop keeping each channel's output on its own, at its volume, for
+    /// `take_stems`. Stems aren't filtered, and being mixed non-linearly, don't
+    /// quite add up to the mix.
+    pub fn set_stems(&mut self, keep: bool) {
+        self.stems = if keep { Some(Default::default()) } else { None };
+        self.stem_accum = [0.0; CHANNELS];
+    }
+
+    pub fn keeps_stems(&self) -> bool {
+        self.stems.is_some()
+    }
+
+    /// Take each channel's samples since the last call, by Channel, if stems are kept
+    pub fn take_stems(&mut self) -> Option<[Vec<f32>; CHANNELS]> {
+        self.stems.as_mut().map(core::mem::take)
+    }
+
+    /// A channel's registers and counters, or None for the expansion sound, which the cartridge has
+    pub fn channel_state(&self, channel: Channel) -> Option<ChannelState> {
+        let cpu_clock = self.cpu_clock as f32;
+        match channel {
+            Channel::Pulse1 => Some(self.pulse1.state(cpu_clock)),
+            Channel::Pulse2 => Some(self.pulse2.state(cpu_clock)),
+            Channel::Triangle => Some(self.triangle.state(cpu_clock)),
+            Channel::Noise => Some(self.noise.state(cpu_clock)),
+            Channel::Dmc => Some(self.dmc.state(cpu_clock)),
+            Channel::Expansion => None,
+        }
+    }
+
+    /// A channel's output at each of the last SCOPE_SAMPLES samples, oldest first, muted or not.
+    /// Empty for the expansion sound.
+    pub fn scope(&self, channel: Channel) -> impl Iterator<Item = u8> + '_ {
+        let (newer, older) = match self.scope.get(channel as usize) {
+            Some(samples) => samples.split_at(self.scope_pos),
+            None => (&[][..], &[][..]),
+        };
+        older.iter().chain(newer).copied()
+    }
+
+    /// Mix the current output of the five channels at their volumes, leaving out
+    /// those muted, in the range [0.0, 1.0]
+    pub fn mix(&self) -> f32 {
+        let pulse = self.level(Channel::Pulse1) + self.level(Channel::Pulse2);
+        let tnd = 3.0 * self.level(Channel::Triangle) + 2.0 * self.level(Channel::Noise) + self.level(Channel::Dmc);
+
+        lookup(&self.pulse_table, pulse) + lookup(&self.tnd_table, tnd)
+    }
+
+    /* A channel's volume in the mix: as set, or 0 if it's muted */
+    fn volume(&self, channel: Channel) -> f32 {
+        if self.muted[channel as usize] { 0.0 } else { self.mixer.channels[channel as usize] }
+    }
+
+    /* A channel's output at its volume, as the mixer's tables take it */
+    fn level(&self, channel: Channel) -> f32 {
+        let output = match channel {
+            Channel::Pulse1 => self.pulse1.output(),
+            Channel::Pulse2 => self.pulse2.output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::Dmc => self.dmc.output(),
+            Channel::Expansion => 0,
+        };
+        output as f32 * self.volume(channel)
+    }
+
+    /* Each channel's part of the mix, were it playing alone */
+    fn solo_mix(&self, expansion: f32) -> [f32; CHANNELS] {
+        [
+            lookup(&self.pulse_table, self.level(Channel::Pulse1)),
+            lookup(&self.pulse_table, self.level(Channel::Pulse2)),
+            lookup(&self.tnd_table, 3.0 * self.level(Channel::Triangle)),
+            lookup(&self.tnd_table, 2.0 * self.level(Channel::Noise)),
+            lookup(&self.tnd_table, self.level(Channel::Dmc)),
+            expansion,
+        ]
+    }
+
+    /* Put a sample through the filters switched in, in the order the NES has them */
+    fn filter(&mut self, sample: f32) -> f32 {
+        let [high_pass_90, high_pass_440, low_pass] = &mut self.filters;
+        let mut sample = sample;
+        if self.mixer.high_pass {
+            sample = high_pass_440.apply(high_pass_90.apply(sample));
+        }
+        if self.mixer.low_pass {
+            sample = low_pass.apply(sample);
+        }
+        sample
+    }
+
+    /// Advance the APU by one CPU cycle, with the cartridge's expansion sound
+    /// (see Cartridge::audio), which is added to the APU's own mix
+    pub fn tick(&mut self, expansion: f32) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        let expansion = expansion * self.volume(Channel::Expansion);
+        self.sample_accum += self.mix() + expansion;
+        self.sample_accum_count += 1;
+        if self.stems.is_some() {
+            let solo = self.solo_mix(expansion);
+            for (accum, solo) in self.stem_accum.iter_mut().zip(solo) {
+                *accum += solo;
+            }
+        }
+        self.sample_timer += 1.0;
+
+        if self.sample_timer >= self.cycles_per_sample {
+            self.sample_timer -= self.cycles_per_sample;
+
+            let outputs = [self.pulse1.output(), self.pulse2.output(), self.triangle.output(), self.noise.output(), self.dmc.output()];
+            for (scope, output) in self.scope.iter_mut().zip(outputs) {
+                scope[self.scope_pos] = output;
+            }
+            self.scope_pos = (self.scope_pos + 1) % SCOPE_SAMPLES;
+
+            let count = self.sample_accum_count as f32;
+            let sample = self.filter(self.sample_accum / count) * self.mixer.volume;
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(sample);
+            }
+            if let Some(stems) = &mut self.stems {
+                for (stem, accum) in stems.iter_mut().zip(&mut self.stem_accum) {
+                    if stem.len() < MAX_BUFFERED_SAMPLES {
+                        stem.push(*accum / count * self.mixer.volume);
+                    }
+                    *accum = 0.0;
+                }
+            }
+            self.sample_accum = 0.0;
+            self.sample_accum_count = 0;
+        }
+    }
+}
+
+impl Default for NESApu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* An entry of one of the mixer's tables, interpolated between them for volumes other than full */
+fn lookup(table: &[f32], index: f32) -> f32 {
+    let whole = index as usize;
+    if whole + 1 >= table.len() {
+        return table[table.len() - 1];
+    }
+    table[whole] + (table[whole + 1] - table[whole]) * (index - whole as f32)
+}
@@ -0,0 +1,232 @@
+use crate::prelude::*;
+use super::ChannelState;
+use crate::state::{StateReader, StateWriter};
+use crate::Region;
+
+/* Timer periods in CPU cycles, of the NTSC 2A03 and the PAL 2A07 */
+const RATE_TABLE_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const RATE_TABLE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// The delta modulation channel ($4010-$4013) plays back 1-bit delta encoded
+/// samples, fetched directly from CPU memory. As the APU cannot see the CPU's
+/// address space, fetches are requested through `pending_fetch` and satisfied
+/// by the CPU with `fill_sample_buffer`.
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub irq_flag: bool,
+    looping: bool,
+    rates: &'static [u16; 16],  /* RATE_TABLE_NTSC or RATE_TABLE_PAL */
+    pub timer_period: u16,
+    timer: u16,
+
+    pub output_level: u8,  /* 7-bit DAC */
+
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    pub bytes_remaining: u16,
+
+    sample_buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            irq_flag: false,
+            looping: false,
+            rates: &RATE_TABLE_NTSC,
+            timer_period: RATE_TABLE_NTSC[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    /// Use the region's rates, keeping the rate selected
+    pub fn set_region(&mut self, region: Region) {
+        let rates = match region {
+            Region::PAL => &RATE_TABLE_PAL,
+            Region::NTSC | Region::Dendy => &RATE_TABLE_NTSC,
+        };
+        if let Some(index) = self.rates.iter().position(|&rate| rate == self.timer_period) {
+            self.timer_period = rates[index];
+        }
+        self.rates = rates;
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0 => {
+                self.irq_enabled = data & 0x80 > 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.looping = data & 0x40 > 0;
+                self.timer_period = self.rates[(data & 0x0F) as usize];
+            }
+            1 => {
+                self.output_level = data & 0x7F;
+            }
+            2 => {
+                self.sample_address = 0xC000 | ((data as u16) << 6);
+            }
+            3 => {
+                self.sample_length = ((data as u16) << 4) + 1;
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    /// Handle the DMC bit of $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// The address the memory reader wants to fetch from, if the sample buffer has run dry
+    pub fn pending_fetch(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                nes_log!(Debug, APU, "DMC IRQ raised at the end of the sample");
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    /// Clocked on every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift = sample;
+                }
+                None => {
+                    self.silence = true;
+                }
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    /* Enabled while it has sample bytes left to play */
+    pub fn state(&self, cpu_clock: f32) -> ChannelState {
+        ChannelState {
+            enabled: self.bytes_remaining > 0,
+            period: self.timer_period,
+            frequency: cpu_clock / self.timer_period as f32,
+            volume: self.output_level,
+            constant_volume: false,
+            looping: self.looping,
+            duty: None,
+            length: self.bytes_remaining,
+            output: self.output(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_flag);
+        w.write_bool(self.looping);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        /* An empty sample buffer is stored as a leading 0 */
+        match self.sample_buffer {
+            Some(data) => { w.write_bool(true); w.write_u8(data); }
+            None => { w.write_bool(false); w.write_u8(0); }
+        }
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = r.read_bool()?;
+        self.irq_flag = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()? & 0x7F;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,50 @@
+//! The first-order RC filters between the NES's mixer and its audio output: two
+//! high-pass filters, at 90Hz and 440Hz, and a low-pass filter at 14kHz (see
+//! NESDEV "APU Mixer"). They run on the output samples, at the sample rate.
+
+use core::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+pub struct Filter {
+    kind: FilterKind,
+    cutoff: f32,  /* In Hz */
+    alpha: f32,   /* The filter's coefficient, at the sample rate */
+    last_input: f32,
+    last_output: f32,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, cutoff: f32) -> Self {
+        Self { kind, cutoff, alpha: 0.0, last_input: 0.0, last_output: 0.0 }
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        let rc = 1.0 / (2.0 * PI * self.cutoff);
+        let dt = 1.0 / rate.max(1) as f32;
+        self.alpha = match self.kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+    }
+
+    /// Forget the samples filtered so far, as when the filter is switched back in
+    pub fn reset(&mut self) {
+        self.last_input = 0.0;
+        self.last_output = 0.0;
+    }
+
+    pub fn apply(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.last_output + input - self.last_input),
+            FilterKind::LowPass => self.last_output + self.alpha * (input - self.last_output),
+        };
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
@@ -0,0 +1,130 @@
+use crate::prelude::*;
+use super::ChannelState;
+use super::units::{Envelope, LengthCounter};
+use crate::state::{StateReader, StateWriter};
+use crate::Region;
+
+/* Timer periods in CPU cycles, of the NTSC 2A03 and the PAL 2A07 */
+const PERIOD_TABLE_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PERIOD_TABLE_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// The noise channel ($400C-$400F), a 15-bit linear feedback shift register
+pub struct Noise {
+    mode: bool,        /* feedback from bit 6 (short mode) rather than bit 1 */
+    periods: &'static [u16; 16],  /* PERIOD_TABLE_NTSC or PERIOD_TABLE_PAL */
+    pub timer_period: u16,
+    timer: u16,
+    shift: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self {
+            mode: false,
+            periods: &PERIOD_TABLE_NTSC,
+            timer_period: PERIOD_TABLE_NTSC[0],
+            timer: 0,
+            shift: 1, /* loaded with 1 on power-up */
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Use the region's timer periods, keeping the period selected
+    pub fn set_region(&mut self, region: Region) {
+        let periods = match region {
+            Region::PAL => &PERIOD_TABLE_PAL,
+            Region::NTSC | Region::Dendy => &PERIOD_TABLE_NTSC,
+        };
+        if let Some(index) = self.periods.iter().position(|&period| period == self.timer_period) {
+            self.timer_period = periods[index];
+        }
+        self.periods = periods;
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0 => {
+                self.length.halt = data & 0x20 > 0;
+                self.envelope.write_control(data);
+            }
+            1 => {}
+            2 => {
+                self.mode = data & 0x80 > 0;
+                self.timer_period = self.periods[(data & 0x0F) as usize];
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    /// Clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            // The period table is in CPU cycles, we are clocked at half that
+            self.timer = self.timer_period / 2 - 1;
+
+            let other_bit = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift & 1) ^ ((self.shift >> other_bit) & 1);
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 == 1 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn state(&self, cpu_clock: f32) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.timer_period,
+            frequency: cpu_clock / self.timer_period as f32,
+            volume: self.envelope.volume(),
+            constant_volume: self.envelope.constant_volume,
+            looping: self.envelope.looping,
+            duty: None,
+            length: self.length.counter as u16,
+            output: self.output(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.mode);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.mode = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.shift = r.read_u16()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
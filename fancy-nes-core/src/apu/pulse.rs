@@ -0,0 +1,175 @@
+use crate::prelude::*;
+use super::ChannelState;
+use super::units::{Envelope, LengthCounter};
+use crate::state::{StateReader, StateWriter};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],  /* 12.5% */
+    [0, 1, 1, 0, 0, 0, 0, 0],  /* 25%   */
+    [0, 1, 1, 1, 1, 0, 0, 0],  /* 50%   */
+    [1, 0, 0, 1, 1, 1, 1, 1],  /* 25% negated */
+];
+
+/// One of the two square wave channels ($4000-$4003, $4004-$4007)
+pub struct Pulse {
+    /* The sweep units differ only in how they negate the change amount -
+       pulse 1 uses one's complement, pulse 2 two's complement. */
+    ones_complement: bool,
+
+    pub duty: u8,
+    duty_step: u8,
+
+    pub timer_period: u16,
+    timer: u16,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Self {
+            ones_complement,
+            duty: 0,
+            duty_step: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    /// Register writes, with addr being the offset (0-3) from the channel's base register
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 > 0;
+                self.envelope.write_control(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 > 0;
+                self.sweep_period = (data >> 4) & 0x7;
+                self.sweep_negate = data & 0x08 > 0;
+                self.sweep_shift = data & 0x7;
+                self.sweep_reload = true;
+            }
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x7) << 8);
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+                self.duty_step = 0;
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    /// Clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.duty_step = (self.duty_step + 1) & 0x7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let negated = if self.ones_complement { change + 1 } else { change };
+            self.timer_period.saturating_sub(negated)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    /* The sweep unit mutes the channel whenever the current period is
+       too small, or the target period overflows - even when disabled. */
+    fn sweep_muting(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    /// Clocked by the frame counter on every half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muting() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep_muting()
+            || DUTY_TABLE[self.duty as usize][self.duty_step as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn state(&self, cpu_clock: f32) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.timer_period,
+            frequency: cpu_clock / (16.0 * (self.timer_period as f32 + 1.0)),
+            volume: self.envelope.volume(),
+            constant_volume: self.envelope.constant_volume,
+            looping: self.envelope.looping,
+            duty: Some(self.duty),
+            length: self.length.counter as u16,
+            output: self.output(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.duty_step);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.duty = r.read_u8()? & 0x3;
+        self.duty_step = r.read_u8()? & 0x7;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        self.envelope.load_state(r)?;
+        self.length.load_state(r)
+    }
+}
@@ -0,0 +1,121 @@
+use crate::prelude::*;
+use super::ChannelState;
+use super::units::LengthCounter;
+use crate::state::{StateReader, StateWriter};
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The triangle channel ($4008-$400B). It has no volume control, but
+/// instead a second "linear" counter with a finer resolution than the length counter.
+#[derive(Default)]
+pub struct Triangle {
+    pub timer_period: u16,
+    timer: u16,
+    step: u8,
+
+    control: bool,          /* also the length counter halt flag */
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0 => {
+                self.control = data & 0x80 > 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | data as u16;
+            }
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x7) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    /// Unlike the other channels, clocked on every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by the frame counter on every quarter frame
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        // Ultrasonic periods are silenced, rather than emulating the resulting "pop"
+        if self.timer_period < 2 {
+            return 7;
+        }
+        SEQUENCE[self.step as usize]
+    }
+
+    /* An octave below a pulse with the same period, its sequence being 32 steps long */
+    pub fn state(&self, cpu_clock: f32) -> ChannelState {
+        ChannelState {
+            enabled: self.length.enabled,
+            period: self.timer_period,
+            frequency: cpu_clock / (32.0 * (self.timer_period as f32 + 1.0)),
+            volume: self.linear_counter,
+            constant_volume: false,
+            looping: self.control,
+            duty: None,
+            length: self.length.counter as u16,
+            output: self.output(),
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.step);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        self.length.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.step = r.read_u8()? & 0x1F;
+        self.control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.length.load_state(r)
+    }
+}
@@ -0,0 +1,121 @@
+//! Building blocks shared between several of the APU channels.
+
+use crate::prelude::*;
+use crate::state::{StateReader, StateWriter};
+
+/* Indexed by the 5-bit value written to the upper bits of
+   $4003, $4007, $400B and $400F. */
+pub const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The envelope generator, used by both pulse channels and the noise channel.
+/// It either outputs a constant volume, or a decaying saw (15 -> 0), optionally looping.
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,          /* shared with the length counter halt flag */
+    pub constant_volume: bool,
+    pub period: u8,             /* also the constant volume, if selected */
+
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    /// Clocked by the frame counter on every quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.looping {
+                self.decay_level = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant_volume { self.period } else { self.decay_level }
+    }
+
+    /// Handle the lower 6 bits of $4000, $4004 and $400C
+    pub fn write_control(&mut self, data: u8) {
+        self.looping = data & 0x20 > 0;
+        self.constant_volume = data & 0x10 > 0;
+        self.period = data & 0x0F;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant_volume);
+        w.write_u8(self.period);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay_level);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant_volume = r.read_bool()?;
+        self.period = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay_level = r.read_u8()?;
+        Ok(())
+    }
+}
+
+/// The length counter silences a channel once it has counted down to zero.
+/// It is clocked on every half frame, unless halted.
+#[derive(Default)]
+pub struct LengthCounter {
+    pub enabled: bool,  /* controlled through $4015 */
+    pub halt: bool,
+    pub counter: u8,
+}
+
+impl LengthCounter {
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.halt);
+        w.write_u8(self.counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
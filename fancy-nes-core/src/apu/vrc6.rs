@@ -0,0 +1,220 @@
+//! The VRC6's expansion sound: two pulse channels with eight duty cycles and a
+//! sawtooth, on the cartridge rather than in the 2A03. Their output goes back
+//! to the console through the cartridge connector, to be mixed with the APU's
+//! (see Mapper::audio). Each channel has three registers, at $9000, $A000 and
+//! $B000 (after the board's address line swap, on mapper 26):
+//!   $x000  Pulse: constant output mode, duty and volume (MDDD VVVV)
+//!          Sawtooth: the amount added to its accumulator (..AA AAAA)
+//!   $x001  Period, low 8 bits
+//!   $x002  Enable, and period high 4 bits (E... PPPP)
+//! and $9003 halts all three, or speeds them up 16 or 256 times.
+
+use crate::prelude::*;
+use crate::state::{StateReader, StateWriter};
+
+/* The VRC6's output relative to the APU's mix, such that a pulse at full volume
+   is about as loud as one of the APU's */
+const OUTPUT_SCALE: f32 = 0.0099;
+
+#[derive(Default)]
+struct Vrc6Pulse {
+    constant: bool,  /* Output the volume regardless of duty */
+    duty: u8,        /* High for duty + 1 of 16 steps */
+    volume: u8,
+    period: u16,
+    enabled: bool,
+
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.constant = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.constant);
+        w.write_u8(self.duty);
+        w.write_u8(self.volume);
+        w.write_u16(self.period);
+        w.write_bool(self.enabled);
+        w.write_u16(self.timer);
+        w.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.constant = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        self.period = r.read_u16()?;
+        self.enabled = r.read_bool()?;
+        self.timer = r.read_u16()?;
+        self.step = r.read_u8()?;
+        Ok(())
+    }
+}
+
+/* Adds its rate to an accumulator on every other step of seven, and outputs the
+   accumulator's top five bits, clearing it on the seventh */
+#[derive(Default)]
+struct Vrc6Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+
+    timer: u16,
+    step: u8,  /* 0-13, two per accumulation */
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.rate);
+        w.write_u16(self.period);
+        w.write_bool(self.enabled);
+        w.write_u16(self.timer);
+        w.write_u8(self.step);
+        w.write_u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.rate = r.read_u8()?;
+        self.period = r.read_u16()?;
+        self.enabled = r.read_bool()?;
+        self.timer = r.read_u16()?;
+        self.step = r.read_u8()?;
+        self.accumulator = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Vrc6Sawtooth,
+
+    halted: bool,
+    shift: u8,  /* Of the periods, to speed the channels up */
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A write to one of the sound registers, by its address as wired for mapper 24
+    /// ($9000-$9003, $A000-$A002 or $B000-$B002). Other addresses are ignored.
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0x9003 => {
+                self.halted = data & 0x01 != 0;
+                self.shift = if data & 0x04 != 0 { 8 } else if data & 0x02 != 0 { 4 } else { 0 };
+            }
+            0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, data),
+            0xB000..=0xB002 => self.sawtooth.write(addr - 0xB000, data),
+            _ => {}
+        }
+    }
+
+    /// Once every CPU cycle
+    pub fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.sawtooth.clock(self.shift);
+    }
+
+    /// The channels' output, mixed linearly, on the scale of the APU's mix
+    pub fn output(&self) -> f32 {
+        (self.pulse1.output() + self.pulse2.output() + self.sawtooth.output()) as f32 * OUTPUT_SCALE
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.sawtooth.save_state(w);
+        w.write_bool(self.halted);
+        w.write_u8(self.shift);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.sawtooth.load_state(r)?;
+        self.halted = r.read_bool()?;
+        self.shift = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+//! Measuring how fast the emulator runs, headlessly and as fast as it can, to
+//! compare changes made for performance against. The criterion benchmarks in
+//! benches/ time the CPU and PPU apart; this times the whole machine, as played.
+
+use core::fmt;
+use std::time::{Duration, Instant};
+
+use crate::Nes;
+use crate::error::NesError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchResult {
+    pub frames: u32,
+    pub cpu_cycles: u64,
+    pub elapsed: Duration,
+    pub frame_rate: f64,  /* Of the console emulated, to compare against */
+}
+
+impl BenchResult {
+    pub fn frames_per_second(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn cycles_per_second(&self) -> f64 {
+        self.cpu_cycles as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// How many times faster than the real console
+    pub fn speed(&self) -> f64 {
+        self.frames_per_second() / self.frame_rate
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} frames ({} CPU cycles) in {:.3}s: {:.1} frames/s, {:.2}M cycles/s, {:.1}x real time",
+            self.frames, self.cpu_cycles, self.elapsed.as_secs_f64(), self.frames_per_second(),
+            self.cycles_per_second() / 1e6, self.speed())
+    }
+}
+
+/// Run a ROM from power on for `frames` frames with no input, taking its audio as a
+/// frontend would, and time it. Loading the ROM isn't timed.
+pub fn run_benchmark(rom: &[u8], frames: u32) -> Result<BenchResult, NesError> {
+    let mut nes = Nes::from_rom(rom)?;
+    let mut audio = [0f32; 4096];
+    let mut cpu_cycles = 0;
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        cpu_cycles += 1;
+        while !nes.tick()? {
+            cpu_cycles += 1;
+        }
+        while nes.take_samples(&mut audio) > 0 {}
+    }
+    let elapsed = start.elapsed();
+
+    Ok(BenchResult { frames, cpu_cycles, elapsed, frame_rate: nes.region().frame_rate() })
+}
@@ -0,0 +1,424 @@
+//! The CPU's view of the rest of the machine. The bus owns every device the
+//! CPU can address - RAM, the PPU and APU, the controllers and the cartridge -
+//! and mediates between them, so no component needs a reference to another.
+//! Signals travelling the other way (NMI from the PPU, DMA stalls) are latched
+//! here for the CPU to collect.
+
+use crate::prelude::*;
+use crate::{Overclock, Region};
+use crate::apu::NESApu;
+use crate::cartridge::Cartridge;
+use crate::cpu::controller::{DeviceInput, InputDevice, InputPoll, Joypad, EXPANSION_PORT, PORTS};
+use crate::cheats::Cheats;
+use crate::debugger::{Breakpoints, WatchList};
+#[cfg(feature = "hooks")]
+use crate::hooks::Hooks;
+use crate::ppu::NESPpu;
+
+/* An OAM DMA halts the CPU for 513 cycles (514 if begun on an odd cycle) */
+const OAM_DMA_CYCLES: u16 = 513;
+
+/* A DMC sample fetch halts the CPU for 4 cycles, or 2 when it slots into an OAM DMA.
+   (Hardware takes 3 if the CPU was writing, which we can't tell between instructions.) */
+const DMC_DMA_CYCLES: u16 = 4;
+const DMC_DMA_CYCLES_DURING_OAM_DMA: u16 = 2;
+
+// Reads fail rather than panic, so that a misbehaving ROM can be
+// stopped and inspected by the frontend.
+pub trait MemoryRead {
+    fn read(&self, addr: u16) -> Result<u8, String>;           /* A side-effect less read */
+    fn read_mut(&mut self, addr: u16) -> Result<u8, String>;   /* A read with side-effects */
+    
+    // Convenience functions to read an address word
+    fn read_16(&self, addr: u16) -> Result<u16, String>;
+    fn read_16_mut(&mut self, addr: u16) -> Result<u16, String>;
+}
+
+// Intrusive read
+impl MemoryRead for Bus {
+    fn read(&self, addr: u16) -> Result<u8, String> {
+        let data = match addr {
+            0x0000..=0x1FFF => {
+                /* Internal RAM */
+                self.internal_ram[(addr & 0x07FF) as usize]
+            }
+            0x2000..=0x3FFF => {
+                return Err(format!("Attempted read of address with side-effect from observer: ${:X}", addr));
+            }
+            0x4015 => {
+                self.apu.read_status() | (self.open_bus & 0x20)
+            }
+            0x4016 | 0x4017 => {
+                let register = addr as usize - 0x4016;
+                self.port_devices(register).fold(self.open_bus & 0xE0, |data, device| data | device.peek(register))
+            }
+            0x4000..=0x4014 | 0x4018..=0x401F => {
+                /* Write-only I/O registers and CPU test mode registers */
+                self.open_bus
+            }
+            0x4020..=0xFFFF if !self.cartridge.decodes(addr) => {
+                self.open_bus
+            }
+            0x4020..=0xFFFF => {
+                self.cartridge.cpu_read(addr)
+            }
+        };
+        Ok(self.cheats.apply(addr, data))
+    }
+
+    fn read_mut(&mut self, addr: u16) -> Result<u8, String> {
+        let data = match addr {
+            0x0000..=0x1FFF => {
+                /* Internal RAM */
+                self.internal_ram[(addr & 0x07FF) as usize]
+            }
+            0x2000..=0x3FFF => {
+                self.catch_up_ppu();
+                if addr & 0x7 == 0x7 {
+                    self.cartridge.log_chr_read(self.ppu.vram_addresses().0 & 0x3FFF);
+                }
+                self.ppu.ppu_register_read(&self.cartridge, 0x2000 + (addr & 0x7))?
+            }
+            0x4000..=0x4017 => {
+                /* I/O registers - defer to MemoryRead */
+                let data: u8;
+
+                if addr == 0x4015 { /* SND_CHN */
+                    // Read inside the CPU, so bit 5 is whatever was last on the external bus,
+                    // and the bus keeps it (see below)
+                    data = self.apu.read_status() | (self.open_bus & 0x20);
+                    self.apu.acknowledge_frame_irq();
+                } else if addr == 0x4016 || addr == 0x4017 { /* JOY1, JOY2 */
+                    // The port's device and the expansion port's both drive the low
+                    // bits (a controller just D0), the top three being open bus.
+                    let register = addr as usize - 0x4016;
+                    data = self.read_devices(register) | (self.open_bus & 0xE0);
+                    self.joypad_read = Some(register);
+                } else { data = self.open_bus; }
+                data
+            }
+            0x4018..=0x401F => {
+                /* CPU test mode registers */
+                self.open_bus
+            }
+            0x4020..=0xFFFF if !self.cartridge.decodes(addr) => {
+                self.open_bus
+            }
+            0x4020..=0xFFFF => {
+                self.cartridge.log_read(addr);
+                self.cartridge.cpu_read(addr)
+            }
+        };
+        let data = self.cheats.apply(addr, data);
+        if addr != 0x4015 {
+            self.open_bus = data;
+        }
+
+        self.breakpoints.check_read(addr, data);
+        #[cfg(feature = "hooks")]
+        self.hook(|hooks| hooks.read(addr, data));
+        Ok(data)
+    }
+
+    fn read_16(&self, addr: u16) -> Result<u16, String> {
+        Ok(((self.read(addr)?) as u16) | (((self.read(addr + 1)?) as u16) << 8))
+    }
+
+    fn read_16_mut(&mut self, addr: u16) -> Result<u16, String> {
+        Ok(((self.read_mut(addr)?) as u16) | (((self.read_mut(addr + 1)?) as u16) << 8))
+    }
+}
+
+type IORegisters = [u8; 0x0018];
+    /* SQ1_VOL */
+    /* SQ1_SWEEP */
+    /* SQ1_LO */
+    /* SQ1_HI */
+    /* SQ2_VOL */
+    /* SQ2_SWEEP */
+    /* SQ2_LO */
+    /* SQ2_HI */
+    /* TRI_LINEAR */
+    /* (unused) */
+    /* TRI_LO */
+    /* TRI_HI */
+    /* NOISE_VOL */
+    /* (unused) */
+    /* NOISE_LO */
+    /* NOISE_HI */
+    /* DMC_FREQ */
+    /* DMC_RAW */
+    /* DMC_START */
+    /* DMC_LEN */
+    /* OAM_DMA */
+    /* SND_CHN */
+    /* JOY1 */
+    /* JOY2 */
+
+pub struct Bus {
+    pub internal_ram: [u8; 0x0800],
+    pub io_registers: IORegisters,
+    pub cartridge: Cartridge,
+    pub ppu: NESPpu,
+    pub apu: NESApu,
+    /* What's plugged into each port, the expansion port last (see controller::PORTS) */
+    pub(crate) devices: [Option<Box<dyn InputDevice>>; PORTS],
+    pub(crate) input_poll: Option<InputPoll>,
+    pub breakpoints: Breakpoints,
+    pub cheats: Cheats,
+    pub watches: WatchList,
+    #[cfg(feature = "hooks")]
+    pub hooks: Option<Box<dyn Hooks>>,
+
+    dma_stall: u16,  /* CPU cycles owed to OAM and DMC DMAs, collected by the CPU */
+    pub(crate) oam_dma_remaining: u16,  /* Cycles left of an OAM DMA in progress */
+
+    /* The last value on the CPU's data bus. Nothing drives it for unmapped addresses and
+       write-only registers, so reading those returns this. */
+    pub(crate) open_bus: u8,
+
+    /* The controller read by the instruction in flight. If a DMC fetch halts the
+       CPU mid-read, the read is repeated and a bit is lost (see tick_apu). */
+    pub(crate) joypad_read: Option<usize>,
+
+    region: Region,
+    pal_dot_phase: u8,  /* Position in the PAL 5 CPU cycle, 16 dot cadence */
+
+    /* PPU dots put off until something could tell the difference (see catch_up_ppu),
+       and how many can be before the PPU does something of its own accord */
+    pub(crate) ppu_owed: u32,
+    pub(crate) ppu_deadline: u32,
+}
+
+impl Bus {
+    /// Build the machine around a cartridge
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self {
+            internal_ram: [0; 2048],
+            io_registers: [0; 24],
+            cartridge,
+            ppu: NESPpu::new(),
+            apu: NESApu::new(),
+            devices: [Some(Box::new(Joypad::default())), Some(Box::new(Joypad::default())), None],
+            input_poll: None,
+            breakpoints: Breakpoints::new(),
+            cheats: Cheats::new(),
+            watches: WatchList::new(),
+            #[cfg(feature = "hooks")]
+            hooks: None,
+            dma_stall: 0,
+            oam_dma_remaining: 0,
+            joypad_read: None,
+            open_bus: 0,
+            region: Region::NTSC,
+            pal_dot_phase: 0,
+            ppu_owed: 0,
+            ppu_deadline: 0,
+        }
+    }
+
+    /// Run the PPU, APU and CPU:PPU clock ratio for the given region
+    pub fn set_region(&mut self, region: Region) {
+        self.catch_up_ppu();
+        self.region = region;
+        self.pal_dot_phase = 0;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    /* The devices a read of $4016 (register 0) or $4017 (register 1) reaches: the
+       controller port's, and the expansion port's */
+    fn port_devices(&self, register: usize) -> impl Iterator<Item = &dyn InputDevice> {
+        [register, EXPANSION_PORT].into_iter().filter_map(|port| self.devices[port].as_deref())
+    }
+
+    fn read_devices(&mut self, register: usize) -> u8 {
+        let [port0, port1, expansion] = &mut self.devices;
+        let port = if register == 0 { port0 } else { port1 };
+        [port, expansion].into_iter().flatten().fold(0, |data, device| data | device.read(register))
+    }
+
+    /// Add idle scanlines to each frame (see Nes::set_overclock)
+    pub fn set_overclock(&mut self, overclock: Overclock) {
+        self.catch_up_ppu();
+        self.ppu.set_overclock(overclock);
+        self.ppu_deadline = self.ppu.dots_until_event();
+    }
+
+    /// Advance the APU by one CPU cycle, servicing any DMC sample fetch,
+    /// which halts the CPU (see take_dma_stall)
+    pub fn tick_apu(&mut self) -> Result<(), String> {
+        self.oam_dma_remaining = self.oam_dma_remaining.saturating_sub(1);
+
+        /* Overclocking's extra lines give the CPU time the APU mustn't see, or music would speed up */
+        if self.ppu.overclocking() {
+            return Ok(());
+        }
+
+        self.apu.tick(self.cartridge.audio());
+        if let Some(addr) = self.apu.dmc.pending_fetch() {
+            let data = self.read(addr)?;
+            nes_log!(Trace, DMA, "DMC sample fetch from ${:0>4X}", addr);
+            self.cartridge.log_sample(addr);
+            self.apu.dmc.fill_sample_buffer(data);
+            self.open_bus = data;
+
+            if self.oam_dma_remaining > 0 {
+                self.dma_stall += DMC_DMA_CYCLES_DURING_OAM_DMA;
+            } else {
+                self.dma_stall += DMC_DMA_CYCLES;
+
+                // The halted CPU repeats its read, so a controller being read shifts twice
+                if let Some(register) = self.joypad_read.take() {
+                    self.read_devices(register);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Advance the PPU by one CPU cycle (three dots, or 3.2 on PAL), though perhaps
+    /// not straight away (see catch_up_ppu)
+    pub fn tick_ppu(&mut self) {
+        let dots = match self.region {
+            Region::NTSC | Region::Dendy => 3,
+            Region::PAL => {
+                /* An extra dot every fifth cycle */
+                self.pal_dot_phase = (self.pal_dot_phase + 1) % 5;
+                if self.pal_dot_phase == 0 { 4 } else { 3 }
+            }
+        };
+        self.ppu_owed += dots;
+        if self.ppu_owed >= self.ppu_deadline || !self.can_defer_ppu() {
+            self.catch_up_ppu();
+        }
+    }
+
+    /* Hooks and scanline breakpoints watch the PPU every cycle, so it can't be left behind */
+    fn can_defer_ppu(&self) -> bool {
+        #[cfg(feature = "hooks")]
+        if self.hooks.is_some() {
+            return false;
+        }
+        !self.breakpoints.has_scanline_breakpoints()
+    }
+
+    /// Run the PPU dots put off so far. Rather than step the PPU three dots at a time,
+    /// the bus lets them mount up while nothing can see the difference: until the CPU
+    /// touches a PPU register, OAM DMA or the cartridge (which may switch CHR banks),
+    /// or the PPU begins vblank or completes a frame. Mappers' IRQs here count CPU
+    /// cycles rather than PPU fetches, so they don't need it kept up to date either.
+    /// Anything looking at the PPU from outside should catch it up first (see Nes::catch_up).
+    pub fn catch_up_ppu(&mut self) {
+        let dots = core::mem::take(&mut self.ppu_owed);
+        if dots > 0 {
+            #[cfg(feature = "hooks")]
+            let scanline = self.ppu.scanline;
+            self.ppu.ppu_tick(&mut self.cartridge, dots as usize);
+            #[cfg(feature = "hooks")]
+            if self.ppu.scanline != scanline {
+                let scanline = self.ppu.scanline;
+                self.hook(|hooks| hooks.scanline(scanline));
+            }
+        }
+        self.ppu_deadline = self.ppu.dots_until_event();
+    }
+
+    /// Report an event to any hooks installed
+    #[cfg(feature = "hooks")]
+    pub(crate) fn hook(&mut self, event: impl FnOnce(&mut dyn Hooks)) {
+        if let Some(hooks) = self.hooks.as_deref_mut() {
+            event(hooks);
+        }
+    }
+
+    /// The level of the CPU's IRQ line, which is shared by the APU and the cartridge
+    pub fn irq(&self) -> bool {
+        self.apu.irq() || self.cartridge.irq()
+    }
+
+    /// Whether the PPU has raised an NMI since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+
+    /// Cycles the CPU must idle for DMAs since the last call
+    pub fn take_dma_stall(&mut self) -> u16 {
+        core::mem::take(&mut self.dma_stall)
+    }
+
+    /* Copy a page of CPU memory into OAM ($4014) */
+    fn oam_dma(&mut self, page: u8) -> Result<(), String> {
+        let base = (page as u16) << 8;
+        for offset in 0..256 {
+            let data = self.read_mut(base + offset)?;
+            self.ppu.oam_dma_write(data);
+        }
+        let cycles = OAM_DMA_CYCLES + self.apu.odd_cycle() as u16;
+        nes_log!(Debug, DMA, "OAM DMA from ${:0>4X}, halting the CPU for {} cycles", base, cycles);
+        self.dma_stall += cycles;
+        self.oam_dma_remaining = cycles;
+        Ok(())
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) -> Result<(), String> {
+        self.breakpoints.check_write(addr, data);
+        #[cfg(feature = "hooks")]
+        self.hook(|hooks| hooks.write(addr, data));
+        self.open_bus = data;
+
+        /* Internal RAM */
+        if (addr & 0xF000) < 0x2000 {
+            self.internal_ram[(addr & 0x07FF) as usize] = data;
+        }
+
+        /* PPU control registers */
+        /* TODO - in reality these are PPU mapped and take effect */
+        if (addr & 0xF000) == 0x2000 || (addr & 0xF000) == 0x3000 {
+            self.catch_up_ppu();
+            self.ppu.ppu_register_write(&mut self.cartridge, 0x2000 + (addr & 0x7), data)?;
+        }
+
+        /* APU and I/O */
+        if (0x4000..=0x4017).contains(&addr) {
+            if addr == 0x4016 {
+                // Every device sees the OUT lines, the controllers' strobe among them.
+                // Raising it reloads their shift registers with the current button
+                // state, which is then shifted out once it falls.
+                if data & 0x1 == 0x1 {
+                    if let Some(poll) = &mut self.input_poll {
+                        for (port, device) in self.devices.iter_mut().enumerate().take(2) {
+                            if let Some(device) = device {
+                                device.set_input(DeviceInput::Buttons(poll(port)));
+                            }
+                        }
+                    }
+                }
+                self.devices.iter_mut().flatten().for_each(|device| device.write(data & 0x7));
+            }
+            if addr == 0x4014 {
+                self.catch_up_ppu();
+                self.oam_dma(data)?;
+            }
+            if addr != 0x4014 && addr != 0x4016 {
+                self.apu.write_register(addr, data);
+            }
+            self.io_registers[(addr - 0x4000) as usize] = data;
+        }
+
+        /* Any address 0x4020 - 0xFFFF is handled by the cartridge */
+        if addr >= 0x4020 {
+            /* Leaving out PRG RAM, which is written too often to be of interest */
+            if !(0x6000..0x8000).contains(&addr) {
+                nes_log!(Trace, MAPPER, "${:0>4X} = ${:0>2X}", addr, data);
+            }
+            /* Only the mapper's registers can change what the PPU sees, so only they need it up to date */
+            if self.cartridge.decodes_write(addr) {
+                self.catch_up_ppu();
+            }
+            self.cartridge.cpu_write(addr, data);
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
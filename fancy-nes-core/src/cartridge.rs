@@ -0,0 +1,312 @@
+//! The cartridge: its PRG and CHR memory, and the mapper which decides what of
+//! that memory the CPU and PPU see where. There is one mapper for both sides of
+//! the machine, as there is one board, so that CPU writes can switch CHR banks
+//! or mirroring, and PPU fetches can switch banks or clock an IRQ counter.
+//!
+//! The bus owns the cartridge, and lends it to the PPU for each access the PPU makes.
+
+use core::ops::{Range, RangeFrom};
+
+use crate::prelude::*;
+use crate::{Mirroring, NESHeaderMetadata};
+use crate::cdl::{CodeDataLog, CHR_DRAWN, CHR_READ};
+use crate::cpu::mapper::Mapper;
+use crate::cpu::mapper_util::{ChrMemory, PrgRom};
+use crate::cpu::registry::{self, MapperInfo};
+use crate::error::NesError;
+use crate::nametable::NametableMapping;
+use crate::state::{StateReader, StateWriter};
+
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+
+/// Where each part of an iNES image lies, as its header describes: the header, then
+/// the trainer if there is one, PRG ROM, CHR ROM, and anything else (NES 2.0's
+/// miscellaneous ROMs, or junk left by the dumper) to the end of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomLayout {
+    pub trainer: Range<usize>,  /* Empty if there's none */
+    pub prg: Range<usize>,
+    pub chr: Range<usize>,
+    pub misc: RangeFrom<usize>,
+}
+
+impl RomLayout {
+    pub fn of(header: &NESHeaderMetadata) -> Self {
+        let trainer = HEADER_SIZE..HEADER_SIZE + if header.has_trainer { TRAINER_SIZE } else { 0 };
+        let prg = trainer.end..trainer.end + header.prg_rom_size as usize;
+        let chr = prg.end..prg.end + header.chr_rom_size as usize;
+        Self { misc: chr.end.., trainer, prg, chr }
+    }
+}
+
+/// Everything on the cartridge the mapper maps into the CPU's and PPU's address
+/// spaces. Mappers switch banks and mirroring by changing it directly.
+pub struct Memory {
+    pub prg_rom: PrgRom,
+    pub prg_ram: Vec<u8>,  /* Work RAM at $6000-$7FFF, empty if the board has none */
+    pub chr: ChrMemory,
+    pub nametables: NametableMapping,
+}
+
+pub struct Cartridge {
+    memory: Memory,
+    mapper: Box<dyn Mapper>,
+    cdl: Option<CodeDataLog>,  /* How the ROM has been used, if it's being logged */
+}
+
+impl Cartridge {
+    /// Build the cartridge an iNES or NES 2.0 image describes, checking that the mapper is
+    /// supported, the ROM sizes suit the board, and the file holds all the header says it does
+    pub fn from_ines(rom: &[u8]) -> Result<Self, NesError> {
+        if rom.len() < HEADER_SIZE {
+            return Err(NesError::Truncated { expected: HEADER_SIZE, found: rom.len() });
+        }
+        let header = NESHeaderMetadata::parse_header(rom).map_err(|e| NesError::Rom(e.to_string()))?;
+        let board = registry::lookup(header.mapper_id).map_err(|_| NesError::UnsupportedMapper(header.mapper_id))?;
+
+        /* Check the ROM sizes suit the board up front, rather than panicking in the mapper */
+        let prg_ok = header.prg_rom_size.is_multiple_of(16384) && (board.prg_ok)(header.prg_rom_size as usize / 16384);
+        let chr_ok = (board.chr_ok)(header.chr_rom_size);
+        if !prg_ok || !chr_ok {
+            return Err(NesError::Rom(format!("Unsupported ROM size for mapper {}: {} bytes PRG, {} bytes CHR",
+                header.mapper_id, header.prg_rom_size, header.chr_rom_size)));
+        }
+
+        /* Miscellaneous ROMs are only found on boards not supported, so they're left be */
+        let layout = RomLayout::of(&header);
+        if rom.len() < layout.chr.end {
+            return Err(NesError::Truncated { expected: layout.chr.end, found: rom.len() });
+        }
+
+        let mut cartridge = Cartridge::new(board, header.mapper_id as usize, header.submapper_id, header.hardwired_mirroring,
+            &rom[layout.prg], &rom[layout.chr]);
+        /* NES 2.0 headers say how much work RAM there is, where iNES leaves it to the board's usual */
+        if header.is_nes2 {
+            cartridge.resize_work_ram((header.prg_ram_size + header.prg_nvram_size) as usize);
+        }
+        if header.has_trainer {
+            cartridge.load_trainer(&rom[layout.trainer]);
+        }
+        Ok(cartridge)
+    }
+
+    /// Build a cartridge for a mapper, with the ROM it's given inserted. The
+    /// mirroring is the header's, for mappers which don't control it.
+    pub fn new(info: &MapperInfo, mapper_id: usize, submapper_id: u8, mirroring: Mirroring, prg: &[u8], chr: &[u8]) -> Self {
+        let mut memory = Memory {
+            prg_rom: PrgRom::new(info.prg_bank_size),
+            prg_ram: vec![0; info.prg_ram_size],
+            chr: ChrMemory::new(info.chr_bank_size),
+            nametables: NametableMapping::new(mirroring),
+        };
+        memory.prg_rom.load(prg);
+        memory.chr.load(chr);
+
+        let mut mapper = (info.build)(mapper_id, submapper_id);
+        mapper.power_on(&mut memory);
+        Self { memory, mapper, cdl: None }
+    }
+
+    /// Whether the cartridge answers CPU reads of an address at all. Where it
+    /// doesn't, the CPU sees open bus.
+    pub fn decodes(&self, addr: u16) -> bool {
+        addr >= 0x8000 || (addr >= 0x6000 && !self.memory.prg_ram.is_empty())
+    }
+
+    /// Whether the mapper has a register at an address, which a CPU write there
+    /// would set
+    pub fn decodes_write(&self, addr: u16) -> bool {
+        self.mapper.decodes_write(addr)
+    }
+
+    /// A CPU read from $4020-$FFFF, without side-effects
+    pub fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.memory.prg_ram.get(addr as usize - 0x6000).copied().unwrap_or(0),
+            0x8000..=0xFFFF => self.memory.prg_rom.read(addr),
+            _ => 0,
+        }
+    }
+
+    /// Where in PRG ROM a CPU read of an address comes from, if it's from ROM at all
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= 0x8000 { Some(self.memory.prg_rom.offset(addr)) } else { None }
+    }
+
+    /// A CPU write to $4020-$FFFF: to work RAM, and then to the mapper, whose
+    /// registers may sit anywhere (even over the RAM)
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if let Some(byte) = self.memory.prg_ram.get_mut(addr as usize - 0x6000) {
+                *byte = data;
+            }
+        }
+        self.mapper.write(&mut self.memory, addr, data);
+    }
+
+    /// A PPU read of CHR at $0000-$1FFF, or of the nametables at $2000-$3EFF.
+    /// Nametables are in the console's VRAM, so are given as the word 0x1***,
+    /// where *** is the address in VRAM the mirroring maps them to.
+    #[inline]
+    pub fn ppu_read(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x1FFF => self.memory.chr.read(addr) as u16,
+            0x2000..=0x2FFF => self.memory.nametables.read(addr),
+            0x3000..=0x3EFF => self.memory.nametables.read(addr - 0x1000),  /* Mirrors $2000-$2EFF */
+            _ => unreachable!(),
+        }
+    }
+
+    /// A PPU write to CHR, or to the nametables, as for `ppu_read`
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> Result<u16, String> {
+        match addr {
+            0x0000..=0x1FFF => {
+                self.memory.chr.write(addr, data);
+                Ok(0)
+            }
+            0x2000..=0x2FFF => Ok(self.memory.nametables.write(addr, data)),
+            0x3000..=0x3EFF => Ok(self.memory.nametables.write(addr - 0x1000, data)),
+            _ => Err(format!("PPU write attempted at invalid address: ${:X}", addr)),
+        }
+    }
+
+    /// Each pattern table fetch the PPU makes while rendering, after it's been read
+    #[inline]
+    pub fn pattern_fetch(&mut self, addr: u16) {
+        if let Some(cdl) = &mut self.cdl {
+            cdl.chr_access(self.memory.chr.offset(addr), CHR_DRAWN);
+        }
+        self.mapper.pattern_fetch(&mut self.memory, addr);
+    }
+
+    /// Once every CPU cycle
+    pub fn clock(&mut self) {
+        self.mapper.clock();
+    }
+
+    /// Whether the cartridge is holding the CPU's IRQ line
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    /// The output of the cartridge's expansion sound, if any, to mix with the APU's
+    pub fn audio(&self) -> f32 {
+        self.mapper.audio()
+    }
+
+    /// Work RAM, if the board has any, for battery saves to be read and restored through
+    pub fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.memory.prg_ram[..]).filter(|ram| !ram.is_empty())
+    }
+
+    /// Work RAM to look at, empty if the board has none
+    pub fn work_ram(&self) -> &[u8] {
+        &self.memory.prg_ram
+    }
+
+    /// Give the board this much work RAM rather than its usual, up to the 8KiB at $6000-$7FFF
+    pub fn resize_work_ram(&mut self, size: usize) {
+        self.memory.prg_ram = vec![0; size.min(0x2000)];
+    }
+
+    /// Put a trainer in work RAM at $7000-$71FF, where the copier it was dumped for loaded it.
+    /// A board without the RAM for it is given 8KiB, as the copier had.
+    pub fn load_trainer(&mut self, trainer: &[u8]) {
+        if self.memory.prg_ram.len() < 0x1000 + trainer.len() {
+            self.resize_work_ram(0x2000);
+        }
+        self.memory.prg_ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+    }
+
+    pub fn prg_rom_size(&self) -> usize {
+        self.memory.prg_rom.size()
+    }
+
+    /// The size of CHR ROM, or 0 for CHR RAM
+    pub fn chr_rom_size(&self) -> usize {
+        if self.memory.chr.is_ram() { 0 } else { self.memory.chr.size() }
+    }
+
+    /// Changes whenever the pattern tables do, by a write to CHR RAM or a bank switch
+    pub fn chr_changes(&self) -> u32 {
+        self.memory.chr.changes()
+    }
+
+    /// Log how the ROM is used from here on (see the cdl module), or stop with None
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cdl = cdl;
+    }
+
+    pub fn take_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take()
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    /* The code/data log's instrumentation, called from the CPU's side */
+    pub(crate) fn log_instruction(&mut self, addr: u16, len: u16, indirect_data: bool, indirect_jump: bool) {
+        if let Some(cdl) = &mut self.cdl {
+            let prg_rom = &self.memory.prg_rom;
+            let offset = |addr: u16| if addr >= 0x8000 { Some(prg_rom.offset(addr)) } else { None };
+            cdl.instruction(addr, len, offset, indirect_data, indirect_jump);
+        }
+    }
+
+    pub(crate) fn log_interrupt(&mut self) {
+        if let Some(cdl) = &mut self.cdl {
+            cdl.interrupt();
+        }
+    }
+
+    pub(crate) fn log_read(&mut self, addr: u16) {
+        if let Some(cdl) = &mut self.cdl {
+            cdl.read(addr, if addr >= 0x8000 { Some(self.memory.prg_rom.offset(addr)) } else { None });
+        }
+    }
+
+    pub(crate) fn log_sample(&mut self, addr: u16) {
+        if let Some(cdl) = &mut self.cdl {
+            cdl.sample(addr, if addr >= 0x8000 { Some(self.memory.prg_rom.offset(addr)) } else { None });
+        }
+    }
+
+    /* A read of CHR through PPUDATA */
+    pub(crate) fn log_chr_read(&mut self, addr: u16) {
+        if let Some(cdl) = &mut self.cdl {
+            if addr < 0x2000 {
+                cdl.chr_access(self.memory.chr.offset(addr), CHR_READ);
+            }
+        }
+    }
+
+    /// What's mapped where, e.g. for crash reports
+    pub fn describe_banks(&self) -> String {
+        let mut description = format!("{}, {}, nametables: {:?}",
+            self.memory.prg_rom.describe(), self.memory.chr.describe(), self.memory.nametables.mirroring());
+        let registers = self.mapper.describe();
+        if !registers.is_empty() {
+            description = format!("{}, {}", description, registers);
+        }
+        description
+    }
+
+    /// Save states only cover mutable state (RAM, banks and registers), never the ROM itself
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.memory.prg_rom.save_state(w);
+        w.write_bytes(&self.memory.prg_ram);
+        self.memory.chr.save_state(w);
+        self.memory.nametables.save_state(w);
+        self.mapper.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.memory.prg_rom.load_state(r)?;
+        r.read_into(&mut self.memory.prg_ram)?;
+        self.memory.chr.load_state(r)?;
+        self.memory.nametables.load_state(r)?;
+        self.mapper.load_state(r)
+    }
+}
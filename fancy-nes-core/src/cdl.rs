@@ -0,0 +1,131 @@
+//! Code/Data Logging, in FCEUX's .cdl format, for ROM hackers and disassemblers:
+//! a byte of flags for each byte of PRG ROM, saying whether it was executed
+//! or read as data as the game ran, then a byte for each byte of CHR ROM,
+//! saying whether it was drawn or read through PPUDATA.
+//!
+//! PRG flags are xPdcAADC:
+//!   C   executed as code
+//!   D   read as data
+//!   AA  which 8KiB window of $8000-$FFFF it was last seen through
+//!   c   the destination of an indirect JMP
+//!   d   read by an indirect (zp,X) or (zp),Y instruction
+//!   P   played as a DMC sample
+//! CHR flags are ------RD: drawn by the PPU, and read by the CPU.
+//!
+//! The cartridge keeps the log, as it knows which bank of ROM is where.
+
+use crate::prelude::*;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+pub const PCM: u8 = 0x40;
+
+pub const CHR_DRAWN: u8 = 0x01;
+pub const CHR_READ: u8 = 0x02;
+
+#[derive(Debug, Clone)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,  /* Empty for CHR RAM, whose contents are the game's own */
+
+    fetch: Option<(u16, u16)>,  /* The instruction executing, whose opcode fetch isn't a data read */
+    indirect_data: bool,        /* The instruction executing reads through a pointer */
+    indirect_code: bool,        /* The last instruction was an indirect JMP */
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        Self {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+            fetch: None,
+            indirect_data: false,
+            indirect_code: false,
+        }
+    }
+
+    /// Carry on from a .cdl file saved before, for a ROM of these sizes
+    pub fn from_bytes(data: &[u8], prg_size: usize, chr_size: usize) -> Result<Self, String> {
+        if data.len() != prg_size + chr_size {
+            return Err(format!("The log is {} bytes, but the ROM has {} bytes of PRG and {} of CHR",
+                data.len(), prg_size, chr_size));
+        }
+        let mut log = Self::new(prg_size, chr_size);
+        log.prg.copy_from_slice(&data[..prg_size]);
+        log.chr.copy_from_slice(&data[prg_size..]);
+        Ok(log)
+    }
+
+    /// The .cdl file: PRG flags, then CHR flags
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    /// How much of the ROM has been seen used, e.g. "PRG: 41.2% code, 12.0% data; CHR: 63.5% drawn"
+    pub fn summary(&self) -> String {
+        let percent = |log: &[u8], flags: u8| 100.0 * log.iter().filter(|&&f| f & flags != 0).count() as f64 / log.len().max(1) as f64;
+        let mut summary = format!("PRG: {:.1}% code, {:.1}% data", percent(&self.prg, CODE), percent(&self.prg, DATA | PCM));
+        if !self.chr.is_empty() {
+            summary += &format!("; CHR: {:.1}% drawn, {:.1}% read", percent(&self.chr, CHR_DRAWN), percent(&self.chr, CHR_READ));
+        }
+        summary
+    }
+
+    /* The window bits of a PRG flag byte */
+    fn window(addr: u16) -> u8 {
+        ((addr >> 13) & 3) as u8 * 4
+    }
+
+    pub(crate) fn instruction(&mut self, addr: u16, len: u16, offset: impl Fn(u16) -> Option<usize>,
+        indirect_data: bool, indirect_jump: bool) {
+        let indirect = if core::mem::take(&mut self.indirect_code) { INDIRECT_CODE } else { 0 };
+        for i in 0..len {
+            let addr = addr.wrapping_add(i);
+            if let Some(flags) = offset(addr).and_then(|offset| self.prg.get_mut(offset)) {
+                *flags = (*flags & !0x0C) | CODE | Self::window(addr) | if i == 0 { indirect } else { 0 };
+            }
+        }
+        self.fetch = Some((addr, len));
+        self.indirect_data = indirect_data;
+        self.indirect_code = indirect_jump;
+    }
+
+    /// An interrupt is being taken, rather than an instruction executed
+    pub(crate) fn interrupt(&mut self) {
+        self.fetch = None;
+        self.indirect_data = false;
+        self.indirect_code = false;
+    }
+
+    pub(crate) fn read(&mut self, addr: u16, offset: Option<usize>) {
+        if self.fetch.is_some_and(|(start, len)| addr.wrapping_sub(start) < len) {
+            return;
+        }
+        let indirect = if self.indirect_data { INDIRECT_DATA } else { 0 };
+        if let Some(flags) = offset.and_then(|offset| self.prg.get_mut(offset)) {
+            *flags = (*flags & !0x0C) | DATA | indirect | Self::window(addr);
+        }
+    }
+
+    pub(crate) fn sample(&mut self, addr: u16, offset: Option<usize>) {
+        if let Some(flags) = offset.and_then(|offset| self.prg.get_mut(offset)) {
+            *flags = (*flags & !0x0C) | PCM | Self::window(addr);
+        }
+    }
+
+    pub(crate) fn chr_access(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.chr.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+}
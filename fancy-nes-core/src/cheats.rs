@@ -0,0 +1,217 @@
+//! Cheats - substitute values for CPU reads, as a Game Genie does.
+//!
+//! Game Genie codes patch cartridge ROM ($8000-$FFFF), optionally only when
+//! the ROM holds an expected value (so that the patch only hits the intended
+//! bank). RAM cheats, written "AAAA:VV" in hex, freeze a RAM address at a value.
+//! Both are applied by the Bus as reads are made, leaving memory untouched.
+
+use core::fmt;
+use crate::prelude::*;
+
+const GAME_GENIE_LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Patch {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,  /* Only substitute when this value would have been read */
+}
+
+impl Patch {
+    /// Decode a 6 or 8 letter Game Genie code, or a "AAAA:VV" RAM freeze
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let code = code.trim().to_ascii_uppercase();
+
+        if let Some((addr, value)) = code.split_once(':') {
+            let addr = u16::from_str_radix(addr.trim_start_matches('$'), 16).map_err(|_| format!("Bad address in cheat {}", code))?;
+            let value = u8::from_str_radix(value.trim_start_matches('$'), 16).map_err(|_| format!("Bad value in cheat {}", code))?;
+            if addr >= 0x2000 && !(0x6000..=0x7FFF).contains(&addr) {
+                return Err(format!("Cheat {} is not a RAM address", code));
+            }
+            return Ok(Self { addr, value, compare: None });
+        }
+
+        let n: Vec<u16> = code.bytes()
+            .map(|c| GAME_GENIE_LETTERS.iter().position(|&l| l == c).map(|n| n as u16))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("Bad Game Genie code {}", code))?;
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie codes are 6 or 8 letters: {}", code));
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8) | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4) | ((n[1] & 8) << 4)
+            | (n[4] & 7) | (n[3] & 8);
+
+        /* The last letter's top bit completes the value, or in 8 letter codes, the compare value */
+        let last = if n.len() == 6 { n[5] } else { n[7] };
+        let value = (((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7) | (last & 8)) as u8;
+        let compare = match n.len() {
+            8 => Some((((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8)) as u8),
+            _ => None,
+        };
+
+        Ok(Self { addr, value, compare })
+    }
+
+    fn apply(&self, addr: u16, data: u8) -> Option<u8> {
+        if addr == self.addr && self.compare.is_none_or(|c| c == data) {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:0>4X}={:0>2X}", self.addr, self.value)?;
+        if let Some(compare) = self.compare {
+            write!(f, "?{:0>2X}", compare)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cheat {
+    pub id: u32,
+    pub code: String,  /* As entered */
+    pub patch: Patch,
+    pub enabled: bool,
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    list: Vec<Cheat>,
+    next_id: u32,
+
+    /* Every read is checked, so keep track of whether we need to look at all */
+    active: bool,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a cheat from a Game Genie code or RAM freeze, returning its id
+    pub fn add(&mut self, code: &str) -> Result<u32, String> {
+        let mut patch = Patch::parse(code)?;
+        if patch.addr < 0x2000 {
+            /* Internal RAM is mirrored four times, so freeze the underlying cell */
+            patch.addr &= 0x07FF;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Cheat { id, code: code.trim().to_ascii_uppercase(), patch, enabled: true });
+        self.update_active();
+        Ok(id)
+    }
+
+    /// Returns false if there was no such cheat
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|c| c.id != id);
+        self.update_active();
+        self.list.len() != len
+    }
+
+    /// Enable or disable a cheat, returning its new state
+    pub fn toggle(&mut self, id: u32) -> Option<bool> {
+        let cheat = self.list.iter_mut().find(|c| c.id == id)?;
+        cheat.enabled = !cheat.enabled;
+        let enabled = cheat.enabled;
+        self.update_active();
+        Some(enabled)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    fn update_active(&mut self) {
+        self.active = self.list.iter().any(|c| c.enabled);
+    }
+
+    /// The value the CPU should see when reading `data` from `addr`
+    pub fn apply(&self, addr: u16, data: u8) -> u8 {
+        if !self.active {
+            return data;
+        }
+        let addr = if addr < 0x2000 { addr & 0x07FF } else { addr };
+        self.list.iter()
+            .filter(|c| c.enabled)
+            .find_map(|c| c.patch.apply(addr, data))
+            .unwrap_or(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_six_letter_codes() {
+        /* Super Mario Bros.' infinite lives */
+        let patch = Patch::parse("SXIOPO").unwrap();
+        assert_eq!(patch, Patch { addr: 0x91D9, value: 0xAD, compare: None });
+        assert_eq!(Patch::parse(" sxiopo ").unwrap(), patch);
+        assert_eq!(patch.to_string(), "$91D9=AD");
+    }
+
+    #[test]
+    fn decodes_eight_letter_codes() {
+        /* NESdev's worked example */
+        let patch = Patch::parse("ZEXPYGLA").unwrap();
+        assert_eq!(patch, Patch { addr: 0x94A7, value: 0x02, compare: Some(0x03) });
+        assert_eq!(patch.to_string(), "$94A7=02?03");
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        assert!(Patch::parse("SXIOP").is_err());
+        assert!(Patch::parse("SXIOPOP").is_err());
+        assert!(Patch::parse("SXIOPB").is_err());
+        assert!(Patch::parse("").is_err());
+    }
+
+    #[test]
+    fn only_freezes_ram() {
+        assert_eq!(Patch::parse("0300:09").unwrap(), Patch { addr: 0x0300, value: 0x09, compare: None });
+        assert_eq!(Patch::parse("$6000:$01").unwrap(), Patch { addr: 0x6000, value: 0x01, compare: None });
+        assert!(Patch::parse("7FFF:FF").is_ok());
+        assert!(Patch::parse("1FFF:00").is_ok());
+        assert!(Patch::parse("2000:00").is_err());
+        assert!(Patch::parse("5FFF:00").is_err());
+        assert!(Patch::parse("8000:00").is_err());
+        assert!(Patch::parse("0300:100").is_err());
+        assert!(Patch::parse("G300:00").is_err());
+    }
+
+    #[test]
+    fn applies_compare_values() {
+        let mut cheats = Cheats::new();
+        cheats.add("ZEXPYGLA").unwrap();
+        assert_eq!(cheats.apply(0x94A7, 0x03), 0x02);
+        assert_eq!(cheats.apply(0x94A7, 0x04), 0x04);
+        assert_eq!(cheats.apply(0x94A8, 0x03), 0x03);
+    }
+
+    #[test]
+    fn freezes_ram_through_its_mirrors() {
+        let mut cheats = Cheats::new();
+        let id = cheats.add("0800:05").unwrap();
+        assert_eq!(cheats.apply(0x0000, 0x01), 0x05);
+        assert_eq!(cheats.apply(0x1800, 0x01), 0x05);
+        assert_eq!(cheats.toggle(id), Some(false));
+        assert_eq!(cheats.apply(0x0000, 0x01), 0x01);
+    }
+}
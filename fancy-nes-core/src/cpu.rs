@@ -1,24 +1,39 @@
-use std::cell::RefCell;
-use std::ops::Add;
-use std::rc::Rc;
-
 use bitflags::bitflags;
 
-use crate::Mirroring;
-use crate::cpu::debug::disasm_6502;
+use crate::prelude::*;
+use crate::bus::{Bus, MemoryRead};
+use crate::state::{StateReader, StateWriter};
+use crate::debugger::TempBreak;
+#[cfg(feature = "hooks")]
+use crate::hooks::Interrupt;
+use crate::cpu::history::{History, HistoryEntry};
+
+use self::decode::{LUT_6502, Instruction, Operation};
 
-use self::decode::{LUT_6502, Instruction};
-use self::mapper000::CPUMapper000;
-use self::mem::*;
 
+pub mod controller;
 pub mod decode;
 pub mod debug;
-pub mod mem;
+pub mod expansion;
+pub mod history;
+#[cfg(feature = "std")]
+pub mod profile;
 pub mod trace;
 
 // Mappers
 pub mod mapper;
+pub mod mapper_util;
 pub mod mapper000;
+pub mod mapper002;
+pub mod mapper003;
+pub mod mapper007;
+pub mod mapper009;
+pub mod mapper011;
+pub mod mapper021;
+pub mod mapper024;
+pub mod mapper034;
+pub mod mapper066;
+pub mod registry;
 
 /* The BREAK flag(s) is only applicable when the
    status register is pushed to the stack. 
@@ -64,7 +79,19 @@ pub enum AddressingMode {
     IndirectIndexed,
 }
 
-pub struct NESCpu<'a> {
+impl AddressingMode {
+    /// The length of an instruction in this mode, opcode included
+    pub fn instruction_len(&self) -> u16 {
+        use AddressingMode::*;
+        match self {
+            Implied | Accumulator => 1,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | Relative | IndexedIndirect | IndirectIndexed => 2,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 3,
+        }
+    }
+}
+
+pub struct NESCpu {
     pub status: StatusRegister,
     pub PC: u16,    /* program counter */
     pub SP: u8,     /* stack pointer */
@@ -73,60 +100,210 @@ pub struct NESCpu<'a> {
     pub Y: u8,      /* index register Y */
 
     /* instructions */
-    pub wait_cycles: u8,      /* pending wait cycles */
+    pub wait_cycles: u16,     /* pending wait cycles */
     pc_skip: u16,     /* how many bytes to advance the PC by for a given instr. */
 
-    pub memory: CPUMemory<'a>,
+    pub bus: Bus,
 
     pub last_legal_instruction: Option<u16>,
-    pub do_nmi: bool,
-
-    pub cycle: u32,
+    history: History,
+    pub do_nmi: bool,  /* An NMI edge has been seen, and not yet serviced */
+
+    /* Interrupts are polled on each instruction's penultimate cycle (see tick),
+       and serviced once it has finished */
+    polling: bool,           /* Whether the CPU is running an instruction, rather than an interrupt sequence */
+    poll_irq_inhibit: bool,  /* The I flag as the poll sees it - CLI, SEI and PLP change it too late */
+    nmi_polled: bool,
+    irq_polled: bool,
+
+    /* An interrupt sequence (or BRK) fetches its vector on its last two cycles, so an
+       NMI arriving before then hijacks it. This is the vector it will fetch. */
+    vector_fetch: Option<u16>,
+
+    /* Instructions run all at once on their first cycle, but make their operand accesses
+       on the cycle they would on hardware (see read_on). The rest of the machine is run
+       ahead to meet those, and these track the cycles already run. */
+    instr_cycles: u16,  /* Base length of the instruction in flight */
+    ahead: u16,         /* Cycles of the rest of the machine already run */
+    nmi_ahead: u32,     /* Which of those cycles noticed an NMI, the next in bit 0 */
+
+    /* Refuse to execute undocumented opcodes, for debugging homebrew */
+    pub strict_opcodes: bool,
+
+    pub cycle: u64,  /* CPU cycles since power on, as traces count them */
 }
 
-impl<'a> NESCpu<'a> {
-    pub fn new(mapper_id: usize, joy1_in: &'a RefCell<u8>) -> Self {
+impl NESCpu {
+    pub fn new(bus: Bus) -> Self {
         Self {
             status: StatusRegister::empty(),
             PC: 0, /* given a correct value from the reset method  */
-            SP: 0, /* given a correct value by the ROM's init code */
+            SP: 0, /* the reset sequence takes 3 from this, leaving $FD */
             A: 0,
             X: 0,
             Y: 0,
             wait_cycles: 0,
             pc_skip: 0,
-            memory: CPUMemory {
-                internal_ram: [0; 2048],
-                ppu_registers: None,  // Begin with PPU detached completely detached from the CPU's address space
-                io_registers: [0; 24],
-                mapper: Box::new(
-                    match mapper_id {
-                        0 => {
-                            CPUMapper000::new()
-                        }
-                        _ => panic!("Unimplemented mapper: {}", mapper_id)
-                    }
-                ),
-                joy1_in,
-                joy_freeze: false,
-            },
+            bus,
             last_legal_instruction: None,
+            history: History::new(),
             do_nmi: false,
+            polling: false,
+            poll_irq_inhibit: true,
+            nmi_polled: false,
+            irq_polled: false,
+            vector_fetch: None,
+            instr_cycles: 0,
+            ahead: 0,
+            nmi_ahead: 0,
+            strict_opcodes: false,
             cycle: 0,
         }
     }
 
     pub fn tick(&mut self) -> Result<(), String> {
-        #[cfg(debug_assertions)]
-        {
-            self.cycle += 1;
+        self.cycle += 1;
+
+        /* Service an interrupt noticed by the last instruction, NMI taking priority */
+        if self.wait_cycles == 0 {
+            if core::mem::take(&mut self.nmi_polled) {
+                self.do_nmi = false;
+                self.irq_polled = false;
+                #[cfg(feature = "hooks")]
+                self.bus.hook(|hooks| hooks.interrupt(Interrupt::Nmi));
+                self.nmi()?;
+            } else if core::mem::take(&mut self.irq_polled) {
+                #[cfg(feature = "hooks")]
+                self.bus.hook(|hooks| hooks.interrupt(Interrupt::Irq));
+                self.irq()?;
+            }
+        }
+
+        self.execute()?;
+
+        if self.wait_cycles == 1 {
+            if let Some(vector) = self.vector_fetch.take() {
+                let vector = if vector != 0xFFFA && self.do_nmi {
+                    self.do_nmi = false;
+                    0xFFFA
+                } else {
+                    vector
+                };
+                self.PC = self.bus.read_16_mut(vector)?;
+            }
+        }
+
+        /* Run the rest of the machine for this cycle, unless the instruction already has */
+        let nmi = if self.ahead > 0 {
+            self.ahead -= 1;
+            let nmi = self.nmi_ahead & 1 != 0;
+            self.nmi_ahead >>= 1;
+            nmi
+        } else {
+            self.clock_machine()?
+        };
+        if nmi {
+            self.do_nmi = true;
         }
 
-        /* NMI takes priority */
-        if self.do_nmi {
-            self.nmi();
-            self.do_nmi = false;
+        /* Interrupts arriving after the poll wait until the end of the next instruction */
+        if self.wait_cycles == 1 && self.polling {
+            self.nmi_polled = self.do_nmi;
+            self.irq_polled = self.bus.irq() && !self.poll_irq_inhibit;
         }
+        Ok(())
+    }
+
+    /// Whether the next cycle begins taking an interrupt, rather than an instruction.
+    /// Only meaningful between instructions.
+    pub fn interrupt_pending(&self) -> bool {
+        self.nmi_polled || self.irq_polled
+    }
+
+    /* One CPU cycle of everything but the CPU. Returns whether an NMI was noticed. */
+    fn clock_machine(&mut self) -> Result<bool, String> {
+        /* An NMI raised by the PPU during the last cycle is only noticed now, so that an
+           instruction reading PPUSTATUS just as vblank began can still suppress it */
+        let nmi = self.bus.take_nmi();
+
+        /* The PPU runs three dots to each CPU cycle */
+        self.bus.tick_ppu();
+
+        /* The APU shares the CPU's clock, and its DMC fetches halt the CPU */
+        self.bus.tick_apu()?;
+        self.wait_cycles += self.bus.take_dma_stall();
+
+        /* So does the cartridge, for mappers with a cycle counting IRQ */
+        self.bus.cartridge.clock();
+        Ok(nmi)
+    }
+
+    /* Run the rest of the machine up to the given cycle of the current instruction (the
+       opcode fetch being cycle 1), so that an access to addr happens on the right PPU dot.
+       RAM has no timing to respect, so isn't waited for. */
+    fn run_ahead_to(&mut self, cycle: u16, addr: u16) -> Result<(), String> {
+        if addr < 0x2000 {
+            return Ok(());
+        }
+        while self.ahead + 1 < cycle {
+            if self.clock_machine()? {
+                self.nmi_ahead |= 1 << self.ahead;
+            }
+            self.ahead += 1;
+        }
+        Ok(())
+    }
+
+    /* An operand read, made on the given cycle of the instruction */
+    fn read_on(&mut self, cycle: u16, addr: u16) -> Result<u8, String> {
+        self.run_ahead_to(cycle, addr)?;
+        self.bus.read_mut(addr)
+    }
+
+    /* An operand write, made on the given cycle of the instruction */
+    fn write_on(&mut self, cycle: u16, addr: u16, data: u8) -> Result<(), String> {
+        self.run_ahead_to(cycle, addr)?;
+        self.bus.write(addr, data)
+    }
+
+    fn indexed(mode: &AddressingMode) -> bool {
+        matches!(mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectIndexed)
+    }
+
+    /* Indexed modes add the index to the address's low byte first, and read from there
+       while fixing up the high byte a cycle later. Reads which didn't cross a page can
+       finish early, skipping the fix-up; stores and read-modify-writes always wait for
+       it, so always make the dummy read. */
+    fn dummy_read(&mut self, cycle: u16, mode: &AddressingMode, addr: u16, page_cross: bool) -> Result<(), String> {
+        if Self::indexed(mode) {
+            let uncorrected = if page_cross { addr.wrapping_sub(0x100) } else { addr };
+            self.read_on(cycle, uncorrected)?;
+        }
+        Ok(())
+    }
+
+    /* An operand read, on the instruction's last cycle, or the one after if indexing
+       crossed a page */
+    fn read_operand(&mut self, mode: &AddressingMode, addr: u16, page_cross: bool) -> Result<u8, String> {
+        if Self::indexed(mode) && page_cross {
+            self.dummy_read(self.instr_cycles, mode, addr, page_cross)?;
+            self.wait_cycles += 1;
+            return self.read_on(self.instr_cycles + 1, addr);
+        }
+        self.read_on(self.instr_cycles, addr)
+    }
+
+    /* Read-modify-write instructions read on their third last cycle, then write the value
+       back unchanged while they modify it, before writing the result on their last */
+    fn read_for_modify(&mut self, mode: &AddressingMode, addr: u16, page_cross: bool) -> Result<u8, String> {
+        self.dummy_read(self.instr_cycles - 3, mode, addr, page_cross)?;
+        let data = self.read_on(self.instr_cycles - 2, addr)?;
+        self.write_on(self.instr_cycles - 1, addr, data)?;
+        Ok(data)
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        use Operation::*;
 
         /* If there are outstanding wait cycles, do nothing */
         if self.wait_cycles > 0 {
@@ -135,91 +312,204 @@ impl<'a> NESCpu<'a> {
         }
 
         /* Fetch stage */
-        let op = self.memory.read_mut(self.PC);
-        let instr_opt = LUT_6502.get(&op);
-        let instr: &Instruction;
-
-        if instr_opt.is_none() {
-            return Err(format!("Instruction not recognised: {:X}", op));
+        self.bus.joypad_read = None;
+        let op = self.bus.read_mut(self.PC)?;
+        self.history.push(HistoryEntry {
+            pc: self.PC,
+            opcode: op,
+            operands: [self.bus.read(self.PC.wrapping_add(1)).unwrap_or_default(),
+                       self.bus.read(self.PC.wrapping_add(2)).unwrap_or_default()],
+            a: self.A,
+            x: self.X,
+            y: self.Y,
+            sp: self.SP,
+            status: self.status.bits(),
+            cycle: self.cycle,
+        });
+        let instr: &Instruction = &LUT_6502[op as usize];
+        if instr.illegal && self.strict_opcodes {
+            return Err(format!("Illegal instruction: {} ({:X})", instr.mnemonic(), op));
         }
-
-        instr = instr_opt.unwrap();
         self.last_legal_instruction = Some(self.PC);
+        self.instr_cycles = instr.cycles as u16;
+        self.bus.open_bus = self.last_operand_fetch(&instr.mode)?;
+        self.polling = true;
+        let irq_inhibit = self.status.contains(StatusRegister::INTERRUPT_DISABLE);
 
         /* Execute stage */
-        match instr.mnemonic {
-            "ADC" => self.A = self.op_arithmetic::<true>(&instr.mode),
-            "AND" => self.A = self.op_bitwise(&instr.mode, |x, y| { x & y }),
-            "ASL" => self.op_rotate(&instr.mode, true, true),
-            "BCC" => self.op_branch(StatusRegister::CARRY, false, &instr.mode),
-            "BCS" => self.op_branch(StatusRegister::CARRY, true, &instr.mode),
-            "BEQ" => self.op_branch(StatusRegister::ZERO, true, &instr.mode),
-            "BIT" => self.op_bit(&instr.mode),
-            "BMI" => self.op_branch(StatusRegister::NEGATIVE, true, &instr.mode),
-            "BNE" => self.op_branch(StatusRegister::ZERO, false, &instr.mode),
-            "BPL" => self.op_branch(StatusRegister::NEGATIVE, false, &instr.mode),
-            "BRK" => self.enter_subroutine(&InterruptType::BRK)?,
-            "BVC" => self.op_branch(StatusRegister::OVERFLOW, false, &instr.mode),
-            "BVS" => self.op_branch(StatusRegister::OVERFLOW, true, &instr.mode),
-            "CLC" => { self.status.set(StatusRegister::CARRY, false); self.pc_skip = 1; },
-            "CLD" => { self.status.set(StatusRegister::DECIMAL_MODE, false); self.pc_skip = 1; },
-            "CLI" => { self.status.set(StatusRegister::INTERRUPT_DISABLE, false); self.pc_skip = 1; },
-            "CLV" => { self.status.set(StatusRegister::OVERFLOW, false); self.pc_skip = 1; },
-            "CMP" => self.op_compare(self.A, &instr.mode),
-            "CPX" => self.op_compare(self.X, &instr.mode),
-            "CPY" => self.op_compare(self.Y, &instr.mode),
-            "DEC" => self.op_incdec_addr(false, &instr.mode),
-            "DEX" => self.X = self.op_incdec(self.X, false),
-            "DEY" => self.Y = self.op_incdec(self.Y, false),
-            "EOR" => self.A = self.op_bitwise(&instr.mode, |x, y| { x ^ y }),
-            "INC" => self.op_incdec_addr(true, &instr.mode),
-            "INX" => self.X = self.op_incdec(self.X, true),
-            "INY" => self.Y = self.op_incdec(self.Y, true),
-            "JMP" => self.op_jump(&instr.mode),
-            "JSR" => self.enter_subroutine(&InterruptType::SUBROUTINE)?,
-            "LDA" => self.A = self.op_load(&instr.mode),
-            "LDX" => self.X = self.op_load(&instr.mode),
-            "LDY" => self.Y = self.op_load(&instr.mode),
-            "LSR" => self.op_rotate(&instr.mode, false, true),
-            "NOP" => { self.pc_skip = 1; },
-            "ORA" => self.A = self.op_bitwise(&instr.mode, |x, y| { x | y }),
-            "PHA" => self.op_stack_push(false),
-            "PHP" => self.op_stack_push(true),
-            "PLA" => self.A = self.op_stack_pull(false),
-            "PLP" => self.status = StatusRegister::from_bits_truncate(self.op_stack_pull(true)),
-            "ROL" => self.op_rotate(&instr.mode, true, false),
-            "ROR" => self.op_rotate(&instr.mode, false, false),
-            "RTI" => self.leave_subroutine(&InterruptType::IRQ),
-            "RTS" => self.leave_subroutine(&InterruptType::SUBROUTINE),
-            "SBC" => self.A = self.op_arithmetic::<false>(&instr.mode),
-            "SEC" => { self.status.set(StatusRegister::CARRY, true); self.pc_skip = 1; },
-            "SED" => { self.status.set(StatusRegister::DECIMAL_MODE, true); self.pc_skip = 1; },
-            "SEI" => { self.status.set(StatusRegister::INTERRUPT_DISABLE, true); self.pc_skip = 1; },
-            "STA" => self.op_store(self.A, &instr.mode),
-            "STX" => self.op_store(self.X, &instr.mode),
-            "STY" => self.op_store(self.Y, &instr.mode),
-            "TAX" => self.X = self.op_transfer_a(self.A, false),
-            "TAY" => self.Y = self.op_transfer_a(self.A, false),
-            "TSX" => self.X = self.op_transfer_a(self.SP, false),
-            "TXS" => self.SP = self.op_transfer_a(self.X, true),
-            "TXA" => self.A = self.op_transfer_a(self.X, false),
-            "TYA" => self.A = self.op_transfer_a(self.Y, false),
-            _     => unimplemented!()
+        match instr.op {
+            ADC => self.A = self.op_arithmetic::<true>(&instr.mode)?,
+            AND => self.A = self.op_bitwise(&instr.mode, |x, y| { x & y })?,
+            ASL => { self.op_rotate(&instr.mode, true, true)?; },
+            BCC => self.op_branch(StatusRegister::CARRY, false, &instr.mode)?,
+            BCS => self.op_branch(StatusRegister::CARRY, true, &instr.mode)?,
+            BEQ => self.op_branch(StatusRegister::ZERO, true, &instr.mode)?,
+            BIT => self.op_bit(&instr.mode)?,
+            BMI => self.op_branch(StatusRegister::NEGATIVE, true, &instr.mode)?,
+            BNE => self.op_branch(StatusRegister::ZERO, false, &instr.mode)?,
+            BPL => self.op_branch(StatusRegister::NEGATIVE, false, &instr.mode)?,
+            BRK => self.enter_subroutine(&InterruptType::BRK)?,
+            BVC => self.op_branch(StatusRegister::OVERFLOW, false, &instr.mode)?,
+            BVS => self.op_branch(StatusRegister::OVERFLOW, true, &instr.mode)?,
+            CLC => { self.status.set(StatusRegister::CARRY, false); self.pc_skip = 1; },
+            CLD => { self.status.set(StatusRegister::DECIMAL_MODE, false); self.pc_skip = 1; },
+            CLI => { self.status.set(StatusRegister::INTERRUPT_DISABLE, false); self.pc_skip = 1; },
+            CLV => { self.status.set(StatusRegister::OVERFLOW, false); self.pc_skip = 1; },
+            CMP => self.op_compare(self.A, &instr.mode)?,
+            CPX => self.op_compare(self.X, &instr.mode)?,
+            CPY => self.op_compare(self.Y, &instr.mode)?,
+            DEC => { self.op_incdec_addr(false, &instr.mode)?; },
+            DEX => self.X = self.op_incdec(self.X, false),
+            DEY => self.Y = self.op_incdec(self.Y, false),
+            EOR => self.A = self.op_bitwise(&instr.mode, |x, y| { x ^ y })?,
+            INC => { self.op_incdec_addr(true, &instr.mode)?; },
+            INX => self.X = self.op_incdec(self.X, true),
+            INY => self.Y = self.op_incdec(self.Y, true),
+            JMP => self.op_jump(&instr.mode)?,
+            JSR => self.enter_subroutine(&InterruptType::SUBROUTINE)?,
+            LDA => self.A = self.op_load(&instr.mode)?,
+            LDX => self.X = self.op_load(&instr.mode)?,
+            LDY => self.Y = self.op_load(&instr.mode)?,
+            LSR => { self.op_rotate(&instr.mode, false, true)?; },
+            NOP => self.op_nop(&instr.mode)?,
+            ORA => self.A = self.op_bitwise(&instr.mode, |x, y| { x | y })?,
+            PHA => self.op_stack_push(false)?,
+            PHP => self.op_stack_push(true)?,
+            PLA => self.A = self.op_stack_pull(false)?,
+            PLP => self.status = StatusRegister::from_bits_truncate(self.op_stack_pull(true)?),
+            ROL => { self.op_rotate(&instr.mode, true, false)?; },
+            ROR => { self.op_rotate(&instr.mode, false, false)?; },
+            RTI => self.leave_subroutine(&InterruptType::IRQ)?,
+            RTS => self.leave_subroutine(&InterruptType::SUBROUTINE)?,
+            SBC => self.A = self.op_arithmetic::<false>(&instr.mode)?,
+            SEC => { self.status.set(StatusRegister::CARRY, true); self.pc_skip = 1; },
+            SED => { self.status.set(StatusRegister::DECIMAL_MODE, true); self.pc_skip = 1; },
+            SEI => { self.status.set(StatusRegister::INTERRUPT_DISABLE, true); self.pc_skip = 1; },
+            STA => self.op_store(self.A, &instr.mode)?,
+            STX => self.op_store(self.X, &instr.mode)?,
+            STY => self.op_store(self.Y, &instr.mode)?,
+            TAX => self.X = self.op_transfer_a(self.A, false),
+            TAY => self.Y = self.op_transfer_a(self.A, false),
+            TSX => self.X = self.op_transfer_a(self.SP, false),
+            TXS => self.SP = self.op_transfer_a(self.X, true),
+            TXA => self.A = self.op_transfer_a(self.X, false),
+            TYA => self.A = self.op_transfer_a(self.Y, false),
+
+            /* Undocumented instructions */
+            AHX => self.op_store_high(self.A & self.X, self.Y, &instr.mode)?,
+            ALR => self.A = self.op_immediate(|cpu, data| {
+                let value = cpu.A & data;
+                cpu.status.set(StatusRegister::CARRY, value & 0x1 > 0);
+                value >> 1
+            })?,
+            ANC => self.A = self.op_immediate(|cpu, data| {
+                let value = cpu.A & data;
+                cpu.status.set(StatusRegister::CARRY, value & 0x80 > 0);
+                value
+            })?,
+            ARR => self.A = self.op_immediate(|cpu, data| {
+                let value = ((cpu.A & data) >> 1) | ((cpu.status.contains(StatusRegister::CARRY) as u8) << 7);
+                cpu.status.set(StatusRegister::CARRY, value & 0x40 > 0);
+                cpu.status.set(StatusRegister::OVERFLOW, ((value >> 6) ^ (value >> 5)) & 0x1 > 0);
+                value
+            })?,
+            AXS => self.X = self.op_immediate(|cpu, data| {
+                let value = cpu.A & cpu.X;
+                cpu.status.set(StatusRegister::CARRY, value >= data);
+                value.wrapping_sub(data)
+            })?,
+            DCP => {
+                let data = self.op_incdec_addr(false, &instr.mode)?;
+                self.compare(self.A, data);
+            },
+            ISC => {
+                let data = self.op_incdec_addr(true, &instr.mode)?;
+                self.A = self.add_with_carry(!data);
+            },
+            JAM => return Err(format!("CPU jammed by opcode {:X}", op)),
+            LAS => {
+                let data = self.op_load(&instr.mode)? & self.SP;
+                self.A = self.set_zero_negative(data);
+                self.X = data;
+                self.SP = data;
+            },
+            LAX => {
+                self.A = self.op_load(&instr.mode)?;
+                self.X = self.A;
+            },
+            LXA => {
+                self.A = self.op_immediate(|cpu, data| (cpu.A | 0xEE) & data)?;
+                self.X = self.A;
+            },
+            RLA => {
+                let data = self.op_rotate(&instr.mode, true, false)?;
+                self.A = self.set_zero_negative(self.A & data);
+            },
+            RRA => {
+                let data = self.op_rotate(&instr.mode, false, false)?;
+                self.A = self.add_with_carry(data);
+            },
+            SAX => self.op_store(self.A & self.X, &instr.mode)?,
+            SHX => self.op_store_high(self.X, self.Y, &instr.mode)?,
+            SHY => self.op_store_high(self.Y, self.X, &instr.mode)?,
+            SLO => {
+                let data = self.op_rotate(&instr.mode, true, true)?;
+                self.A = self.set_zero_negative(self.A | data);
+            },
+            SRE => {
+                let data = self.op_rotate(&instr.mode, false, true)?;
+                self.A = self.set_zero_negative(self.A ^ data);
+            },
+            TAS => {
+                self.SP = self.A & self.X;
+                self.op_store_high(self.A & self.X, self.Y, &instr.mode)?;
+            },
+            XAA => self.A = self.op_immediate(|cpu, data| (cpu.A | 0xEE) & cpu.X & data)?,
         }
 
         /* Set base number of idle cycles for this instruction.
            Some instructions will have this increased by 1 for a page cross. */
         // One less because _this_ tick is a cycle too.
-        self.wait_cycles += instr.cycles - 1;
+        self.wait_cycles += (instr.cycles - 1) as u16;
 
         self.PC += self.pc_skip;
+
+        /* CLI, SEI and PLP change the I flag after the poll, so an IRQ is taken (or not) one instruction late */
+        self.poll_irq_inhibit = match instr.op {
+            CLI | SEI | PLP => irq_inhibit,
+            _ => self.status.contains(StatusRegister::INTERRUPT_DISABLE),
+        };
+
+        /* An OAM DMA halts the CPU while it copies */
+        self.wait_cycles += self.bus.take_dma_stall();
         Ok(())
     }
 
+    /* The byte the CPU last fetched before accessing its operand, which is left on the data
+       bus for reads of open bus: the address's high byte, or the one after the opcode */
+    fn last_operand_fetch(&self, mode: &AddressingMode) -> Result<u8, String> {
+        let operand = self.PC.wrapping_add(1);
+        match mode {
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => self.bus.read(self.PC.wrapping_add(2)),
+            AddressingMode::IndexedIndirect => {
+                let pointer = self.bus.read(operand)?.wrapping_add(self.X).wrapping_add(1);
+                self.bus.read(pointer as u16)
+            }
+            AddressingMode::IndirectIndexed => {
+                let pointer = self.bus.read(operand)?.wrapping_add(1);
+                self.bus.read(pointer as u16)
+            }
+            _ => self.bus.read(operand),
+        }
+    }
+
     /* resolve the address presented in the operand in
        accorance with addressing mode rules */
     /* Returns (resolved_address, page_cross, pc_skip) */
-    fn resolve_address(&self, mode: &AddressingMode) -> (u16, bool, u16) {
+    fn resolve_address(&self, mode: &AddressingMode) -> Result<(u16, bool, u16), String> {
         let target_address: u16;
         let pc_skip: u16;
 
@@ -231,14 +521,14 @@ impl<'a> NESCpu<'a> {
             AddressingMode::IndirectIndexed |
             AddressingMode::IndexedIndirect |
             AddressingMode::Relative => {
-                target_address = self.memory.read(self.PC + 1) as u16;
+                target_address = self.bus.read(self.PC + 1)? as u16;
                 pc_skip = 2;
             },
             AddressingMode::Absolute |
             AddressingMode::AbsoluteX |
             AddressingMode::AbsoluteY |
             AddressingMode::Indirect => {
-                target_address = self.memory.read_16(self.PC + 1);
+                target_address = self.bus.read_16(self.PC + 1)?;
                 pc_skip = 3;
             },
             AddressingMode::Immediate => {
@@ -257,48 +547,49 @@ impl<'a> NESCpu<'a> {
             // on a different page to the target. If so, add
             // a cycle.
             AddressingMode::Immediate => {
-                return (self.PC + 1, false, pc_skip);
+                return Ok((self.PC + 1, false, pc_skip));
             },
             AddressingMode::ZeroPage => {
                 // Are we entering the zero-page?
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != 0;
-                return (target_address & 0xFF, page_cross, pc_skip);
+                return Ok((target_address & 0xFF, page_cross, pc_skip));
             },
             AddressingMode::ZeroPageX => {
                 // Are we entering the zero-page?
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != 0;
-                return (((target_address & 0xFF) + self.X as u16) & 0xFF, page_cross, pc_skip); 
+                return Ok((((target_address & 0xFF) + self.X as u16) & 0xFF, page_cross, pc_skip)); 
             }
             AddressingMode::ZeroPageY => {
                 // Are we entering the zero-page?
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != 0;
-                return (((target_address & 0xFF) + self.Y as u16) & 0xFF, page_cross, pc_skip);
+                return Ok((((target_address & 0xFF) + self.Y as u16) & 0xFF, page_cross, pc_skip));
             }
             AddressingMode::Relative => {
                 let target = self.PC.wrapping_add((target_address as i8) as u16);
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                return Ok((target, page_cross, pc_skip));
             }
             AddressingMode::Absolute => {
                 let target = target_address;
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                return Ok((target, page_cross, pc_skip));
             }
+            // Indexed modes cross a page when indexing carries into the high byte
             AddressingMode::AbsoluteX => {
-                let target = target_address + self.X as u16;
-                let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                let target = target_address.wrapping_add(self.X as u16);
+                let page_cross = target_address & 0xFF00 != target & 0xFF00;
+                return Ok((target, page_cross, pc_skip));
             }
             AddressingMode::AbsoluteY => {
                 let target = target_address.wrapping_add(self.Y as u16);
-                let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                let page_cross = target_address & 0xFF00 != target & 0xFF00;
+                return Ok((target, page_cross, pc_skip));
             }
             AddressingMode::Indirect => {
-                let addr_lsb: u8 = self.memory.read(target_address);
-                let addr_msb: u8 = self.memory.read( 
+                let addr_lsb: u8 = self.bus.read(target_address)?;
+                let addr_msb: u8 = self.bus.read( 
                     target_address & 0xFF00 | 
-                    (target_address + 1) & 0x00FF); // See notes below
+                    (target_address + 1) & 0x00FF)?; // See notes below
 
                  /* An original 6502 has does not correctly fetch 
                     the target address if the indirect vector falls on a page boundary (e.g. $xxFF where 
@@ -306,43 +597,43 @@ impl<'a> NESCpu<'a> {
                     but takes the MSB from $xx00. This is fixed in some later chips like the 65SC02 so 
                     for compatibility always ensure the indirect vector is not at the end of the page.
                  */
-                #[cfg(debug_assertions)]
                 if (target_address + 1) & 0x00FF == 0x0000 {
-                    println!("Indirect JMP at ${:X} falls at end of page. Using \"broken\" behaviour.", &self.PC);
+                    nes_log!(Debug, CPU, "Indirect JMP at ${:0>4X} through ${:0>4X} takes its high byte from ${:0>4X}",
+                        self.PC, target_address, target_address & 0xFF00);
                 }
 
                 let target = addr_lsb as u16 | ((addr_msb as u16) << 8);
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                return Ok((target, page_cross, pc_skip));
             }
             AddressingMode::IndexedIndirect => {
-                let zp_addr_lsb: u8 = self.memory.read((target_address + self.X as u16) & 0xFF);
-                let zp_addr_msb: u8 = self.memory.read((target_address + self.X as u16 + 1) & 0xFF);
+                let zp_addr_lsb: u8 = self.bus.read((target_address + self.X as u16) & 0xFF)?;
+                let zp_addr_msb: u8 = self.bus.read((target_address + self.X as u16 + 1) & 0xFF)?;
 
                 let target = (zp_addr_lsb as u16) | ((zp_addr_msb as u16) << 8);
                 let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                return Ok((target, page_cross, pc_skip));
             }
             AddressingMode::IndirectIndexed => {
-                let mut zp_addr_lsb: u16 = self.memory.read(target_address) as u16 + (self.Y as u16);
+                let mut zp_addr_lsb: u16 = self.bus.read(target_address)? as u16 + (self.Y as u16);
                 let carry: u16 = (zp_addr_lsb > 0xFF) as u16;
                 zp_addr_lsb &= 0xFF;
-                let zp_addr_msb: u16 = (self.memory.read((target_address + 1) & 0xFF) as u16 + carry) & 0xFF;
+                let zp_addr_msb: u16 = (self.bus.read((target_address + 1) & 0xFF)? as u16 + carry) & 0xFF;
 
 
                 let target = (zp_addr_lsb) | ((zp_addr_msb) << 8);
-                let page_cross = (self.PC + pc_skip) & 0xFF00 != target & 0xFF00;
-                return (target, page_cross, pc_skip);
+                let page_cross = carry != 0;
+                return Ok((target, page_cross, pc_skip));
             }
-            _ => panic!("Attempt at address resoluton for non-sensical mode: {:?}", mode)
+            _ => Err(format!("Attempt at address resolution for non-sensical mode: {:?}", mode))
         }
     }
 
     /* arithmetic operations - ADC, SBC */
-    fn op_arithmetic<const ADD: bool>(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, page_cross, pc_skip) = self.resolve_address(mode);
+    fn op_arithmetic<const ADD: bool>(&mut self, mode: &AddressingMode) -> Result<u8, String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        let mut data = self.memory.read_mut(addr);
+        let mut data = self.read_operand(mode, addr, page_cross)?;
 
         /* Interestingly, a simple one's complement works here, including all flags
            (exercise for the reader :-) ) */
@@ -350,6 +641,12 @@ impl<'a> NESCpu<'a> {
             data = !data;
         }
 
+        let result = self.add_with_carry(data);
+        Ok(result)
+    }
+
+    /* A + data + carry, setting the flags. Returns the result. */
+    fn add_with_carry(&mut self, data: u8) -> u8 {
         let (result, carry_data) = self.A.overflowing_add(data);
         let (result, carry_cin) = result.overflowing_add(self.status.contains(StatusRegister::CARRY) as u8);
 
@@ -357,69 +654,94 @@ impl<'a> NESCpu<'a> {
         self.status.set(StatusRegister::ZERO, result == 0);
         self.status.set(StatusRegister::OVERFLOW, (self.A ^ result) & (data ^ result) & 0x80 != 0);
         self.status.set(StatusRegister::NEGATIVE, result & 0x80 > 0);
-
-        if page_cross {
-            self.wait_cycles +=
-                match mode {
-                    AddressingMode::AbsoluteX
-                    | AddressingMode::AbsoluteY
-                    | AddressingMode::IndirectIndexed => { 1 }
-                    _ => { 0 }
-                };
-        }
         result
     }
 
     /* load operations - LDA, LDX, LDY */
-    fn op_load(&mut self, mode: &AddressingMode) -> u8 {
-        let (addr, page_cross, pc_skip) = self.resolve_address(mode);
+    fn op_load(&mut self, mode: &AddressingMode) -> Result<u8, String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        let data = self.memory.read_mut(addr);
+        let data = self.read_operand(mode, addr, page_cross)?;
         self.status.set(StatusRegister::ZERO, data == 0);
         self.status.set(StatusRegister::NEGATIVE, data & 0b10000000 > 0);
-        if page_cross {
-            self.wait_cycles +=
-                match mode {
-                    AddressingMode::AbsoluteX
-                    | AddressingMode::AbsoluteY
-                    | AddressingMode::IndirectIndexed => { 1 }
-                    _ => { 0 }
-                };
-        }
-        data
+        Ok(data)
     }
 
     /* store operations - STA, STX, STY */
-    fn op_store(&mut self, data: u8, mode: &AddressingMode) {
-        let (addr, _, pc_skip) = self.resolve_address(mode);
+    fn op_store(&mut self, data: u8, mode: &AddressingMode) -> Result<(), String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
+        self.pc_skip = pc_skip;
+        self.dummy_read(self.instr_cycles - 1, mode, addr, page_cross)?;
+        self.write_on(self.instr_cycles, addr, data)
+    }
+
+    /* The unstable stores - SHX, SHY, AHX, TAS. The value stored is ANDed with the
+       high byte of the base address plus one, and if indexing crossed a page, that
+       value also replaces the high byte of the address written to. */
+    fn op_store_high(&mut self, data: u8, index: u8, mode: &AddressingMode) -> Result<(), String> {
+        let (mut addr, page_cross, pc_skip) = self.resolve_address(mode)?;
+        self.pc_skip = pc_skip;
+        self.dummy_read(self.instr_cycles - 1, mode, addr, page_cross)?;
+
+        let base = addr.wrapping_sub(index as u16);
+        let value = data & ((base >> 8) as u8).wrapping_add(1);
+        if base & 0xFF00 != addr & 0xFF00 {
+            addr = ((value as u16) << 8) | (addr & 0xFF);
+        }
+        self.write_on(self.instr_cycles, addr, value)
+    }
+
+    /* Immediate mode ALU operations - ALR, ANC, ARR, AXS, LXA, XAA. `func` produces
+       the result from the operand, setting any flags other than zero and negative. */
+    fn op_immediate(&mut self, func: impl Fn(&mut Self, u8) -> u8) -> Result<u8, String> {
+        let data = self.bus.read_mut(self.PC + 1)?;
+        let result = func(self, data);
+        self.status.set(StatusRegister::ZERO, result == 0);
+        self.status.set(StatusRegister::NEGATIVE, result & 0x80 > 0);
+
+        self.pc_skip = 2;
+        Ok(result)
+    }
+
+    /* NOP, including the undocumented ones which read an operand and discard it */
+    fn op_nop(&mut self, mode: &AddressingMode) -> Result<(), String> {
+        if matches!(mode, AddressingMode::Implied) {
+            self.pc_skip = 1;
+            return Ok(());
+        }
+
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        self.memory.write(addr, data);
+        self.read_operand(mode, addr, page_cross)?;
+        Ok(())
     }
 
     /* jump operations - JMP, JSR, RTI, RTS */
-    fn op_jump(&mut self, mode: &AddressingMode) {
-        let (addr, _, pc_skip) = self.resolve_address(mode);
+    fn op_jump(&mut self, mode: &AddressingMode) -> Result<(), String> {
+        let (addr, _, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
 
         self.PC = addr;
         self.pc_skip = 0;
+        Ok(())
     }
 
     /* bit test */
-    fn op_bit(&mut self, mode: &AddressingMode) {
-        let (addr, _, pc_skip) = self.resolve_address(mode);
-        let data = self.memory.read_mut(addr);
+    fn op_bit(&mut self, mode: &AddressingMode) -> Result<(), String> {
+        let (addr, _, pc_skip) = self.resolve_address(mode)?;
+        let data = self.read_on(self.instr_cycles, addr)?;
 
         self.status.set(StatusRegister::ZERO, self.A & data == 0);
         self.status.set(StatusRegister::OVERFLOW, data & 0x40 > 0);
         self.status.set(StatusRegister::NEGATIVE, data & 0x80 > 0);
 
         self.pc_skip = pc_skip;
+        Ok(())
     }
 
     /* conditional branch operations - BMI, BEQ, BNE, BPL, BVC, BVS */
-    fn op_branch(&mut self, reg: StatusRegister, set: bool, mode: &AddressingMode) {
-        let (addr, page_cross, pc_skip) = self.resolve_address(mode);
+    fn op_branch(&mut self, reg: StatusRegister, set: bool, mode: &AddressingMode) -> Result<(), String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
 
         if self.status.contains(reg) == set {
@@ -430,39 +752,33 @@ impl<'a> NESCpu<'a> {
             }
             self.PC = addr;
         }
+        Ok(())
     }
 
     /* Bitwise operators - AND, EOR, ORA */
-    fn op_bitwise(&mut self, mode: &AddressingMode, func: impl Fn(u8, u8) -> u8) -> u8 {
-        let (addr, page_cross, pc_skip) = self.resolve_address(mode);
+    fn op_bitwise(&mut self, mode: &AddressingMode, func: impl Fn(u8, u8) -> u8) -> Result<u8, String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        let data = self.memory.read_mut(addr);
+        let data = self.read_operand(mode, addr, page_cross)?;
 
         let result = func(self.A, data);
         self.status.set(StatusRegister::ZERO, result == 0);
         self.status.set(StatusRegister::NEGATIVE, result & 0x80 > 0);
-
-        if page_cross {
-            self.wait_cycles +=
-                match mode {
-                    AddressingMode::AbsoluteX
-                    | AddressingMode::AbsoluteY
-                    | AddressingMode::IndirectIndexed => { 1 }
-                    _ => { 0 }
-                };
-        }
-        result
+        Ok(result)
     }
 
-    fn op_incdec_addr(&mut self, inc: bool, mode: &AddressingMode) {
-        let (addr, _, pc_skip) = self.resolve_address(mode);
+    /* Increment/decrement memory - INC, DEC. Returns the value written. */
+    fn op_incdec_addr(&mut self, inc: bool, mode: &AddressingMode) -> Result<u8, String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        let data = self.memory.read_mut(addr);
+
+        let data = self.read_for_modify(mode, addr, page_cross)?;
 
         let result = if inc { data.wrapping_add(1) } else { data.wrapping_sub(1) };
-        self.memory.write(addr, result);
+        self.write_on(self.instr_cycles, addr, result)?;
         self.status.set(StatusRegister::ZERO, result == 0);
         self.status.set(StatusRegister::NEGATIVE, result & 0x80 > 0);
+        Ok(result)
     }
 
     /* Increment/decrement operators - INC, INX, INY, DEC, DEX, DEY */
@@ -475,16 +791,17 @@ impl<'a> NESCpu<'a> {
         result
     }
 
-    /* Rotate operators - ROL, ROR */
-    fn op_rotate(&mut self, mode: &AddressingMode, left: bool, arith: bool) {
+    /* Rotate operators - ROL, ROR, and shifts - ASL, LSR. Returns the value written. */
+    fn op_rotate(&mut self, mode: &AddressingMode, left: bool, arith: bool) -> Result<u8, String> {
         let mut addr: u16 = 0;
         let pc_skip: u16;
         let mut data = if matches!(mode, AddressingMode::Accumulator) {
             pc_skip = 1;
             self.A
         } else {
-            (addr, _, pc_skip) = self.resolve_address(mode);
-            self.memory.read_mut(addr)
+            let page_cross;
+            (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
+            self.read_for_modify(mode, addr, page_cross)?
         };
 
         let old_carry = self.status.contains(StatusRegister::CARRY) as u8;
@@ -506,9 +823,17 @@ impl<'a> NESCpu<'a> {
             self.A = data;
             self.pc_skip = pc_skip;
         } else {
-            self.memory.write(addr, data);
+            self.write_on(self.instr_cycles, addr, data)?;
             self.pc_skip = pc_skip;
         }
+        Ok(data)
+    }
+
+    /* For the combined undocumented instructions, which update A after memory */
+    fn set_zero_negative(&mut self, value: u8) -> u8 {
+        self.status.set(StatusRegister::ZERO, value == 0);
+        self.status.set(StatusRegister::NEGATIVE, value & 0x80 > 0);
+        value
     }
 
     /* Register transfers - TAX, TXA, TAY, TYA, TSX, TXS */
@@ -523,47 +848,44 @@ impl<'a> NESCpu<'a> {
     }
 
     /* Comparison instructions - CMP, CPX, CPY */
-    fn op_compare(&mut self, lhs: u8, mode: &AddressingMode) {
-        let (addr, page_cross, pc_skip) = self.resolve_address(mode);
+    fn op_compare(&mut self, lhs: u8, mode: &AddressingMode) -> Result<(), String> {
+        let (addr, page_cross, pc_skip) = self.resolve_address(mode)?;
         self.pc_skip = pc_skip;
-        let rhs = self.memory.read_mut(addr);
+        let rhs = self.read_operand(mode, addr, page_cross)?;
 
+        self.compare(lhs, rhs);
+        Ok(())
+    }
+
+    fn compare(&mut self, lhs: u8, rhs: u8) {
         self.status.set(StatusRegister::CARRY, lhs >= rhs);
         self.status.set(StatusRegister::ZERO, lhs == rhs);
         self.status.set(StatusRegister::NEGATIVE, lhs.wrapping_sub(rhs) & 0x80 > 0);
-        if page_cross {
-            self.wait_cycles +=
-                match mode {
-                    AddressingMode::AbsoluteX
-                    | AddressingMode::AbsoluteY
-                    | AddressingMode::IndirectIndexed => { 1 }
-                    _ => { 0 }
-                };
-        }
     }
 
     /* Stack operations - PHA, PHP, PLA, PLP */
-    pub fn op_stack_push(&mut self, status: bool) {
+    pub fn op_stack_push(&mut self, status: bool) -> Result<(), String> {
         if status {
-            self.memory.write(self.SP as u16 + 0x0100, (self.status |
-                StatusRegister::BREAK_LOW | StatusRegister::BREAK_HIGH).bits());
+            self.bus.write(self.SP as u16 + 0x0100, (self.status |
+                StatusRegister::BREAK_LOW | StatusRegister::BREAK_HIGH).bits())?;
         } else {
-            self.memory.write(self.SP as u16 + 0x0100, self.A);
+            self.bus.write(self.SP as u16 + 0x0100, self.A)?;
         }
-        self.SP -= 1;
+        self.SP = self.SP.wrapping_sub(1);
         self.pc_skip = 1;
+        Ok(())
     }
 
-    fn op_stack_pull(&mut self, status: bool) -> u8 {
-        self.SP += 1;
+    fn op_stack_pull(&mut self, status: bool) -> Result<u8, String> {
+        self.SP = self.SP.wrapping_add(1);
         self.pc_skip = 1;
         if status {
-            return self.memory.read_mut(self.SP as u16 + 0x0100);
+            return self.bus.read_mut(self.SP as u16 + 0x0100);
         } else {
-            let result = self.memory.read_mut(self.SP as u16 + 0x0100);
+            let result = self.bus.read_mut(self.SP as u16 + 0x0100)?;
             self.status.set(StatusRegister::ZERO, result == 0);
             self.status.set(StatusRegister::NEGATIVE, result & 0x80 > 0);
-            return result;
+            return Ok(result);
         }
     }
 
@@ -592,43 +914,40 @@ impl<'a> NESCpu<'a> {
             _ => {}
         }
 
-        self.memory.write(self.SP as u16 + 0x0100, (self.PC >> 8) as u8); /* PC, MSB */
-        if let Some(i) = self.SP.checked_sub(1) {
-            self.SP = i;
-        } else {
-            return Err("Stack underflow occurred".to_string());
-        }
-        self.memory.write(self.SP as u16 + 0x0100, self.PC as u8); /* PC, LSB */
-        if let Some(i) = self.SP.checked_sub(1) {
-            self.SP = i;
-        } else {
-            return Err("Stack underflow occurred".to_string());
+        /* Interrupt sequences don't poll for interrupts, so a handler's first instruction always runs */
+        if !matches!(inttype, InterruptType::SUBROUTINE) {
+            self.polling = false;
         }
+
+        self.bus.write(self.SP as u16 + 0x0100, (self.PC >> 8) as u8)?; /* PC, MSB */
+        self.SP = self.SP.wrapping_sub(1);
+        self.bus.write(self.SP as u16 + 0x0100, self.PC as u8)?; /* PC, LSB */
+        self.SP = self.SP.wrapping_sub(1);
         
         match inttype {
             InterruptType::SUBROUTINE => {
-                self.PC = self.memory.read_16_mut(self.PC - 1);
+                self.PC = self.bus.read_16_mut(self.PC - 1)?;
             },
             InterruptType::BRK => {
                 self.status.insert(StatusRegister::BREAK_LOW);
-                self.memory.write(self.SP as u16 + 0x0100, self.status.bits());
+                self.bus.write(self.SP as u16 + 0x0100, self.status.bits())?;
                 self.status.insert(StatusRegister::INTERRUPT_DISABLE);
-                self.SP -= 1;
-                self.PC = self.memory.read_16_mut(0xFFFA);
+                self.SP = self.SP.wrapping_sub(1);
+                self.vector_fetch = Some(0xFFFE);
             },
             InterruptType::IRQ => {
                 self.status.remove(StatusRegister::BREAK_LOW);
-                self.memory.write(self.SP as u16 + 0x0100, self.status.bits());
+                self.bus.write(self.SP as u16 + 0x0100, self.status.bits())?;
                 self.status.insert(StatusRegister::INTERRUPT_DISABLE);
-                self.SP -= 1;
-                self.PC = self.memory.read_16_mut(0xFFFE);
+                self.SP = self.SP.wrapping_sub(1);
+                self.vector_fetch = Some(0xFFFE);
             },
             InterruptType::NMI => {
                 self.status.remove(StatusRegister::BREAK_LOW);
-                self.memory.write(self.SP as u16 + 0x0100, self.status.bits());
+                self.bus.write(self.SP as u16 + 0x0100, self.status.bits())?;
                 self.status.insert(StatusRegister::INTERRUPT_DISABLE);
-                self.SP -= 1;
-                self.PC = self.memory.read_16_mut(0xFFFA);
+                self.SP = self.SP.wrapping_sub(1);
+                self.vector_fetch = Some(0xFFFA);
             }
         }
 
@@ -638,24 +957,24 @@ impl<'a> NESCpu<'a> {
     }
 
     /* return from a subroutine or interrupt */
-    fn leave_subroutine(&mut self, inttype: &InterruptType) {
+    fn leave_subroutine(&mut self, inttype: &InterruptType) -> Result<(), String> {
         let mut pc: u16 = 0;
 
         match inttype {
             InterruptType::IRQ
             | InterruptType::BRK
             | InterruptType::NMI => {
-                self.SP += 1;
-                self.status = StatusRegister::from_bits_truncate(self.memory.read_mut(self.SP as u16 + 0x0100));
+                self.SP = self.SP.wrapping_add(1);
+                self.status = StatusRegister::from_bits_truncate(self.bus.read_mut(self.SP as u16 + 0x0100)?);
                 // self.status.remove(StatusRegister::INTERRUPT_DISABLE);
             }
             _ => {}
         }
 
-        self.SP += 1;
-        pc |= self.memory.read_mut(self.SP as u16 + 0x0100) as u16;
-        self.SP += 1;
-        pc |= (self.memory.read_mut(self.SP as u16 + 0x0100) as u16) << 8;
+        self.SP = self.SP.wrapping_add(1);
+        pc |= self.bus.read_mut(self.SP as u16 + 0x0100)? as u16;
+        self.SP = self.SP.wrapping_add(1);
+        pc |= (self.bus.read_mut(self.SP as u16 + 0x0100)? as u16) << 8;
 
         /* Actually start at the next instruction, unless this is an RTI */
         match inttype {
@@ -669,18 +988,157 @@ impl<'a> NESCpu<'a> {
         self.PC = pc;
 
         self.pc_skip = 0;
+        Ok(())
+    }
+
+    /// The most recently fetched instructions, including one which failed to execute
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// If we are about to fetch a new instruction, is there an execution breakpoint on it?
+    pub fn breakpoint_at_pc(&self) -> Option<u32> {
+        if self.wait_cycles > 0 {
+            return None;
+        }
+        self.bus.breakpoints.check_execute(self.PC)
+    }
+
+    /// If we are about to fetch a new instruction, has the temporary breakpoint been reached?
+    pub fn temporary_break_at_pc(&mut self) -> bool {
+        if self.wait_cycles > 0 {
+            return false;
+        }
+        self.bus.breakpoints.check_temporary(self.PC, self.SP)
+    }
+
+    /// Set a temporary breakpoint after the JSR at the PC, so that running
+    /// steps over the subroutine. Returns false if the next instruction isn't a
+    /// JSR, in which case stepping over it is just a single step.
+    pub fn step_over(&mut self) -> Result<bool, String> {
+        if self.bus.read(self.PC)? != 0x20 {
+            return Ok(false);
+        }
+        self.bus.breakpoints.temporary = Some(TempBreak::Return { pc: self.PC.wrapping_add(3), sp: self.SP });
+        Ok(true)
+    }
+
+    /// Set a temporary breakpoint so that running stops once the current subroutine returns
+    pub fn step_out(&mut self) {
+        self.bus.breakpoints.temporary = Some(TempBreak::StackAbove(self.SP));
+    }
+
+    /// Set a temporary breakpoint so that running stops at `addr`
+    pub fn run_to(&mut self, addr: u16) {
+        self.bus.breakpoints.temporary = Some(TempBreak::RunTo(addr));
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.status.bits());
+        w.write_u16(self.PC);
+        w.write_u8(self.SP);
+        w.write_u8(self.A);
+        w.write_u8(self.X);
+        w.write_u8(self.Y);
+        w.write_u16(self.wait_cycles);
+        w.write_bool(self.do_nmi);
+        w.write_bool(self.polling);
+        w.write_bool(self.poll_irq_inhibit);
+        w.write_bool(self.nmi_polled);
+        w.write_bool(self.irq_polled);
+        w.write_u16(self.vector_fetch.unwrap_or(0));
+        w.write_u16(self.instr_cycles);
+        w.write_u16(self.ahead);
+        w.write_u32(self.nmi_ahead);
+        w.write_u64(self.cycle);
+
+        w.write_bytes(&self.bus.internal_ram);
+        w.write_bytes(&self.bus.io_registers);
+        for device in &self.bus.devices {
+            w.write_bytes(device.as_ref().map_or("none", |device| device.name()).as_bytes());
+            if let Some(device) = device {
+                device.save_state(w);
+            }
+        }
+        w.write_u16(self.bus.oam_dma_remaining);
+        w.write_u8(self.bus.joypad_read.map_or(0xFF, |register| register as u8));
+        w.write_u8(self.bus.open_bus);
+        w.write_u32(self.bus.ppu_owed);
+        self.bus.apu.save_state(w);
+        self.bus.cartridge.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.status = StatusRegister::from_bits_truncate(r.read_u8()?);
+        self.PC = r.read_u16()?;
+        self.SP = r.read_u8()?;
+        self.A = r.read_u8()?;
+        self.X = r.read_u8()?;
+        self.Y = r.read_u8()?;
+        self.wait_cycles = r.read_u16()?;
+        self.do_nmi = r.read_bool()?;
+        self.polling = r.read_bool()?;
+        self.poll_irq_inhibit = r.read_bool()?;
+        self.nmi_polled = r.read_bool()?;
+        self.irq_polled = r.read_bool()?;
+        self.vector_fetch = match r.read_u16()? {
+            0 => None,
+            vector => Some(vector),
+        };
+        self.instr_cycles = r.read_u16()?;
+        self.ahead = r.read_u16()?;
+        self.nmi_ahead = r.read_u32()?;
+        self.cycle = r.read_u64()?;
+
+        r.read_into(&mut self.bus.internal_ram)?;
+        r.read_into(&mut self.bus.io_registers)?;
+        for (port, device) in self.bus.devices.iter_mut().enumerate() {
+            let name = device.as_ref().map_or("none", |device| device.name());
+            let saved = r.read_bytes()?;
+            if saved != name.as_bytes() {
+                return Err(format!("The state was saved with a {} in port {}, not a {}",
+                    String::from_utf8_lossy(saved), port + 1, name));
+            }
+            if let Some(device) = device {
+                device.load_state(r)?;
+            }
+        }
+        self.bus.oam_dma_remaining = r.read_u16()?;
+        self.bus.joypad_read = match r.read_u8()? {
+            register @ 0..=1 => Some(register as usize),
+            _ => None,
+        };
+        self.bus.open_bus = r.read_u8()?;
+        self.bus.ppu_owed = r.read_u32()?;
+        self.bus.ppu_deadline = 0;  /* Found again from the PPU loaded, at the next cycle */
+        self.bus.apu.load_state(r)?;
+        self.bus.cartridge.load_state(r)
     }
 
     /* The NES's reset signal handling */
-    pub fn reset(&mut self) {
+    pub fn reset(&mut self) -> Result<(), String> {
         self.status.insert(StatusRegister::INTERRUPT_DISABLE);
         self.status.insert(StatusRegister::BREAK_HIGH); /* always 1 */
-        self.PC = self.memory.read_16_mut(0xFFFC);
+        self.SP = self.SP.wrapping_sub(3); /* stacks nothing, but moves as if it pushed PC and P */
+        self.PC = self.bus.read_16_mut(0xFFFC)?;
+        self.vector_fetch = None;
+        self.nmi_polled = false;
+        self.irq_polled = false;
+        Ok(())
+    }
+
+    /* Handle an IRQ (interrupt request) - from the APU or a mapper. The line is level
+       triggered, so the handler must acknowledge the source before clearing the I flag. */
+    pub fn irq(&mut self) -> Result<(), String> {
+        nes_log!(Trace, CPU, "IRQ at ${:0>4X}", self.PC);
+        self.wait_cycles = 7; /* IRQ takes 7 cycles, this one included */
+        self.enter_subroutine(&InterruptType::IRQ)
     }
 
     /* Handle the NMI (non-maskable interrupt) - called primarily by the PPU */
-    pub fn nmi(&mut self) {
-        self.wait_cycles = 6; /* NMI takes 7 cycles */
-        self.enter_subroutine(&InterruptType::NMI);
+    pub fn nmi(&mut self) -> Result<(), String> {
+        nes_log!(Trace, CPU, "NMI at ${:0>4X}", self.PC);
+        self.wait_cycles = 7; /* NMI takes 7 cycles, this one included */
+        self.enter_subroutine(&InterruptType::NMI)
     }
 }
@@ -0,0 +1,258 @@
+use bitflags::bitflags;
+
+use crate::prelude::*;
+use crate::cpu::expansion::{ArkanoidPaddle, FamilyKeyboard};
+use crate::state::{StateReader, StateWriter};
+
+/// Asked for the buttons held on a port (0 or 1), as a bitmask of JoypadButton, each
+/// time the game latches the controllers - e.g. to read GPIO pins (see Nes::set_input_poll)
+pub type InputPoll = Box<dyn FnMut(usize) -> u8 + Send>;
+
+/* The order in which a standard controller reports its buttons,
+   from the first read of $4016/$4017 onwards. */
+bitflags! {
+    pub struct JoypadButton: u8 {
+        const A      = 0b00000001;
+        const B      = 0b00000010;
+        const SELECT = 0b00000100;
+        const START  = 0b00001000;
+        const UP     = 0b00010000;
+        const DOWN   = 0b00100000;
+        const LEFT   = 0b01000000;
+        const RIGHT  = 0b10000000;
+    }
+}
+
+/// What the frontend tells a device is being done with it (see Nes::set_device_input).
+/// Devices ignore input meant for another kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceInput {
+    Buttons(u8),                          /* A controller's buttons held, as JoypadButton bits */
+    ExtraButtons(u8),                     /* Likewise, for a Four Score's second controller on the port (players 3 and 4) */
+    Key(usize, bool),                     /* A Family BASIC keyboard key (an index into expansion::KEYS), and whether it's down */
+    Paddle { position: u8, fire: bool },  /* The Arkanoid paddle's knob (see expansion::PADDLE_MIN) and button */
+}
+
+/// Something plugged into one of the controller ports, or the Famicom's expansion
+/// port. The CPU talks to all of them through $4016 and $4017: a write to $4016
+/// sets the three OUT lines (OUT0 being the controllers' strobe), and a read of
+/// either returns what the devices put on its data lines D0-D4. Devices must be
+/// Send, so that the machine which owns them can run on its own thread.
+pub trait InputDevice: Send {
+    // What's plugged in, e.g. "joypad". Save states record it, so that one is only
+    // loaded with the same devices plugged in.
+    fn name(&self) -> &'static str;
+
+    // Every write to $4016, with just the OUT lines (bits 0-2)
+    fn write(&mut self, out: u8);
+
+    // A read of $4016 (register 0) or $4017 (register 1), giving the data lines
+    // the device drives, and nothing on those it doesn't
+    fn read(&mut self, register: usize) -> u8;
+
+    // The same, without side-effects, e.g. for the disassembler
+    fn peek(&self, register: usize) -> u8;
+
+    fn set_input(&mut self, input: DeviceInput);
+
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+/// The ports devices plug into: the two controller ports, and then the Famicom's expansion port
+pub const PORTS: usize = 3;
+pub const EXPANSION_PORT: usize = 2;
+
+/// The devices fancy-nes has, for a frontend to pick between by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    None,
+    Joypad,
+    FamilyKeyboard,
+    ArkanoidPaddle,
+    FourScore,
+}
+
+impl DeviceKind {
+    pub const ALL: [DeviceKind; 5] = [DeviceKind::None, DeviceKind::Joypad, DeviceKind::FamilyKeyboard,
+        DeviceKind::ArkanoidPaddle, DeviceKind::FourScore];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::None => "none",
+            DeviceKind::Joypad => "joypad",
+            DeviceKind::FamilyKeyboard => "keyboard",
+            DeviceKind::ArkanoidPaddle => "paddle",
+            DeviceKind::FourScore => "fourscore",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        DeviceKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Make one to plug into a port, if it goes there: a controller in a controller
+    /// port, the keyboard in the expansion port, and the paddle in either. The Four
+    /// Score takes both controller ports, so needs making for each.
+    pub fn create(self, port: usize) -> Result<Option<Box<dyn InputDevice>>, String> {
+        let expansion = port == EXPANSION_PORT;
+        Ok(match self {
+            DeviceKind::None => None,
+            DeviceKind::Joypad if !expansion => Some(Box::new(Joypad::default())),
+            DeviceKind::FamilyKeyboard if expansion => Some(Box::new(FamilyKeyboard::new())),
+            DeviceKind::ArkanoidPaddle => Some(Box::new(ArkanoidPaddle::new(expansion))),
+            DeviceKind::FourScore if !expansion => Some(Box::new(FourScore::new(port))),
+            _ => return Err(format!("A {} can't be plugged into {}", self.name(),
+                if expansion { "the expansion port".to_string() } else { format!("port {}", port + 1) })),
+        })
+    }
+}
+
+/// A standard NES controller, which is just a parallel-in,
+/// serial-out (4021) shift register wired up to eight buttons.
+#[derive(Default, Clone, Copy)]
+pub struct Joypad {
+    pub buttons: u8,  /* Live button state, as set by the frontend */
+    shift: u8,        /* Snapshot taken when the strobe was last high */
+    strobe: bool,
+}
+
+impl Joypad {
+    /// While the strobe line is held high, the shift register is continuously reloaded
+    pub fn latch(&mut self) {
+        self.shift = self.buttons;
+    }
+
+    /// Shift out the next button. The serial input is tied high, so once all
+    /// eight have been read, every further read reports 1.
+    pub fn read(&mut self) -> u8 {
+        let data = self.shift & 0x1;
+        self.shift = (self.shift >> 1) | 0x80;
+        data
+    }
+
+    /// Observe the next bit without shifting, e.g. for the disassembler
+    pub fn peek(&self) -> u8 {
+        self.shift & 0x1
+    }
+
+    pub fn shift_register(&self) -> u8 {
+        self.shift
+    }
+
+    pub fn set_shift_register(&mut self, shift: u8) {
+        self.shift = shift;
+    }
+}
+
+impl InputDevice for Joypad {
+    fn name(&self) -> &'static str {
+        "joypad"
+    }
+
+    fn write(&mut self, out: u8) {
+        self.strobe = out & 0x1 == 0x1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    /* The button on D0, whichever port it's in */
+    fn read(&mut self, _register: usize) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        Joypad::read(self)
+    }
+
+    fn peek(&self, _register: usize) -> u8 {
+        Joypad::peek(self)
+    }
+
+    fn set_input(&mut self, input: DeviceInput) {
+        if let DeviceInput::Buttons(buttons) = input {
+            self.buttons = buttons;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.shift);
+        w.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.shift = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
+
+/* What follows the two controllers on each port of a Four Score, for games to tell it's
+   there: a 1 on the 20th read of $4016, and the 19th of $4017 */
+const FOUR_SCORE_SIGNATURES: [u32; 2] = [0x08, 0x04];
+
+/// The Four Score (or NES Satellite), which takes four controllers in place of two.
+/// Each port reads two of them, players 1 and 3 on $4016 and 2 and 4 on $4017, and
+/// then the port's signature: 24 bits in all, with 1s after, as from a controller.
+pub struct FourScore {
+    port: usize,
+    buttons: [u8; 2],  /* The port's two controllers, as set by the frontend */
+    shift: u32,
+    strobe: bool,
+}
+
+impl FourScore {
+    /// The half of it plugged into a port (0 or 1)
+    pub fn new(port: usize) -> Self {
+        Self { port, buttons: [0; 2], shift: 0, strobe: false }
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.buttons[0] as u32 | (self.buttons[1] as u32) << 8 | FOUR_SCORE_SIGNATURES[self.port] << 16;
+    }
+}
+
+impl InputDevice for FourScore {
+    fn name(&self) -> &'static str {
+        "fourscore"
+    }
+
+    fn write(&mut self, out: u8) {
+        self.strobe = out & 0x1 == 0x1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, register: usize) -> u8 {
+        if self.strobe {
+            self.latch();
+        }
+        let data = self.peek(register);
+        self.shift = (self.shift >> 1) | 0x80_0000;
+        data
+    }
+
+    fn peek(&self, _register: usize) -> u8 {
+        (self.shift & 0x1) as u8
+    }
+
+    fn set_input(&mut self, input: DeviceInput) {
+        match input {
+            DeviceInput::Buttons(buttons) => self.buttons[0] = buttons,
+            DeviceInput::ExtraButtons(buttons) => self.buttons[1] = buttons,
+            _ => {}
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.shift);
+        w.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.shift = r.read_u32()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
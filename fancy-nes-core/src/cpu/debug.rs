@@ -1,16 +1,17 @@
-use std::cell::Ref;
-use std::ops::Deref;
+use core::ops::Deref;
 
 
-use crate::cpu::mem::*;
-use crate::cpu::{decode::{LUT_6502, Instruction}};
+use crate::prelude::*;
+use crate::bus::*;
+use crate::cpu::decode::LUT_6502;
+use crate::symbols::Symbols;
 
 /// Provide the facilities necessary for the nes-platform
 /// crate to generate a disasm view of the current NES PRG.
 
-use super::{AddressingMode, mem::CPUMemory, NESCpu};
+use super::{AddressingMode, NESCpu};
 
-pub fn cpu_dump<'a>(cpu: impl Deref<Target = NESCpu<'a>>) -> String {
+pub fn cpu_dump(cpu: impl Deref<Target = NESCpu>) -> String {
     let mut dump: String = String::new();
     let items_on_stack = 0xFF - cpu.SP;
 
@@ -19,11 +20,12 @@ pub fn cpu_dump<'a>(cpu: impl Deref<Target = NESCpu<'a>>) -> String {
     if cpu.last_legal_instruction.is_some() {
         dump.push_str(format!("\tPrevious: ${:X}: {}\n", 
             cpu.last_legal_instruction.unwrap(),
-            disasm_6502(cpu.last_legal_instruction.unwrap(), &cpu.memory).0.as_str()).as_str());
+            disasm_6502(cpu.last_legal_instruction.unwrap(), &cpu.bus).0.as_str()).as_str());
     }
     dump.push_str(format!("Stack (descending - {} items)\n", items_on_stack).as_str());
     for saddr in ((cpu.SP as u16+0x0101)..=0x01FFu16).rev() {
-        dump.push_str(format!("${:X}: {:0>2X}\n", saddr, cpu.memory.read(saddr)).as_str());
+        /* The stack always lives in internal RAM, so this cannot fail */
+        dump.push_str(format!("${:X}: {:0>2X}\n", saddr, cpu.bus.read(saddr).unwrap_or_default()).as_str());
     }
 
     dump
@@ -31,84 +33,105 @@ pub fn cpu_dump<'a>(cpu: impl Deref<Target = NESCpu<'a>>) -> String {
 
 // Returns the string of disassembly, as well as the address delta to the next
 // instruction.
-pub fn disasm_6502(instruction_addr: u16, mem: &CPUMemory) -> (String, u16) {
-    use AddressingMode::*;
-
-    let opcode = &mem.read(instruction_addr);
-    let instr_opt = LUT_6502.get(opcode);
-    let instr: &Instruction;
-    let operand: u16;
-
-    if instr_opt.is_none() {
-        return (format!("Unknown disassembly for opcode {:X}", opcode), 0);
-    }
+pub fn disasm_6502(instruction_addr: u16, mem: &Bus) -> (String, u16) {
+    disasm_6502_labelled(instruction_addr, mem, None)
+}
 
-    instr = instr_opt.unwrap();
+// As disasm_6502, naming the addresses operands refer to from a symbol file,
+// e.g. "JSR reset_ppu" rather than "JSR $8123", and "BNE loop" for branches.
+pub fn disasm_6502_labelled(instruction_addr: u16, mem: &Bus, symbols: Option<&Symbols>) -> (String, u16) {
+    let opcode = match mem.read(instruction_addr) {
+        Ok(op) => op,
+        Err(e) => return (e, 0),
+    };
+    let instr = &LUT_6502[opcode as usize];
 
-    match instr.mode {
+    let operand = match instr.mode {
         AddressingMode::ZeroPage |
         AddressingMode::ZeroPageX |
         AddressingMode::ZeroPageY |
         AddressingMode::IndirectIndexed |
         AddressingMode::IndexedIndirect |
         AddressingMode::Immediate |
-        AddressingMode::Relative => {
-            operand = mem.read(instruction_addr + 1) as u16;
-        },
+        AddressingMode::Relative => mem.read(instruction_addr + 1).map(|data| data as u16),
         AddressingMode::Absolute |
         AddressingMode::AbsoluteX |
         AddressingMode::AbsoluteY |
-        AddressingMode::Indirect => {
-            operand = mem.read_16(instruction_addr + 1);
-        },
-        _ => { operand = 0xDEAD; }
+        AddressingMode::Indirect => mem.read_16(instruction_addr + 1),
+        _ => Ok(0),
+    };
+
+    let label = |addr: u16| symbols.and_then(|symbols| symbols.label(addr, &mem.cartridge)).map(str::to_string);
+    match operand {
+        Ok(operand) => format_instruction(opcode, operand, instruction_addr, label),
+        Err(e) => (e, 0),
     }
+}
+
+// As disasm_6502, for an instruction already fetched. Instructions with a
+// one byte operand take it from the low byte of `operand`.
+pub fn disasm_instruction(opcode: u8, operand: u16) -> (String, u16) {
+    format_instruction(opcode, operand, 0, |_| None)
+}
+
+/* Addresses which `label` names are shown by name. A branch's target is found from `instruction_addr`. */
+fn format_instruction(opcode: u8, operand: u16, instruction_addr: u16, label: impl Fn(u16) -> Option<String>) -> (String, u16) {
+    use AddressingMode::*;
+
+    let instr = &LUT_6502[opcode as usize];
+    let name = |addr: u16, zero_page: bool| {
+        let addr = if zero_page { addr & 0xFF } else { addr };
+        label(addr).unwrap_or_else(|| format!("${:X}", addr))
+    };
     let disasm: (String, u16);
 
     match instr.mode {
         Accumulator => {
-            disasm = (format!("{0}", instr.mnemonic), 1);
+            disasm = (format!("{0}", instr.mnemonic()), 1);
         }
         Implied => {
-            disasm = (format!("{0}", instr.mnemonic), 1);
+            disasm = (format!("{0}", instr.mnemonic()), 1);
         }
         Immediate => {
-            disasm = (format!("{0} #${1:X}", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} #${1:X}", instr.mnemonic(), operand as u8), 2);
         }
         Absolute => {
-            disasm = (format!("{0} ${1:X}", instr.mnemonic, operand), 3);
+            disasm = (format!("{0} {1}", instr.mnemonic(), name(operand, false)), 3);
         }
         ZeroPage => {
-            disasm = (format!("{0} ${1:X}", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} {1}", instr.mnemonic(), name(operand, true)), 2);
         }
         Relative => {
-            if (operand as u8) & 0b10000000 > 0 {
-                disasm = (format!("{0} ${1:X} (-${2:X})", instr.mnemonic, operand as u8,
+            let target = instruction_addr.wrapping_add(2).wrapping_add(operand as u8 as i8 as u16);
+            if let Some(target) = label(target) {
+                disasm = (format!("{0} {1}", instr.mnemonic(), target), 2);
+            } else if (operand as u8) & 0b10000000 > 0 {
+                disasm = (format!("{0} ${1:X} (-${2:X})", instr.mnemonic(), operand as u8,
                     !(operand as u8) + 1), 2)
             } else {
-                disasm = (format!("{0} ${1:X}", instr.mnemonic, operand as u8), 2);
+                disasm = (format!("{0} ${1:X}", instr.mnemonic(), operand as u8), 2);
             }
         }
         ZeroPageX => {
-            disasm = (format!("{0} ${1:X},X", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} {1},X", instr.mnemonic(), name(operand, true)), 2);
         }
         ZeroPageY => {
-            disasm = (format!("{0} ${1:X},Y", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} {1},Y", instr.mnemonic(), name(operand, true)), 2);
         }
         Indirect => {
-            disasm = (format!("{0} (${1:X})", instr.mnemonic, operand), 3);
+            disasm = (format!("{0} ({1})", instr.mnemonic(), name(operand, false)), 3);
         }
         AbsoluteX => {
-            disasm = (format!("{0} ${1:X},X", instr.mnemonic, operand), 3);
+            disasm = (format!("{0} {1},X", instr.mnemonic(), name(operand, false)), 3);
         }
         AbsoluteY => {
-            disasm = (format!("{0} ${1:X},Y", instr.mnemonic, operand), 3);
+            disasm = (format!("{0} {1},Y", instr.mnemonic(), name(operand, false)), 3);
         }
         IndexedIndirect => {
-            disasm = (format!("{0} (${1:X},X)", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} ({1},X)", instr.mnemonic(), name(operand, true)), 2);
         }
         IndirectIndexed => {
-            disasm = (format!("{0} (${1:X}),Y", instr.mnemonic, operand as u8), 2);
+            disasm = (format!("{0} ({1}),Y", instr.mnemonic(), name(operand, true)), 2);
         }
     }
 
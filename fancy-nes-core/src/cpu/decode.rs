@@ -1,112 +1,203 @@
-extern crate lazy_static;
+// The 6502's instruction set, decoded by a table of all 256 opcodes, which the
+// CPU indexes by the opcode it fetches.
 
-use std::{collections::HashMap, vec};
-use lazy_static::lazy_static;
 use super::AddressingMode;
 
-#[derive(Clone, Copy)]
+/* Declare the operations, with their mnemonics as written */
+macro_rules! operations {
+    ($($op:ident),* $(,)?) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Operation {
+            $($op),*
+        }
+
+        impl Operation {
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $(Operation::$op => stringify!($op)),*
+                }
+            }
+        }
+    };
+}
+
+operations! {
+    ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC, CLD, CLI, CLV, CMP,
+    CPX, CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP, JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA,
+    PHP, PLA, PLP, ROL, ROR, RTI, RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA,
+    TXS, TYA,
+
+    /* Undocumented */
+    AHX, ALR, ANC, ARR, AXS, DCP, ISC, JAM, LAS, LAX, LXA, RLA, RRA, SAX, SHX, SHY, SLO, SRE,
+    TAS, XAA
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Instruction {
-    pub mnemonic: &'static str,
+    pub op: Operation,
     pub mode: AddressingMode,
     pub cycles: u8,
+    pub illegal: bool,  /* undocumented, but present on real silicon */
 }
 
-lazy_static! {
-    pub static ref LUT_6502: HashMap<u8, Instruction> = {
-        let mut lut = HashMap::new();
-        let mut add = |ops_str, ops: Vec<(u8, AddressingMode, u8)>| {
-            for op in ops {
-                lut.insert(op.0, Instruction { mnemonic: ops_str, mode: op.1, cycles: op.2 });
-            }
-        };
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        self.op.mnemonic()
+    }
+}
 
-        /* We're going to need shorter aliases for that large LUT! */
-        use AddressingMode::Immediate as IMM;
-        use AddressingMode::Implied as IMP;
-        use AddressingMode::Accumulator as ACC;
-        use AddressingMode::ZeroPage as ZP;
-        use AddressingMode::ZeroPageX as ZPX;
-        use AddressingMode::ZeroPageY as ZPY;
-        use AddressingMode::Relative as REL;
-        use AddressingMode::Absolute as ABS;
-        use AddressingMode::AbsoluteX as ABX;
-        use AddressingMode::AbsoluteY as ABY;
-        use AddressingMode::Indirect as IND;
-        use AddressingMode::IndexedIndirect as IDI;
-        use AddressingMode::IndirectIndexed as IID;
+/// Every opcode's instruction, indexed by the opcode
+pub static LUT_6502: [Instruction; 256] = build_lut();
 
-        add("ADC", vec![(0x69, IMM, 2), (0x65, ZP, 3), (0x75, ZPX, 4), (0x6D, ABS, 4),
-                (0x7D, ABX, 4), (0x79, ABY, 4), (0x61, IDI, 6), (0x71, IID, 5)]);
-        add("AND", vec![(0x29, IMM, 2), (0x25, ZP, 3), (0x35, ZPX, 4), (0x2D, ABS, 4),
-                (0x3D, ABX, 4), (0x39, ABY, 4), (0x21, IDI, 6), (0x31, IID, 5)]);
-        add("ASL", vec![(0x0A, ACC, 2), (0x06, ZP, 5), (0x16, ZPX, 6), (0x0E, ABS, 6), 
-                (0x1E, ABX, 7)]);
-        add("BCC", vec![(0x90, REL, 2)]);
-        add("BCS", vec![(0xB0, REL, 2)]);
-        add("BEQ", vec![(0xF0, REL, 2)]);
-        add("BIT", vec![(0x24, ZP, 3), (0x2C, ABS, 4)]);
-        add("BMI", vec![(0x30, REL, 2)]);
-        add("BNE", vec![(0xD0, REL, 2)]);
-        add("BPL", vec![(0x10, REL, 2)]);
-        add("BRK", vec![(0x00, IMP, 7)]);
-        add("BVC", vec![(0x50, REL, 2)]);
-        add("BVS", vec![(0x70, REL, 2)]);
-        add("CLC", vec![(0x18, IMP, 2)]);
-        add("CLD", vec![(0xD8, IMP, 2)]);
-        add("CLI", vec![(0x58, IMP, 2)]);
-        add("CLV", vec![(0xB8, IMP, 2)]);
-        add("CMP", vec![(0xC9, IMM, 2), (0xC5, ZP, 3), (0xD5, ZPX, 4), (0xCD, ABS, 4),
-                (0xDD, ABX, 4), (0xD9, ABY, 4), (0xC1, IDI, 6), (0xD1, IID, 5)]);
-        add("CPX", vec![(0xE0, IMM, 2), (0xE4, ZP, 3), (0xEC, ABS, 4)]);
-        add("CPY", vec![(0xC0, IMM, 2), (0xC4, ZP, 3), (0xCC, ABS, 4)]);
-        add("DEC", vec![(0xC6, ZP, 5), (0xD6, ZPX, 6), (0xCE, ABS, 6), (0xDE, ABX, 7)]);
-        add("DEX", vec![(0xCA, IMP, 2)]);
-        add("DEY", vec![(0x88, IMP, 2)]);
-        add("EOR", vec![(0x49, IMM, 2), (0x45, ZP, 3), (0x55, ZPX, 4), (0x4D, ABS, 4),
-                (0x5D, ABX, 4), (0x59, ABY, 3), (0x41, IDI, 6), (0x51, IID, 5)]);
-        add("INC", vec![(0xE6, ZP, 5), (0xF6, ZPX, 6), (0xEE, ABS, 6), (0xFE, ABX, 7)]);
-        add("INX", vec![(0xE8, IMP, 2)]);
-        add("INY", vec![(0xC8, IMP, 2)]);
-        add("JMP", vec![(0x4C, ABS, 3), (0x6C, IND, 5)]);
-        add("JSR", vec![(0x20, ABS, 6)]);
-        add("LDA", vec![(0xA9, IMM, 2), (0xA5, ZP, 3), (0xB5, ZPX, 4), (0xAD, ABS, 4),
-                (0xBD, ABX, 4), (0xB9, ABY, 4), (0xA1, IDI, 6), (0xB1, IID, 5)]);
-        add("LDX", vec![(0xA2, IMM, 2), (0xA6, ZP, 3), (0xB6, ZPY, 4), (0xAE, ABS, 4),
-                (0xBE, ABY, 4)]);
-        add("LDY", vec![(0xA0, IMM, 2), (0xA4, ZP, 3), (0xB4, ZPX, 4), (0xAC, ABS, 4),
-                (0xBC, ABX, 4)]);
-        add("LSR", vec![(0x4A, ACC, 2), (0x46, ZP, 5), (0x56, ZPX, 6), (0x4E, ABS, 6),
-                (0x5E, ABX, 7)]);
-        add("NOP", vec![(0xEA, IMP, 2)]);
-        add("ORA", vec![(0x09, IMM, 2), (0x05, ZP, 3), (0x15, ZPX, 4), (0x0D, ABS, 4),
-                (0x1D, ABX, 4), (0x19, ABY, 4), (0x01, IDI, 6), (0x11, IID, 5)]);
-        add("PHA", vec![(0x48, IMP, 3)]);
-        add("PHP", vec![(0x08, IMP, 3)]);
-        add("PLA", vec![(0x68, IMP, 4)]);
-        add("PLP", vec![(0x28, IMP, 4)]);
-        add("ROL", vec![(0x2A, ACC, 2), (0x26, ZP, 5), (0x36, ZPX, 6), (0x2E, ABS, 6),
-                (0x3E, ABX, 7)]);
-        add("ROR", vec![(0x6A, ACC, 2), (0x66, ZP, 5), (0x76, ZPX, 6), (0x6E, ABS, 6),
-                (0x7E, ABX, 7)]);
-        add("RTI", vec![(0x40, IMP, 6)]);
-        add("RTS", vec![(0x60, IMP, 6)]);
-        add("SBC", vec![(0xE9, IMM, 2), (0xE5, ZP, 3), (0xF5, ZPX, 4), (0xED, ABS, 4),
-                (0xFD, ABX, 4), (0xF9, ABY, 4), (0xE1, IDI, 6), (0xF1, IID, 5)]);
-        add("SEC", vec![(0x38, IMP, 2)]);
-        add("SED", vec![(0xF8, IMP, 2)]);
-        add("SEI", vec![(0x78, IMP, 2)]);
-        add("STA", vec![(0x85, ZP, 3), (0x95, ZPX, 4), (0x8D, ABS, 4), (0x9D, ABX, 5),
-                (0x99, ABY, 5), (0x81, IDI, 6), (0x91, IID, 6)]);
-        add("STX", vec![(0x86, ZP, 3), (0x96, ZPY, 4), (0x8E, ABS, 4)]);
-        add("STY", vec![(0x84, ZP, 3), (0x94, ZPX, 4), (0x8C, ABS, 4)]);
-        add("TAX", vec![(0xAA, IMP, 2)]);
-        add("TAY", vec![(0xA8, IMP, 2)]);
-        add("TSX", vec![(0xBA, IMP, 2)]);
-        add("TXA", vec![(0x8A, IMP, 2)]);
-        add("TXS", vec![(0x9A, IMP, 2)]);
-        add("TYA", vec![(0x98, IMP, 2)]);
+const fn add(lut: &mut [Option<Instruction>; 256], op: Operation, ops: &[(u8, AddressingMode, u8)], illegal: bool) {
+    let mut i = 0;
+    while i < ops.len() {
+        let (opcode, mode, cycles) = ops[i];
+        lut[opcode as usize] = Some(Instruction { op, mode, cycles, illegal });
+        i += 1;
+    }
+}
 
-        /* TODO: Illegal opcodes */
-        lut
-    };
-}
\ No newline at end of file
+const fn build_lut() -> [Instruction; 256] {
+    use Operation::*;
+
+    let mut lut: [Option<Instruction>; 256] = [None; 256];
+
+    /* We're going to need shorter aliases for that large LUT! */
+    use AddressingMode::Immediate as IMM;
+    use AddressingMode::Implied as IMP;
+    use AddressingMode::Accumulator as ACC;
+    use AddressingMode::ZeroPage as ZP;
+    use AddressingMode::ZeroPageX as ZPX;
+    use AddressingMode::ZeroPageY as ZPY;
+    use AddressingMode::Relative as REL;
+    use AddressingMode::Absolute as ABS;
+    use AddressingMode::AbsoluteX as ABX;
+    use AddressingMode::AbsoluteY as ABY;
+    use AddressingMode::Indirect as IND;
+    use AddressingMode::IndexedIndirect as IDI;
+    use AddressingMode::IndirectIndexed as IID;
+
+    add(&mut lut, ADC, &[(0x69, IMM, 2), (0x65, ZP, 3), (0x75, ZPX, 4), (0x6D, ABS, 4),
+            (0x7D, ABX, 4), (0x79, ABY, 4), (0x61, IDI, 6), (0x71, IID, 5)], false);
+    add(&mut lut, AND, &[(0x29, IMM, 2), (0x25, ZP, 3), (0x35, ZPX, 4), (0x2D, ABS, 4),
+            (0x3D, ABX, 4), (0x39, ABY, 4), (0x21, IDI, 6), (0x31, IID, 5)], false);
+    add(&mut lut, ASL, &[(0x0A, ACC, 2), (0x06, ZP, 5), (0x16, ZPX, 6), (0x0E, ABS, 6), 
+            (0x1E, ABX, 7)], false);
+    add(&mut lut, BCC, &[(0x90, REL, 2)], false);
+    add(&mut lut, BCS, &[(0xB0, REL, 2)], false);
+    add(&mut lut, BEQ, &[(0xF0, REL, 2)], false);
+    add(&mut lut, BIT, &[(0x24, ZP, 3), (0x2C, ABS, 4)], false);
+    add(&mut lut, BMI, &[(0x30, REL, 2)], false);
+    add(&mut lut, BNE, &[(0xD0, REL, 2)], false);
+    add(&mut lut, BPL, &[(0x10, REL, 2)], false);
+    add(&mut lut, BRK, &[(0x00, IMP, 7)], false);
+    add(&mut lut, BVC, &[(0x50, REL, 2)], false);
+    add(&mut lut, BVS, &[(0x70, REL, 2)], false);
+    add(&mut lut, CLC, &[(0x18, IMP, 2)], false);
+    add(&mut lut, CLD, &[(0xD8, IMP, 2)], false);
+    add(&mut lut, CLI, &[(0x58, IMP, 2)], false);
+    add(&mut lut, CLV, &[(0xB8, IMP, 2)], false);
+    add(&mut lut, CMP, &[(0xC9, IMM, 2), (0xC5, ZP, 3), (0xD5, ZPX, 4), (0xCD, ABS, 4),
+            (0xDD, ABX, 4), (0xD9, ABY, 4), (0xC1, IDI, 6), (0xD1, IID, 5)], false);
+    add(&mut lut, CPX, &[(0xE0, IMM, 2), (0xE4, ZP, 3), (0xEC, ABS, 4)], false);
+    add(&mut lut, CPY, &[(0xC0, IMM, 2), (0xC4, ZP, 3), (0xCC, ABS, 4)], false);
+    add(&mut lut, DEC, &[(0xC6, ZP, 5), (0xD6, ZPX, 6), (0xCE, ABS, 6), (0xDE, ABX, 7)], false);
+    add(&mut lut, DEX, &[(0xCA, IMP, 2)], false);
+    add(&mut lut, DEY, &[(0x88, IMP, 2)], false);
+    add(&mut lut, EOR, &[(0x49, IMM, 2), (0x45, ZP, 3), (0x55, ZPX, 4), (0x4D, ABS, 4),
+            (0x5D, ABX, 4), (0x59, ABY, 3), (0x41, IDI, 6), (0x51, IID, 5)], false);
+    add(&mut lut, INC, &[(0xE6, ZP, 5), (0xF6, ZPX, 6), (0xEE, ABS, 6), (0xFE, ABX, 7)], false);
+    add(&mut lut, INX, &[(0xE8, IMP, 2)], false);
+    add(&mut lut, INY, &[(0xC8, IMP, 2)], false);
+    add(&mut lut, JMP, &[(0x4C, ABS, 3), (0x6C, IND, 5)], false);
+    add(&mut lut, JSR, &[(0x20, ABS, 6)], false);
+    add(&mut lut, LDA, &[(0xA9, IMM, 2), (0xA5, ZP, 3), (0xB5, ZPX, 4), (0xAD, ABS, 4),
+            (0xBD, ABX, 4), (0xB9, ABY, 4), (0xA1, IDI, 6), (0xB1, IID, 5)], false);
+    add(&mut lut, LDX, &[(0xA2, IMM, 2), (0xA6, ZP, 3), (0xB6, ZPY, 4), (0xAE, ABS, 4),
+            (0xBE, ABY, 4)], false);
+    add(&mut lut, LDY, &[(0xA0, IMM, 2), (0xA4, ZP, 3), (0xB4, ZPX, 4), (0xAC, ABS, 4),
+            (0xBC, ABX, 4)], false);
+    add(&mut lut, LSR, &[(0x4A, ACC, 2), (0x46, ZP, 5), (0x56, ZPX, 6), (0x4E, ABS, 6),
+            (0x5E, ABX, 7)], false);
+    add(&mut lut, NOP, &[(0xEA, IMP, 2)], false);
+    add(&mut lut, ORA, &[(0x09, IMM, 2), (0x05, ZP, 3), (0x15, ZPX, 4), (0x0D, ABS, 4),
+            (0x1D, ABX, 4), (0x19, ABY, 4), (0x01, IDI, 6), (0x11, IID, 5)], false);
+    add(&mut lut, PHA, &[(0x48, IMP, 3)], false);
+    add(&mut lut, PHP, &[(0x08, IMP, 3)], false);
+    add(&mut lut, PLA, &[(0x68, IMP, 4)], false);
+    add(&mut lut, PLP, &[(0x28, IMP, 4)], false);
+    add(&mut lut, ROL, &[(0x2A, ACC, 2), (0x26, ZP, 5), (0x36, ZPX, 6), (0x2E, ABS, 6),
+            (0x3E, ABX, 7)], false);
+    add(&mut lut, ROR, &[(0x6A, ACC, 2), (0x66, ZP, 5), (0x76, ZPX, 6), (0x6E, ABS, 6),
+            (0x7E, ABX, 7)], false);
+    add(&mut lut, RTI, &[(0x40, IMP, 6)], false);
+    add(&mut lut, RTS, &[(0x60, IMP, 6)], false);
+    add(&mut lut, SBC, &[(0xE9, IMM, 2), (0xE5, ZP, 3), (0xF5, ZPX, 4), (0xED, ABS, 4),
+            (0xFD, ABX, 4), (0xF9, ABY, 4), (0xE1, IDI, 6), (0xF1, IID, 5)], false);
+    add(&mut lut, SEC, &[(0x38, IMP, 2)], false);
+    add(&mut lut, SED, &[(0xF8, IMP, 2)], false);
+    add(&mut lut, SEI, &[(0x78, IMP, 2)], false);
+    add(&mut lut, STA, &[(0x85, ZP, 3), (0x95, ZPX, 4), (0x8D, ABS, 4), (0x9D, ABX, 5),
+            (0x99, ABY, 5), (0x81, IDI, 6), (0x91, IID, 6)], false);
+    add(&mut lut, STX, &[(0x86, ZP, 3), (0x96, ZPY, 4), (0x8E, ABS, 4)], false);
+    add(&mut lut, STY, &[(0x84, ZP, 3), (0x94, ZPX, 4), (0x8C, ABS, 4)], false);
+    add(&mut lut, TAX, &[(0xAA, IMP, 2)], false);
+    add(&mut lut, TAY, &[(0xA8, IMP, 2)], false);
+    add(&mut lut, TSX, &[(0xBA, IMP, 2)], false);
+    add(&mut lut, TXA, &[(0x8A, IMP, 2)], false);
+    add(&mut lut, TXS, &[(0x9A, IMP, 2)], false);
+    add(&mut lut, TYA, &[(0x98, IMP, 2)], false);
+
+    /* The undocumented opcodes. Cycle counts are as per the NESdev wiki;
+       the unstable ones (XAA, LXA, AHX, SHX, SHY, TAS) are given their
+       most commonly observed behaviour. */
+    add(&mut lut, AHX, &[(0x9F, ABY, 5), (0x93, IID, 6)], true);
+    add(&mut lut, ALR, &[(0x4B, IMM, 2)], true);
+    add(&mut lut, ANC, &[(0x0B, IMM, 2), (0x2B, IMM, 2)], true);
+    add(&mut lut, ARR, &[(0x6B, IMM, 2)], true);
+    add(&mut lut, AXS, &[(0xCB, IMM, 2)], true);
+    add(&mut lut, DCP, &[(0xC7, ZP, 5), (0xD7, ZPX, 6), (0xCF, ABS, 6), (0xDF, ABX, 7),
+            (0xDB, ABY, 7), (0xC3, IDI, 8), (0xD3, IID, 8)], true);
+    add(&mut lut, ISC, &[(0xE7, ZP, 5), (0xF7, ZPX, 6), (0xEF, ABS, 6), (0xFF, ABX, 7),
+            (0xFB, ABY, 7), (0xE3, IDI, 8), (0xF3, IID, 8)], true);
+    add(&mut lut, JAM, &[(0x02, IMP, 2), (0x12, IMP, 2), (0x22, IMP, 2), (0x32, IMP, 2),
+            (0x42, IMP, 2), (0x52, IMP, 2), (0x62, IMP, 2), (0x72, IMP, 2), (0x92, IMP, 2),
+            (0xB2, IMP, 2), (0xD2, IMP, 2), (0xF2, IMP, 2)], true);
+    add(&mut lut, LAS, &[(0xBB, ABY, 4)], true);
+    add(&mut lut, LAX, &[(0xA7, ZP, 3), (0xB7, ZPY, 4), (0xAF, ABS, 4), (0xBF, ABY, 4),
+            (0xA3, IDI, 6), (0xB3, IID, 5)], true);
+    add(&mut lut, LXA, &[(0xAB, IMM, 2)], true);
+    add(&mut lut, NOP, &[(0x1A, IMP, 2), (0x3A, IMP, 2), (0x5A, IMP, 2), (0x7A, IMP, 2),
+            (0xDA, IMP, 2), (0xFA, IMP, 2), (0x80, IMM, 2), (0x82, IMM, 2), (0x89, IMM, 2),
+            (0xC2, IMM, 2), (0xE2, IMM, 2), (0x04, ZP, 3), (0x44, ZP, 3), (0x64, ZP, 3),
+            (0x14, ZPX, 4), (0x34, ZPX, 4), (0x54, ZPX, 4), (0x74, ZPX, 4), (0xD4, ZPX, 4),
+            (0xF4, ZPX, 4), (0x0C, ABS, 4), (0x1C, ABX, 4), (0x3C, ABX, 4), (0x5C, ABX, 4),
+            (0x7C, ABX, 4), (0xDC, ABX, 4), (0xFC, ABX, 4)], true);
+    add(&mut lut, RLA, &[(0x27, ZP, 5), (0x37, ZPX, 6), (0x2F, ABS, 6), (0x3F, ABX, 7),
+            (0x3B, ABY, 7), (0x23, IDI, 8), (0x33, IID, 8)], true);
+    add(&mut lut, RRA, &[(0x67, ZP, 5), (0x77, ZPX, 6), (0x6F, ABS, 6), (0x7F, ABX, 7),
+            (0x7B, ABY, 7), (0x63, IDI, 8), (0x73, IID, 8)], true);
+    add(&mut lut, SAX, &[(0x87, ZP, 3), (0x97, ZPY, 4), (0x8F, ABS, 4), (0x83, IDI, 6)], true);
+    add(&mut lut, SBC, &[(0xEB, IMM, 2)], true);
+    add(&mut lut, SHX, &[(0x9E, ABY, 5)], true);
+    add(&mut lut, SHY, &[(0x9C, ABX, 5)], true);
+    add(&mut lut, SLO, &[(0x07, ZP, 5), (0x17, ZPX, 6), (0x0F, ABS, 6), (0x1F, ABX, 7),
+            (0x1B, ABY, 7), (0x03, IDI, 8), (0x13, IID, 8)], true);
+    add(&mut lut, SRE, &[(0x47, ZP, 5), (0x57, ZPX, 6), (0x4F, ABS, 6), (0x5F, ABX, 7),
+            (0x5B, ABY, 7), (0x43, IDI, 8), (0x53, IID, 8)], true);
+    add(&mut lut, TAS, &[(0x9B, ABY, 5)], true);
+    add(&mut lut, XAA, &[(0x8B, IMM, 2)], true);
+
+    /* Every opcode does something, if only jam the CPU - a gap is a mistake in the table */
+    let mut table = [Instruction { op: JAM, mode: IMP, cycles: 2, illegal: true }; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = match lut[opcode] {
+            Some(instruction) => instruction,
+            None => panic!("An opcode is missing from LUT_6502"),
+        };
+        opcode += 1;
+    }
+    table
+}
@@ -0,0 +1,170 @@
+//! Input devices other than the standard controller, which the Famicom takes
+//! in its expansion port (and the NES, for the paddle, in a controller port):
+//!
+//! The Family BASIC keyboard, a matrix of 72 keys read 4 at a time. Writes to
+//! $4016 pick the row and which half of it (the column), and reads of $4017
+//! return its keys on D1-D4, 0 for those down. Row 9, past the last, reads all
+//! 1s, which is how Family BASIC knows the keyboard is there.
+//!
+//! The Arkanoid "Vaus" paddle, a knob and a button. Strobing $4016 latches the
+//! knob's position, which is then read a bit at a time, most significant first
+//! and inverted: on D1 of $4017 for the Famicom's, or D4 of its port for the
+//! NES's, whose button is on D3 (the Famicom's on D1 of $4016).
+
+use crate::prelude::*;
+use crate::cpu::controller::{DeviceInput, InputDevice};
+use crate::state::{StateReader, StateWriter};
+
+/// The keyboard's keys, as `DeviceInput::Key` numbers them: eight to a row, the
+/// first column's four (D4 down to D1) and then the second's
+pub const KEYS: [&str; 72] = [
+    "]", "[", "Return", "F8", "Stop", "Yen", "RShift", "Kana",
+    ";", ":", "@", "F7", "^", "-", "/", "_",
+    "K", "L", "O", "F6", "0", "P", ",", ".",
+    "J", "U", "I", "F5", "8", "9", "N", "M",
+    "H", "G", "Y", "F4", "6", "7", "V", "B",
+    "D", "R", "T", "F3", "4", "5", "C", "F",
+    "A", "S", "W", "F2", "3", "E", "Z", "X",
+    "Ctr", "Q", "Esc", "F1", "2", "1", "Grph", "LShift",
+    "Left", "Right", "Up", "ClrHome", "Ins", "Del", "Space", "Down",
+];
+
+const ROWS: usize = KEYS.len() / 8;
+
+/// The number of a key in KEYS, by its name there
+pub fn key_index(name: &str) -> Option<usize> {
+    KEYS.iter().position(|key| key.eq_ignore_ascii_case(name))
+}
+
+/// The range of the paddle's knob, from fully left to fully right
+pub const PADDLE_MIN: u8 = 98;
+pub const PADDLE_MAX: u8 = 242;
+
+#[derive(Default)]
+pub struct FamilyKeyboard {
+    keys: [u8; ROWS * 2],  /* By row and column, the keys down as the D1-D4 bits they read on */
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InputDevice for FamilyKeyboard {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    /* OUT0 goes back to the first row, OUT1 picks the column (moving on a row as it
+       falls) and OUT2 enables the matrix */
+    fn write(&mut self, out: u8) {
+        let column = ((out >> 1) & 0x1) as usize;
+        if self.column == 1 && column == 0 {
+            self.row = (self.row + 1).min(ROWS);
+        }
+        self.column = column;
+        if out & 0x1 == 0x1 {
+            self.row = 0;
+        }
+        self.enabled = out & 0x4 == 0x4;
+    }
+
+    fn read(&mut self, register: usize) -> u8 {
+        self.peek(register)
+    }
+
+    fn peek(&self, register: usize) -> u8 {
+        if register == 0 || !self.enabled {
+            return 0;
+        }
+        match self.keys.get(self.row * 2 + self.column) {
+            Some(keys) => !keys & 0x1E,
+            None => 0x1E,
+        }
+    }
+
+    fn set_input(&mut self, input: DeviceInput) {
+        if let DeviceInput::Key(index, down) = input {
+            let Some(keys) = self.keys.get_mut(index / 4) else { return };
+            let bit = 0x10 >> (index % 4);
+            if down { *keys |= bit } else { *keys &= !bit }
+        }
+    }
+
+    /* Which keys are down is the frontend's to say, not the state's */
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.row as u8);
+        w.write_u8(self.column as u8);
+        w.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.row = (r.read_u8()? as usize).min(ROWS);
+        self.column = (r.read_u8()? & 0x1) as usize;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+}
+
+pub struct ArkanoidPaddle {
+    famicom: bool,  /* In the expansion port, rather than a controller port */
+    position: u8,
+    fire: bool,
+    shift: u8,      /* The position latched, shifting out */
+}
+
+impl ArkanoidPaddle {
+    /// The Famicom's paddle, in the expansion port, or the NES's, in a controller port
+    pub fn new(famicom: bool) -> Self {
+        Self { famicom, position: PADDLE_MIN, fire: false, shift: 0 }
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn name(&self) -> &'static str {
+        "paddle"
+    }
+
+    fn write(&mut self, out: u8) {
+        if out & 0x1 == 0x1 {
+            self.shift = self.position;
+        }
+    }
+
+    fn read(&mut self, register: usize) -> u8 {
+        let data = self.peek(register);
+        if !self.famicom || register == 1 {
+            self.shift <<= 1;
+        }
+        data
+    }
+
+    fn peek(&self, register: usize) -> u8 {
+        let bit = !self.shift >> 7;
+        match (self.famicom, register) {
+            (true, 0) => (self.fire as u8) << 1,
+            (true, _) => bit << 1,
+            (false, _) => bit << 4 | (self.fire as u8) << 3,
+        }
+    }
+
+    fn set_input(&mut self, input: DeviceInput) {
+        if let DeviceInput::Paddle { position, fire } = input {
+            self.position = position.clamp(PADDLE_MIN, PADDLE_MAX);
+            self.fire = fire;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.shift);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.shift = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+// A record of the last instructions executed, for the debugger and crash reports.
+// Unlike the TraceUnit, this is always on, so recording an instruction is only
+// a copy into a fixed ring buffer - formatting waits until someone looks.
+
+use core::fmt;
+
+use crate::prelude::*;
+use super::debug::disasm_instruction;
+
+/// How many of the most recently executed instructions are remembered
+pub const HISTORY_LEN: usize = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    pub operands: [u8; 2],  /* The two bytes after the opcode, whether the instruction uses them or not */
+
+    /* Registers as the instruction found them */
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub status: u8,
+    pub cycle: u64,
+}
+
+impl HistoryEntry {
+    /// Disassemble the instruction as it was fetched, which may no longer be
+    /// what's in memory if a mapper has switched banks since
+    pub fn disassemble(&self) -> String {
+        disasm_instruction(self.opcode, u16::from_le_bytes(self.operands)).0
+    }
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:0>4X}: {:<16} A:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X} CYC:{}",
+            self.pc, self.disassemble(), self.a, self.x, self.y, self.status, self.sp, self.cycle)
+    }
+}
+
+pub struct History {
+    entries: [HistoryEntry; HISTORY_LEN],
+    next: usize,  /* Where the next entry goes, overwriting the oldest once full */
+    len: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            entries: [HistoryEntry::default(); HISTORY_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.len = (self.len + 1).min(HISTORY_LEN);
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The entries, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> + ExactSizeIterator {
+        let start = (self.next + HISTORY_LEN - self.len) % HISTORY_LEN;
+        (0..self.len).map(move |i| &self.entries[(start + i) % HISTORY_LEN])
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
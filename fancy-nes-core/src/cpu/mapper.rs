@@ -1,11 +1,49 @@
-/// Mappers need to describe how to handle addresses in the range 0x4020-0xFFFF.
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+/// A mapper is the logic on a cartridge board which decides what of the cartridge's
+/// memory appears where: its bank switching registers, and any IRQ counter. The
+/// memory itself belongs to the Cartridge, which gives it to the mapper to change.
 /// In reality, most mappers don't handle addresses < $6000, where work RAM typically begins.
+/// Mappers must be Send, so that the machine which owns them can run on its own thread.
+
+pub trait Mapper: Send {
+    // Map the banks the board powers on with, once the ROM has been loaded.
+    // Until then, the first bank of PRG ROM is at $8000, the last banks above
+    // it, and the first banks of CHR in order.
+    fn power_on(&mut self, _memory: &mut Memory) {}
+
+    // Every CPU write to the cartridge ($4020-$FFFF). Writes to work RAM have
+    // already been made.
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8);
+
+    // Whether `write` does anything with an address: whether the board has a
+    // register there. The PPU is only brought up to date before such writes,
+    // since others can't change what it sees.
+    fn decodes_write(&self, addr: u16) -> bool { addr >= 0x8000 }
+
+    // Each pattern table fetch the PPU makes while rendering, after it's been
+    // read, for mappers which switch CHR banks on seeing particular tiles
+    // fetched (MMC2's latches)
+    fn pattern_fetch(&mut self, _memory: &mut Memory, _addr: u16) {}
+
+    // Once every CPU cycle, for mappers which count them
+    fn clock(&mut self) {}
+
+    // Mappers with an IRQ counter hold the CPU's IRQ line while it's asserted
+    fn irq(&self) -> bool { false }
+
+    // Boards with their own sound chip (expansion audio) feed its output back to
+    // the console, which adds it to the APU's. This is the level, on the scale of
+    // the APU's mix (see NESApu::mix), once per CPU cycle after `clock`.
+    fn audio(&self) -> f32 { 0.0 }
 
-pub trait Mapper<Tr, Tw> {
-    // Use the &self version for no-side-effect (fake) accesses, such as
-    // querying memory for the disassembler
-    fn read(&self, addr: u16) -> Tr;
-    fn write(&mut self, addr: u16, data: u8) -> Result<Tw, String>;
+    // Registers whose effect isn't evident from the banks mapped, e.g. for crash reports
+    fn describe(&self) -> String { String::new() }
 
-    fn load_rom(&mut self, rom: &Vec<u8>);
-}
\ No newline at end of file
+    // Save states only cover the mapper's registers. The Cartridge saves the
+    // banks mapped and RAM itself.
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
@@ -0,0 +1,30 @@
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// UxROM (UNROM, UOROM) - used by Mega Man, Castlevania, Contra, DuckTales...
+// $8000-$BFFF is a switchable 16KiB PRG bank, selected by writing
+// to anywhere in $8000-$FFFF. $C000-$FFFF is fixed to the last bank.
+// The ROM drives the bus during the write too, so games write the bank number
+// over a ROM byte holding the same value. The conflict itself isn't emulated.
+
+// Almost all UxROM boards carry 8KiB of CHR RAM rather than CHR ROM,
+// which ChrMemory allocates when the ROM image provides no CHR data.
+
+pub struct Mapper002;
+
+impl Mapper for Mapper002 {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            memory.prg_rom.select(0, data as usize);
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
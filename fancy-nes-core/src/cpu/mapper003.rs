@@ -0,0 +1,27 @@
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// CNROM - used by Gradius, Paperboy, Arkanoid, Solomon's Key...
+// PRG is laid out exactly as NROM (16K or 32K, no PRG RAM). An 8KiB CHR bank
+// is selected by writing to anywhere in $8000-$FFFF.
+// As on UxROM, the write conflicts with the ROM byte at its address, which
+// games avoid by writing through a table of bank numbers. Not emulated.
+
+pub struct Mapper003;
+
+impl Mapper for Mapper003 {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            memory.chr.select(0, data as usize);
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
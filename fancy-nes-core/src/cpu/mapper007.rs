@@ -0,0 +1,35 @@
+use crate::prelude::*;
+use crate::Mirroring;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// AxROM (ANROM, AOROM, AMROM) - used by Battletoads, Marble Madness, Wizards & Warriors...
+// All of $8000-$FFFF is one switchable 32KiB PRG bank, selected by bits 0-2 of
+// a write to anywhere in $8000-$FFFF. Bit 4 of the same write chooses which of
+// the two nametables in VRAM is shown on all four screens, ignoring the header's
+// mirroring.
+
+// AxROM boards carry 8KiB of CHR RAM rather than CHR ROM.
+
+pub struct Mapper007;
+
+impl Mapper for Mapper007 {
+    fn power_on(&mut self, memory: &mut Memory) {
+        memory.nametables.set(Mirroring::SingleScreenA);
+    }
+
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            memory.prg_rom.select(0, (data & 0x07) as usize);
+            memory.nametables.set(if data & 0x10 == 0 { Mirroring::SingleScreenA } else { Mirroring::SingleScreenB });
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::prelude::*;
+use crate::Mirroring;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// MMC2 (PxROM) - used by Mike Tyson's Punch-Out!! and Punch-Out!!
+// $8000-$9FFF is a switchable 8KiB PRG bank, selected by writing to $A000-$AFFF,
+// and $A000-$FFFF is fixed to the last three banks. $F000-$FFFF selects mirroring.
+
+// Each 4KiB half of CHR has two bank registers, one for each state of a latch,
+// which flips when the PPU fetches the pattern of tile $FD or $FE from that half:
+// $0FD8 / $0FE8 for the left half, and $1FD8-$1FDF / $1FE8-$1FEF for the right.
+// The new bank takes effect from the next fetch. This lets the game switch
+// graphics part way down the screen without an IRQ.
+
+#[derive(Default)]
+pub struct Mapper009 {
+    chr_banks: [[u8; 2]; 2],  /* The 4KiB banks for each half of CHR, while its latch is $FD and $FE */
+    latches: [bool; 2],       /* Whether each half's latch is $FE */
+}
+
+impl Mapper009 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Map each half the bank its latch selects */
+    fn update_banks(&self, memory: &mut Memory) {
+        for half in 0..2 {
+            memory.chr.select(half, self.chr_banks[half][self.latches[half] as usize] as usize);
+        }
+    }
+}
+
+impl Mapper for Mapper009 {
+    fn power_on(&mut self, memory: &mut Memory) {
+        self.update_banks(memory);
+    }
+
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        match addr {
+            0xA000..=0xAFFF => {
+                memory.prg_rom.select(0, (data & 0x0F) as usize);
+            }
+            0xB000..=0xEFFF => {
+                let register = (addr as usize - 0xB000) / 0x1000;
+                self.chr_banks[register / 2][register % 2] = data & 0x1F;
+                self.update_banks(memory);
+            }
+            0xF000..=0xFFFF => {
+                memory.nametables.set(if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal });
+            }
+            _ => {}
+        }
+    }
+
+    fn pattern_fetch(&mut self, memory: &mut Memory, addr: u16) {
+        let latch = match addr {
+            0x0FD8 | 0x1FD8..=0x1FDF => false,
+            0x0FE8 | 0x1FE8..=0x1FEF => true,
+            _ => return,
+        };
+        self.latches[(addr >> 12) as usize] = latch;
+        self.update_banks(memory);
+    }
+
+    fn describe(&self) -> String {
+        format!("latches: ${:X}/${:X}",
+            if self.latches[0] { 0xFE } else { 0xFD }, if self.latches[1] { 0xFE } else { 0xFD })
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        for banks in &self.chr_banks {
+            w.write_bytes(banks);
+        }
+        w.write_bool(self.latches[0]);
+        w.write_bool(self.latches[1]);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for banks in &mut self.chr_banks {
+            r.read_into(banks)?;
+        }
+        self.latches = [r.read_bool()?, r.read_bool()?];
+        Ok(())
+    }
+}
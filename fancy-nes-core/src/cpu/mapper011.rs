@@ -0,0 +1,28 @@
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// Color Dreams - used by Crystal Mines, Menace Beach, Metal Fighter and other unlicensed games.
+// A write to anywhere in $8000-$FFFF selects both banks at once: bits 0-1 the
+// 32KiB PRG bank at $8000-$FFFF, and bits 4-7 the 8KiB CHR bank.
+// The board has bus conflicts with its ROM, like the licensed discrete boards;
+// these aren't emulated either.
+
+pub struct Mapper011;
+
+impl Mapper for Mapper011 {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            memory.prg_rom.select(0, (data & 0x03) as usize);
+            memory.chr.select(0, (data >> 4) as usize);
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
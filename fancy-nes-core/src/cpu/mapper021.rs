@@ -0,0 +1,180 @@
+use crate::prelude::*;
+use crate::Mirroring;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+use super::mapper_util::VrcIrq;
+
+// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25) - used by Gradius II, Contra (J),
+// Ganbare Goemon 2, Tiny Toon Adventures...
+// Each chip has four registers at every $1000 from $8000, selected by two of its
+// pins which each board wires to different CPU address lines (see Variant):
+//   $8000      8KiB PRG bank at $8000 (or at $C000, in VRC4's swapped mode)
+//   $9000      Mirroring (VRC4: $9000-$9001, with the PRG mode at $9002)
+//   $A000      8KiB PRG bank at $A000
+//   $B000-$E003  1KiB CHR banks, two registers each - the low 4 bits, then the rest
+//   $F000-$F003  VRC4 only: IRQ latch low and high 4 bits, control and acknowledge
+// The last two 8KiB PRG banks are fixed, at $C000 (or $8000) and $E000.
+// There is 8KiB of PRG RAM at $6000 (VRC2 boards have at most a 1-bit latch there,
+// which RAM behaves like).
+
+/// How a board wires the chip's register select pins to the CPU's address lines.
+/// Where the submapper doesn't say, both wirings a mapper number was used for
+/// are decoded at once, which suits almost every game.
+#[derive(Clone, Copy)]
+struct Variant {
+    a0: u16,          /* The address lines driving the chip's A0 pin */
+    a1: u16,          /* ...and its A1 pin */
+    vrc4: bool,       /* VRC2 lacks the PRG mode, the single screen mirrorings and the IRQ */
+    chr_shift: bool,  /* VRC2a ignores the low bit of CHR bank numbers */
+}
+
+impl Variant {
+    fn new(mapper_id: usize, submapper_id: u8) -> Self {
+        let (a0, a1, vrc4) = match (mapper_id, submapper_id) {
+            (21, 1) => (0x02, 0x04, true),  /* VRC4a */
+            (21, 2) => (0x40, 0x80, true),  /* VRC4c */
+            (21, _) => (0x42, 0x84, true),
+            (22, _) => (0x02, 0x01, false), /* VRC2a */
+            (23, 1) => (0x01, 0x02, true),  /* VRC4f */
+            (23, 2) => (0x04, 0x08, true),  /* VRC4e */
+            (23, 3) => (0x01, 0x02, false), /* VRC2b */
+            (23, _) => (0x05, 0x0A, true),
+            (25, 1) => (0x02, 0x01, true),  /* VRC4b */
+            (25, 2) => (0x08, 0x04, true),  /* VRC4d */
+            (25, 3) => (0x02, 0x01, false), /* VRC2c */
+            (25, _) => (0x0A, 0x05, true),
+            _ => unreachable!(),
+        };
+        Self { a0, a1, vrc4, chr_shift: mapper_id == 22 }
+    }
+
+    /* The register an address selects, as $x000-$x003 */
+    fn register(&self, addr: u16) -> u16 {
+        (addr & 0xF000) | (addr & self.a0 != 0) as u16 | ((addr & self.a1 != 0) as u16) << 1
+    }
+}
+
+pub struct Mapper021 {
+    variant: Variant,
+
+    prg_banks: [u8; 2],   /* Selected 8KiB banks at $8000 (or $C000) and $A000 */
+    prg_swapped: bool,    /* Whether the first selected bank is at $C000, the fixed one at $8000 */
+    chr_banks: [u16; 8],  /* Selected 1KiB banks, before VRC2a's shift */
+    irq: VrcIrq,
+}
+
+impl Mapper021 {
+    pub fn new(mapper_id: usize, submapper_id: u8) -> Self {
+        Self {
+            variant: Variant::new(mapper_id, submapper_id),
+            prg_banks: [0, 0],
+            prg_swapped: false,
+            chr_banks: [0; 8],
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn update_prg_banks(&self, memory: &mut Memory) {
+        let second_last = memory.prg_rom.bank_count() - 2;
+        let (at_8000, at_c000) = if self.prg_swapped {
+            (second_last, self.prg_banks[0] as usize)
+        } else {
+            (self.prg_banks[0] as usize, second_last)
+        };
+        memory.prg_rom.select(0, at_8000);
+        memory.prg_rom.select(1, self.prg_banks[1] as usize);
+        memory.prg_rom.select(2, at_c000);
+    }
+
+    fn update_chr_banks(&self, memory: &mut Memory) {
+        let shift = self.variant.chr_shift as u16;
+        for (window, bank) in self.chr_banks.iter().enumerate() {
+            memory.chr.select(window, (bank >> shift) as usize);
+        }
+    }
+}
+
+impl Mapper for Mapper021 {
+    fn power_on(&mut self, memory: &mut Memory) {
+        self.update_prg_banks(memory);
+    }
+
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        match self.variant.register(addr) {
+            0x8000..=0x8003 => {
+                self.prg_banks[0] = data & 0x1F;
+                self.update_prg_banks(memory);
+            }
+            0x9000..=0x9003 if !self.variant.vrc4 => {
+                memory.nametables.set(if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal });
+            }
+            0x9000 | 0x9001 => {
+                memory.nametables.set(match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                });
+            }
+            0x9002 => {
+                self.prg_swapped = data & 0x02 != 0;
+                self.update_prg_banks(memory);
+            }
+            0xA000..=0xA003 => {
+                self.prg_banks[1] = data & 0x1F;
+                self.update_prg_banks(memory);
+            }
+            register @ 0xB000..=0xEFFF => {
+                /* Two registers per bank, two banks per $1000 */
+                let window = ((register - 0xB000) >> 12) as usize * 2 + (register as usize & 0x02) / 2;
+                let bank = &mut self.chr_banks[window];
+                *bank = if register & 0x01 == 0 {
+                    (*bank & !0x0F) | (data & 0x0F) as u16
+                } else {
+                    (*bank & 0x0F) | ((data & 0x1F) as u16) << 4
+                };
+                self.update_chr_banks(memory);
+            }
+            0xF000 if self.variant.vrc4 => {
+                self.irq.set_latch((self.irq.latch() & 0xF0) | (data & 0x0F));
+            }
+            0xF001 if self.variant.vrc4 => {
+                self.irq.set_latch((self.irq.latch() & 0x0F) | (data << 4));
+            }
+            0xF002 if self.variant.vrc4 => self.irq.write_control(data),
+            0xF003 if self.variant.vrc4 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.asserted()
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_banks);
+        w.write_bool(self.prg_swapped);
+        for bank in &self.chr_banks {
+            w.write_u16(*bank);
+        }
+        self.irq.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_into(&mut self.prg_banks)?;
+        self.prg_swapped = r.read_bool()?;
+        for bank in &mut self.chr_banks {
+            *bank = r.read_u16()?;
+        }
+        self.irq.load_state(r)
+    }
+}
@@ -0,0 +1,126 @@
+use crate::prelude::*;
+use crate::Mirroring;
+use crate::apu::vrc6::Vrc6Audio;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+use super::mapper_util::VrcIrq;
+
+// Konami VRC6 (mappers 24 and 26) - used by Akumajou Densetsu (24), Madara and
+// Esper Dream 2 (26). Mapper 26 boards swap the chip's A0 and A1 pins, so its
+// registers are decoded as mapper 24's after swapping the two address lines back.
+//   $8000      16KiB PRG bank at $8000
+//   $9000-$B002  Expansion sound (see apu::vrc6)
+//   $B003      Mirroring, in bits 2-3
+//   $C000      8KiB PRG bank at $C000
+//   $D000-$E003  1KiB CHR banks
+//   $F000-$F002  IRQ latch, control and acknowledge
+// The last 8KiB PRG bank is fixed at $E000, and there is 8KiB of PRG RAM at $6000.
+// Only the usual PPU banking mode is supported (eight 1KiB CHR banks, with
+// nametables in console VRAM), which every licensed game uses.
+
+pub struct Mapper024 {
+    swap_lines: bool,  /* Mapper 26 */
+
+    prg_banks: [u8; 2],  /* Selected 16KiB bank at $8000, and 8KiB bank at $C000 */
+    chr_banks: [u8; 8],
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Mapper024 {
+    pub fn new(mapper_id: usize) -> Self {
+        Self {
+            swap_lines: mapper_id == 26,
+            prg_banks: [0, 0],
+            chr_banks: [0; 8],
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn update_banks(&self, memory: &mut Memory) {
+        let bank_16k = self.prg_banks[0] as usize * 2;
+        memory.prg_rom.select(0, bank_16k);
+        memory.prg_rom.select(1, bank_16k + 1);
+        memory.prg_rom.select(2, self.prg_banks[1] as usize);
+        memory.prg_rom.select(3, memory.prg_rom.bank_count() - 1);
+
+        for (window, &bank) in self.chr_banks.iter().enumerate() {
+            memory.chr.select(window, bank as usize);
+        }
+    }
+}
+
+impl Mapper for Mapper024 {
+    fn power_on(&mut self, memory: &mut Memory) {
+        self.update_banks(memory);
+    }
+
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let register = if self.swap_lines {
+            (addr & 0xF000) | (addr & 0x01) << 1 | (addr & 0x02) >> 1
+        } else {
+            addr & 0xF003
+        };
+        match register {
+            0x8000..=0x8003 => {
+                self.prg_banks[0] = data & 0x0F;
+                self.update_banks(memory);
+            }
+            0x9000..=0xB002 => self.audio.write(register, data),
+            0xB003 => {
+                memory.nametables.set(match (data >> 2) & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                });
+            }
+            0xC000..=0xC003 => {
+                self.prg_banks[1] = data & 0x1F;
+                self.update_banks(memory);
+            }
+            0xD000..=0xEFFF => {
+                let window = ((register - 0xD000) >> 12) as usize * 4 + (register & 0x03) as usize;
+                self.chr_banks[window] = data;
+                self.update_banks(memory);
+            }
+            0xF000 => self.irq.set_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.asserted()
+    }
+
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_banks);
+        w.write_bytes(&self.chr_banks);
+        self.irq.save_state(w);
+        self.audio.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_into(&mut self.prg_banks)?;
+        r.read_into(&mut self.chr_banks)?;
+        self.irq.load_state(r)?;
+        self.audio.load_state(r)
+    }
+}
@@ -0,0 +1,39 @@
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// Mapper 34 covers two unrelated boards which both switch 32KiB of PRG at a time:
+// BNROM - used by Deadly Towers, which selects the PRG bank by writing to
+// anywhere in $8000-$FFFF and has 8KiB of CHR RAM, and
+// NINA-001 - used by Impossible Mission II, which has 8KiB of PRG RAM at $6000,
+// with registers over its last three bytes: $7FFD selects the PRG bank, and
+// $7FFE / $7FFF the 4KiB CHR banks at $0000 / $1000.
+
+// Either board's PRG register is accepted, as neither board's games write to
+// the other's. They are told apart by CHR ROM, which only NINA-001 has.
+
+pub struct Mapper034;
+
+impl Mapper for Mapper034 {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        match addr {
+            0x7FFD | 0x8000..=0xFFFF => memory.prg_rom.select(0, data as usize),
+            /* BNROM's CHR RAM isn't banked */
+            0x7FFE if !memory.chr.is_ram() => memory.chr.select(0, data as usize),
+            0x7FFF if !memory.chr.is_ram() => memory.chr.select(1, data as usize),
+            _ => {}
+        }
+    }
+
+    fn decodes_write(&self, addr: u16) -> bool {
+        addr >= 0x7FFD
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+use crate::prelude::*;
+use crate::cartridge::Memory;
+use crate::state::{StateReader, StateWriter};
+
+use super::mapper::Mapper;
+
+// GxROM (GNROM, MHROM) - used by Super Mario Bros. + Duck Hunt, Gumshoe, Dragon Power...
+// A write to anywhere in $8000-$FFFF selects both banks at once: bits 4-5 the
+// 32KiB PRG bank at $8000-$FFFF, and bits 0-1 the 8KiB CHR bank.
+
+pub struct Mapper066;
+
+impl Mapper for Mapper066 {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            memory.prg_rom.select(0, ((data >> 4) & 0x03) as usize);
+            memory.chr.select(0, (data & 0x03) as usize);
+        }
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! Building blocks for mappers: PRG and CHR divided into banks, which the
+//! mapper maps into windows of the CPU's and PPU's address spaces, and the IRQ
+//! counter of Konami's boards. With these, a simple board is mostly a matter of
+//! which register write selects which bank.
+
+use crate::prelude::*;
+use crate::state::{StateReader, StateWriter};
+
+/// PRG ROM, which the CPU sees at $8000-$FFFF through equally sized windows.
+/// The mapper chooses which bank appears in each; until it does, the first
+/// window shows the first bank, and the rest the last banks, in order.
+pub struct PrgRom {
+    data: Vec<u8>,
+    bank_size: usize,
+    banks: Vec<usize>,  /* The bank mapped into each window */
+}
+
+impl PrgRom {
+    pub fn new(bank_size: usize) -> Self {
+        assert!(bank_size > 0 && 32768 % bank_size == 0);
+
+        Self {
+            data: vec![],
+            bank_size,
+            banks: vec![0; 32768 / bank_size],
+        }
+    }
+
+    pub fn load(&mut self, rom: &[u8]) {
+        assert!(!rom.is_empty() && rom.len().is_multiple_of(self.bank_size));
+        self.data = rom.to_vec();
+
+        let count = self.bank_count();
+        let windows = self.banks.len();
+        for window in 1..windows {
+            self.banks[window] = (count + window).saturating_sub(windows) % count;
+        }
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.data.len() / self.bank_size
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Map a bank into a window. Out of range banks wrap, as the unused
+    /// high bits of a bank register are not connected.
+    pub fn select(&mut self, window: usize, bank: usize) {
+        self.banks[window] = bank % self.bank_count();
+    }
+
+    /// Read from $8000-$FFFF
+    pub fn read(&self, addr: u16) -> u8 {
+        self.data[self.offset(addr)]
+    }
+
+    /// Where in the ROM a read from $8000-$FFFF comes from, as the banks are mapped now
+    pub fn offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x7FFF;
+        self.banks[addr / self.bank_size] * self.bank_size + addr % self.bank_size
+    }
+
+    /// Which bank is in each window, e.g. "PRG ROM: bank 2 of 8 at $8000, bank 7 of 8 at $C000"
+    pub fn describe(&self) -> String {
+        let windows: Vec<String> = self.banks.iter().enumerate()
+            .map(|(window, bank)| format!("bank {} of {} at ${:0>4X}", bank, self.bank_count(), 0x8000 + window * self.bank_size))
+            .collect();
+        format!("PRG ROM: {}", windows.join(", "))
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.banks {
+            w.write_u16(*bank as u16);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for window in 0..self.banks.len() {
+            let bank = r.read_u16()? as usize;
+            if bank >= self.bank_count() {
+                return Err(format!("Save state selects PRG bank {}, but the cartridge has {}", bank, self.bank_count()));
+            }
+            self.banks[window] = bank;
+        }
+        Ok(())
+    }
+}
+
+/// Pattern table memory on the cartridge: CHR ROM, or 8KiB of CHR RAM if the ROM
+/// image has none. The PPU sees $0000-$1FFF through equally sized windows, and
+/// the mapper chooses which bank of CHR appears in each.
+pub struct ChrMemory {
+    data: Vec<u8>,
+    is_ram: bool,
+    bank_size: usize,
+    banks: Vec<usize>,  /* The bank mapped into each window */
+    changes: u32,       /* Counts changes to what the PPU sees - see `changes` */
+}
+
+impl ChrMemory {
+    pub fn new(bank_size: usize) -> Self {
+        assert!(bank_size > 0 && 8192 % bank_size == 0);
+
+        Self {
+            data: vec![],
+            is_ram: false,
+            bank_size,
+            banks: (0..8192 / bank_size).collect(),
+            changes: 0,
+        }
+    }
+
+    pub fn load(&mut self, rom: &[u8]) {
+        if rom.is_empty() {
+            self.data = vec![0; 8192];
+            self.is_ram = true;
+        } else {
+            assert!(rom.len().is_multiple_of(self.bank_size));
+            self.data = rom.to_vec();
+            self.is_ram = false;
+        }
+        self.changes = self.changes.wrapping_add(1);
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.data.len() / self.bank_size
+    }
+
+    pub fn is_ram(&self) -> bool {
+        self.is_ram
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Map a bank into a window. Out of range banks wrap, as the unused
+    /// high bits of a bank register are not connected.
+    pub fn select(&mut self, window: usize, bank: usize) {
+        let bank = bank % self.bank_count();
+        if self.banks[window] != bank {
+            self.banks[window] = bank;
+            self.changes = self.changes.wrapping_add(1);
+        }
+    }
+
+    /// A count of the writes and bank switches which have changed the pattern tables,
+    /// so that views of them can be kept until it changes
+    pub fn changes(&self) -> u32 {
+        self.changes
+    }
+
+    /// Where in CHR a PPU access to $0000-$1FFF goes, as the banks are mapped now
+    #[inline]
+    pub fn offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        self.banks[addr / self.bank_size] * self.bank_size + addr % self.bank_size
+    }
+
+    #[inline]
+    pub fn read(&self, addr: u16) -> u8 {
+        self.data[self.offset(addr)]
+    }
+
+    /// Which bank is in each window, e.g. "CHR ROM: bank 2 of 4 at $0000"
+    pub fn describe(&self) -> String {
+        let windows: Vec<String> = self.banks.iter().enumerate()
+            .map(|(window, bank)| format!("bank {} of {} at ${:0>4X}", bank, self.bank_count(), window * self.bank_size))
+            .collect();
+        format!("CHR {}: {}", if self.is_ram { "RAM" } else { "ROM" }, windows.join(", "))
+    }
+
+    /// Writes to CHR ROM are ignored
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if self.is_ram {
+            let offset = self.offset(addr);
+            self.data[offset] = data;
+            self.changes = self.changes.wrapping_add(1);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.banks {
+            w.write_u16(*bank as u16);
+        }
+        if self.is_ram {
+            w.write_bytes(&self.data);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for window in 0..self.banks.len() {
+            let bank = r.read_u16()? as usize;
+            if bank >= self.bank_count() {
+                return Err(format!("Save state selects CHR bank {}, but the cartridge has {}", bank, self.bank_count()));
+            }
+            self.banks[window] = bank;
+        }
+        if self.is_ram {
+            r.read_into(&mut self.data)?;
+        }
+        self.changes = self.changes.wrapping_add(1);
+        Ok(())
+    }
+}
+
+/// The IRQ counter shared by Konami's VRC4, VRC6 and VRC7. An 8-bit counter
+/// counts up from a reloadable latch, raising an IRQ as it overflows, either on
+/// every CPU cycle or once a scanline, as measured by a prescaler (341 PPU dots
+/// being 113.67 CPU cycles) rather than by watching the PPU.
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,          /* Counts down three each CPU cycle, from 341 */
+    enabled: bool,
+    enable_after_ack: bool,  /* What acknowledging sets enabled to */
+    cycle_mode: bool,        /* Whether to count CPU cycles rather than scanlines */
+    asserted: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            asserted: false,
+        }
+    }
+
+    pub fn latch(&self) -> u8 {
+        self.latch
+    }
+
+    pub fn set_latch(&mut self, latch: u8) {
+        self.latch = latch;
+    }
+
+    /// The control register: bit 0 enables the counter once the IRQ is
+    /// acknowledged, bit 1 enables it now (reloading it), and bit 2 chooses
+    /// cycle mode. Writing it acknowledges the IRQ.
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.asserted = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.asserted = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    /// Once per CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.count();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.count();
+            }
+        }
+    }
+
+    fn count(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.asserted = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+
+    pub fn asserted(&self) -> bool {
+        self.asserted
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.latch);
+        w.write_u8(self.counter);
+        w.write_u16(self.prescaler as u16);
+        w.write_bool(self.enabled);
+        w.write_bool(self.enable_after_ack);
+        w.write_bool(self.cycle_mode);
+        w.write_bool(self.asserted);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.latch = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.prescaler = r.read_u16()? as i16;
+        self.enabled = r.read_bool()?;
+        self.enable_after_ack = r.read_bool()?;
+        self.cycle_mode = r.read_bool()?;
+        self.asserted = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,210 @@
+// Count the CPU cycles spent at each instruction, and within each subroutine,
+// to find where a program spends its time. Attach one with Nes::start_profile,
+// and save its report once stopped.
+//
+// Subroutines are followed by watching JSR, RTS, RTI and interrupts on a
+// shadow of the call stack. Returns are matched by the stack pointer, so that
+// code which discards a return address, or jumps by pushing one and RTSing,
+// doesn't throw the stack out of step.
+
+use std::collections::HashMap;
+use core::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::prelude::*;
+use crate::bus::MemoryRead;
+use crate::cartridge::Cartridge;
+use crate::symbols::Symbols;
+use super::NESCpu;
+use super::debug::disasm_instruction;
+
+/* The hardware stack holds at most 128 return addresses, so anything deeper has been abandoned */
+const MAX_DEPTH: usize = 128;
+
+/* How many of the hottest instructions the report lists */
+const REPORT_INSTRUCTIONS: usize = 50;
+
+const BANK_SIZE: usize = 0x4000;  /* Banks are numbered in 16KiB, as FCEUX does */
+
+/// Where code ran: a CPU address, and if it's in PRG ROM, where in the ROM,
+/// so that the same address in different banks is told apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub addr: u16,
+    pub rom_offset: Option<usize>,
+}
+
+impl Location {
+    fn of(addr: u16, cartridge: &Cartridge) -> Self {
+        Self { addr, rom_offset: cartridge.prg_rom_offset(addr) }
+    }
+
+    fn name(&self, symbols: Option<&Symbols>) -> String {
+        match symbols.and_then(|symbols| symbols.label_at(self.addr, self.rom_offset)) {
+            Some(label) => format!("{} {}", self, label),
+            None => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rom_offset {
+            Some(offset) => write!(f, "{:0>2X}:{:0>4X}", offset / BANK_SIZE, self.addr),
+            None => write!(f, "  ${:0>4X}", self.addr),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct InstructionCounts {
+    executions: u64,
+    cycles: u64,
+    bytes: [u8; 3],  /* As first fetched, to disassemble in the report */
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SubroutineCounts {
+    calls: u64,
+    inclusive: u64,  /* Cycles from entry to return, counted once per return (so twice over for recursion) */
+    exclusive: u64,  /* Cycles spent in the subroutine itself, not those it called */
+}
+
+struct Call {
+    subroutine: Location,
+    sp: u8,        /* The stack pointer before the call pushed its return address */
+    entered: u64,  /* The cycle count on entry */
+}
+
+pub struct Profiler {
+    path: PathBuf,
+    cycles: u64,
+    top_level: u64,  /* Cycles spent outside of any subroutine seen called */
+    instructions: HashMap<Location, InstructionCounts>,
+    subroutines: HashMap<Location, SubroutineCounts>,
+    stack: Vec<Call>,
+    current: Option<Location>,  /* The instruction executing, or None while taking an interrupt */
+    calling: Option<u8>,        /* A call or interrupt is under way, from this stack pointer */
+}
+
+impl Profiler {
+    /// Profile, to save a report to `path` once done
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            cycles: 0,
+            top_level: 0,
+            instructions: HashMap::new(),
+            subroutines: HashMap::new(),
+            stack: Vec::new(),
+            current: None,
+            calling: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The CPU is about to execute the instruction at its PC, or if `interrupting`, to take an interrupt
+    pub fn instruction(&mut self, cpu: &NESCpu, interrupting: bool) {
+        let location = Location::of(cpu.PC, &cpu.bus.cartridge);
+
+        /* A call has landed - this is the subroutine, or interrupt handler */
+        if let Some(sp) = self.calling.take() {
+            if self.stack.len() == MAX_DEPTH {
+                self.stack.remove(0);
+            }
+            self.stack.push(Call { subroutine: location, sp, entered: self.cycles });
+            self.subroutines.entry(location).or_default().calls += 1;
+        }
+
+        if interrupting {
+            self.current = None;
+            self.calling = Some(cpu.SP);
+            return;
+        }
+
+        let bytes = [0, 1, 2].map(|i| cpu.bus.read(cpu.PC.wrapping_add(i)).unwrap_or_default());
+        let counts = self.instructions.entry(location).or_insert(InstructionCounts { bytes, ..Default::default() });
+        counts.executions += 1;
+        self.current = Some(location);
+
+        match bytes[0] {
+            0x20 | 0x00 => self.calling = Some(cpu.SP),    /* JSR, BRK */
+            0x60 => self.leave(cpu.SP as u16 + 2),          /* RTS pulls the return address */
+            0x40 => self.leave(cpu.SP as u16 + 3),          /* RTI pulls the status too */
+            _ => {}
+        }
+    }
+
+    // A CPU cycle has passed, spent on the current instruction
+    pub fn cycle(&mut self) {
+        self.cycles += 1;
+        if let Some(location) = self.current {
+            if let Some(counts) = self.instructions.get_mut(&location) {
+                counts.cycles += 1;
+            }
+        }
+        match self.stack.last() {
+            Some(call) => self.subroutines.entry(call.subroutine).or_default().exclusive += 1,
+            None => self.top_level += 1,
+        }
+    }
+
+    /* Return from every call made at or below this depth */
+    fn leave(&mut self, sp: u16) {
+        while let Some(call) = self.stack.last() {
+            if call.sp as u16 > sp {
+                break;
+            }
+            let counts = self.subroutines.entry(call.subroutine).or_default();
+            counts.inclusive += self.cycles - call.entered;
+            self.stack.pop();
+        }
+    }
+
+    /// The report: subroutines by the cycles spent within them, and the hottest instructions.
+    /// Subroutines still running are counted up to now.
+    pub fn report(&self, symbols: Option<&Symbols>) -> String {
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.cycles.max(1) as f64;
+
+        let mut subroutines = self.subroutines.clone();
+        for call in &self.stack {
+            subroutines.entry(call.subroutine).or_default().inclusive += self.cycles - call.entered;
+        }
+        let mut subroutines: Vec<(Location, SubroutineCounts)> = subroutines.into_iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+
+        let mut instructions: Vec<(&Location, &InstructionCounts)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+
+        let mut lines = vec![
+            format!("Profile of {} CPU cycles, {} ({:.1}%) outside any subroutine", self.cycles, self.top_level, percent(self.top_level)),
+            String::new(),
+            "Subroutines, by cycles from entry to return".to_string(),
+            format!("{:>8} {:>12} {:>6} {:>12} {:>6}  Subroutine", "Calls", "Inclusive", "%", "Exclusive", "%"),
+        ];
+        lines.extend(subroutines.iter().map(|(location, counts)| format!("{:>8} {:>12} {:>6.2} {:>12} {:>6.2}  {}",
+            counts.calls, counts.inclusive, percent(counts.inclusive), counts.exclusive, percent(counts.exclusive),
+            location.name(symbols))));
+
+        lines.push(String::new());
+        lines.push(format!("Hottest {} instructions", REPORT_INSTRUCTIONS.min(instructions.len())));
+        lines.push(format!("{:>12} {:>6} {:>10}  Instruction", "Cycles", "%", "Count"));
+        lines.extend(instructions.iter().take(REPORT_INSTRUCTIONS).map(|(location, counts)| {
+            let operand = u16::from_le_bytes([counts.bytes[1], counts.bytes[2]]);
+            format!("{:>12} {:>6.2} {:>10}  {}  {}", counts.cycles, percent(counts.cycles), counts.executions,
+                location.name(symbols), disasm_instruction(counts.bytes[0], operand).0)
+        }));
+
+        lines.join("\n") + "\n"
+    }
+
+    /// Write the report to the file given when profiling began
+    pub fn save(&self, symbols: Option<&Symbols>) -> Result<(), String> {
+        fs::write(&self.path, self.report(symbols))
+            .map_err(|e| format!("Can't write profile {}: {}", self.path.display(), e))
+    }
+}
@@ -0,0 +1,200 @@
+//! Every mapper the emulator supports, by iNES mapper number: how to build it
+//! and the cartridge memory it banks, which ROM sizes its boards came with, and
+//! what it can do.
+//! Supporting a new mapper is a matter of adding it to MAPPERS.
+//!
+//! Mappers whose registers are written through the ROM they overlay take the
+//! written value as-is: bus conflicts with the ROM's data are not emulated.
+
+use bitflags::bitflags;
+
+use crate::prelude::*;
+use super::mapper::Mapper;
+use super::mapper000::Mapper000;
+use super::mapper002::Mapper002;
+use super::mapper003::Mapper003;
+use super::mapper007::Mapper007;
+use super::mapper009::Mapper009;
+use super::mapper011::Mapper011;
+use super::mapper021::Mapper021;
+use super::mapper024::Mapper024;
+use super::mapper034::Mapper034;
+use super::mapper066::Mapper066;
+
+bitflags! {
+    /// What a mapper offers a game beyond NROM's fixed 32KiB of PRG and 8KiB of CHR
+    pub struct Capabilities: u8 {
+        const PRG_BANKING = 0b00000001;
+        const CHR_BANKING = 0b00000010;
+        const PRG_RAM     = 0b00000100;  /* Work RAM at $6000-$7FFF */
+        const CHR_RAM     = 0b00001000;  /* In place of CHR ROM, when the ROM image has none */
+        const MIRRORING   = 0b00010000;  /* Switches mirroring at runtime, ignoring the header's */
+        const IRQ         = 0b00100000;
+        const AUDIO       = 0b01000000;  /* Expansion sound, mixed with the APU's */
+    }
+}
+
+impl Capabilities {
+    /// The capabilities' names, e.g. for listing supported mappers
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (Capabilities::PRG_BANKING, "PRG banking"),
+            (Capabilities::CHR_BANKING, "CHR banking"),
+            (Capabilities::PRG_RAM, "PRG RAM"),
+            (Capabilities::CHR_RAM, "CHR RAM"),
+            (Capabilities::MIRRORING, "mirroring control"),
+            (Capabilities::IRQ, "IRQ"),
+            (Capabilities::AUDIO, "expansion audio"),
+        ].iter().filter(|(flag, _)| self.contains(*flag)).map(|(_, name)| *name).collect()
+    }
+}
+
+pub struct MapperInfo {
+    pub ids: &'static [u16],
+    pub name: &'static str,  /* The boards it covers */
+    pub capabilities: Capabilities,
+
+    /* The sizes of the banks the mapper switches between, and of its PRG RAM (0 if none) */
+    pub prg_bank_size: usize,
+    pub chr_bank_size: usize,
+    pub prg_ram_size: usize,
+
+    /* Whether the board came with this much PRG ROM (in 16KiB banks) and CHR ROM (in bytes) */
+    pub prg_ok: fn(usize) -> bool,
+    pub chr_ok: fn(u32) -> bool,
+
+    /* Build the mapper, for a mapper and submapper number in ids.
+       Each mapper interprets the submapper, if it needs to. */
+    pub build: fn(usize, u8) -> Box<dyn Mapper>,
+}
+
+/* Boards without CHR ROM carry 8KiB of CHR RAM (see ChrMemory) */
+fn chr_8k_or_ram(size: u32) -> bool {
+    size == 0 || size == 8192
+}
+
+pub static MAPPERS: &[MapperInfo] = &[
+    MapperInfo {
+        ids: &[0],
+        name: "NROM",
+        capabilities: Capabilities::PRG_RAM.union(Capabilities::CHR_RAM),
+        prg_bank_size: 16384,
+        chr_bank_size: 8192,
+        prg_ram_size: 8192,
+        prg_ok: |banks| banks == 1 || banks == 2,
+        chr_ok: chr_8k_or_ram,
+        build: |_, _| Box::new(Mapper000),
+    },
+    MapperInfo {
+        ids: &[2],
+        name: "UxROM",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_RAM),
+        prg_bank_size: 16384,
+        chr_bank_size: 8192,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks > 0,
+        chr_ok: chr_8k_or_ram,
+        build: |_, _| Box::new(Mapper002),
+    },
+    MapperInfo {
+        ids: &[3],
+        name: "CNROM",
+        capabilities: Capabilities::CHR_BANKING,
+        prg_bank_size: 16384,
+        chr_bank_size: 8192,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks == 1 || banks == 2,
+        chr_ok: |size| size > 0 && size.is_multiple_of(8192),
+        build: |_, _| Box::new(Mapper003),
+    },
+    MapperInfo {
+        ids: &[7],
+        name: "AxROM",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_RAM).union(Capabilities::MIRRORING),
+        prg_bank_size: 32768,
+        chr_bank_size: 8192,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks > 0 && banks.is_multiple_of(2),
+        chr_ok: chr_8k_or_ram,
+        build: |_, _| Box::new(Mapper007),
+    },
+    MapperInfo {
+        ids: &[9],
+        name: "MMC2 (PxROM)",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_BANKING).union(Capabilities::MIRRORING),
+        prg_bank_size: 8192,
+        chr_bank_size: 4096,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks >= 2,
+        chr_ok: |size| size > 0 && size.is_multiple_of(4096),
+        build: |_, _| Box::new(Mapper009::new()),
+    },
+    MapperInfo {
+        ids: &[11],
+        name: "Color Dreams",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_BANKING),
+        prg_bank_size: 32768,
+        chr_bank_size: 8192,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks > 0 && banks.is_multiple_of(2),
+        chr_ok: |size| size > 0 && size.is_multiple_of(8192),
+        build: |_, _| Box::new(Mapper011),
+    },
+    MapperInfo {
+        ids: &[21, 22, 23, 25],
+        name: "Konami VRC2/VRC4",
+        capabilities: Capabilities::all().difference(Capabilities::AUDIO),
+        prg_bank_size: 8192,
+        chr_bank_size: 1024,
+        prg_ram_size: 8192,
+        prg_ok: |banks| banks > 0,
+        chr_ok: |size| size.is_multiple_of(1024),
+        build: |id, submapper_id| Box::new(Mapper021::new(id, submapper_id)),
+    },
+    MapperInfo {
+        ids: &[24, 26],
+        name: "Konami VRC6",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_BANKING).union(Capabilities::PRG_RAM)
+            .union(Capabilities::MIRRORING).union(Capabilities::IRQ).union(Capabilities::AUDIO),
+        prg_bank_size: 8192,
+        chr_bank_size: 1024,
+        prg_ram_size: 8192,
+        prg_ok: |banks| banks > 0,
+        chr_ok: |size| size > 0 && size.is_multiple_of(1024),
+        build: |id, _| Box::new(Mapper024::new(id)),
+    },
+    MapperInfo {
+        ids: &[34],
+        name: "BNROM, NINA-001",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_BANKING)
+            .union(Capabilities::PRG_RAM).union(Capabilities::CHR_RAM),
+        prg_bank_size: 32768,
+        chr_bank_size: 4096,
+        prg_ram_size: 8192,
+        prg_ok: |banks| banks > 0 && banks.is_multiple_of(2),
+        chr_ok: |size| size.is_multiple_of(4096),
+        build: |_, _| Box::new(Mapper034),
+    },
+    MapperInfo {
+        ids: &[66],
+        name: "GxROM",
+        capabilities: Capabilities::PRG_BANKING.union(Capabilities::CHR_BANKING),
+        prg_bank_size: 32768,
+        chr_bank_size: 8192,
+        prg_ram_size: 0,
+        prg_ok: |banks| banks > 0 && banks.is_multiple_of(2),
+        chr_ok: |size| size > 0 && size.is_multiple_of(8192),
+        build: |_, _| Box::new(Mapper066),
+    },
+];
+
+/// Find a mapper by number, or explain that it isn't supported
+pub fn lookup(id: u16) -> Result<&'static MapperInfo, String> {
+    MAPPERS.iter().find(|info| info.ids.contains(&id)).ok_or_else(|| unsupported(id))
+}
+
+/// Explain that a mapper isn't supported, and which are
+pub fn unsupported(id: u16) -> String {
+    let supported: Vec<String> = MAPPERS.iter().flat_map(|info| info.ids).map(|id| id.to_string()).collect();
+    format!("Unsupported mapper {} - fancy-nes supports mappers {}", id, supported.join(", "))
+}
@@ -1,56 +1,227 @@
-// Produce a dump to a file
+// Log each instruction to a file as it executes, in the format of another
+// emulator's trace logger, so that the two can be diffed to find where
+// emulation diverges. Attach one with Nes::start_trace, or check a run against
+// a golden log (e.g. nestest.log) directly with verify_log. Without the std
+// feature, lines are handed to a callback instead of written to a file.
 
-use std::{fs::File, path::Path, io::Write, cell::Ref};
-use std::ops::Deref;
+use core::fmt;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
 
-use super::AddressingMode;
-use super::decode::{LUT_6502, Instruction};
-use super::mem::MemoryRead;
-use super::{NESCpu, debug::disasm_6502};
+use crate::prelude::*;
+use crate::Nes;
+use crate::bus::MemoryRead;
+use super::{NESCpu, StatusRegister, debug::disasm_6502};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// As nestest.log: `C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 P:24 SP:FD CYC:7`
+    Nestest,
+    /// As FCEUX's trace logger: `c7  A:00 X:00 Y:00 S:FD P:nvUbdIzc  $C000:4C F5 C5  JMP $C5F5`
+    Fceux,
+    /// As Mesen's trace logger: `C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 S:FD P:nvUbdIzc Cycle:7`
+    Mesen,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "nestest" => Ok(TraceFormat::Nestest),
+            "fceux" => Ok(TraceFormat::Fceux),
+            "mesen" => Ok(TraceFormat::Mesen),
+            _ => Err(format!("Unknown trace format {} (expected nestest, fceux or mesen)", name)),
+        }
+    }
+}
+
+impl fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TraceFormat::Nestest => "nestest",
+            TraceFormat::Fceux => "fceux",
+            TraceFormat::Mesen => "mesen",
+        })
+    }
+}
+
+/* Where the lines go */
+enum TraceOutput {
+    #[cfg(feature = "std")]
+    File(BufWriter<File>),
+    Callback(Box<dyn FnMut(&str) + Send>),
+}
 
 pub struct TraceUnit {
-    out_file: File,
+    output: TraceOutput,
+    format: TraceFormat,
+    ppu_columns: bool,  /* Add the PPU's scanline and dot, as nestest.log and Mesen can */
 }
 
 impl TraceUnit {
-    pub fn new(path: &Path) -> Self {
+    #[cfg(feature = "std")]
+    pub fn new(path: &Path, format: TraceFormat, ppu_columns: bool) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Can't create trace {}: {}", path.display(), e))?;
+        Ok(Self {
+            output: TraceOutput::File(BufWriter::new(file)),
+            format,
+            ppu_columns,
+        })
+    }
+
+    /// Hand each line, without its newline, to `output` - e.g. to send it down a serial port
+    pub fn with_callback(output: impl FnMut(&str) + Send + 'static, format: TraceFormat, ppu_columns: bool) -> Self {
         Self {
-            out_file: File::create(path).unwrap(),
+            output: TraceOutput::Callback(Box::new(output)),
+            format,
+            ppu_columns,
+        }
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    // Write a line for the instruction about to be executed at the PC
+    pub fn dump(&mut self, cpu: &NESCpu) -> Result<(), String> {
+        let line = trace_line(cpu, self.format, self.ppu_columns)?;
+        match &mut self.output {
+            #[cfg(feature = "std")]
+            TraceOutput::File(file) => writeln!(file, "{}", line).map_err(|e| format!("Can't write trace: {}", e)),
+            TraceOutput::Callback(output) => {
+                output(&line);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), String> {
+        match &mut self.output {
+            #[cfg(feature = "std")]
+            TraceOutput::File(file) => file.flush().map_err(|e| format!("Can't write trace: {}", e)),
+            TraceOutput::Callback(_) => Ok(()),
         }
     }
+}
 
-    // Generates a single line in the text file, containing:
-    // Address Mnemonic A XX Y P SP PPU: LINE, TICK Cycle
-    // nestest will always report break lo as being 0... always
-    // nestest will always report break hi as being 1... always
+/// The trace line for the instruction about to be executed at the PC, with the
+/// registers as it finds them
+pub fn trace_line(cpu: &NESCpu, format: TraceFormat, ppu_columns: bool) -> Result<String, String> {
+    let (disasm, len) = disasm_6502(cpu.PC, &cpu.bus);
+    let bytes = (0..len.max(1))
+        .map(|i| cpu.bus.read(cpu.PC.wrapping_add(i)).map(|b| format!("{:0>2X}", b)))
+        .collect::<Result<Vec<_>, _>>()?
+        .join(" ");
+    let ppu = &cpu.bus.ppu;
 
-    pub fn dump(&mut self, cpu: &dyn Deref<Target = NESCpu>) {
-        // Get instruction information
-        let op = &cpu.memory.read(cpu.PC);
-        let instr_opt = LUT_6502.get(&op);
-        let instr: &Instruction;
+    Ok(match format {
+        TraceFormat::Nestest => {
+            /* nestest.log shows the B flag clear, and the unused bit set */
+            let mut line = format!("{:0>4X}  {:<8}  {:<32}A:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X}",
+                cpu.PC, bytes, disasm, cpu.A, cpu.X, cpu.Y, (cpu.status.bits() & 0xEF) | 0x20, cpu.SP);
+            if ppu_columns {
+                line.push_str(&format!(" PPU:{:>3},{:>3}", ppu.scanline, ppu.tick));
+            }
+            line + &format!(" CYC:{}", cpu.cycle)
+        }
+        TraceFormat::Fceux => {
+            let mut line = format!("c{:<10} A:{:0>2X} X:{:0>2X} Y:{:0>2X} S:{:0>2X} P:{}",
+                cpu.cycle, cpu.A, cpu.X, cpu.Y, cpu.SP, flags(cpu.status));
+            if ppu_columns {
+                line.push_str(&format!(" SL:{:<3} DOT:{:<3}", ppu.scanline, ppu.tick));
+            }
+            line + &format!("  ${:0>4X}:{:<9} {}", cpu.PC, bytes, disasm)
+        }
+        TraceFormat::Mesen => {
+            let mut line = format!("{:0>4X}  {:<8}  {:<32}A:{:0>2X} X:{:0>2X} Y:{:0>2X} S:{:0>2X} P:{}",
+                cpu.PC, bytes, disasm, cpu.A, cpu.X, cpu.Y, cpu.SP, flags(cpu.status));
+            if ppu_columns {
+                line.push_str(&format!(" V:{:<3} H:{:<3}", ppu.scanline, ppu.tick));
+            }
+            line + &format!(" Cycle:{}", cpu.cycle)
+        }
+    })
+}
 
-        instr = instr_opt.unwrap_or_else(|| { panic!("Unknown instruction in trace unit: ${:0>4X}: {:0>2X}", cpu.PC, op) });
+/* How many of the lines which matched to show before a divergence */
+const CONTEXT_LINES: usize = 5;
 
-        let target_address: u16;
-        if !matches!(instr.mode, AddressingMode::Implied | AddressingMode::Accumulator) {
-            target_address = cpu.resolve_address(&instr.mode).0;
-        } else {
-            target_address = 0;
+/* In nestest format, the registers start after the disassembly's column */
+const REGISTERS_COLUMN: usize = 48;
+
+/// Where a run first differed from a golden log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub line: usize,           /* In the golden log, from 1 */
+    pub expected: String,
+    pub actual: String,        /* Our trace line, or the error which stopped emulation */
+    pub context: Vec<String>,  /* The golden log's lines before it, which matched */
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Diverged from the log at line {}:", self.line)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
         }
+        writeln!(f, "- {}", self.expected)?;
+        write!(f, "+ {}", self.actual)
+    }
+}
 
-        let line = format!(
-            "{:0>4X}\t{}\t\tA:{:0>2X} X:{:0>2X} Y:{:0>2X} P:{:0>2X} SP:{:0>2X} CYC:{} EA:{:0>4X}\n",
-            cpu.PC, disasm_6502(cpu.PC, &cpu.memory).0,
-            cpu.A,
-            cpu.X,
-            cpu.Y,
-            (cpu.status.bits() & 0xEF) | 0x20,
-            cpu.SP,
-            cpu.cycle,
-            target_address,
-        );
+/// Run the machine an instruction at a time against a nestest format golden log,
+/// starting at the log's first instruction (see Nes::start_at), until the log ends
+/// or the CPU's state differs from it. Returns the number of lines which matched.
+pub fn verify_log(nes: &mut Nes, golden: &str) -> Result<usize, Divergence> {
+    let golden: Vec<&str> = golden.lines().map(str::trim_end).filter(|line| !line.is_empty()).collect();
+    let divergence = |line: usize, actual: String| Divergence {
+        line: line + 1,
+        expected: golden[line].to_string(),
+        actual,
+        context: golden[line.saturating_sub(CONTEXT_LINES)..line].iter().map(|l| l.to_string()).collect(),
+    };
 
-        self.out_file.write(line.as_bytes()).unwrap();
+    if let Some(pc) = golden.first().and_then(|line| u16::from_str_radix(line.get(0..4)?, 16).ok()) {
+        nes.start_at(pc);
     }
-}
\ No newline at end of file
+
+    for (n, expected) in golden.iter().enumerate() {
+        let actual = trace_line(nes.cpu(), TraceFormat::Nestest, false).map_err(|e| divergence(n, e))?;
+        match (cpu_columns(expected), cpu_columns(&actual)) {
+            (Some(expected), Some(actual)) if expected == actual => {}
+            (None, _) => return Err(divergence(n, "(not a nestest format line)".to_string())),
+            _ => return Err(divergence(n, actual)),
+        }
+
+        if n + 1 < golden.len() {
+            nes.step().map_err(|e| divergence(n + 1, e.to_string()))?;
+        }
+    }
+    Ok(golden.len())
+}
+
+/* The parts of a nestest format line describing the CPU: the PC, the instruction's bytes, the
+   registers and the cycle count. Disassembly differs in style from one emulator to the next,
+   and the PPU's columns on how it lined up with the CPU at power on, so those are left out. */
+fn cpu_columns(line: &str) -> Option<String> {
+    let instruction = line.get(0..14)?.trim_end();
+    let registers = line.get(REGISTERS_COLUMN..REGISTERS_COLUMN + 25)?;
+    let cycle = &line[line.rfind("CYC:")?..];
+    Some(format!("{}  {} {}", instruction, registers, cycle))
+}
+
+/* The status register as FCEUX and Mesen show it, e.g. nvUbdIzc: set flags in capitals */
+fn flags(status: StatusRegister) -> String {
+    b"NVUBDIZC".iter().enumerate()
+        .map(|(i, &c)| {
+            let set = status.bits() & (0x80 >> i) != 0;
+            if set { c as char } else { c.to_ascii_lowercase() as char }
+        })
+        .collect()
+}
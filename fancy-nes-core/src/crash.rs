@@ -0,0 +1,96 @@
+//! Crash reports - a snapshot of the machine taken when emulation stops on an
+//! error, to be attached to bug reports. The report is plain text (see its
+//! Display impl), so it can be read without the emulator. Writing it out is
+//! left to the frontend, as the core never touches files or the clock.
+
+use core::fmt;
+
+use crate::prelude::*;
+use crate::Nes;
+use crate::bus::MemoryRead;
+use crate::cpu::history::HistoryEntry;
+
+pub struct CrashReport {
+    pub error: Option<String>,  /* What went wrong, if the caller knows */
+
+    pub instructions: Vec<HistoryEntry>,  /* The last instructions fetched, oldest first */
+
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub status: u8,
+    pub stack: Vec<u8>,  /* From the top of the stack down to $01FF */
+
+    pub scanline: u16,
+    pub tick: u16,
+    pub vram_v: u16,
+    pub vram_t: u16,
+
+    pub banks: String,  /* What the cartridge has mapped where */
+    pub ram: [u8; 2048],
+}
+
+impl CrashReport {
+    pub fn capture(nes: &Nes) -> Self {
+        let cpu = nes.cpu();
+        let ppu = nes.ppu();
+        let (vram_v, vram_t) = ppu.vram_addresses();
+
+        Self {
+            error: None,
+            instructions: cpu.history().iter().copied().collect(),
+            pc: cpu.PC,
+            a: cpu.A,
+            x: cpu.X,
+            y: cpu.Y,
+            sp: cpu.SP,
+            status: cpu.status.bits(),
+            /* The stack always lives in internal RAM, so reading it can't fail */
+            stack: (cpu.SP as u16 + 0x0101..=0x01FF).map(|addr| cpu.bus.read(addr).unwrap_or_default()).collect(),
+            scanline: ppu.scanline,
+            tick: ppu.tick,
+            vram_v,
+            vram_t,
+            banks: cpu.bus.cartridge.describe_banks(),
+            ram: cpu.bus.internal_ram,
+        }
+    }
+
+    pub fn with_error(self, error: &str) -> Self {
+        Self { error: Some(error.to_string()), ..self }
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fancy-nes crash report")?;
+        if let Some(error) = &self.error {
+            writeln!(f, "Error: {}", error)?;
+        }
+
+        writeln!(f, "\nLast {} instructions (oldest first):", self.instructions.len())?;
+        for entry in &self.instructions {
+            writeln!(f, "  {}", entry)?;
+        }
+
+        writeln!(f, "\nCPU: PC ${:0>4X}  A ${:0>2X}  X ${:0>2X}  Y ${:0>2X}  SP ${:0>2X}  P ${:0>2X}",
+            self.pc, self.a, self.x, self.y, self.sp, self.status)?;
+        writeln!(f, "Stack ({} bytes):", self.stack.len())?;
+        for (i, data) in self.stack.iter().enumerate() {
+            writeln!(f, "  ${:0>4X}: {:0>2X}", self.sp as usize + 0x0101 + i, data)?;
+        }
+
+        writeln!(f, "\nPPU: scanline {}, dot {}  v ${:0>4X}  t ${:0>4X}", self.scanline, self.tick, self.vram_v, self.vram_t)?;
+        writeln!(f, "\nMapper:")?;
+        writeln!(f, "  {}", self.banks)?;
+
+        writeln!(f, "\nRAM:")?;
+        for (row, bytes) in self.ram.chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:0>2X}", b)).collect();
+            writeln!(f, "  ${:0>4X}: {}", row * 16, hex.join(" "))?;
+        }
+        Ok(())
+    }
+}
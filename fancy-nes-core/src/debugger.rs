@@ -0,0 +1,351 @@
+//! Breakpoints and watchpoints for the debugger.
+//!
+//! Execution breakpoints are checked by the frontend at instruction boundaries
+//! (see NESCpu::breakpoint_at_pc), scanline breakpoints as the PPU moves onto a
+//! new line. Memory watchpoints are checked by the Bus on every access with
+//! side-effects, and latched in `hit` until the frontend collects them.
+//!
+//! Step over, step out and run to cursor are built on a single temporary
+//! breakpoint, which has no id, isn't listed, and is cleared once reached.
+//!
+//! The watch list holds the variables the debugger shows the values of as the
+//! machine runs. A frozen watch has its value written back as each frame completes.
+
+use core::fmt;
+use core::str::FromStr;
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakCondition {
+    Execute(u16),   /* PC reaches this address */
+    Read(Watch),    /* CPU reads from these addresses */
+    Write(Watch),   /* CPU writes to these addresses */
+    Scanline(u16),  /* PPU begins this scanline */
+}
+
+/// The memory a watchpoint covers: an inclusive range of CPU addresses,
+/// optionally only triggering when a particular value is read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch {
+    pub start: u16,
+    pub end: u16,
+    pub value: Option<u8>,
+}
+
+impl Watch {
+    pub fn addr(addr: u16) -> Self {
+        Self { start: addr, end: addr, value: None }
+    }
+
+    pub fn range(start: u16, end: u16) -> Self {
+        Self { start: start.min(end), end: start.max(end), value: None }
+    }
+
+    pub fn with_value(self, value: u8) -> Self {
+        Self { value: Some(value), ..self }
+    }
+
+    fn matches(&self, addr: u16, data: u8) -> bool {
+        (self.start..=self.end).contains(&addr) && self.value.is_none_or(|v| v == data)
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:0>4X}", self.start)?;
+        if self.end != self.start {
+            write!(f, "-${:0>4X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " =${:0>2X}", value)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BreakCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BreakCondition::Execute(addr) => write!(f, "exec  ${:0>4X}", addr),
+            BreakCondition::Read(watch) => write!(f, "read  {}", watch),
+            BreakCondition::Write(watch) => write!(f, "write {}", watch),
+            BreakCondition::Scanline(line) => write!(f, "line  {}", line),
+        }
+    }
+}
+
+/// Where a temporary breakpoint stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempBreak {
+    RunTo(u16),                  /* PC reaches this address */
+    Return { pc: u16, sp: u8 },  /* PC reaches this address with the stack no deeper than SP, i.e. a JSR has returned */
+    StackAbove(u8),              /* SP rises above this, i.e. the current subroutine has returned */
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub condition: BreakCondition,
+    pub enabled: bool,
+}
+
+#[derive(Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: u32,
+
+    /* Memory accesses are frequent, so keep track of whether we need to look at all */
+    has_watchpoints: bool,
+    has_scanline_breakpoints: bool,
+
+    /// The id of the last watchpoint triggered, if not yet collected
+    pub hit: Option<u32>,
+
+    /// Internal breakpoint for stepping, see TempBreak
+    pub temporary: Option<TempBreak>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Internal RAM is mirrored four times, so watch the underlying cell */
+    fn canonical(addr: u16) -> u16 {
+        if addr < 0x2000 { addr & 0x07FF } else { addr }
+    }
+
+    /* A watch entirely within one mirror of internal RAM is moved onto the first */
+    fn canonical_watch(watch: Watch) -> Watch {
+        let (start, end) = (Self::canonical(watch.start), Self::canonical(watch.end));
+        if watch.end < 0x2000 && end >= start && end - start == watch.end - watch.start {
+            Watch { start, end, ..watch }
+        } else {
+            watch
+        }
+    }
+
+    /// Add a breakpoint, returning its id
+    pub fn add(&mut self, condition: BreakCondition) -> u32 {
+        let condition = match condition {
+            BreakCondition::Read(watch) => BreakCondition::Read(Self::canonical_watch(watch)),
+            BreakCondition::Write(watch) => BreakCondition::Write(Self::canonical_watch(watch)),
+            _ => condition,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint { id, condition, enabled: true });
+        self.update_watchpoints();
+        id
+    }
+
+    /// Returns false if there was no such breakpoint
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.update_watchpoints();
+        self.list.len() != len
+    }
+
+    /// Enable or disable a breakpoint, returning its new state
+    pub fn toggle(&mut self, id: u32) -> Option<bool> {
+        let bp = self.list.iter_mut().find(|b| b.id == id)?;
+        bp.enabled = !bp.enabled;
+        let enabled = bp.enabled;
+        self.update_watchpoints();
+        Some(enabled)
+    }
+
+    /// The id of the first breakpoint with this condition, enabled or not
+    pub fn find(&self, condition: BreakCondition) -> Option<u32> {
+        self.list.iter().find(|b| b.condition == condition).map(|b| b.id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Whether any scanline breakpoints are enabled, which need the PPU kept up to date every cycle
+    pub fn has_scanline_breakpoints(&self) -> bool {
+        self.has_scanline_breakpoints
+    }
+
+    fn update_watchpoints(&mut self) {
+        self.has_watchpoints = self.list.iter().any(|b| b.enabled
+            && matches!(b.condition, BreakCondition::Read(_) | BreakCondition::Write(_)));
+        self.has_scanline_breakpoints = self.list.iter().any(|b| b.enabled
+            && matches!(b.condition, BreakCondition::Scanline(_)));
+    }
+
+    fn find_enabled(&self, condition: BreakCondition) -> Option<u32> {
+        self.list.iter()
+            .find(|b| b.enabled && b.condition == condition)
+            .map(|b| b.id)
+    }
+
+    pub fn check_execute(&self, pc: u16) -> Option<u32> {
+        if self.list.is_empty() {
+            return None;
+        }
+        self.find_enabled(BreakCondition::Execute(pc))
+    }
+
+    /// Checked at instruction boundaries, like execution breakpoints.
+    /// Clears the temporary breakpoint if it has been reached.
+    pub fn check_temporary(&mut self, pc: u16, sp: u8) -> bool {
+        let reached = match self.temporary {
+            None => return false,
+            Some(TempBreak::RunTo(addr)) => pc == addr,
+            Some(TempBreak::Return { pc: addr, sp: depth }) => pc == addr && sp >= depth,
+            Some(TempBreak::StackAbove(depth)) => sp > depth,
+        };
+        if reached {
+            self.temporary = None;
+        }
+        reached
+    }
+
+    pub fn check_scanline(&self, scanline: u16) -> Option<u32> {
+        if self.list.is_empty() {
+            return None;
+        }
+        self.find_enabled(BreakCondition::Scanline(scanline))
+    }
+
+    pub fn check_read(&mut self, addr: u16, data: u8) {
+        if self.has_watchpoints {
+            if let Some(id) = self.find_watch(addr, data, |c| match c { BreakCondition::Read(w) => Some(w), _ => None }) {
+                self.hit = Some(id);
+            }
+        }
+    }
+
+    pub fn check_write(&mut self, addr: u16, data: u8) {
+        if self.has_watchpoints {
+            if let Some(id) = self.find_watch(addr, data, |c| match c { BreakCondition::Write(w) => Some(w), _ => None }) {
+                self.hit = Some(id);
+            }
+        }
+    }
+
+    /* Ranges which straddle RAM mirrors are left as given, so check the address both as-is and canonically */
+    fn find_watch(&self, addr: u16, data: u8, kind: impl Fn(&BreakCondition) -> Option<&Watch>) -> Option<u32> {
+        let canonical = Self::canonical(addr);
+        self.list.iter()
+            .filter(|b| b.enabled)
+            .find(|b| kind(&b.condition).is_some_and(|w| w.matches(addr, data) || w.matches(canonical, data)))
+            .map(|b| b.id)
+    }
+}
+
+/// How a watch shows its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchFormat {
+    Hex,
+    Dec,
+    Bin,
+    Word,  /* 16 bits, little-endian, in hex and decimal */
+}
+
+impl WatchFormat {
+    /// The bytes the value takes up
+    pub fn width(&self) -> u16 {
+        match self {
+            WatchFormat::Word => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn format(&self, value: u16) -> String {
+        match self {
+            WatchFormat::Hex => format!("${:0>2X}", value),
+            WatchFormat::Dec => format!("{}", value),
+            WatchFormat::Bin => format!("%{:0>8b}", value),
+            WatchFormat::Word => format!("${:0>4X} {}", value, value),
+        }
+    }
+}
+
+impl FromStr for WatchFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hex" => Ok(WatchFormat::Hex),
+            "dec" => Ok(WatchFormat::Dec),
+            "bin" => Ok(WatchFormat::Bin),
+            "word" => Ok(WatchFormat::Word),
+            _ => Err(format!("Unknown watch format {} - expected hex, dec, bin or word", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchEntry {
+    pub id: u32,
+    pub addr: u16,
+    pub name: String,         /* The label it was added by, or its address */
+    pub format: WatchFormat,
+    pub frozen: Option<u16>,  /* The value written back each frame */
+}
+
+#[derive(Default)]
+pub struct WatchList {
+    list: Vec<WatchEntry>,
+    next_id: u32,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watch a variable, returning its id
+    pub fn add(&mut self, addr: u16, name: &str, format: WatchFormat) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(WatchEntry { id, addr, name: name.to_string(), format, frozen: None });
+        id
+    }
+
+    /// Returns false if there was no such watch
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|w| w.id != id);
+        self.list.len() != len
+    }
+
+    /// Freeze a watch at a value, or thaw it with None. Returns false if there was no such watch.
+    pub fn freeze(&mut self, id: u32, value: Option<u16>) -> bool {
+        match self.list.iter_mut().find(|w| w.id == id) {
+            Some(watch) => {
+                watch.frozen = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&WatchEntry> {
+        self.list.iter().find(|w| w.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WatchEntry> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// The bytes to write back for frozen watches, low byte first
+    pub fn frozen_bytes(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.list.iter()
+            .filter_map(|w| w.frozen.map(|value| (w, value)))
+            .flat_map(|(w, value)| (0..w.format.width()).map(move |i| (w.addr.wrapping_add(i), (value >> (8 * i)) as u8)))
+    }
+}
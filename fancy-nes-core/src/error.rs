@@ -0,0 +1,54 @@
+//! Errors surfaced by `Nes`. Inside the core, components report failures as
+//! plain strings; `Nes` classifies them on the way out, adding where the
+//! machine was when emulation stopped, so a frontend can report them
+//! (or show them in a debugger) instead of crashing.
+
+use core::error::Error;
+use core::fmt;
+use crate::prelude::*;
+use crate::cpu::registry;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NesError {
+    /// The ROM image can't be run: a bad header, or ROM sizes the board never had
+    Rom(String),
+
+    /// The ROM image is shorter than its header says
+    Truncated { expected: usize, found: usize },
+
+    /// The ROM is for a mapper that isn't supported
+    UnsupportedMapper(u16),
+
+    /// The emulated machine stopped, e.g. on a jammed CPU or an illegal opcode
+    Emulation {
+        message: String,
+        pc: u16,        /* Of the instruction being executed */
+        scanline: u16,
+        tick: u16,      /* The PPU's dot on the scanline */
+    },
+
+    /// A save state doesn't match this build or cartridge
+    State(String),
+
+    /// A debugger's edit was refused, e.g. a poke of ROM
+    Memory(String),
+}
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NesError::Rom(message) => write!(f, "Can't load ROM: {}", message),
+            NesError::Truncated { expected, found } => {
+                write!(f, "Can't load ROM: it is truncated - expected {} bytes, found {}", expected, found)
+            }
+            NesError::UnsupportedMapper(id) => write!(f, "Can't load ROM: {}", registry::unsupported(*id)),
+            NesError::Emulation { message, pc, scanline, tick } => {
+                write!(f, "{} (PC ${:0>4X}, scanline {}, dot {})", message, pc, scanline, tick)
+            }
+            NesError::State(message) => write!(f, "Can't load state: {}", message),
+            NesError::Memory(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for NesError {}
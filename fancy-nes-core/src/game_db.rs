@@ -0,0 +1,231 @@
+//! Repairing ROMs whose headers are wrong. Many dumps in circulation have the
+//! wrong mapper, mirroring or RAM sizes in their iNES header, or predate the
+//! fields that say so. A `GameDb` holds the right values for known games, by
+//! the CRC32 of the ROM after its header, as read from the NES 2.0 XML
+//! database (nes20db.xml) - one `<game>` element per ROM:
+//!
+//! ```text
+//! <game>
+//!   <rom size="40960" crc32="3337EC46"/>
+//!   <prgrom size="32768"/> <chrrom size="8192"/>
+//!   <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//!   <prgram size="8192"/>
+//!   <console type="0" region="0"/>
+//! </game>
+//! ```
+//!
+//! A `HeaderFix` found there, or made up by hand (e.g. from a frontend's
+//! --force-mapper), is applied by `repair_header`, which gives back the ROM
+//! with a NES 2.0 header saying what the fix does. Only the header changes, so
+//! the ROM's checksum for movies and netplay stays the same.
+
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+use alloc::collections::BTreeMap;
+
+use crate::prelude::*;
+use crate::{Mirroring, NESHeaderMetadata, Timing};
+use crate::cartridge::{RomLayout, HEADER_SIZE};
+use crate::png::crc32;
+
+/// Header fields to change, each left alone if None
+#[derive(Debug, Default, Clone)]
+pub struct HeaderFix {
+    pub mapper_id: Option<u16>,
+    pub submapper_id: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: Option<bool>,
+    pub prg_rom_size: Option<u32>,
+    pub chr_rom_size: Option<u32>,
+    pub prg_ram_size: Option<u32>,
+    pub prg_nvram_size: Option<u32>,
+    pub chr_ram_size: Option<u32>,
+    pub chr_nvram_size: Option<u32>,
+    pub timing: Option<Timing>,
+}
+
+impl HeaderFix {
+    /// Whether the fix changes nothing
+    pub fn is_empty(&self) -> bool {
+        self.mapper_id.is_none() && self.submapper_id.is_none() && self.mirroring.is_none() && self.has_battery.is_none()
+            && self.prg_rom_size.is_none() && self.chr_rom_size.is_none() && self.prg_ram_size.is_none()
+            && self.prg_nvram_size.is_none() && self.chr_ram_size.is_none() && self.chr_nvram_size.is_none()
+            && self.timing.is_none()
+    }
+
+    /// Change the header's fields to the fix's. Anything not fixed is left as it was.
+    pub fn apply(&self, header: &mut NESHeaderMetadata) {
+        header.mapper_id = self.mapper_id.unwrap_or(header.mapper_id);
+        header.submapper_id = self.submapper_id.unwrap_or(header.submapper_id);
+        header.hardwired_mirroring = self.mirroring.unwrap_or(header.hardwired_mirroring);
+        header.has_battery = self.has_battery.unwrap_or(header.has_battery);
+        header.prg_rom_size = self.prg_rom_size.unwrap_or(header.prg_rom_size);
+        header.chr_rom_size = self.chr_rom_size.unwrap_or(header.chr_rom_size);
+        header.prg_ram_size = self.prg_ram_size.unwrap_or(header.prg_ram_size);
+        header.prg_nvram_size = self.prg_nvram_size.unwrap_or(header.prg_nvram_size);
+        header.chr_ram_size = self.chr_ram_size.unwrap_or(header.chr_ram_size);
+        header.chr_nvram_size = self.chr_nvram_size.unwrap_or(header.chr_nvram_size);
+        header.timing = self.timing.unwrap_or(header.timing);
+    }
+
+    /// Combine two fixes, this one's fields winning where both set one
+    pub fn or(self, other: &HeaderFix) -> HeaderFix {
+        HeaderFix {
+            mapper_id: self.mapper_id.or(other.mapper_id),
+            submapper_id: self.submapper_id.or(other.submapper_id),
+            mirroring: self.mirroring.or(other.mirroring),
+            has_battery: self.has_battery.or(other.has_battery),
+            prg_rom_size: self.prg_rom_size.or(other.prg_rom_size),
+            chr_rom_size: self.chr_rom_size.or(other.chr_rom_size),
+            prg_ram_size: self.prg_ram_size.or(other.prg_ram_size),
+            prg_nvram_size: self.prg_nvram_size.or(other.prg_nvram_size),
+            chr_ram_size: self.chr_ram_size.or(other.chr_ram_size),
+            chr_nvram_size: self.chr_nvram_size.or(other.chr_nvram_size),
+            timing: self.timing.or(other.timing),
+        }
+    }
+}
+
+/// The ROM with its header rewritten as NES 2.0, with the fix applied
+pub fn repair_header(rom: &[u8], fix: &HeaderFix) -> Result<Vec<u8>, String> {
+    let mut header = NESHeaderMetadata::parse_header(rom).map_err(|e| e.to_string())?;
+    fix.apply(&mut header);
+    let mut repaired = header.to_nes2_header().map_err(|e| e.to_string())?.to_vec();
+    repaired.extend_from_slice(&rom[HEADER_SIZE..]);
+    Ok(repaired)
+}
+
+/// The CRC32 of everything after the header and any trainer - the PRG and CHR ROM,
+/// as the database identifies games by. None if the ROM has no valid header.
+pub fn rom_crc32(rom: &[u8]) -> Option<u32> {
+    let header = NESHeaderMetadata::parse_header(rom).ok()?;
+    Some(crc32(rom.get(RomLayout::of(&header).prg.start..)?))
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GameDb {
+    games: BTreeMap<u32, HeaderFix>,  /* By rom_crc32 */
+}
+
+impl GameDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    /// Add a game's fix, in place of any it had
+    pub fn insert(&mut self, crc32: u32, fix: HeaderFix) {
+        self.games.insert(crc32, fix);
+    }
+
+    /// The fix for a ROM, if the database knows it
+    pub fn lookup(&self, rom: &[u8]) -> Option<&HeaderFix> {
+        self.games.get(&rom_crc32(rom)?)
+    }
+
+    /// Read a file in the NES 2.0 XML database's format, adding its games
+    #[cfg(feature = "std")]
+    pub fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        self.parse_xml(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Add the games from the text of a NES 2.0 XML database, returning how many there were.
+    /// Games without a `<rom crc32>` to know them by are skipped.
+    pub fn parse_xml(&mut self, text: &str) -> Result<usize, String> {
+        let mut count = 0;
+        let mut rest = text;
+        while let Some(start) = rest.find("<game>") {
+            let end = rest[start..].find("</game>").ok_or("A <game> is missing its </game>")? + start;
+            let game = &rest[start + "<game>".len()..end];
+            rest = &rest[end + "</game>".len()..];
+
+            let mut crc = None;
+            let mut fix = HeaderFix::default();
+            for (name, attributes) in elements(game) {
+                let number = |key: &str| -> Result<Option<u32>, String> {
+                    attribute(attributes, key).map(|value| value.parse::<u32>()
+                        .map_err(|_| format!("<{} {}=\"{}\"> is not a number", name, key, value))).transpose()
+                };
+                match name {
+                    "rom" => crc = attribute(attributes, "crc32")
+                        .map(|value| u32::from_str_radix(value, 16).map_err(|_| format!("<rom crc32=\"{}\"> is not a CRC32", value)))
+                        .transpose()?,
+                    "prgrom" => fix.prg_rom_size = number("size")?,
+                    "chrrom" => fix.chr_rom_size = number("size")?,
+                    "prgram" => fix.prg_ram_size = number("size")?,
+                    "prgnvram" => fix.prg_nvram_size = number("size")?,
+                    "chrram" => fix.chr_ram_size = number("size")?,
+                    "chrnvram" => fix.chr_nvram_size = number("size")?,
+                    "pcb" => {
+                        fix.mapper_id = number("mapper")?.map(|id| id as u16);
+                        fix.submapper_id = number("submapper")?.map(|id| id as u8);
+                        fix.has_battery = number("battery")?.map(|battery| battery != 0);
+                        fix.mirroring = match attribute(attributes, "mirroring") {
+                            Some("H") => Some(Mirroring::Horizontal),
+                            Some("V") => Some(Mirroring::Vertical),
+                            Some("4") => Some(Mirroring::FourScreen),
+                            _ => None,  /* Mapper controlled, or not known */
+                        };
+                    }
+                    "console" => fix.timing = match number("region")? {
+                        Some(0) => Some(Timing::NTSC),
+                        Some(1) => Some(Timing::PAL),
+                        Some(2) => Some(Timing::MultiRegion),
+                        Some(3) => Some(Timing::Dendy),
+                        _ => None,
+                    },
+                    _ => {}
+                }
+            }
+
+            /* The database lists what RAM a board has, so what it doesn't list it hasn't */
+            fix.prg_ram_size.get_or_insert(0);
+            fix.prg_nvram_size.get_or_insert(0);
+            fix.chr_ram_size.get_or_insert(0);
+            fix.chr_nvram_size.get_or_insert(0);
+            fix.chr_rom_size.get_or_insert(0);
+
+            if let Some(crc) = crc {
+                self.insert(crc, fix);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/* The elements within some XML, as their name and the text of their attributes, skipping comments */
+fn elements(xml: &str) -> impl Iterator<Item = (&str, &str)> {
+    xml.split('<').skip(1)
+        .filter(|tag| !tag.starts_with("!--") && !tag.starts_with('/') && !tag.starts_with('?'))
+        .filter_map(|tag| {
+            let tag = tag[..tag.find('>')?].trim_end_matches('/');
+            let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+            Some((name, attributes))
+        })
+}
+
+/* An attribute's value, from the text of an element's attributes */
+fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start().strip_prefix('"')?;
+        let end = value.find('"')?;
+        if name == key {
+            return Some(&value[..end]);
+        }
+        rest = &value[end + 1..];
+    }
+    None
+}
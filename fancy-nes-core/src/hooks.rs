@@ -0,0 +1,42 @@
+//! Callbacks for tools which watch the machine run - coverage analysers,
+//! custom debuggers, achievement systems - without forking the emulator.
+//! A tool implements `Hooks`, overriding only the events it cares about, and
+//! installs it with `Nes::set_hooks`.
+//!
+//! Hooks are only compiled in with the `hooks` feature. Without it, none of the
+//! calls exist, so the emulator pays nothing for them.
+
+use core::any::Any;
+
+use crate::cpu::NESCpu;
+use crate::nes::Frame;
+
+/// The kinds of interrupt the CPU services
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+/// Events reported as the machine runs. Every method does nothing by default.
+/// Hooks are called from the emulation thread, in the middle of a CPU cycle, so
+/// they should be quick.
+pub trait Hooks: Any + Send {
+    /// The CPU is about to execute the instruction at its PC
+    fn instruction(&mut self, _cpu: &NESCpu) {}
+
+    /// A CPU read with side-effects (not debugger peeks), after cheats are applied
+    fn read(&mut self, _addr: u16, _data: u8) {}
+
+    /// A CPU write
+    fn write(&mut self, _addr: u16, _data: u8) {}
+
+    /// The CPU has begun servicing an interrupt
+    fn interrupt(&mut self, _interrupt: Interrupt) {}
+
+    /// The PPU has moved onto a new scanline (0-261 NTSC, 0-311 PAL and Dendy)
+    fn scanline(&mut self, _scanline: u16) {}
+
+    /// The PPU has completed a frame
+    fn frame(&mut self, _frame: &Frame) {}
+}
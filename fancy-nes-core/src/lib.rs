@@ -1,22 +1,157 @@
-//use core::fmt;
+//! The emulator itself, without any window, audio device or input of its own.
+//! Without the std feature (on by default), it is `no_std`, needing only an
+//! allocator: reading and writing files (traces, profiles, symbol files,
+//! regression fixtures), timing and the modelled palette are left out, and a
+//! frontend passes ROMs, frames and input through slices and callbacks.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+/* What std's prelude would bring in, for the modules which need it */
+mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::format;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec;
+    pub use alloc::vec::Vec;
+}
+
+#[macro_use]
+pub mod logging;
+
+pub mod apu;
+#[cfg(feature = "std")]
+pub mod bench;
+pub mod bus;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
 pub mod cpu;
+pub mod crash;
+pub mod debugger;
+pub mod error;
+pub mod game_db;
+#[cfg(feature = "hooks")]
+pub mod hooks;
+pub mod memory;
+pub mod movie;
+pub mod nametable;
+pub mod nes;
+pub mod nsf;
+pub mod palette;
+pub mod png;
 pub mod ppu;
+#[cfg(feature = "std")]
+pub mod regression;
+pub mod rom_info;
+pub mod state;
+pub mod symbols;
+pub mod test_rom;
 
-#[derive(Debug)]
+pub use error::NesError;
+pub use nes::Nes;
+
+#[derive(Debug, Clone, Copy)]
 pub enum Mirroring {
     Horizontal,  /* vertical arrangement */
     Vertical,    /* horizontal arrangement */
     FourScreen, 
+    SingleScreenA,  /* All four nametables show the first 1KiB of VRAM */
+    SingleScreenB,  /* ...or the second */
+}
+
+/// The CPU/PPU timing a cartridge was designed for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    NTSC,         /* RP2C02 */
+    PAL,          /* RP2C07 */
+    MultiRegion,  /* Runs on either */
+    Dendy,        /* UA6538 */
+}
+
+/// The console timing actually emulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    Dendy,  /* PAL famiclones: PAL's frame, with NTSC's CPU:PPU ratio and a late vblank */
+}
+
+impl Region {
+    /// The region to run a cartridge in
+    pub fn from_timing(timing: Timing) -> Self {
+        match timing {
+            Timing::PAL => Region::PAL,
+            Timing::Dendy => Region::Dendy,
+            Timing::NTSC | Timing::MultiRegion => Region::NTSC,
+        }
+    }
+
+    /// CPU clock, in Hz
+    pub fn cpu_clock(&self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    /// Frames per second
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL | Region::Dendy => 50.0070,
+        }
+    }
+
+    /// Scanlines per frame, including vertical blanking and the pre-render line
+    pub fn scanlines(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::Dendy => 312,
+        }
+    }
+
+    /// The scanline vblank begins on. Dendy idles for 51 lines after rendering
+    /// first, so that vblank is as long as NTSC's and lies at the end of the frame.
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291,
+        }
+    }
+}
+
+/// Idle scanlines added to each frame, during which the CPU runs but nothing else
+/// does, giving games which lag more time per frame (see Nes::set_overclock).
+/// Lines before vblank begins suit most games; lines after it ends give the NMI
+/// handler more time too, but break games which time their vblank work.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Overclock {
+    pub pre_nmi: u16,
+    pub post_nmi: u16,
 }
 
 #[derive(Debug)]
 pub struct NESHeaderMetadata {
+    pub is_nes2: bool,
     pub hardwired_mirroring: Mirroring,
-    pub mapper_id: u8,
+    pub has_battery: bool,
+    pub mapper_id: u16,
+    pub submapper_id: u8,        /* Always 0 for iNES headers */
     pub prg_rom_size: u32,
     pub chr_rom_size: u32,
+    pub prg_ram_size: u32,       /* Volatile PRG RAM */
+    pub prg_nvram_size: u32,     /* Battery-backed PRG RAM (or EEPROM) */
+    pub chr_ram_size: u32,
+    pub chr_nvram_size: u32,
     pub has_trainer: bool,
+    pub timing: Timing,
+    pub console_type: u8,        /* 0: NES/Famicom, 1: Vs. System, 2: PlayChoice-10, 3: extended */
+    pub hardware_type: u8,       /* NES2.0 - Vs. System PPU and hardware type, or the extended console type */
+    pub misc_roms: u8,           /* NES2.0 */
+    pub expansion_device: u8,    /* NES2.0 - default expansion device, e.g. 1 for standard controllers */
 }
 
 struct NESHeader {
@@ -24,17 +159,55 @@ struct NESHeader {
     chr_rom: u8,
     flags6: u8,
     flags7: u8,
-    mapper: u8,          /* NES2.0 */
-    prg_chr_msb: u8,     /* NES2.0 */
+    mapper: u8,          /* NES2.0 - mapper MSB and submapper. iNES - PRG RAM size */
+    prg_chr_msb: u8,     /* NES2.0. iNES - TV system */
     prg_eeprom_sz: u8,   /* NES2.0 */
+    chr_ram_sz: u8,      /* NES2.0 */
     cpu_ppu_timing: u8,  /* NES2.0 */
     hw_type: u8,         /* NES2.0 */
     misc_roms: u8,       /* NES2.0 */
     exp_device: u8,      /* NES2.0 */
 }
 
+impl NESHeader {
+    /* NES2.0 ROM sizes are 12 bits of units. If the top nybble is all set, the
+       lower byte is instead an exponent-multiplier: 2^EEEEEE * (MM*2+1) bytes. */
+    fn rom_size(lsb: u8, msb: u8, unit: u32) -> u32 {
+        if msb == 0xF {
+            (1u32 << (lsb >> 2)) * ((lsb & 0x3) as u32 * 2 + 1)
+        } else {
+            (((msb as u32) << 8) | lsb as u32) * unit
+        }
+    }
+
+    /* NES2.0 RAM sizes are given as a shift count - 64 << n bytes, or 0 for none */
+    fn ram_size(shift: u8) -> u32 {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+
+    /* The reverse of rom_size: units if the size is a whole number of them, otherwise
+       the exponent-multiplier form. Returns (lsb, msb nybble). */
+    fn encode_rom_size(size: u32, unit: u32) -> Option<(u8, u8)> {
+        if size.is_multiple_of(unit) && size / unit < 0xF00 {
+            return Some(((size / unit) as u8, ((size / unit) >> 8) as u8));
+        }
+        let exponent = size.trailing_zeros().min(63);
+        let multiplier = size >> exponent;
+        matches!(multiplier, 1 | 3 | 5 | 7).then(|| ((exponent << 2) as u8 | (multiplier / 2) as u8, 0xF))
+    }
+
+    /* The reverse of ram_size, rounding up to the next size it can express */
+    fn encode_ram_size(size: u32) -> u8 {
+        (1..15).find(|&shift| 64 << shift >= size).map_or(15, |shift| if size == 0 { 0 } else { shift })
+    }
+}
+
 impl NESHeaderMetadata {
-    pub fn parse_header(header: &Vec<u8>) -> Result<Self, &'static str> {
+    pub fn parse_header(header: &[u8]) -> Result<Self, &'static str> {
+       if header.len() < 16 {
+           return Err("Header is truncated");
+       }
+
        if header[0..=3] != [b'N', b'E', b'S', 0x1A] {
            return Err("Header missing NES<EOF> magic");
        }
@@ -48,10 +221,11 @@ impl NESHeaderMetadata {
            mapper: header[8],
            prg_chr_msb: header[9],
            prg_eeprom_sz: header[10],
-           cpu_ppu_timing: header[11],
-           hw_type: header[12],
-           misc_roms: header[13],
-           exp_device: header[14]
+           chr_ram_sz: header[11],
+           cpu_ppu_timing: header[12],
+           hw_type: header[13],
+           misc_roms: header[14],
+           exp_device: header[15]
        };
 
        /* check whether this is a "NES2.0" or "iNES"-style header */
@@ -60,7 +234,7 @@ impl NESHeaderMetadata {
        /* bit 3 takes priority and indicates FourScreen mirroring.
           otherwise use bits 0-1 to determine Horizontal or Vertical mirroring. 
           */
-       let hardwired_mirroring = if nes_header.flags6 & (1 << 3) == 1 {
+       let hardwired_mirroring = if nes_header.flags6 & (1 << 3) != 0 {
             Mirroring::FourScreen
        } else {
             match nes_header.flags6 & 1 {
@@ -68,27 +242,120 @@ impl NESHeaderMetadata {
                 _ => Mirroring::Vertical,
             }
        };
+
+       let has_battery = nes_header.flags6 & 0x2 > 0;
        
        /* get mapper number from flags6 and flags7 */
-       let mapper_id = (nes_header.flags6 & 0b11110000) >> 4
-                         | (nes_header.flags7 & 0b11110000);
+       let mut mapper_id = ((nes_header.flags6 & 0b11110000) >> 4
+                         | (nes_header.flags7 & 0b11110000)) as u16;
+
+       let has_trainer = nes_header.flags6 & 0x4 > 0;
+       let console_type = nes_header.flags7 & 0x3;
 
-       /* get the size of the PRG ROM - declared in 16 KB units */
-       let prg_rom_size = nes_header.prg_rom as u32 * 16 * 1024;
+       if is_nes2 {
+           /* NES2.0 extends the mapper number to 12 bits, and adds a 4 bit submapper */
+           mapper_id |= ((nes_header.mapper & 0x0F) as u16) << 8;
+           let submapper_id = nes_header.mapper >> 4;
 
-       /* get the size of the CHR ROM - declared in 8 KB units
-        * may be 0, in which case only CHR RAM is used.
-        */
-       let chr_rom_size = nes_header.chr_rom as u32 * 8 * 1024;
+           let prg_rom_size = NESHeader::rom_size(nes_header.prg_rom, nes_header.prg_chr_msb & 0x0F, 16 * 1024);
+           let chr_rom_size = NESHeader::rom_size(nes_header.chr_rom, nes_header.prg_chr_msb >> 4, 8 * 1024);
 
-       let has_trainer = nes_header.flags6 & 0x4 > 0;
+           let timing = match nes_header.cpu_ppu_timing & 0x3 {
+               0 => Timing::NTSC,
+               1 => Timing::PAL,
+               2 => Timing::MultiRegion,
+               _ => Timing::Dendy,
+           };
+
+           Ok(Self {
+               is_nes2,
+               hardwired_mirroring,
+               has_battery,
+               mapper_id,
+               submapper_id,
+               prg_rom_size,
+               chr_rom_size,
+               prg_ram_size: NESHeader::ram_size(nes_header.prg_eeprom_sz & 0x0F),
+               prg_nvram_size: NESHeader::ram_size(nes_header.prg_eeprom_sz >> 4),
+               chr_ram_size: NESHeader::ram_size(nes_header.chr_ram_sz & 0x0F),
+               chr_nvram_size: NESHeader::ram_size(nes_header.chr_ram_sz >> 4),
+               has_trainer,
+               timing,
+               console_type,
+               hardware_type: nes_header.hw_type,
+               misc_roms: nes_header.misc_roms & 0x3,
+               expansion_device: nes_header.exp_device & 0x3F,
+           })
+       } else {
+           /* get the size of the PRG ROM - declared in 16 KB units */
+           let prg_rom_size = nes_header.prg_rom as u32 * 16 * 1024;
+
+           /* get the size of the CHR ROM - declared in 8 KB units
+            * may be 0, in which case only CHR RAM is used.
+            */
+           let chr_rom_size = nes_header.chr_rom as u32 * 8 * 1024;
+
+           /* iNES declares PRG RAM in 8 KB units, where 0 infers 8 KB for compatibility.
+              Whether it is battery-backed is only known from flags6. */
+           let prg_ram_size = nes_header.mapper.max(1) as u32 * 8 * 1024;
+           let (prg_ram_size, prg_nvram_size) = if has_battery { (0, prg_ram_size) } else { (prg_ram_size, 0) };
 
-       Ok(Self {
-           hardwired_mirroring,
-           mapper_id,
-           prg_rom_size,
-           chr_rom_size,
-           has_trainer
-       })
+           let timing = if nes_header.prg_chr_msb & 0x1 > 0 { Timing::PAL } else { Timing::NTSC };
+
+           Ok(Self {
+               is_nes2,
+               hardwired_mirroring,
+               has_battery,
+               mapper_id,
+               submapper_id: 0,
+               prg_rom_size,
+               chr_rom_size,
+               prg_ram_size,
+               prg_nvram_size,
+               chr_ram_size: if chr_rom_size == 0 { 8 * 1024 } else { 0 },
+               chr_nvram_size: 0,
+               has_trainer,
+               timing,
+               console_type,
+               hardware_type: 0,
+               misc_roms: 0,
+               expansion_device: 0,
+           })
+       }
+    }
+
+    /// Write out a NES 2.0 header describing the cartridge, e.g. to repair a ROM whose
+    /// header is wrong (see game_db). Fails if a ROM size can't be expressed.
+    pub fn to_nes2_header(&self) -> Result<[u8; 16], &'static str> {
+        let (prg_lsb, prg_msb) = NESHeader::encode_rom_size(self.prg_rom_size, 16 * 1024).ok_or("PRG ROM size can't be expressed")?;
+        let (chr_lsb, chr_msb) = NESHeader::encode_rom_size(self.chr_rom_size, 8 * 1024).ok_or("CHR ROM size can't be expressed")?;
+        let mirroring = match self.hardwired_mirroring {
+            Mirroring::Vertical => 0x1,
+            Mirroring::FourScreen => 0x8,
+            _ => 0x0,
+        };
+        let timing = match self.timing {
+            Timing::NTSC => 0,
+            Timing::PAL => 1,
+            Timing::MultiRegion => 2,
+            Timing::Dendy => 3,
+        };
+
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_lsb;
+        header[5] = chr_lsb;
+        header[6] = ((self.mapper_id & 0x0F) << 4) as u8 | mirroring
+            | if self.has_trainer { 0x4 } else { 0 } | if self.has_battery { 0x2 } else { 0 };
+        header[7] = (self.mapper_id & 0xF0) as u8 | 0x8 | (self.console_type & 0x3);
+        header[8] = (self.submapper_id << 4) | ((self.mapper_id >> 8) & 0x0F) as u8;
+        header[9] = (chr_msb << 4) | prg_msb;
+        header[10] = (NESHeader::encode_ram_size(self.prg_nvram_size) << 4) | NESHeader::encode_ram_size(self.prg_ram_size);
+        header[11] = (NESHeader::encode_ram_size(self.chr_nvram_size) << 4) | NESHeader::encode_ram_size(self.chr_ram_size);
+        header[12] = timing;
+        header[13] = self.hardware_type;
+        header[14] = self.misc_roms & 0x3;
+        header[15] = self.expansion_device & 0x3F;
+        Ok(header)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,32 @@
+//! Diagnostic messages from the emulator, through the `log` crate, so that the
+//! frontend decides where they go and how much of each subsystem it wants.
+//! Each subsystem logs under its own target:
+//!   cpu     interrupts, and quirks of the 6502 a program has run into
+//!   ppu     vblank, and register writes
+//!   mapper  writes to cartridge registers
+//!   apu     frame counter and DMC IRQs
+//!   dma     OAM DMA and DMC sample fetches
+//!
+//! Without the logging feature, the messages and the work of formatting them
+//! are compiled out altogether.
+
+pub const CPU: &str = "cpu";
+pub const PPU: &str = "ppu";
+pub const MAPPER: &str = "mapper";
+pub const APU: &str = "apu";
+pub const DMA: &str = "dma";
+
+pub const TARGETS: [&str; 5] = [CPU, PPU, MAPPER, APU, DMA];
+
+/* nes_log!(Debug, CPU, "format", args...) - a log::log! at that level, with that target.
+   Compiled out, the arguments are still type checked, so that they count as used. */
+macro_rules! nes_log {
+    ($level:ident, $target:ident, $($arg:tt)+) => {
+        #[cfg(feature = "logging")]
+        log::log!(target: $crate::logging::$target, log::Level::$level, $($arg)+);
+        #[cfg(not(feature = "logging"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
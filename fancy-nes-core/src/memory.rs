@@ -0,0 +1,79 @@
+//! Memory as achievement systems see it - rcheevos, or a homemade engine which
+//! checks conditions on a game's variables every frame. `Nes::memory` gives a
+//! `MemoryView` of the machine, read without side-effects and by the CPU
+//! addresses rcheevos uses for the NES: the console's RAM at $0000-$07FF
+//! (mirrored up to $1FFF), the cartridge's work RAM at $6000-$7FFF and PRG ROM
+//! at $8000-$FFFF, as it is mapped now. Registers read as 0, since reading
+//! them would disturb the hardware, and cheats are not applied.
+//!
+//! For the conditions to be checked in step with the game, whatever pace the
+//! frontend runs it at, `Nes::set_frame_callback` calls back with the view as
+//! each frame completes. That's at the same point in emulation every time, so
+//! a movie replays with the same achievements.
+
+use crate::prelude::*;
+use crate::bus::Bus;
+
+/// Called with the machine's memory as each frame completes (see Nes::set_frame_callback)
+pub type FrameCallback = Box<dyn FnMut(&MemoryView) + Send>;
+
+pub struct MemoryView<'a> {
+    bus: &'a Bus,
+}
+
+impl<'a> MemoryView<'a> {
+    pub(crate) fn new(bus: &'a Bus) -> Self {
+        Self { bus }
+    }
+
+    /// The console's 2KB of RAM
+    pub fn ram(&self) -> &'a [u8] {
+        &self.bus.internal_ram
+    }
+
+    /// The cartridge's work RAM at $6000, whether battery-backed or not. Empty if the board has none.
+    pub fn prg_ram(&self) -> &'a [u8] {
+        self.bus.cartridge.work_ram()
+    }
+
+    /// Read a byte
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.bus.internal_ram[(addr & 0x07FF) as usize],
+            0x6000..=0xFFFF => self.bus.cartridge.cpu_read(addr),
+            _ => 0,
+        }
+    }
+
+    /// Read a little-endian 16-bit value, as the 6502 stores them
+    pub fn read_u16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    /// Read a little-endian 32-bit value
+    pub fn read_u32(&self, addr: u16) -> u32 {
+        let bytes = [0, 1, 2, 3].map(|i| self.read(addr.wrapping_add(i)));
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Fill `buf` from `addr` onwards, returning how many bytes were read before the end of
+    /// the address space - the shape of rcheevos' read_memory callback
+    pub fn read_into(&self, addr: u32, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(0x10000usize.saturating_sub(addr as usize));
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.read((addr as usize + i) as u16);
+        }
+        count
+    }
+
+    /// Where in PRG ROM a read of $8000-$FFFF comes from, as the mapper has the banks now -
+    /// e.g. to tell which of a game's banks of level code is running
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.bus.cartridge.prg_rom_offset(addr)
+    }
+
+    /// The mapper's banks and registers, as the debugger shows them
+    pub fn describe_banks(&self) -> String {
+        self.bus.cartridge.describe_banks()
+    }
+}
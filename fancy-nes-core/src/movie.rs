@@ -0,0 +1,376 @@
+//! Movies - controller input recorded frame by frame from power-on, which
+//! replays identically because the core never consults the wall clock.
+//!
+//! Movies are stored in FCEUX's text format (.fm2), so that TAS movies made
+//! in FCEUX can be played here and ours checked there: `key value` header
+//! lines, then one line per frame, e.g. `|0|...T....|........||` for Start
+//! held on the first controller. The first field holds commands to run
+//! before the frame (see COMMAND_RESET and COMMAND_POWER).
+//!
+//! The ROM is identified as FCEUX does it, by the MD5 of its PRG and CHR data,
+//! in base64. Movies which start from a save state, use the binary input log
+//! or peripherals other than two standard controllers are not supported.
+
+use crate::prelude::*;
+use crate::{NESHeaderMetadata, Region};
+
+/// Press the reset button before the frame
+pub const COMMAND_RESET: u8 = 1;
+/// Power cycle the console before the frame
+pub const COMMAND_POWER: u8 = 2;
+
+/* FCEUX reads this to decide which of its historical quirks to emulate, so claim a modern version */
+const EMU_VERSION: u32 = 22020;
+
+/* Button characters in the order they appear in a frame, from JoypadButton::RIGHT down to A */
+const BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub command: u8,       /* COMMAND_* bits */
+    pub buttons: [u8; 2],  /* Buttons held on each controller, as JoypadButton bits */
+}
+
+pub struct Movie {
+    pub rom_filename: String,
+    pub rom_checksum: Option<[u8; 16]>,  /* Only absent from hand-written movies */
+    pub guid: String,
+    pub region: Region,
+    pub rerecord_count: u32,
+    pub comments: Vec<String>,
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// An empty movie, to record onto
+    pub fn new(rom: &[u8], rom_filename: &str, region: Region) -> Self {
+        let checksum = rom_checksum(rom);
+
+        /* Any unique identifier will do, so borrow the checksum's format */
+        let hex: String = checksum.iter().map(|b| format!("{:0>2X}", b)).collect();
+        let guid = format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]);
+
+        Self {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: Some(checksum),
+            guid,
+            region,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Add the next frame
+    pub fn record(&mut self, frame: MovieFrame) {
+        self.frames.push(frame);
+    }
+
+    /// A frame's commands and input, or None once the movie has finished
+    pub fn frame(&self, frame: usize) -> Option<MovieFrame> {
+        self.frames.get(frame).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), String> {
+        match self.rom_checksum {
+            Some(checksum) if checksum != rom_checksum(rom) => {
+                Err(format!("Movie was recorded with a different ROM ({})", self.rom_filename))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        out.push_str("version 3\n");
+        out.push_str(&format!("emuVersion {}\n", EMU_VERSION));
+        out.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        out.push_str(&format!("palFlag {}\n", (self.region == Region::PAL) as u8));
+        out.push_str(&format!("romFilename {}\n", self.rom_filename));
+        if let Some(checksum) = self.rom_checksum {
+            out.push_str(&format!("romChecksum base64:{}\n", base64_encode(&checksum)));
+        }
+        out.push_str(&format!("guid {}\n", self.guid));
+        out.push_str("fourscore 0\nport0 1\nport1 1\nport2 0\n");
+        for comment in &self.comments {
+            out.push_str(&format!("comment {}\n", comment));
+        }
+
+        for frame in &self.frames {
+            out.push_str(&format!("|{}|", frame.command));
+            for port in frame.buttons {
+                for (bit, name) in BUTTONS.iter().enumerate() {
+                    out.push(if port & (0x80 >> bit) != 0 { *name as char } else { '.' });
+                }
+                out.push('|');
+            }
+            out.push_str("|\n");
+        }
+        out
+    }
+
+    pub fn from_fm2(text: &str) -> Result<Self, String> {
+        let mut movie = Self {
+            rom_filename: String::new(),
+            rom_checksum: None,
+            guid: String::new(),
+            region: Region::NTSC,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            frames: Vec::new(),
+        };
+        let mut has_version = false;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            let bad = |what: &str| format!("Bad {} on line {}", what, number + 1);
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if !line.starts_with('|') {
+                let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                match key {
+                    "version" => {
+                        if value != "3" {
+                            return Err(format!("Unsupported movie version {}", value));
+                        }
+                        has_version = true;
+                    }
+                    "rerecordCount" => movie.rerecord_count = value.parse().map_err(|_| bad("rerecord count"))?,
+                    "palFlag" => movie.region = if value == "1" { Region::PAL } else { Region::NTSC },
+                    "romFilename" => movie.rom_filename = value.to_string(),
+                    "romChecksum" => {
+                        let checksum = value.strip_prefix("base64:")
+                            .and_then(base64_decode)
+                            .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+                            .ok_or_else(|| bad("ROM checksum"))?;
+                        movie.rom_checksum = Some(checksum);
+                    }
+                    "guid" => movie.guid = value.to_string(),
+                    "comment" => movie.comments.push(value.to_string()),
+                    "binary" if value == "1" => return Err("Binary movies are not supported".to_string()),
+                    "savestate" => return Err("Movies starting from a save state are not supported".to_string()),
+                    "fourscore" if value == "1" => return Err("Four Score movies are not supported".to_string()),
+                    "FDS" if value == "1" => return Err("Famicom Disk System movies are not supported".to_string()),
+                    "port0" | "port1" if value != "1" => return Err(format!("Unsupported controller type {} in {}", value, key)),
+                    /* emuVersion, NewPPU, length, subtitle, ... don't affect playback */
+                    _ => {}
+                }
+                continue;
+            }
+
+            // |commands|port0|port1|port2|
+            let fields: Vec<&str> = line.split('|').collect();
+            if fields.len() < 5 {
+                return Err(bad("frame"));
+            }
+            let command: u8 = fields[1].trim().parse().map_err(|_| bad("command"))?;
+            if command & !(COMMAND_RESET | COMMAND_POWER) != 0 {
+                return Err(format!("Unsupported command {} on line {}", command, number + 1));
+            }
+
+            let mut frame = MovieFrame { command, buttons: [0; 2] };
+            for port in 0..2 {
+                for (bit, c) in fields[2 + port].bytes().take(8).enumerate() {
+                    if c != b'.' && c != b' ' {
+                        frame.buttons[port] |= 0x80 >> bit;
+                    }
+                }
+            }
+            movie.frames.push(frame);
+        }
+
+        if !has_version {
+            return Err("Not an FM2 movie - no version".to_string());
+        }
+        Ok(movie)
+    }
+}
+
+/// FCEUX identifies a ROM by the MD5 of its PRG and CHR data, i.e. the image
+/// without its header or trainer
+pub fn rom_checksum(rom: &[u8]) -> [u8; 16] {
+    let data = match NESHeaderMetadata::parse_header(rom) {
+        Ok(header) => {
+            let start = 16 + if header.has_trainer { 512 } else { 0 };
+            let end = start + header.prg_rom_size as usize + header.chr_rom_size as usize;
+            rom.get(start..end.min(rom.len())).unwrap_or(&[])
+        }
+        Err(_) => rom,
+    };
+    md5(data)
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        bits = bits << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/* RFC 1321 */
+pub(crate) fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    /* The integer part of abs(sin(i + 1)) * 2^32 */
+    const K: [u32; 64] = [
+        0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+        0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+        0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+        0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+        0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+        0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+        0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+        0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+        0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+        0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+        0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+        0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+        0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+        0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+        0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+        0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(K[i]).wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut digest = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{:0>2x}", b)).collect()
+    }
+
+    #[test]
+    fn md5_matches_rfc_1321() {
+        let vectors = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            ("abcdefghijklmnopqrstuvwxyz", "c3fcd3d76192e4007dfb496cca67e13b"),
+            ("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789", "d174ab98d277d9f5a5611c2c9f419d9f"),
+            ("12345678901234567890123456789012345678901234567890123456789012345678901234567890", "57edf4a22be3c955ac49da2e2107b67a"),
+        ];
+        for (input, digest) in vectors {
+            assert_eq!(hex(md5(input.as_bytes())), digest, "MD5 of {:?}", input);
+        }
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for (data, text) in vectors {
+            assert_eq!(base64_encode(data.as_bytes()), text);
+            assert_eq!(base64_decode(text).unwrap(), data.as_bytes());
+        }
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn fm2_round_trips() {
+        let rom = b"not a real ROM, so checksummed whole";
+        let mut movie = Movie::new(rom, "game.nes", Region::PAL);
+        movie.rerecord_count = 12;
+        movie.comments.push("author someone".to_string());
+        movie.record(MovieFrame { command: COMMAND_POWER, buttons: [0, 0] });
+        movie.record(MovieFrame { command: 0, buttons: [0x08, 0x81] });
+        movie.record(MovieFrame { command: COMMAND_RESET, buttons: [0xFF, 0] });
+
+        let text = movie.to_fm2();
+        assert!(text.contains("|0|....T...|R......A||\n"));
+        let read = Movie::from_fm2(&text).unwrap();
+        assert_eq!(read.rom_filename, "game.nes");
+        assert_eq!(read.rom_checksum, Some(md5(rom)));
+        assert_eq!(read.guid, movie.guid);
+        assert_eq!(read.region, Region::PAL);
+        assert_eq!(read.rerecord_count, 12);
+        assert_eq!(read.comments, movie.comments);
+        assert_eq!(read.len(), 3);
+        for frame in 0..3 {
+            assert_eq!(read.frame(frame), movie.frame(frame));
+        }
+        assert!(read.check_rom(rom).is_ok());
+        assert!(read.check_rom(b"another ROM").is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_movies() {
+        assert!(Movie::from_fm2("|0|........|........||\n").is_err());
+        assert!(Movie::from_fm2("version 2\n").is_err());
+        assert!(Movie::from_fm2("version 3\nbinary 1\n").is_err());
+        assert!(Movie::from_fm2("version 3\n|4|........|........||\n").is_err());
+        assert!(Movie::from_fm2("version 3\nromChecksum base64:Zm9v\n").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Which 1KiB of memory each of the PPU's four nametables ($2000-$2FFF) is.
+//! The console has only 2KiB of VRAM, enough for two, so the cartridge decides
+//! how they're mirrored over the four - by wiring, or through its mapper, which
+//! may switch mirroring at any time. Four screen boards carry another 2KiB of
+//! VRAM of their own, so that each nametable is distinct.
+
+use crate::prelude::*;
+use crate::Mirroring;
+use crate::state::{StateReader, StateWriter};
+
+const CARTRIDGE_VRAM_SIZE: usize = 2048;
+
+pub struct NametableMapping {
+    mirroring: Mirroring,
+    vram: Vec<u8>,  /* The cartridge's own VRAM for nametables 2 and 3, empty unless it's four screen */
+}
+
+impl NametableMapping {
+    pub fn new(mirroring: Mirroring) -> Self {
+        let mut nametables = Self { mirroring, vram: Vec::new() };
+        nametables.set(mirroring);
+        nametables
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Reprogram the mirroring, e.g. from a mapper register. Switching to four
+    /// screen gives the cartridge its extra VRAM, if it didn't have it already.
+    pub fn set(&mut self, mirroring: Mirroring) {
+        if let Mirroring::FourScreen = mirroring {
+            self.vram.resize(CARTRIDGE_VRAM_SIZE, 0);
+        }
+        self.mirroring = mirroring;
+    }
+
+    /* Translate a nametable address ($2000-$2FFF) into an offset into console VRAM,
+       or None if it's one of the nametables in the cartridge's VRAM */
+    #[inline]
+    fn console_offset(&self, mut addr: u16) -> Option<u16> {
+        match self.mirroring {
+            Mirroring::Horizontal => {
+                addr &= !(1 << 10);
+                if addr & 0x800 > 0 { addr -= 0x400 }
+            }
+            Mirroring::Vertical => {
+                addr &= !(1 << 11);
+            }
+            Mirroring::SingleScreenA => {
+                addr &= !0x0C00;
+            }
+            Mirroring::SingleScreenB => {
+                addr = (addr & !0x0C00) | 0x0400;
+            }
+            Mirroring::FourScreen => {
+                if addr & 0x800 > 0 { return None }
+            }
+        }
+        Some(addr - 0x2000)
+    }
+
+    /// A read of a nametable, as the word expected back from a cartridge PPU
+    /// read: 0x1*** where *** indexes console VRAM, or else the data itself.
+    #[inline]
+    pub fn read(&self, addr: u16) -> u16 {
+        match self.console_offset(addr) {
+            Some(offset) => 0x1000 | offset,
+            None => self.vram[addr as usize & 0x07FF] as u16,
+        }
+    }
+
+    /// A write to a nametable, giving back the word as for `read`. Writes to
+    /// the cartridge's VRAM are done here, and given back as 0.
+    pub fn write(&mut self, addr: u16, data: u8) -> u16 {
+        match self.console_offset(addr) {
+            Some(offset) => 0x1000 | offset,
+            None => {
+                self.vram[addr as usize & 0x07FF] = data;
+                0
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(match self.mirroring {
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => 1,
+            Mirroring::FourScreen => 2,
+            Mirroring::SingleScreenA => 3,
+            Mirroring::SingleScreenB => 4,
+        });
+        w.write_bytes(&self.vram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.mirroring = match r.read_u8()? {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::FourScreen,
+            3 => Mirroring::SingleScreenA,
+            4 => Mirroring::SingleScreenB,
+            mirroring => return Err(format!("Save state has unknown mirroring {}", mirroring)),
+        };
+        let vram = r.read_bytes()?;
+        if !vram.is_empty() && vram.len() != CARTRIDGE_VRAM_SIZE {
+            return Err(format!("Save state has {} bytes of cartridge VRAM, expected {}", vram.len(), CARTRIDGE_VRAM_SIZE));
+        }
+        self.vram = vram.to_vec();
+        if let Mirroring::FourScreen = self.mirroring {
+            self.vram.resize(CARTRIDGE_VRAM_SIZE, 0);
+        }
+        Ok(())
+    }
+}
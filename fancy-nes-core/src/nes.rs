@@ -0,0 +1,579 @@
+//! A self-contained NES, for use by frontends and tests which don't need
+//! to reach into the individual components. A frontend loads a ROM with
+//! `Nes::from_rom`, feeds input with `set_controller`, and then either calls
+//! `run_frame` once per displayed frame, or `tick`/`step` for finer control
+//! (e.g. to check breakpoints), presenting `framebuffer` whenever a frame completes.
+//!
+//! `Nes` owns the CPU, which in turn owns the bus and everything on it, so
+//! the whole machine is a plain value which can be moved between threads.
+//!
+//! Failures are returned as a `NesError`, rather than panicking.
+
+use crate::prelude::*;
+use crate::{NESHeaderMetadata, Overclock, Region, Timing};
+use crate::apu::{Channel, NESApu};
+use crate::bus::{Bus, MemoryRead};
+use crate::cartridge::Cartridge;
+use crate::cdl::CodeDataLog;
+use crate::cpu::{AddressingMode, NESCpu};
+use crate::cpu::controller::{DeviceInput, InputDevice, InputPoll};
+use crate::cpu::decode::LUT_6502;
+#[cfg(feature = "std")]
+use crate::cpu::profile::Profiler;
+use crate::cpu::trace::TraceUnit;
+use crate::error::NesError;
+#[cfg(feature = "hooks")]
+use crate::hooks::Hooks;
+use crate::memory::{FrameCallback, MemoryView};
+use crate::palette::{self, Palette, PixelFormat};
+use crate::ppu::NESPpu;
+use crate::state;
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+/// A frame is one word per pixel: an index into the NES's 64 colour palette
+/// in bits 0-5, and the colour emphasis bits of PPUMASK (red, green, blue) in
+/// bits 6-8, giving 512 possible colours (see Palette::emphasised)
+pub type Frame = [u16; FRAME_WIDTH * FRAME_HEIGHT];
+
+/// The address spaces a debugger can inspect with `peek` and `poke`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySpace {
+    Cpu,  /* $0000-$FFFF, as the CPU sees it */
+    Ppu,  /* $0000-$3FFF: pattern tables, nametables and palettes */
+    Oam,  /* $00-$FF: sprite attributes */
+}
+
+impl MemorySpace {
+    /// The number of addresses in the space
+    pub fn size(&self) -> usize {
+        match self {
+            MemorySpace::Cpu => 0x10000,
+            MemorySpace::Ppu => 0x4000,
+            MemorySpace::Oam => 0x100,
+        }
+    }
+}
+
+
+pub struct Nes {
+    cpu: NESCpu,
+
+    frame: Box<Frame>,  /* The last completed frame, boxed to keep Nes small enough to build on a thread's stack */
+    timing: Timing,
+    battery: bool,  /* Whether the cartridge's work RAM is battery-backed, and so saved between sessions */
+    rom: Vec<u8>,  /* Kept to power cycle with */
+    trace: Option<TraceUnit>,
+    #[cfg(feature = "std")]
+    profile: Option<Profiler>,
+    palette: Palette,  /* The colours of screenshots, set by frontends to the colours they present */
+    colours: Vec<[u8; 3]>,  /* The palette's colour for each pixel value */
+    frame_callback: Option<FrameCallback>,
+}
+
+impl Nes {
+    /// Power on a NES with the given iNES ROM image inserted
+    pub fn from_rom(rom: &[u8]) -> Result<Self, NesError> {
+        let cartridge = Cartridge::from_ines(rom)?;
+        let header = NESHeaderMetadata::parse_header(rom).map_err(|e| NesError::Rom(e.to_string()))?;
+        let mut bus = Bus::new(cartridge);
+        bus.set_region(Region::from_timing(header.timing));
+
+        let mut nes = Self {
+            cpu: NESCpu::new(bus),
+            frame: Box::new([0; FRAME_WIDTH * FRAME_HEIGHT]),
+            timing: header.timing,
+            battery: header.has_battery,
+            rom: rom.to_vec(),
+            trace: None,
+            #[cfg(feature = "std")]
+            profile: None,
+            palette: Palette::default(),
+            colours: Palette::default().frame_colours(),
+            frame_callback: None,
+        };
+        nes.reset()?;
+        Ok(nes)
+    }
+
+    /// Swap the cartridge for another, power cycling the machine. The audio
+    /// sample rate, mixer, channels muted and stems, overclocking, opcode
+    /// strictness, palette, any trace or profile, input devices and poll, frame
+    /// callback and hooks are kept, but the region is taken from the new
+    /// cartridge. On error, the current cartridge stays inserted.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), NesError> {
+        let sample_rate = self.cpu.bus.apu.sample_rate();
+        let muted = Channel::ALL.map(|channel| self.cpu.bus.apu.muted(channel));
+        let mixer = self.cpu.bus.apu.mixer();
+        let stems = self.cpu.bus.apu.keeps_stems();
+        let overclock = self.overclock();
+        let strict_opcodes = self.cpu.strict_opcodes;
+        let palette = core::mem::take(&mut self.palette);
+        let input_poll = self.cpu.bus.input_poll.take();
+        let devices = core::mem::take(&mut self.cpu.bus.devices);
+        let frame_callback = self.frame_callback.take();
+
+        let trace = self.trace.take();
+        #[cfg(feature = "std")]
+        let profile = self.profile.take();
+        #[cfg(feature = "hooks")]
+        let hooks = self.cpu.bus.hooks.take();
+        match Nes::from_rom(rom) {
+            Ok(nes) => *self = nes,
+            Err(e) => {
+                self.trace = trace;
+                #[cfg(feature = "std")]
+                {
+                    self.profile = profile;
+                }
+                self.palette = palette;
+                self.cpu.bus.input_poll = input_poll;
+                self.cpu.bus.devices = devices;
+                self.frame_callback = frame_callback;
+                #[cfg(feature = "hooks")]
+                {
+                    self.cpu.bus.hooks = hooks;
+                }
+                return Err(e);
+            }
+        }
+        self.cpu.bus.apu.set_sample_rate(sample_rate);
+        for (channel, muted) in Channel::ALL.into_iter().zip(muted) {
+            self.cpu.bus.apu.set_muted(channel, muted);
+        }
+        self.cpu.bus.apu.set_mixer(mixer);
+        self.cpu.bus.apu.set_stems(stems);
+        self.set_overclock(overclock);
+        self.cpu.strict_opcodes = strict_opcodes;
+        self.trace = trace;
+        #[cfg(feature = "std")]
+        {
+            self.profile = profile;
+        }
+        self.set_palette(palette);
+        self.cpu.bus.input_poll = input_poll;
+        self.cpu.bus.devices = devices;
+        self.frame_callback = frame_callback;
+        #[cfg(feature = "hooks")]
+        {
+            self.cpu.bus.hooks = hooks;
+        }
+        Ok(())
+    }
+
+    /// Press the reset button
+    pub fn reset(&mut self) -> Result<(), NesError> {
+        self.cpu.reset().map_err(|e| self.emulation_error(e))
+    }
+
+    /// Turn the console off and on again, clearing RAM. Unlike `load_rom`, the
+    /// region is kept even if it was overridden, as are breakpoints, cheats, watches and any code/data log.
+    pub fn power_cycle(&mut self) -> Result<(), NesError> {
+        let region = self.region();
+        let breakpoints = core::mem::take(&mut self.cpu.bus.breakpoints);
+        let cheats = core::mem::take(&mut self.cpu.bus.cheats);
+        let watches = core::mem::take(&mut self.cpu.bus.watches);
+        let cdl = self.cpu.bus.cartridge.take_cdl();
+        let rom = core::mem::take(&mut self.rom);
+
+        let result = self.load_rom(&rom);
+        if result.is_err() {
+            self.rom = rom;
+        }
+        self.set_region(region);
+        self.cpu.bus.breakpoints = breakpoints;
+        self.cpu.bus.cheats = cheats;
+        self.cpu.bus.watches = watches;
+        self.cpu.bus.cartridge.set_cdl(cdl);
+        result
+    }
+
+    /// Run a single CPU cycle, and the PPU cycles which accompany it.
+    /// Returns whether a frame was completed, in which case it is
+    /// available from `framebuffer`.
+    pub fn tick(&mut self) -> Result<bool, NesError> {
+        if let Some(trace) = &mut self.trace {
+            /* Not when an interrupt is taken instead, as the instruction at the PC waits for its handler */
+            if self.cpu.wait_cycles == 0 && !self.cpu.interrupt_pending() {
+                self.cpu.bus.catch_up_ppu();  /* For the PPU's position, if traced */
+                if let Err(e) = trace.dump(&self.cpu) {
+                    return Err(self.emulation_error(e));
+                }
+            }
+        }
+        if self.cpu.wait_cycles == 0 && self.cpu.bus.cartridge.cdl().is_some() {
+            self.log_code();
+        }
+        #[cfg(feature = "std")]
+        if let Some(profile) = &mut self.profile {
+            if self.cpu.wait_cycles == 0 {
+                profile.instruction(&self.cpu, self.cpu.interrupt_pending());
+            }
+            profile.cycle();
+        }
+        #[cfg(feature = "hooks")]
+        if self.cpu.wait_cycles == 0 {
+            /* Lent out of the bus, so that they can see the whole CPU */
+            if let Some(mut hooks) = self.cpu.bus.hooks.take() {
+                hooks.instruction(&self.cpu);
+                self.cpu.bus.hooks = Some(hooks);
+            }
+        }
+        self.cpu.tick().map_err(|e| self.emulation_error(e))?;
+
+        let ppu = &mut self.cpu.bus.ppu;
+        if ppu.frame_ready {
+            ppu.frame_ready = false;
+            self.frame.copy_from_slice(&ppu.frame[..]);
+            self.write_frozen_watches();
+            #[cfg(feature = "hooks")]
+            if let Some(hooks) = self.cpu.bus.hooks.as_deref_mut() {
+                hooks.frame(&self.frame);
+            }
+            if let Some(callback) = &mut self.frame_callback {
+                callback(&MemoryView::new(&self.cpu.bus));
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /* Log the instruction about to execute as code, and whether its reads are indirect */
+    fn log_code(&mut self) {
+        let cpu = &mut self.cpu;
+        if cpu.interrupt_pending() {
+            cpu.bus.cartridge.log_interrupt();
+            return;
+        }
+        let opcode = cpu.bus.read(cpu.PC).unwrap_or_default();
+        let instr = &LUT_6502[opcode as usize];
+        let indirect_data = matches!(instr.mode, AddressingMode::IndexedIndirect | AddressingMode::IndirectIndexed);
+        let indirect_jump = matches!(instr.mode, AddressingMode::Indirect);
+        cpu.bus.cartridge.log_instruction(cpu.PC, instr.mode.instruction_len(), indirect_data, indirect_jump);
+    }
+
+    /* Write frozen watches' values back, as each frame completes. Those outside RAM can't be. */
+    fn write_frozen_watches(&mut self) {
+        let frozen: Vec<(u16, u8)> = self.cpu.bus.watches.frozen_bytes().collect();
+        for (addr, data) in frozen {
+            let _ = self.poke(MemorySpace::Cpu, addr, data);
+        }
+    }
+
+    /// Run until the CPU has finished its current instruction, or if it is
+    /// between instructions, execute the next one. Returns whether a frame was completed.
+    pub fn step(&mut self) -> Result<bool, NesError> {
+        let mut frame_done = self.tick()?;
+        while self.cpu.wait_cycles > 0 {
+            frame_done |= self.tick()?;
+        }
+        Ok(frame_done)
+    }
+
+    /// Run until the PPU completes the next frame
+    pub fn run_frame(&mut self) -> Result<&Frame, NesError> {
+        while !self.tick()? {}
+        Ok(&self.frame)
+    }
+
+    /// Log each instruction executed from here on, in place of any trace already running
+    pub fn start_trace(&mut self, trace: TraceUnit) {
+        self.trace = Some(trace);
+    }
+
+    /// Stop tracing, handing back the trace to be flushed (or dropped, which flushes it quietly)
+    pub fn stop_trace(&mut self) -> Option<TraceUnit> {
+        self.trace.take()
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Count the cycles spent at each instruction and in each subroutine from here on,
+    /// in place of any profile already running
+    #[cfg(feature = "std")]
+    pub fn start_profile(&mut self, profile: Profiler) {
+        self.profile = Some(profile);
+    }
+
+    /// Stop profiling, handing back the profile for its report
+    #[cfg(feature = "std")]
+    pub fn stop_profile(&mut self) -> Option<Profiler> {
+        self.profile.take()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn profiling(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Log how the ROM is used from here on, in FCEUX's .cdl format (see the cdl module).
+    /// Given a log saved before, carry on from it.
+    pub fn start_cdl(&mut self, saved: Option<&[u8]>) -> Result<(), String> {
+        let cartridge = &mut self.cpu.bus.cartridge;
+        let (prg_size, chr_size) = (cartridge.prg_rom_size(), cartridge.chr_rom_size());
+        let cdl = match saved {
+            Some(data) => CodeDataLog::from_bytes(data, prg_size, chr_size)?,
+            None => CodeDataLog::new(prg_size, chr_size),
+        };
+        cartridge.set_cdl(Some(cdl));
+        Ok(())
+    }
+
+    /// Stop logging, handing back the log to be saved
+    pub fn stop_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cpu.bus.cartridge.take_cdl()
+    }
+
+    pub fn cdl(&self) -> Option<&CodeDataLog> {
+        self.cpu.bus.cartridge.cdl()
+    }
+
+    /// Report events to `hooks` from here on, in place of any already installed
+    #[cfg(feature = "hooks")]
+    pub fn set_hooks(&mut self, hooks: Box<dyn Hooks>) {
+        self.cpu.bus.hooks = Some(hooks);
+    }
+
+    /// Remove the installed hooks, handing them back
+    #[cfg(feature = "hooks")]
+    pub fn take_hooks(&mut self) -> Option<Box<dyn Hooks>> {
+        self.cpu.bus.hooks.take()
+    }
+
+    /// The installed hooks, if they are a `T` - e.g. to collect what they've gathered
+    #[cfg(feature = "hooks")]
+    pub fn hooks_mut<T: Hooks>(&mut self) -> Option<&mut T> {
+        let hooks: &mut dyn core::any::Any = self.cpu.bus.hooks.as_deref_mut()?;
+        hooks.downcast_mut()
+    }
+
+    /// Start executing at `pc` rather than the reset vector, with the stack pointer
+    /// and cycle count as the reset sequence leaves them - e.g. at $C000 for
+    /// nestest's automated mode, which nestest.log is a trace of
+    pub fn start_at(&mut self, pc: u16) {
+        self.cpu.PC = pc;
+        self.cpu.SP = 0xFD;
+        self.cpu.cycle = 7;
+    }
+
+    /* Where the machine was when a component failed */
+    fn emulation_error(&mut self, message: String) -> NesError {
+        self.catch_up();
+        NesError::Emulation {
+            message,
+            pc: self.cpu.PC,
+            scanline: self.ppu().scanline,
+            tick: self.ppu().tick,
+        }
+    }
+
+    /// The region the cartridge was made for, according to its header
+    pub fn timing(&self) -> Timing {
+        self.timing
+    }
+
+    /// The region being emulated. Chosen from the header's timing unless overridden.
+    pub fn region(&self) -> Region {
+        self.cpu.bus.ppu.region()
+    }
+
+    /// Force NTSC or PAL timing, e.g. for a cartridge with an inaccurate header
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.bus.set_region(region);
+    }
+
+    /// Add idle scanlines to each frame, so that games which lag get through more
+    /// of their work per frame. The APU is held during them, so sound keeps its
+    /// pitch and tempo. Movies and netplay only stay in sync with the same setting.
+    pub fn set_overclock(&mut self, overclock: Overclock) {
+        self.cpu.bus.set_overclock(overclock);
+    }
+
+    pub fn overclock(&self) -> Overclock {
+        self.cpu.bus.ppu.overclock()
+    }
+
+    /// The last completed frame
+    pub fn framebuffer(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// A hash of the last completed frame (64-bit FNV-1a of its pixels), for
+    /// regression tests to compare against
+    pub fn frame_hash(&self) -> u64 {
+        self.frame.iter().fold(0xcbf29ce484222325u64, |hash, &pixel| {
+            (hash ^ pixel as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    /// The last completed frame as RGB pixels, row by row, in the colours of `palette`
+    pub fn screenshot(&self) -> Vec<[u8; 3]> {
+        self.frame.iter().map(|&pixel| self.colours[pixel as usize & 0x1FF]).collect()
+    }
+
+    /// Draw the last completed frame into `buf`, in the colours of `palette`, as rows
+    /// of `pitch` bytes - e.g. straight into a texture (see palette::draw_frame)
+    pub fn render_frame(&self, buf: &mut [u8], pitch: usize, format: PixelFormat) {
+        palette::draw_frame(&self.frame, &self.colours, buf, pitch, format);
+    }
+
+    /// The palette screenshots are taken in, the built-in NTSC palette unless set
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.colours = palette.frame_colours();
+        self.palette = palette;
+    }
+
+    /// Set the buttons held on a player's controller, as a bitmask of cpu::controller::JoypadButton.
+    /// Players 1 and 2 (0 and 1) are in the ports, and 3 and 4 only with a Four Score plugged in.
+    pub fn set_controller(&mut self, player: usize, buttons: u8) {
+        let input = if player < 2 { DeviceInput::Buttons(buttons) } else { DeviceInput::ExtraButtons(buttons) };
+        self.set_device_input(player % 2, input);
+    }
+
+    /// Tell the device in a port (0, 1 or controller::EXPANSION_PORT) what's being done with it,
+    /// e.g. a key pressed on the keyboard. Nothing happens if it's not that kind of device.
+    pub fn set_device_input(&mut self, port: usize, input: DeviceInput) {
+        if let Some(device) = &mut self.cpu.bus.devices[port] {
+            device.set_input(input);
+        }
+    }
+
+    /// Plug a device into a port (0, 1 or controller::EXPANSION_PORT), or leave it empty with
+    /// None. Each controller port has a controller in to begin with, and the expansion port nothing.
+    pub fn set_device(&mut self, port: usize, device: Option<Box<dyn InputDevice>>) {
+        self.cpu.bus.devices[port] = device;
+    }
+
+    /// What's plugged into a port, by its InputDevice::name
+    pub fn device_name(&self, port: usize) -> Option<&'static str> {
+        self.cpu.bus.devices[port].as_ref().map(|device| device.name())
+    }
+
+    /// Rather than being told with `set_controller`, ask `poll` for the buttons held
+    /// whenever the game latches the controllers, or stop asking with None
+    pub fn set_input_poll(&mut self, poll: Option<InputPoll>) {
+        self.cpu.bus.input_poll = poll;
+    }
+
+    /// The machine's memory, as an achievement system would read it (see the memory module)
+    pub fn memory(&self) -> MemoryView<'_> {
+        MemoryView::new(&self.cpu.bus)
+    }
+
+    /// Call `callback` with the machine's memory as each frame completes, e.g. to check
+    /// achievements' conditions, or stop with None
+    pub fn set_frame_callback(&mut self, callback: Option<FrameCallback>) {
+        self.frame_callback = callback;
+    }
+
+    /// The cartridge's battery-backed work RAM, to be saved when the game is
+    /// closed and restored once it's loaded. None if the cartridge has no battery.
+    pub fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        if !self.battery {
+            return None;
+        }
+        self.cpu.bus.cartridge.prg_ram()
+    }
+
+    /// Make undocumented opcodes an error rather than executing them
+    pub fn set_strict_opcodes(&mut self, strict: bool) {
+        self.cpu.strict_opcodes = strict;
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(rate);
+    }
+
+    /// Fill `buf` with pending audio samples, returning how many were written
+    pub fn take_samples(&mut self, buf: &mut [f32]) -> usize {
+        self.cpu.bus.apu.take_samples(buf)
+    }
+
+    /// Read memory without side-effects, e.g. for a memory viewer. The PPU
+    /// registers can't be read without disturbing the PPU, so give None.
+    pub fn peek(&self, space: MemorySpace, addr: u16) -> Option<u8> {
+        match space {
+            MemorySpace::Cpu => self.cpu.bus.read(addr).ok(),
+            MemorySpace::Ppu => Some(self.ppu().peek(&self.cpu.bus.cartridge, addr)),
+            MemorySpace::Oam => self.ppu().oam().get(addr as usize).copied(),
+        }
+    }
+
+    /// Change memory without side-effects, e.g. from a memory editor. In CPU
+    /// space only RAM can be changed, as writes elsewhere are commands to
+    /// the hardware. Writes to CHR ROM are ignored, as they are by the PPU.
+    pub fn poke(&mut self, space: MemorySpace, addr: u16, data: u8) -> Result<(), NesError> {
+        self.catch_up();
+        match space {
+            MemorySpace::Cpu => match addr {
+                0x0000..=0x1FFF => self.cpu.bus.internal_ram[(addr & 0x07FF) as usize] = data,
+                0x6000..=0x7FFF => self.cpu.bus.cartridge.cpu_write(addr, data),
+                _ => return Err(NesError::Memory(format!("Can't poke ${:0>4X}, only RAM", addr))),
+            },
+            MemorySpace::Ppu => {
+                let bus = &mut self.cpu.bus;
+                bus.ppu.poke(&mut bus.cartridge, addr, data).map_err(NesError::Memory)?
+            }
+            MemorySpace::Oam => match self.ppu_mut().oam_mut().get_mut(addr as usize) {
+                Some(byte) => *byte = data,
+                None => return Err(NesError::Memory(format!("OAM has no address ${:X}", addr))),
+            },
+        }
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        state::save_state(&self.cpu)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), NesError> {
+        state::load_state(&mut self.cpu, data).map_err(NesError::State)
+    }
+
+    /// Run the PPU up to the CPU, having been left behind while nothing could tell
+    /// (see Bus::catch_up_ppu). Frames are completed and interrupts raised on time
+    /// without this, but a debugger halted mid-frame should call it before looking at the PPU.
+    pub fn catch_up(&mut self) {
+        self.cpu.bus.catch_up_ppu();
+    }
+
+    /* Direct access to the components, e.g. for debuggers */
+
+    pub fn cpu(&self) -> &NESCpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut NESCpu {
+        &mut self.cpu
+    }
+
+    pub fn ppu(&self) -> &NESPpu {
+        &self.cpu.bus.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut NESPpu {
+        self.catch_up();
+        &mut self.cpu.bus.ppu
+    }
+
+    pub fn apu(&self) -> &NESApu {
+        &self.cpu.bus.apu
+    }
+
+    /// The APU, e.g. to mute its channels (see NESApu::set_muted)
+    pub fn apu_mut(&mut self) -> &mut NESApu {
+        &mut self.cpu.bus.apu
+    }
+
+    /// The cartridge, which the PPU needs lending for its debug views
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cpu.bus.cartridge
+    }
+}
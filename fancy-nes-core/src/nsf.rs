@@ -0,0 +1,357 @@
+//! Playing NSF music files: the sound code and data ripped from a game, with a
+//! header saying where it loads and which routines to call. There's no
+//! cartridge program to run, so the player takes its place: for each song it
+//! powers on a machine with the NSF's code as 4KiB banks of PRG ROM, calls the
+//! init routine with the song number in A, and from then on calls the play
+//! routine at the rate the header asks for (usually 60Hz), while the APU plays
+//! on between calls.
+//!
+//! The routines are called by pushing a return address no real code runs at,
+//! and running the CPU until it returns there.
+
+use crate::prelude::*;
+use crate::{Mirroring, Region, Timing};
+use crate::apu::vrc6::Vrc6Audio;
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, Memory};
+use crate::cpu::{NESCpu, StatusRegister};
+use crate::cpu::mapper::Mapper;
+use crate::cpu::registry::{Capabilities, MapperInfo};
+use crate::error::NesError;
+use crate::state::{StateReader, StateWriter};
+
+const HEADER_SIZE: usize = 0x80;
+const EXPANSION_CHIPS: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "Namco 163", "Sunsoft 5B"];
+const EXPANSION_VRC6: u8 = 0x01;
+const MAGIC: &[u8] = b"NESM\x1A";
+const BANK_SIZE: usize = 4096;
+
+/* The bank registers, one for each 4KiB of $8000-$FFFF */
+const BANK_REGISTERS: u16 = 0x5FF8;
+
+/* Where the init and play routines return to. Nothing is mapped here, so it is never run. */
+const RETURN_ADDRESS: u16 = 0x4100;
+
+/* Play rates to use for a header which gives none, in microseconds */
+const NTSC_PLAY_SPEED: u16 = 16639;
+const PAL_PLAY_SPEED: u16 = 19997;
+
+/// What an NSF's header says about it
+#[derive(Debug, Clone)]
+pub struct NsfHeader {
+    pub version: u8,
+    pub songs: u8,
+    pub starting_song: u8,  /* From 0, where the header counts from 1 */
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ntsc_speed: u16,     /* Microseconds between play calls */
+    pub pal_speed: u16,
+    pub bankswitch: [u8; 8], /* The initial banks, or all 0 if the NSF isn't bankswitched */
+    pub timing: Timing,      /* NTSC, PAL or MultiRegion */
+    pub expansion: u8,       /* Expansion sound chips, of which only VRC6 is emulated (see expansion_chips) */
+}
+
+impl NsfHeader {
+    pub fn parse(nsf: &[u8]) -> Result<Self, String> {
+        if nsf.len() < HEADER_SIZE || &nsf[0..5] != MAGIC {
+            return Err("Not an NSF file".to_string());
+        }
+        let word = |offset: usize| u16::from_le_bytes([nsf[offset], nsf[offset + 1]]);
+        let text = |offset: usize| nsf[offset..offset + 32].iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect::<String>();
+
+        let mut bankswitch = [0; 8];
+        bankswitch.copy_from_slice(&nsf[0x70..0x78]);
+
+        Ok(Self {
+            version: nsf[0x05],
+            songs: nsf[0x06],
+            starting_song: nsf[0x07].saturating_sub(1),
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            title: text(0x0E),
+            artist: text(0x2E),
+            copyright: text(0x4E),
+            ntsc_speed: word(0x6E),
+            pal_speed: word(0x78),
+            bankswitch,
+            timing: match nsf[0x7A] & 0x03 {
+                0 => Timing::NTSC,
+                1 => Timing::PAL,
+                _ => Timing::MultiRegion,
+            },
+            expansion: nsf[0x7B],
+        })
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bankswitch.iter().any(|&bank| bank != 0)
+    }
+
+    /// The names of the expansion sound chips the NSF was written for
+    pub fn expansion_chips(&self) -> Vec<&'static str> {
+        Self::chip_names(self.expansion)
+    }
+
+    /// Those of the expansion chips which the player can't play the channels of
+    pub fn unemulated_chips(&self) -> Vec<&'static str> {
+        Self::chip_names(self.expansion & !EXPANSION_VRC6)
+    }
+
+    fn chip_names(chips: u8) -> Vec<&'static str> {
+        EXPANSION_CHIPS.iter().enumerate()
+            .filter(|(bit, _)| chips & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/* The NSF "board": 8KiB of work RAM, 4KiB PRG banks chosen by writes to $5FF8-$5FFF,
+   and the VRC6's sound registers, if the NSF uses them */
+struct NsfBoard {
+    vrc6: Option<Vrc6Audio>,
+}
+
+impl Mapper for NsfBoard {
+    fn write(&mut self, memory: &mut Memory, addr: u16, data: u8) {
+        if let BANK_REGISTERS..=0x5FFF = addr {
+            memory.prg_rom.select((addr - BANK_REGISTERS) as usize, data as usize);
+        }
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.write(addr, data);
+        }
+    }
+
+    fn decodes_write(&self, addr: u16) -> bool {
+        addr >= BANK_REGISTERS
+    }
+
+    fn clock(&mut self) {
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.clock();
+        }
+    }
+
+    fn audio(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, Vrc6Audio::output)
+    }
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+static NSF_BOARD: MapperInfo = MapperInfo {
+    ids: &[],
+    name: "NSF",
+    capabilities: Capabilities::PRG_BANKING.union(Capabilities::PRG_RAM),
+    prg_bank_size: BANK_SIZE,
+    chr_bank_size: 8192,
+    prg_ram_size: 8192,
+    prg_ok: |_| true,
+    chr_ok: |_| true,
+    /* Built with the header's expansion chips in place of a mapper number */
+    build: |expansion, _| Box::new(NsfBoard {
+        vrc6: Some(Vrc6Audio::new()).filter(|_| expansion as u8 & EXPANSION_VRC6 != 0),
+    }),
+};
+
+pub struct NsfPlayer {
+    header: NsfHeader,
+    prg: Vec<u8>,  /* The NSF's data, as whole banks */
+    cpu: NESCpu,
+    region: Region,
+    sample_rate: Option<u32>,
+
+    song: u8,
+    in_routine: bool,  /* A routine was called and hasn't returned yet */
+    play_period: f64,  /* CPU cycles between play calls */
+    until_play: f64,
+}
+
+impl NsfPlayer {
+    /// Load an NSF, ready to play its starting song
+    pub fn new(nsf: &[u8]) -> Result<Self, NesError> {
+        let header = NsfHeader::parse(nsf).map_err(NesError::Rom)?;
+        if header.songs == 0 {
+            return Err(NesError::Rom("NSF has no songs".to_string()));
+        }
+        if header.load_addr < 0x8000 {
+            return Err(NesError::Rom(format!("NSF loads at ${:0>4X}, below PRG ROM", header.load_addr)));
+        }
+
+        /* A bankswitched NSF's data starts as far into its first bank as the load address is
+           into 4KiB. Otherwise, it's simply loaded at the load address. */
+        let data = &nsf[HEADER_SIZE..];
+        let mut prg = if header.is_bankswitched() {
+            let mut prg = vec![0; header.load_addr as usize & (BANK_SIZE - 1)];
+            prg.extend_from_slice(data);
+            prg
+        } else {
+            let mut prg = vec![0; 0x8000];
+            let start = header.load_addr as usize - 0x8000;
+            let len = data.len().min(prg.len() - start);
+            prg[start..start + len].copy_from_slice(&data[..len]);
+            prg
+        };
+        prg.resize(prg.len().max(1).div_ceil(BANK_SIZE) * BANK_SIZE, 0);
+
+        let region = Region::from_timing(header.timing);
+        let cartridge = Cartridge::new(&NSF_BOARD, header.expansion as usize, 0, Mirroring::Horizontal, &prg, &[]);
+        let cpu = NESCpu::new(Bus::new(cartridge));
+        let mut player = Self {
+            song: header.starting_song,
+            header,
+            prg,
+            cpu,
+            region,
+            sample_rate: None,
+            in_routine: false,
+            play_period: 0.0,
+            until_play: 0.0,
+        };
+        player.start_song(player.song)?;
+        Ok(player)
+    }
+
+    pub fn header(&self) -> &NsfHeader {
+        &self.header
+    }
+
+    /// The song playing, from 0
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = Some(rate);
+        self.cpu.bus.apu.set_sample_rate(rate);
+    }
+
+    /// Audio generated since the last call, as for `Nes::take_samples`
+    pub fn take_samples(&mut self, buf: &mut [f32]) -> usize {
+        self.cpu.bus.apu.take_samples(buf)
+    }
+
+    /// Start a song (from 0) from the beginning, on a freshly powered on machine
+    pub fn start_song(&mut self, song: u8) -> Result<(), NesError> {
+        if song >= self.header.songs {
+            return Err(NesError::Rom(format!("NSF has no song {} - it has {}", song + 1, self.header.songs)));
+        }
+        self.song = song;
+
+        let mut bus = Bus::new(Cartridge::new(&NSF_BOARD, self.header.expansion as usize, 0,
+            Mirroring::Horizontal, &self.prg, &[]));
+        bus.set_region(self.region);
+        if let Some(rate) = self.sample_rate {
+            bus.apu.set_sample_rate(rate);
+        }
+        self.cpu = NESCpu::new(bus);
+        self.cpu.SP = 0xFD;
+        self.cpu.status.insert(StatusRegister::INTERRUPT_DISABLE);
+        self.cpu.status.insert(StatusRegister::BREAK_HIGH);
+
+        let (speed, default_speed) = match self.region {
+            Region::NTSC => (self.header.ntsc_speed, NTSC_PLAY_SPEED),
+            Region::PAL | Region::Dendy => (self.header.pal_speed, PAL_PLAY_SPEED),
+        };
+        let speed = if speed == 0 { default_speed } else { speed };
+        self.play_period = speed as f64 * self.region.cpu_clock() / 1_000_000.0;
+        self.until_play = 0.0;
+
+        /* The init routine expects the sound registers silenced, the frame IRQ off, and the
+           initial banks mapped */
+        let mut writes: Vec<(u16, u8)> = (0x4000..=0x4013).map(|addr| (addr, 0)).collect();
+        writes.extend([(0x4015, 0x00), (0x4015, 0x0F), (0x4017, 0x40)]);
+        let banks = if self.header.is_bankswitched() { self.header.bankswitch } else { [0, 1, 2, 3, 4, 5, 6, 7] };
+        writes.extend(banks.iter().enumerate().map(|(window, &bank)| (BANK_REGISTERS + window as u16, bank)));
+        for (addr, data) in writes {
+            self.cpu.bus.write(addr, data).map_err(|e| self.emulation_error(e))?;
+        }
+
+        self.cpu.A = song;
+        self.cpu.X = match self.region {
+            Region::NTSC => 0,
+            Region::PAL | Region::Dendy => 1,
+        };
+        self.call(self.header.init_addr).map_err(|e| self.emulation_error(e))?;
+
+        /* Init should return promptly. Give it a second. */
+        let mut cycles = self.region.cpu_clock() as u32;
+        while self.in_routine {
+            if cycles == 0 {
+                return Err(self.emulation_error("NSF init routine didn't return".to_string()));
+            }
+            self.tick().map_err(|e| self.emulation_error(e))?;
+            cycles -= 1;
+        }
+        Ok(())
+    }
+
+    /// Play on for the given number of CPU cycles. A play routine still
+    /// running when the next call is due carries on, and that call is skipped.
+    pub fn run(&mut self, cycles: u32) -> Result<(), NesError> {
+        for _ in 0..cycles {
+            if self.until_play <= 0.0 {
+                self.until_play += self.play_period;
+                if !self.in_routine {
+                    self.call(self.header.play_addr).map_err(|e| self.emulation_error(e))?;
+                }
+            }
+            self.until_play -= 1.0;
+            self.tick().map_err(|e| self.emulation_error(e))?;
+        }
+        Ok(())
+    }
+
+    /* Call a routine, as a JSR from just before RETURN_ADDRESS would */
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        let [lo, hi] = (RETURN_ADDRESS - 1).to_le_bytes();
+        for byte in [hi, lo] {
+            self.cpu.bus.write(0x0100 + self.cpu.SP as u16, byte)?;
+            self.cpu.SP = self.cpu.SP.wrapping_sub(1);
+        }
+        self.cpu.PC = addr;
+        self.in_routine = true;
+        Ok(())
+    }
+
+    /* One CPU cycle: of the routine being run, or with the CPU idle, of the rest of the machine */
+    fn tick(&mut self) -> Result<(), String> {
+        if self.in_routine && self.cpu.wait_cycles == 0 && self.cpu.PC == RETURN_ADDRESS {
+            self.in_routine = false;
+        }
+        if self.in_routine {
+            return self.cpu.tick();
+        }
+        let bus = &mut self.cpu.bus;
+        bus.take_nmi();
+        bus.tick_ppu();
+        bus.tick_apu()?;
+        bus.take_dma_stall();
+        bus.cartridge.clock();
+        Ok(())
+    }
+
+    fn emulation_error(&self, message: String) -> NesError {
+        NesError::Emulation {
+            message,
+            pc: self.cpu.PC,
+            scanline: self.cpu.bus.ppu.scanline,
+            tick: self.cpu.bus.ppu.tick,
+        }
+    }
+}
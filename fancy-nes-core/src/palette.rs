@@ -0,0 +1,212 @@
+//! The NES's colours. The PPU outputs an index into a 64 entry palette for
+//! each pixel, along with three colour emphasis bits (see nes::Frame), which
+//! frontends turn into RGB with a `Palette`.
+//! A palette for NTSC consoles is built in, one can be read from a .pal file,
+//! or one can be generated by modelling the PPU's composite video signal and
+//! the TV decoding it (see `Palette::generate`).
+
+#[cfg(feature = "std")]
+use std::f32::consts::PI;
+
+use crate::prelude::*;
+use crate::nes::{Frame, FRAME_WIDTH};
+
+/* The NTSC palette shipped as data/palette/default.pal */
+const NTSC: [[u8; 3]; 64] = [
+    [0x46, 0x46, 0x46], [0x00, 0x06, 0x5A], [0x00, 0x06, 0x78], [0x02, 0x06, 0x73],
+    [0x35, 0x03, 0x4C], [0x57, 0x00, 0x0E], [0x5A, 0x00, 0x00], [0x41, 0x00, 0x00],
+    [0x12, 0x02, 0x00], [0x00, 0x14, 0x00], [0x00, 0x1E, 0x00], [0x00, 0x1E, 0x00],
+    [0x00, 0x15, 0x21], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0x9D, 0x9D, 0x9D], [0x00, 0x4A, 0xB9], [0x05, 0x30, 0xE1], [0x57, 0x18, 0xDA],
+    [0x9F, 0x07, 0xA7], [0xCC, 0x02, 0x55], [0xCF, 0x0B, 0x00], [0xA4, 0x23, 0x00],
+    [0x5C, 0x3F, 0x00], [0x0B, 0x58, 0x00], [0x00, 0x66, 0x00], [0x00, 0x67, 0x13],
+    [0x00, 0x5E, 0x6E], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFE, 0xFF, 0xFF], [0x1F, 0x9E, 0xFF], [0x53, 0x76, 0xFF], [0x98, 0x65, 0xFF],
+    [0xFC, 0x67, 0xFF], [0xFF, 0x6C, 0xB3], [0xFF, 0x74, 0x66], [0xFF, 0x80, 0x14],
+    [0xC4, 0x9A, 0x00], [0x71, 0xB3, 0x00], [0x28, 0xC4, 0x21], [0x00, 0xC8, 0x74],
+    [0x00, 0xBF, 0xD0], [0x2B, 0x2B, 0x2B], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFE, 0xFF, 0xFF], [0x9E, 0xD5, 0xFF], [0xAF, 0xC0, 0xFF], [0xD0, 0xB8, 0xFF],
+    [0xFE, 0xBF, 0xFF], [0xFF, 0xC0, 0xE0], [0xFF, 0xC3, 0xBD], [0xFF, 0xCA, 0x9C],
+    [0xE7, 0xD5, 0x8B], [0xC5, 0xDF, 0x8E], [0xA6, 0xE6, 0xA3], [0x94, 0xE8, 0xC5],
+    [0x92, 0xE4, 0xEB], [0xA7, 0xA7, 0xA7], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+
+/* Emphasised colour channels have their signal attenuated by about a quarter */
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// Adjustments made by the virtual TV in `Palette::generate`, like the knobs on a real one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscSettings {
+    pub hue: f32,         /* Rotation of the colour wheel, in degrees */
+    pub saturation: f32,  /* 1.0 is unchanged, 0.0 greyscale */
+    pub brightness: f32,  /* Added to every level, -1.0 to 1.0 */
+    pub contrast: f32,    /* 1.0 is unchanged */
+    pub gamma: f32,       /* Of the TV being imitated. The output is for a 2.2 gamma display. */
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        Self { hue: 0.0, saturation: 1.0, brightness: 0.0, contrast: 1.0, gamma: 2.0 }
+    }
+}
+
+/// The colours for each of the 64 palette indices, and if known, for the
+/// seven combinations of colour emphasis bits (PPUMASK bits 5-7) too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,  /* 64, or 512 ordered by emphasis then index */
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self { colours: NTSC.to_vec() }
+    }
+}
+
+impl Palette {
+    /// Read a .pal file: 64 RGB triples, or 512 including the emphasis variants
+    pub fn from_pal(data: &[u8]) -> Result<Self, String> {
+        if data.len() != 64 * 3 && data.len() != 512 * 3 {
+            return Err(format!("A .pal file should have 64 or 512 colours, not {} bytes", data.len()));
+        }
+        Ok(Self { colours: data.chunks(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect() })
+    }
+
+    /// Derive all 512 colours from the PPU's signal. Each pixel is a square wave
+    /// alternating between two voltages, whose phase within the 12 phase colour
+    /// subcarrier is set by the colour's hue (the index's low nibble), and whose
+    /// levels are set by its luma (the high bits). The TV averages the wave to
+    /// get brightness (Y), and mixes it with the subcarrier to get colour (I, Q).
+    /// Needs the std feature, for its trigonometry.
+    #[cfg(feature = "std")]
+    pub fn generate(settings: NtscSettings) -> Self {
+        /* Composite signal voltages for each luma level, with the colour wave low and high.
+           Black and white are the levels of colour $0F and $20. From measurements on nesdev. */
+        const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+        const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+        const BLACK: f32 = 0.518;
+        const WHITE: f32 = 1.962;
+
+        let mut colours = Vec::with_capacity(512);
+
+        for emphasis in 0..8u8 {
+            for index in 0..64u8 {
+                let (hue, luma) = (index & 0x0F, (index >> 4) as usize);
+
+                /* Hues $E and $F are black, and $D is only the low voltage, $0 only the high */
+                let (mut low, mut high) = if hue >= 0x0E { (SIGNAL_LOW[1], SIGNAL_LOW[1]) } else { (SIGNAL_LOW[luma], SIGNAL_HIGH[luma]) };
+                if hue == 0x00 {
+                    low = high;
+                } else if hue == 0x0D {
+                    high = low;
+                }
+
+                let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+                for phase in 0..12u8 {
+                    let in_phase = |h: u8| (h + phase) % 12 < 6;
+                    let mut signal = if in_phase(hue) { high } else { low };
+
+                    /* Emphasis bits red, green and blue darken the phases of hues $C, $4 and $8 */
+                    let emphasised = (emphasis & 1 != 0 && in_phase(0x0C))
+                        || (emphasis & 2 != 0 && in_phase(0x04))
+                        || (emphasis & 4 != 0 && in_phase(0x08));
+                    if emphasised && hue < 0x0E {
+                        signal *= EMPHASIS_ATTENUATION;
+                    }
+
+                    let level = (signal - BLACK) / (WHITE - BLACK) / 12.0;
+                    /* Measured against the colour burst, which is in phase with hue $8 */
+                    let angle = PI * (phase as f32 - 8.0) / 6.0 + settings.hue.to_radians();
+                    y += level;
+                    i += level * angle.cos();
+                    q += level * angle.sin();
+                }
+
+                y = y * settings.contrast + settings.brightness;
+                i *= settings.saturation * settings.contrast;
+                q *= settings.saturation * settings.contrast;
+
+                /* YIQ to RGB, as the FCC defines it */
+                let rgb = [
+                    y + 0.946882 * i + 0.623557 * q,
+                    y - 0.274788 * i - 0.635691 * q,
+                    y - 1.108545 * i + 1.709007 * q,
+                ];
+                colours.push(rgb.map(|c| (c.max(0.0).powf(2.2 / settings.gamma) * 255.0).round().min(255.0) as u8));
+            }
+        }
+
+        Self { colours }
+    }
+
+    /// The RGB colour for a palette index from the PPU
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        self.colours[(index & 0x3F) as usize]
+    }
+
+    /// The RGB colour for a palette index with colour emphasis (PPUMASK bits 5-7,
+    /// shifted down). Palettes without emphasis variants approximate it by
+    /// dimming the colour channels which aren't emphasised.
+    pub fn emphasised(&self, index: u8, emphasis: u8) -> [u8; 3] {
+        let emphasis = emphasis & 0x7;
+        if let Some(&rgb) = self.colours.get(emphasis as usize * 64 + (index & 0x3F) as usize) {
+            return rgb;
+        }
+
+        let mut rgb = self.rgb(index);
+        if index & 0x0F < 0x0E {
+            for (channel, value) in rgb.iter_mut().enumerate() {
+                let dimmed = (0..3).filter(|&bit| bit != channel && emphasis & (1 << bit) != 0).count();
+                let attenuation = (0..dimmed).fold(1.0, |a, _| a * EMPHASIS_ATTENUATION);
+                *value = (*value as f32 * attenuation) as u8;
+            }
+        }
+        rgb
+    }
+
+    /// The colour for each pixel value in a nes::Frame
+    pub fn frame_colours(&self) -> Vec<[u8; 3]> {
+        (0..512u16).map(|pixel| self.emphasised(pixel as u8 & 0x3F, (pixel >> 6) as u8)).collect()
+    }
+
+    /// The 64 unemphasised colours
+    pub fn iter(&self) -> impl Iterator<Item = &[u8; 3]> {
+        self.colours.iter().take(64)
+    }
+}
+
+/// How `draw_frame` lays out the bytes of each pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb24,   /* R, G, B */
+    Rgba32,  /* R, G, B, then 255 for alpha */
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32 => 4,
+        }
+    }
+}
+
+/// Draw a frame into `buf` in rows of `pitch` bytes - e.g. straight into a locked
+/// texture - in `colours`, as given by `Palette::frame_colours`. Whatever of the
+/// frame doesn't fit in `buf` is left out.
+pub fn draw_frame(frame: &Frame, colours: &[[u8; 3]], buf: &mut [u8], pitch: usize, format: PixelFormat) {
+    let rows = buf.chunks_mut(pitch).zip(frame.chunks(FRAME_WIDTH));
+    match format {
+        PixelFormat::Rgb24 => for (row, line) in rows {
+            for (rgb, &pixel) in row.chunks_exact_mut(3).zip(line) {
+                rgb.copy_from_slice(&colours[pixel as usize & 0x1FF]);
+            }
+        },
+        PixelFormat::Rgba32 => for (row, line) in rows {
+            for (rgba, &pixel) in row.chunks_exact_mut(4).zip(line) {
+                rgba[..3].copy_from_slice(&colours[pixel as usize & 0x1FF]);
+                rgba[3] = 0xFF;
+            }
+        },
+    }
+}
@@ -0,0 +1,402 @@
+//! Just enough PNG to save a frame as a screenshot, and to read one back as a
+//! reference image for regression tests (see `regression`), without pulling in
+//! a crate for it. Frames are written as 8-bit RGB, compressed with deflate's
+//! fixed Huffman codes - NES frames are mostly long runs, so that does well.
+//! Reading takes 8-bit greyscale, RGB, RGBA or paletted images, not interlaced,
+//! as most tools save them.
+
+use crate::prelude::*;
+use crate::nes::{Frame, FRAME_WIDTH, FRAME_HEIGHT};
+use crate::palette::Palette;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/* Deflate's length and distance codes: the smallest value of each, and how many extra bits follow */
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/* The order code length code lengths are sent in, in a dynamic block's header */
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const WINDOW: usize = 32768;
+const MAX_MATCH: usize = 258;
+
+/// Encode a frame as a PNG, in the colours of `palette`
+pub fn encode_frame(frame: &Frame, palette: &Palette) -> Vec<u8> {
+    let colours = palette.frame_colours();
+    let pixels: Vec<[u8; 3]> = frame.iter().map(|&pixel| colours[pixel as usize & 0x1FF]).collect();
+    encode(FRAME_WIDTH, FRAME_HEIGHT, &pixels)
+}
+
+/// Encode an RGB image as a PNG
+pub fn encode(width: usize, height: usize, pixels: &[[u8; 3]]) -> Vec<u8> {
+    /* Each row is preceded by its filter type, which is always none */
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in pixels.chunks(width).take(height) {
+        raw.push(0);
+        raw.extend(row.iter().flatten());
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);  /* 8-bit RGB, deflate, no interlacing */
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(deflate(&raw));
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Decode a PNG into its width, height and RGB pixels. Any alpha channel is ignored.
+pub fn decode(png: &[u8]) -> Result<(usize, usize, Vec<[u8; 3]>), String> {
+    if !png.starts_with(&SIGNATURE) {
+        return Err("Not a PNG".to_string());
+    }
+
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut zlib = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= png.len() {
+        let length = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+        let kind = &png[pos + 4..pos + 8];
+        let data = png.get(pos + 8..pos + 8 + length).ok_or("PNG is truncated")?;
+        match kind {
+            b"IHDR" if length == 13 => header = Some(data.to_vec()),
+            b"PLTE" => palette = data.chunks(3).map(|c| [c[0], c.get(1).copied().unwrap_or(0), c.get(2).copied().unwrap_or(0)]).collect(),
+            b"IDAT" => zlib.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += length + 12;
+    }
+
+    let header = header.ok_or("PNG has no header")?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, colour_type, interlace) = (header[8], header[9], header[12]);
+    let channels = match colour_type {
+        0 => 1,  /* Greyscale */
+        2 => 3,  /* RGB */
+        3 => 1,  /* Paletted */
+        4 => 2,  /* Greyscale and alpha */
+        6 => 4,  /* RGBA */
+        _ => return Err(format!("Unknown PNG colour type {}", colour_type)),
+    };
+    if depth != 8 || interlace != 0 {
+        return Err("Only 8-bit, non-interlaced PNGs are supported".to_string());
+    }
+
+    if zlib.len() < 2 {
+        return Err("PNG has no image data".to_string());
+    }
+    let raw = inflate(&zlib[2..])?;
+    let rows = unfilter(&raw, width, height, channels)?;
+
+    rows.chunks(channels).map(|p| match colour_type {
+        0 | 4 => Ok([p[0], p[0], p[0]]),
+        3 => palette.get(p[0] as usize).copied().ok_or_else(|| "PNG palette index out of range".to_string()),
+        _ => Ok([p[0], p[1], p[2]]),
+    }).collect::<Result<Vec<_>, _>>().map(|pixels| (width, height, pixels))
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/* Undo each row's filter, leaving the pixels alone */
+fn unfilter(raw: &[u8], width: usize, height: usize, channels: usize) -> Result<Vec<u8>, String> {
+    let stride = width * channels;
+    let mut out = vec![0u8; stride * height];
+    for y in 0..height {
+        let row = raw.get(y * (stride + 1)..(y + 1) * (stride + 1)).ok_or("PNG image data is truncated")?;
+        let (filter, row) = (row[0], &row[1..]);
+        for x in 0..stride {
+            let left = if x >= channels { out[y * stride + x - channels] } else { 0 };
+            let up = if y > 0 { out[(y - 1) * stride + x] } else { 0 };
+            let up_left = if y > 0 && x >= channels { out[(y - 1) * stride + x - channels] } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => paeth(left, up, up_left),
+                _ => return Err(format!("Unknown PNG filter {}", filter)),
+            };
+            out[y * stride + x] = row[x].wrapping_add(predicted);
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    /* Values go in least significant bit first... */
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /* ...but Huffman codes most significant bit first */
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/* Deflate's fixed Huffman code for a literal byte or length symbol */
+fn write_literal(w: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xC0 + symbol - 280, 8),
+    }
+}
+
+/* A single fixed Huffman block, matching against the last occurrence of each three bytes */
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { out: Vec::new(), bits: 0, count: 0 };
+    w.write(1, 1);  /* Final block */
+    w.write(1, 2);  /* Fixed codes */
+
+    let mut last_seen = vec![usize::MAX; 1 << 15];
+    let hash = |i: usize| ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & 0x7FFF;
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut length = 0;
+        let mut distance = 0;
+        if i + 2 < data.len() {
+            let h = hash(i);
+            let candidate = last_seen[h];
+            last_seen[h] = i;
+            if candidate != usize::MAX && i - candidate <= WINDOW {
+                let max = MAX_MATCH.min(data.len() - i);
+                length = (0..max).take_while(|&n| data[candidate + n] == data[i + n]).count();
+                distance = i - candidate;
+            }
+        }
+
+        if length < 3 {
+            write_literal(&mut w, data[i] as u32);
+            i += 1;
+            continue;
+        }
+
+        let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+        write_literal(&mut w, 257 + code as u32);
+        w.write((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+        let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+        w.write_code(code as u32, 5);
+        w.write((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+
+        /* Remember the positions skipped over, so later runs can match them */
+        for j in i + 1..(i + length).min(data.len().saturating_sub(2)) {
+            last_seen[hash(j)] = j;
+        }
+        i += length;
+    }
+
+    write_literal(&mut w, 256);
+    w.finish()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u32, String> {
+        let byte = *self.data.get(self.pos).ok_or("Compressed data is truncated")?;
+        let bit = (byte >> self.bit) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+        Ok(bit as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        (0..count).try_fold(0, |value, n| Ok(value | self.bit()? << n))
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/* A canonical Huffman code, as the number of codes of each length and the symbols in code order */
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] != 0).collect();
+        symbols.sort_by_key(|&s| lengths[s as usize]);
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= r.bit()? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("Bad Huffman code in compressed data".to_string())
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut r = BitReader { data, pos: 0, bit: 0 };
+    let mut out = Vec::new();
+
+    loop {
+        let last = r.bit()? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let header = data.get(r.pos..r.pos + 4).ok_or("Compressed data is truncated")?;
+                let length = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let block = data.get(r.pos + 4..r.pos + 4 + length).ok_or("Compressed data is truncated")?;
+                out.extend_from_slice(block);
+                r.pos += 4 + length;
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(&mut r, &mut out, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            }
+            2 => {
+                let literals = r.bits(5)? as usize + 257;
+                let distances = r.bits(5)? as usize + 1;
+                let code_lengths = r.bits(4)? as usize + 4;
+
+                let mut lengths = [0u8; 19];
+                for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+                    lengths[symbol] = r.bits(3)? as u8;
+                }
+                let code_length_code = Huffman::new(&lengths);
+
+                let mut lengths = Vec::with_capacity(literals + distances);
+                while lengths.len() < literals + distances {
+                    let (value, repeat) = match code_length_code.decode(&mut r)? {
+                        16 => (*lengths.last().ok_or("Bad code lengths in compressed data")?, 3 + r.bits(2)?),
+                        17 => (0, 3 + r.bits(3)?),
+                        18 => (0, 11 + r.bits(7)?),
+                        length => (length as u8, 1),
+                    };
+                    lengths.extend(core::iter::repeat_n(value, repeat as usize));
+                }
+                lengths.truncate(literals + distances);
+                inflate_block(&mut r, &mut out, &Huffman::new(&lengths[..literals]), &Huffman::new(&lengths[literals..]))?;
+            }
+            _ => return Err("Bad block type in compressed data".to_string()),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn inflate_block(r: &mut BitReader, out: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(r)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let code = symbol - 257;
+        let length = *LENGTH_BASE.get(code).ok_or("Bad length in compressed data")? as usize
+            + r.bits(LENGTH_EXTRA[code] as u32)? as usize;
+        let code = distances.decode(r)? as usize;
+        let distance = *DISTANCE_BASE.get(code).ok_or("Bad distance in compressed data")? as usize
+            + r.bits(DISTANCE_EXTRA[code] as u32)? as usize;
+        if distance > out.len() {
+            return Err("Bad distance in compressed data".to_string());
+        }
+
+        /* The copy may overlap what it produces, repeating a run */
+        let start = out.len() - distance;
+        for n in 0..length {
+            out.push(out[start + n]);
+        }
+    }
+}
@@ -1,16 +1,13 @@
-use std::{cell::RefCell, rc::Rc};
-
 /// The PPU (picture processing unit) generates 2D graphics and
-/// is effectively a separate processor (Ricoh 2C02 on NTSC units).
-/// While untrue for the PAL NES (TODO), its clock is approximated as
-/// 3 PPU "dots" = 1 CPU cycle. Here is some important information (excl. Dendy):
-/// TODO
+/// is effectively a separate processor (Ricoh 2C02 on NTSC units, 2C07 on PAL).
+/// It runs 3 PPU "dots" to each CPU cycle on NTSC, and 3.2 on PAL (see Bus::tick_ppu).
+/// A PAL frame has 312 scanlines rather than 262, the extra 50 lengthening vertical blank.
 use bitflags::bitflags;
 
-use crate::Mirroring;
-use crate::cpu::NESCpu;
-use crate::cpu::mapper::Mapper;
-use crate::cpu::mapper000::PPUMapper000;
+use crate::prelude::*;
+use crate::{Overclock, Region};
+use crate::cartridge::Cartridge;
+use crate::state::{StateReader, StateWriter};
 mod PPUAddress {
     pub const PPUCTRL: u16   = 0x2000;
     pub const PPUMASK: u16   = 0x2001;
@@ -23,6 +20,13 @@ mod PPUAddress {
     pub const OAMDMA: u16    = 0x4014;
 }
 
+/* Bits of the I/O latch fade to 0 around 600ms after they were last driven high */
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+/* Flags of a sprite pixel in NESPpu::sprite_line, besides its palette entry */
+const SPRITE_BEHIND: u8 = 0x20;
+const SPRITE_ZERO: u8 = 0x40;
+
 bitflags! {
     struct PPUCTRL: u8 {
         const BASE_NAMETABLE_ADDR_LO = 0b00000001;
@@ -47,6 +51,7 @@ bitflags! {
         const EMPH_GREEN      = 0b01000000;
         const EMPH_BLUE       = 0b10000000;
 
+        /* Rendering is enabled when either layer is shown - test with intersects, not contains */
         const RENDERING = Self::BACKGROUND.bits | Self::SPRITES.bits;
     }
 }
@@ -59,7 +64,7 @@ bitflags! {
     }
 }
 
-pub struct NESPpu<'a> {
+pub struct NESPpu {
     /* Palette memory map:
         0      - universal background colour     \
         1..3   - background palette 0            /`--- (bg 0 selected)
@@ -82,13 +87,18 @@ pub struct NESPpu<'a> {
         + can actually contain unique data - xx10,xx14,xx1C are then aliases of
           these. In this emulator, these all map to xx00, however 
     */
-    pub palette: [u8; 32],
+    palette: [u8; 32],
+    colours: [u16; 32], /* Each palette entry as drawn, greyscale and emphasis applied - see update_colours */
     vram: [u8; 2048],   /* 2KB of RAM inside the NES dedicated to the PPU     */
     oam: [u8; 256],     /* CPU can manipulate via memory-mapped DMA registers */
+    oam_addr: u8,       /* OAMADDR - where the next OAMDATA access or DMA byte goes */
 
     write_toggle: bool, /* The latch shared by $2005, $2006 to distinguish 
                           between first and second writes. */
-    pub scanline: u16,      /* The next scanline to be rendered (0-261 NTSC) */
+    pub scanline: u16,      /* The next scanline to be rendered (0-261 NTSC, 0-311 PAL and Dendy, plus any overclocking's) */
+    odd_frame: bool,        /* Odd frames are a dot shorter - see ppu_tick */
+    region: Region,
+    overclock: Overclock,
 
     /* Note that the vram_v and vram_t are organised as follows:
         yyy NN YYYYY XXXXX
@@ -127,25 +137,44 @@ pub struct NESPpu<'a> {
     bg_next_tile: u8,
     bg_next_attr: u8,
 
+    /* The sprites on the scanline being drawn, fetched at the end of the one before */
+    sprite_count: usize,
+    sprite_patterns: [[u8; 2]; 8],  /* Low and high bit planes of each sprite's row, flipped as need be */
+    sprite_attributes: [u8; 8],
+    sprite_x: [u8; 8],
+    sprite_zero_on_line: bool,      /* Whether the first of them is sprite 0 */
+    sprite_line: [u8; 256],         /* The sprite pixel at each x on this scanline - see draw_sprite_line */
+
     // PPUDATA is buffered by one CPU access
     data_bus_next: u8,
 
-    cpu: Rc<RefCell<NESCpu<'a>>>,             /* A ref to CPU which lives at least as long as the PPU! (for interrupts) */
+    /* The data lines between the CPU and the PPU's registers hold their charge, so reading
+       a write-only register (or the unused bits of another) returns what was last there */
+    io_latch: u8,
+    io_latch_decay: [u8; 8],  /* Frames until each bit fades */
 
-    pub frame: [u8; 61440],  /* A frame, to be rendered when frame_complete is signalled */
-    pub frame_ready: bool,
+    /* The NMI output is VBLANK && NMI_ENABLED, and the CPU responds to it going high.
+       That edge is latched here until the bus collects it (see take_nmi). */
+    nmi_pending: bool,
+    vblank_suppressed: bool,  /* PPUSTATUS was read just as vblank began - see ppu_register_read */
 
-    pub mapper: Box<dyn Mapper<u16, u16>>,
+    pub frame: Box<[u16; 61440]>,  /* A frame, to be rendered when frame_complete is signalled (see nes::Frame) */
+    pub frame_ready: bool,
 } 
 
-impl<'a> NESPpu<'a> {
-    pub fn new(mapper_id: usize, cpu: Rc<RefCell<NESCpu<'a>>>, mirroring: Mirroring) -> Self {
+impl NESPpu {
+    pub fn new() -> Self {
         Self {
             palette: [0; 32],
+            colours: [0; 32],
             vram: [0; 2048],
             oam: [0; 256],
+            oam_addr: 0,
             write_toggle: false,
             scanline: 261,
+            odd_frame: false,
+            region: Region::NTSC,
+            overclock: Overclock::default(),
             vram_v: 0,
             vram_t: 0,
             vram_x: 0,
@@ -169,28 +198,109 @@ impl<'a> NESPpu<'a> {
             bg_next_attr: 0,
             bg_next_tile: 0,
 
+            sprite_count: 0,
+            sprite_patterns: [[0; 2]; 8],
+            sprite_attributes: [0; 8],
+            sprite_x: [0; 8],
+            sprite_zero_on_line: false,
+            sprite_line: [0; 256],
+
             data_bus_next: 0,
+            io_latch: 0,
+            io_latch_decay: [0; 8],
 
-            frame: [0; 61440],
+            frame: Box::new([0; 61440]),
             frame_ready: false,
-            cpu,
+            nmi_pending: false,
+            vblank_suppressed: false,
+
+        }
+    }
+
+    /// Switch between NTSC and PAL frame timing, restarting at the pre-render scanline
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.scanline = self.pre_render_scanline();
+        self.tick = 0;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Add idle scanlines to each frame, from the next on if the PPU is already past
+    /// where they'd go. A PPU beyond the end of the shortened frame starts the pre-render line.
+    pub fn set_overclock(&mut self, overclock: Overclock) {
+        self.overclock = overclock;
+        if self.scanline > self.pre_render_scanline() {
+            self.scanline = self.pre_render_scanline();
+            self.tick = 0;
+        }
+    }
+
+    pub fn overclock(&self) -> Overclock {
+        self.overclock
+    }
+
+    /// The last scanline of the frame (261 NTSC, 311 PAL and Dendy, without overclocking)
+    fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines() - 1 + self.overclock.pre_nmi + self.overclock.post_nmi
+    }
+
+    /// The scanline vblank begins on, after any overclocking's extra lines before it
+    pub fn vblank_scanline(&self) -> u16 {
+        self.region.vblank_scanline() + self.overclock.pre_nmi
+    }
+
+    /* Where the overclocking's extra lines are, before vblank and at the end of it */
+    fn extra_scanlines(&self) -> [core::ops::Range<u16>; 2] {
+        let post_nmi = self.region.scanlines() - 1 + self.overclock.pre_nmi;
+        [self.region.vblank_scanline()..self.vblank_scanline(), post_nmi..post_nmi + self.overclock.post_nmi]
+    }
+
+    /// Whether the PPU is on one of the overclocking's extra lines, during which the
+    /// APU is held so that it keeps time (see Bus::tick_apu)
+    pub fn overclocking(&self) -> bool {
+        self.overclock != Overclock::default() && self.extra_scanlines().iter().any(|lines| lines.contains(&self.scanline))
+    }
+
+    /* On odd frames with rendering enabled, the NTSC PPU skips the last dot of the
+       pre-render line, so that its colour artifacts alternate between frames. The PAL PPU doesn't. */
+    fn skips_last_dot(&self) -> bool {
+        self.odd_frame && self.ppu_mask.intersects(PPUMASK::RENDERING) && self.region == Region::NTSC
+    }
 
-            mapper: Box::new(
-                match mapper_id {
-                    0 => { PPUMapper000::new(mirroring) }
-                    _ => { unimplemented!() }
+    /// Dots until the PPU next does something the CPU sees without asking for it -
+    /// beginning vblank (and so perhaps raising an NMI), or completing a frame -
+    /// counting the dot itself. Until then its work can be put off, as long as
+    /// nothing reads or changes its state (see Bus::catch_up_ppu).
+    /// Entering or leaving overclocking's extra lines counts too, as the APU must know.
+    pub fn dots_until_event(&self) -> u32 {
+        let (vblank, pre_render) = (self.vblank_scanline(), self.pre_render_scanline());
+        let here = self.scanline as u32 * 341 + self.tick as u32;
+        let frame_end = pre_render as u32 * 341 + if self.skips_last_dot() { 339 } else { 340 };
+        let mut event = if here <= vblank as u32 * 341 + 1 { vblank as u32 * 341 + 1 } else { frame_end };
+        if self.overclock != Overclock::default() {
+            /* The last dot before each line the extra lines begin or end at */
+            for lines in self.extra_scanlines().iter().filter(|lines| !lines.is_empty()) {
+                for line in [lines.start, lines.end] {
+                    let dot = line as u32 * 341 - 1;
+                    if dot >= here && dot < event {
+                        event = dot;
+                    }
                 }
-            )
+            }
         }
+        event - here + 1
     }
 
-    pub fn read(&self, mut addr: u16) -> u8 {
+    pub fn read(&self, cart: &Cartridge, addr: u16) -> u8 {
         match addr {
             // Remappable addresses by the mapper - might come straight back to internal VRAM if mapped that way!
             // If the mapper returns a word starting with 0x1***, treat *** as an index into PPU RAM.
             0x0000..=0x3EFF => {
                 let word: u16;
-                word = self.mapper.read(addr);
+                word = cart.ppu_read(addr);
 
                 if word & 0x1000 > 0 {
                     self.vram[word as usize & 0x0FFF]
@@ -198,23 +308,291 @@ impl<'a> NESPpu<'a> {
                     (word & 0xFF) as u8
                 }
             }
-            0x3F00..=0x3FFF => {
-                // Alias sprite clear accesses to the background clear accesses.
-                match addr {
-                    0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => { addr -= 0x10; }
-                    _ => {}
+            0x3F00..=0x3FFF => self.palette_entry(addr as u8 & 0x1F),
+            _ => { unreachable!() }
+        }
+    }
+
+    /* A colour from palette RAM, masked if in greyscale mode */
+    fn palette_entry(&self, mut index: u8) -> u8 {
+        // Alias sprite clear accesses to the background clear accesses.
+        if index & 0x13 == 0x10 {
+            index -= 0x10;
+        }
+        self.palette[index as usize] & (if self.ppu_mask.contains(PPUMASK::GREYSCALE) { 0x30 } else { 0x3F })
+    }
+
+    /* Decode palette RAM for drawing, after it or PPUMASK changes, so that pixels don't each look it up */
+    fn update_colours(&mut self) {
+        let emphasis = (self.ppu_mask.bits() as u16 & 0xE0) << 1;
+        for index in 0..32 {
+            self.colours[index] = self.palette_entry(index as u8) as u16 | emphasis;
+        }
+    }
+
+    /// Palette RAM, as written: $3F00-$3F1F
+    pub fn palette_ram(&self) -> &[u8; 32] {
+        &self.palette
+    }
+
+    /// A read for debuggers. Like `read`, but palette entries aren't affected by greyscale mode.
+    pub fn peek(&self, cart: &Cartridge, addr: u16) -> u8 {
+        match addr & 0x3FFF {
+            0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => self.palette[(addr & 0x0F) as usize],
+            0x3F00..=0x3FFF => self.palette[(addr & 0x1F) as usize],
+            addr => self.read(cart, addr),
+        }
+    }
+
+    /// A write for debuggers, which doesn't disturb the PPU's address registers
+    pub fn poke(&mut self, cart: &mut Cartridge, addr: u16, data: u8) -> Result<(), String> {
+        self.write(cart, addr & 0x3FFF, data)
+    }
+
+    /// The current and temporary VRAM addresses (v and t), e.g. for crash reports
+    pub fn vram_addresses(&self) -> (u16, u16) {
+        (self.vram_v, self.vram_t)
+    }
+
+    /// The top-left of the background scroll window within the four nametables,
+    /// which together are 512x480 pixels. Taken from the temporary VRAM address,
+    /// which the CPU sets through PPUCTRL and PPUSCROLL, so mid-frame changes
+    /// to the scroll (e.g. for a status bar) show up only as they are written.
+    pub fn scroll(&self) -> (u16, u16) {
+        let t = self.vram_t;
+        let x = ((t >> 10) & 1) * 256 + (t & 0x1F) * 8 + self.vram_x;
+        let y = ((t >> 11) & 1) * 240 + ((t >> 5) & 0x1F) * 8 + ((t >> 12) & 0x7);
+        (x, y)
+    }
+
+    /// Draw the four nametables as a 2x2 grid of palette indices, 512x480, as the
+    /// background would appear with no scrolling. For debuggers.
+    pub fn render_nametables(&self, cart: &Cartridge, buf: &mut [u8]) {
+        assert!(buf.len() == 512 * 480);
+        let pattern_table: u16 = if self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_TABLE_ADDR) { 0x1000 } else { 0 };
+
+        for nametable in 0..4u16 {
+            let base = 0x2000 + nametable * 0x400;
+            let (left, top) = ((nametable & 1) as usize * 256, (nametable >> 1) as usize * 240);
+
+            for tile_y in 0..30u16 {
+                for tile_x in 0..32u16 {
+                    let tile = self.peek(cart, base + tile_y * 32 + tile_x) as u16;
+                    let attribute = self.peek(cart, base + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4);
+                    let shift = ((tile_y & 2) << 1) | (tile_x & 2);
+                    let palette = ((attribute >> shift) & 0x3) as u16;
+
+                    for fine_y in 0..8u16 {
+                        let lo = self.peek(cart, pattern_table + tile * 16 + fine_y);
+                        let hi = self.peek(cart, pattern_table + tile * 16 + fine_y + 8);
+                        for fine_x in 0..8 {
+                            let pixel = (((hi >> (7 - fine_x)) & 1) << 1 | ((lo >> (7 - fine_x)) & 1)) as u16;
+                            let colour = self.read(cart, 0x3F00 | if pixel == 0 { 0 } else { palette << 2 | pixel });
+
+                            let x = left + tile_x as usize * 8 + fine_x;
+                            let y = top + tile_y as usize * 8 + fine_y as usize;
+                            buf[y * 512 + x] = colour;
+                        }
+                    }
                 }
-                self.palette[addr as usize & 0x1F] & (if self.ppu_mask.contains(PPUMASK::GREYSCALE) { 0x30 } else { 0x3F })
             }
-            _ => { unreachable!() }
         }
     }
 
-    fn write(&mut self, addr: u16, data: u8) {
+    /// Object attribute memory: 64 sprites of 4 bytes each
+    pub fn oam(&self) -> &[u8; 256] {
+        &self.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8; 256] {
+        &mut self.oam
+    }
+
+    /// Sprites are 8x8, or 8x16 if PPUCTRL says so
+    pub fn sprite_height(&self) -> u16 {
+        if self.ppu_ctrl.contains(PPUCTRL::SPRITE_SIZE) { 16 } else { 8 }
+    }
+
+    /// The OAM indices of the sprites shown on a scanline: the first eight in
+    /// OAM which cover it, as sprite evaluation would copy to secondary OAM.
+    /// Sprites are drawn a line below their Y coordinate.
+    pub fn sprites_on_scanline(&self, scanline: u16) -> Vec<usize> {
+        (0..64)
+            .filter(|&i| {
+                let top = self.oam[i * 4] as u16 + 1;
+                scanline >= top && scanline - top < self.sprite_height()
+            })
+            .take(8)
+            .collect()
+    }
+
+    /* Whether sprite evaluation for a scanline sets the overflow flag. Once eight
+       sprites are found, the PPU goes on checking the rest of OAM for a ninth, but
+       a hardware bug has it increment the byte within each sprite along with the
+       sprite, so it compares tile, attribute and X bytes as if they were Y. */
+    fn sprite_overflows(&self, scanline: u16) -> bool {
+        let in_range = |y: u8| scanline > y as u16 && scanline - (y as u16 + 1) < self.sprite_height();
+
+        let (mut n, mut found) = (0, 0);
+        while n < 64 && found < 8 {
+            if in_range(self.oam[n * 4]) {
+                found += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                return true;
+            }
+            n += 1;
+            m = (m + 1) & 3;  /* Should stay 0 */
+        }
+        false
+    }
+
+    /// Draw a sprite's pattern as palette indices, flipped as its attributes say.
+    /// `buf` is 8 pixels wide and `sprite_height` tall. Transparent pixels are
+    /// given the backdrop colour. For debuggers.
+    pub fn render_sprite(&self, cart: &Cartridge, index: usize, buf: &mut [u8]) {
+        let height = self.sprite_height();
+        assert!(buf.len() == 8 * height as usize);
+
+        let attributes = self.oam[index * 4 + 2];
+        let palette = 4 + (attributes & 0x3) as u16;
+        let flip_x = attributes & 0x40 != 0;
+
+        for row in 0..height {
+            let addr = self.sprite_pattern_addr(index, row);
+            let (lo, hi) = (self.peek(cart, addr), self.peek(cart, addr + 8));
+
+            for column in 0..8 {
+                let x = if flip_x { 7 - column } else { column };
+                let pixel = (((hi >> (7 - x)) & 1) << 1 | ((lo >> (7 - x)) & 1)) as u16;
+                buf[row as usize * 8 + column] = self.read(cart, 0x3F00 | if pixel == 0 { 0 } else { palette << 2 | pixel });
+            }
+        }
+    }
+
+    /* The address of the low bit plane of a row of a sprite's pattern, flipped vertically as need be */
+    fn sprite_pattern_addr(&self, index: usize, row: u16) -> u16 {
+        let height = self.sprite_height();
+        let tile = self.oam[index * 4 + 1] as u16;
+        let y = if self.oam[index * 4 + 2] & 0x80 != 0 { height - 1 - row } else { row };
+
+        /* 8x16 sprites choose their pattern table with bit 0 of the tile, rather than PPUCTRL */
+        let pattern = match height {
+            16 => (tile & 1) * 0x1000 + (tile & 0xFE) * 16,
+            _ => (if self.ppu_ctrl.contains(PPUCTRL::SPRITE_TABLE_ADDR) { 0x1000 } else { 0 }) + tile * 16,
+        };
+        /* The bottom half of an 8x16 sprite is the next tile */
+        pattern + (y / 8) * 16 + (y % 8)
+    }
+
+    /* Scroll horizontally (algorithm taken from NESDEV) */
+    fn increment_coarse_x(&mut self) {
+        if self.vram_v & 0x001F == 31 { // Are we at the end of a nametable?
+            self.vram_v &= !0x001F;     // Reset course X to 0
+            self.vram_v ^= 0x0400;      // Switch the horizontal nametable
+        } else {
+            self.vram_v += 1; // Increment as usual :-)
+        }
+    }
+
+    /* Scroll vertically, fine Y then coarse Y. Again, this algorithm is lovingly taken from NESDEV. */
+    fn increment_y(&mut self) {
+        if self.vram_v & 0x7000 != 0x7000 {
+            self.vram_v += 0x1000; // Standard fine-Y increment
+        } else {
+            self.vram_v &= !0x7000;                       // Reset fine-Y to 0
+            let mut y = (self.vram_v & 0x03E0) >> 5; // Fine-y = course-y
+            if y == 29 {
+                y = 0;
+                self.vram_v ^= 0x0800;  // Switch the vertical nametable
+            } else if y == 31 {
+                y = 0;                  // Reset course Y, but don't switch nametable
+            } else {
+                y += 1;                 // Increment course-Y
+            }
+            self.vram_v = (self.vram_v & !0x03E0) | (y << 5);
+        }
+    }
+
+    /* After a PPUDATA access, v moves on by 1 or 32 as PPUCTRL says. While the PPU is
+       rendering, though, the access collides with its own fetches, and v gets the
+       coarse X and Y increments at once instead. Some games rely on this. */
+    fn increment_after_data_access(&mut self) {
+        let rendering = self.scanline < 240 || self.scanline == self.pre_render_scanline();
+        if rendering && self.ppu_mask.intersects(PPUMASK::RENDERING) {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            let increment = if self.ppu_ctrl.contains(PPUCTRL::VRAM_INCREMENT) { 32 } else { 1 };
+            self.vram_v = (self.vram_v + increment) & 0x7FFF;
+        }
+    }
+
+    /* A pattern table read by the rendering pipeline, which the mapper may be watching for */
+    fn fetch_pattern(&mut self, cart: &mut Cartridge, addr: u16) -> u8 {
+        let data = self.read(cart, addr);
+        cart.pattern_fetch(addr);
+        data
+    }
+
+    /* Sprite evaluation and fetches for the next scanline, done all at once at the end of this one */
+    fn fetch_sprites(&mut self, cart: &mut Cartridge, next_scanline: u16) {
+        let sprites = if next_scanline < 240 { self.sprites_on_scanline(next_scanline) } else { vec![] };
+        self.sprite_count = sprites.len();
+        self.sprite_zero_on_line = sprites.first() == Some(&0);
+
+        // There's no evaluation on the pre-render scanline, so only visible ones can overflow
+        if (1..=240).contains(&next_scanline) && self.sprite_overflows(next_scanline) {
+            self.ppu_status.insert(PPUSTATUS::SPRITE_OVERFLOW);
+        }
+
+        for (slot, &i) in sprites.iter().enumerate() {
+            let row = next_scanline - (self.oam[i * 4] as u16 + 1);
+            let addr = self.sprite_pattern_addr(i, row);
+            let (mut lo, mut hi) = (self.fetch_pattern(cart, addr), self.fetch_pattern(cart, addr + 8));
+            let attributes = self.oam[i * 4 + 2];
+            if attributes & 0x40 != 0 {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+            self.sprite_patterns[slot] = [lo, hi];
+            self.sprite_attributes[slot] = attributes;
+            self.sprite_x[slot] = self.oam[i * 4 + 3];
+        }
+        self.draw_sprite_line();
+    }
+
+    /* Draw the sprites fetched into sprite_line, rather than look through them at each dot.
+       Each entry is the palette entry of the front-most opaque sprite pixel there (0 if
+       there's none), with SPRITE_BEHIND if it's behind the background, and SPRITE_ZERO if it's sprite 0. */
+    fn draw_sprite_line(&mut self) {
+        self.sprite_line = [0; 256];
+        // Back to front, so that the front-most is drawn last
+        for slot in (0..self.sprite_count).rev() {
+            let [lo, hi] = self.sprite_patterns[slot];
+            let attributes = self.sprite_attributes[slot];
+            let flags = if attributes & 0x20 != 0 { SPRITE_BEHIND } else { 0 }
+                | if slot == 0 && self.sprite_zero_on_line { SPRITE_ZERO } else { 0 };
+            for column in 0..8 {
+                let x = self.sprite_x[slot] as usize + column;
+                let pixel = ((hi >> (7 - column)) & 1) << 1 | ((lo >> (7 - column)) & 1);
+                if x < 256 && pixel != 0 {
+                    self.sprite_line[x] = 0x10 | (attributes & 0x3) << 2 | pixel | flags;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, cart: &mut Cartridge, addr: u16, data: u8) -> Result<(), String> {
         match addr {
             0x0000..=0x3EFF => {
                 let word: u16;
-                word = self.mapper.write(addr, data).unwrap();
+                word = cart.ppu_write(addr, data)?;
 
                 if word & 0x1000 > 0 {
                     self.vram[word as usize & 0x0FFF] = data;
@@ -224,9 +602,120 @@ impl<'a> NESPpu<'a> {
             }
             0x3F00..=0x3FFF => {
                 self.palette[(addr & 0x1F) as usize] = data;
+                self.update_colours();
             }
-            _ => { unreachable!() }
+            _ => { return Err(format!("PPU write attempted at invalid address: ${:X}", addr)) }
+        }
+        Ok(())
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.palette);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam);
+        w.write_u8(self.oam_addr);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.vblank_suppressed);
+
+        w.write_bool(self.write_toggle);
+        w.write_u16(self.scanline);
+        w.write_u16(self.tick);
+        w.write_bool(self.odd_frame);
+        w.write_u16(self.vram_v);
+        w.write_u16(self.vram_t);
+        w.write_u16(self.vram_x);
+
+        w.write_u8(self.ppu_ctrl.bits());
+        w.write_u8(self.ppu_mask.bits());
+        w.write_u8(self.ppu_status.bits());
+        w.write_u8(self.data_bus_next);
+        w.write_u8(self.io_latch);
+        w.write_bytes(&self.io_latch_decay);
+
+        /* Background fetch pipeline, so a state saved mid-scanline resumes cleanly */
+        w.write_u16(self.addr_data_bus);
+        w.write_u16(self.bg_pattern_shift_reg_hi);
+        w.write_u16(self.bg_pattern_shift_reg_lo);
+        w.write_u8(self.bg_pattern_next_hi);
+        w.write_u8(self.bg_pattern_next_lo);
+        w.write_u16(self.bg_attribute_shift_reg_hi);
+        w.write_u16(self.bg_attribute_shift_reg_lo);
+        w.write_u8(self.bg_attribute_next_hi);
+        w.write_u8(self.bg_attribute_next_lo);
+        w.write_u8(self.bg_next_tile);
+        w.write_u8(self.bg_next_attr);
+
+        w.write_u8(self.sprite_count as u8);
+        for slot in 0..8 {
+            w.write_bytes(&self.sprite_patterns[slot]);
+            w.write_u8(self.sprite_attributes[slot]);
+            w.write_u8(self.sprite_x[slot]);
         }
+        w.write_bool(self.sprite_zero_on_line);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.read_into(&mut self.palette)?;
+        r.read_into(&mut self.vram)?;
+        r.read_into(&mut self.oam)?;
+        self.oam_addr = r.read_u8()?;
+        self.nmi_pending = r.read_bool()?;
+        self.vblank_suppressed = r.read_bool()?;
+
+        self.write_toggle = r.read_bool()?;
+        self.scanline = r.read_u16()?;
+        self.tick = r.read_u16()?;
+        if self.scanline > self.pre_render_scanline() {
+            /* Saved with more overclocking's lines than there are now */
+            self.scanline = self.pre_render_scanline();
+            self.tick = 0;
+        }
+        self.odd_frame = r.read_bool()?;
+        self.vram_v = r.read_u16()?;
+        self.vram_t = r.read_u16()?;
+        self.vram_x = r.read_u16()?;
+
+        self.ppu_ctrl = PPUCTRL::from_bits_truncate(r.read_u8()?);
+        self.ppu_mask = PPUMASK::from_bits_truncate(r.read_u8()?);
+        self.ppu_status = PPUSTATUS::from_bits_truncate(r.read_u8()?);
+        self.data_bus_next = r.read_u8()?;
+        self.io_latch = r.read_u8()?;
+        r.read_into(&mut self.io_latch_decay)?;
+
+        self.addr_data_bus = r.read_u16()?;
+        self.bg_pattern_shift_reg_hi = r.read_u16()?;
+        self.bg_pattern_shift_reg_lo = r.read_u16()?;
+        self.bg_pattern_next_hi = r.read_u8()?;
+        self.bg_pattern_next_lo = r.read_u8()?;
+        self.bg_attribute_shift_reg_hi = r.read_u16()?;
+        self.bg_attribute_shift_reg_lo = r.read_u16()?;
+        self.bg_attribute_next_hi = r.read_u8()?;
+        self.bg_attribute_next_lo = r.read_u8()?;
+        self.bg_next_tile = r.read_u8()?;
+        self.bg_next_attr = r.read_u8()?;
+
+        self.sprite_count = (r.read_u8()? as usize).min(8);
+        for slot in 0..8 {
+            r.read_into(&mut self.sprite_patterns[slot])?;
+            self.sprite_attributes[slot] = r.read_u8()?;
+            self.sprite_x[slot] = r.read_u8()?;
+        }
+        self.sprite_zero_on_line = r.read_bool()?;
+        self.draw_sprite_line();
+        self.update_colours();
+        Ok(())
+    }
+
+    /// Write the next byte of OAM, as done by both OAMDATA and OAM DMA
+    pub fn oam_dma_write(&mut self, data: u8) {
+        // Bits 2-4 of each sprite's attribute byte don't exist in OAM, so read back as 0
+        self.oam[self.oam_addr as usize] = if self.oam_addr & 3 == 2 { data & 0xE3 } else { data };
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    /// Collect (and clear) an NMI raised since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        core::mem::take(&mut self.nmi_pending)
     }
 
     /// Fetches the address of the tile and attribute data for a given VRAM access
@@ -235,18 +724,53 @@ impl<'a> NESPpu<'a> {
          (0x23C0 | (addr & 0x0C00) | ((addr >> 4) & 0x38) | ((addr >> 2) & 0x07)))
     }
 
+    /* Drive the given bits of the I/O latch, recharging them */
+    fn refresh_io_latch(&mut self, data: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (data & mask);
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_decay[bit] = IO_LATCH_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_io_latch(&mut self) {
+        for bit in 0..8 {
+            if self.io_latch_decay[bit] > 0 {
+                self.io_latch_decay[bit] -= 1;
+                if self.io_latch_decay[bit] == 0 {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
+    }
+
     // Interpreted in terms of the CPU's address space
-    pub fn ppu_register_write(&mut self, addr: u16, data: u8) {
+    pub fn ppu_register_write(&mut self, cart: &mut Cartridge, addr: u16, data: u8) -> Result<(), String> {
+        nes_log!(Trace, PPU, "${:0>4X} = ${:0>2X} at scanline {}, dot {}", addr, data, self.scanline, self.tick);
+
+        // Every write drives the whole latch, even to read-only PPUSTATUS
+        self.refresh_io_latch(data, 0xFF);
+
         match addr {
         PPUAddress::PPUCTRL => {
             // Populate lo-nybble of high byte of base nametable address
             self.vram_t = (self.vram_t & 0xF3FF) | ((data as u16 & 0x3) << 10);
 
-            self.ppu_ctrl = PPUCTRL::from_bits_truncate(data);
+            let ctrl = PPUCTRL::from_bits_truncate(data);
+            let vblank = self.ppu_status.contains(PPUSTATUS::VBLANK);
+            if ctrl.contains(PPUCTRL::NMI_ENABLED) && !self.ppu_ctrl.contains(PPUCTRL::NMI_ENABLED) && vblank {
+                // Enabling NMI during vblank raises the output, and so an NMI, straight away
+                self.nmi_pending = true;
+            } else if !ctrl.contains(PPUCTRL::NMI_ENABLED) {
+                // Disabling it before the CPU has noticed the edge cancels the NMI
+                self.nmi_pending = false;
+            }
+            self.ppu_ctrl = ctrl;
         }
         PPUAddress::PPUMASK => {
-            // TODO: Implement background and sprite hiding in the leftmost 8 pixels + colour emphasis
             self.ppu_mask = PPUMASK::from_bits_truncate(data);
+            self.update_colours();
         }
         PPUAddress::PPUSCROLL => {
             if !self.write_toggle {
@@ -269,218 +793,367 @@ impl<'a> NESPpu<'a> {
         }
         PPUAddress::PPUDATA => {
             // Just immediately write the data
-            self.write(self.vram_v & 0x3FFF, data);
+            self.write(cart, self.vram_v & 0x3FFF, data)?;
 
-            // Perform VRAM addr increment
-            let increment = if self.ppu_ctrl.contains(PPUCTRL::VRAM_INCREMENT) { 32 } else { 1 };
-            self.vram_v += increment;
+            self.increment_after_data_access();
         }
         PPUAddress::OAMADDR => {
-            // TODO
+            self.oam_addr = data;
         }
         PPUAddress::OAMDATA => {
-            // TODO
+            self.oam_dma_write(data);
         }
-        _ => { panic!("{:#X}", addr) }
+        PPUAddress::PPUSTATUS => {}
+        _ => { return Err(format!("Write to unsupported PPU register ${:X}", addr)) }
         }
+        Ok(())
     }
 
     // Addresses interpreted in terms of the CPU's address space
     // Reads from these registers typically exhibit side effects (hence the mut ref)
-    pub fn ppu_register_read(&mut self, addr: u16) -> u8 {
+    pub fn ppu_register_read(&mut self, cart: &Cartridge, addr: u16) -> Result<u8, String> {
         let data: u8;
 
         match addr {
         PPUAddress::PPUSTATUS => {
-            data = self.ppu_status.bits();
+            if self.scanline == self.vblank_scanline() && self.tick == 1 {
+                // Reading a dot before vblank begins sees it clear, and stops it beginning this frame
+                self.vblank_suppressed = true;
+            }
+            // Only the top three bits are driven; the rest come from the latch
+            data = (self.ppu_status.bits() & 0xE0) | (self.io_latch & 0x1F);
+            self.refresh_io_latch(data, 0xE0);
             self.ppu_status.remove(PPUSTATUS::VBLANK);
             self.write_toggle = false;
+
+            // Reading as it begins clears the NMI output before the CPU notices it went high
+            self.nmi_pending = false;
         }
         PPUAddress::PPUDATA => {
-            if self.vram_v < 0x03F00 {
+            if self.vram_v & 0x3FFF < 0x3F00 {
                 // Update the internal buffer
                 data = self.data_bus_next;
-                self.data_bus_next = self.read(self.vram_v & 0x3FFF);
+                self.data_bus_next = self.read(cart, self.vram_v & 0x3FFF);
+                self.refresh_io_latch(data, 0xFF);
             } else {
-                // Otherwise, we get palette data via combinatorial logic
-                data = self.read(addr & 0x3FFF);
+                // Otherwise, we get palette data via combinatorial logic. Palette entries are
+                // six bits, the top two coming from the latch. The buffer is filled from the
+                // nametable underneath.
+                data = (self.read(cart, self.vram_v & 0x3FFF) & 0x3F) | (self.io_latch & 0xC0);
+                self.data_bus_next = self.read(cart, self.vram_v & 0x2FFF);
+                self.refresh_io_latch(data, 0x3F);
             }
 
-            // Perform VRAM addr increment
-            let increment = if self.ppu_ctrl.contains(PPUCTRL::VRAM_INCREMENT) { 32 } else { 1 };
-            self.vram_v += increment;
+            self.increment_after_data_access();
         }
-        _ => { panic!("{:X}", addr) }
+        PPUAddress::OAMDATA => {
+            // While secondary OAM is being cleared for the next scanline, reads see the $FF it's cleared to
+            let clearing = self.ppu_mask.intersects(PPUMASK::RENDERING) && self.scanline < 240 && (1..=64).contains(&self.tick);
+            data = if clearing { 0xFF } else { self.oam[self.oam_addr as usize] };
+            self.refresh_io_latch(data, 0xFF);
         }
-        data
+        // Write-only registers
+        _ => {
+            data = self.io_latch;
+        }
+        }
+        Ok(data)
     }
 
-    /// (NTSC) 3 of these happen per CPU tick.
-    /// Events within the PPU are "batched" together if at all possible.
-    /// That is to say, if self.tcount < <Event's tick> <= (self.tcount + count),
-    /// that event is executed, otherwise tcount is incremented by count
-    /// and we move on with life.
-    pub fn ppu_tick(&mut self, count: usize) {
-        for _ in 0..count {
+    /// Run `count` dots. (NTSC) 3 of these happen per CPU tick, but the bus
+    /// may run many more at once, having put them off (see Bus::catch_up_ppu).
+    pub fn ppu_tick(&mut self, cart: &mut Cartridge, count: usize) {
+        let pre_render = self.pre_render_scanline();
+        let vblank = self.vblank_scanline();
+        let mut remaining = count;
+        while remaining > 0 {
+            // Nothing happens between rendering ending and the pre-render line but vblank
+            // beginning, so go straight to its dot, or to the pre-render line
+            if (240..pre_render).contains(&self.scanline) && (self.scanline, self.tick) != (vblank, 1) {
+                let here = self.scanline as usize * 341 + self.tick as usize;
+                let until = if (self.scanline, self.tick) < (vblank, 1) { vblank as usize * 341 + 1 } else { pre_render as usize * 341 };
+                let skip = remaining.min(until - here);
+                self.scanline = ((here + skip) / 341) as u16;
+                self.tick = ((here + skip) % 341) as u16;
+                remaining -= skip;
+                continue;
+            }
+
+            // Registers can't change partway through, so a whole line owed can be run at once
+            if self.tick == 0 && remaining >= 341 && (self.scanline <= 239 || self.scanline == pre_render) {
+                remaining -= self.render_line(cart);
+                continue;
+            }
+            remaining -= 1;
+
             match self.scanline {
                 // All "rendering" scanlines - those which make standard PPU memory accesses.
-                0..=239 | 261 => {
-                    // Idle-skip on first scanline (picture crispness - apparently)
-                    if self.scanline == 0 && self.tick == 0 {
-                        self.tick = 1;
-                    }
-
+                s if s <= 239 || s == pre_render => {
                     // Pre-render scanline
-                    if self.scanline == 261 && self.tick == 1 {
+                    if self.scanline == pre_render && self.tick == 1 {
                         // Clear the PPU's status
                         self.ppu_status = PPUSTATUS::from_bits_truncate(0);
+                        self.vblank_suppressed = false;
                     }
 
-                    if matches!(self.tick, 2..=256 | 321..=336) {
-                        if self.ppu_mask.contains(PPUMASK::BACKGROUND) {
-                            self.bg_attribute_shift_reg_hi <<= 1;
-                            self.bg_attribute_shift_reg_lo <<= 1;
-
-                            self.bg_pattern_shift_reg_hi <<= 1;
-                            self.bg_pattern_shift_reg_lo <<= 1;
-                        }
-
-                        match (self.tick - 1) % 8 {
-                            0 => {
-                                // Load the background shift registers with pattern table data
-                                self.bg_pattern_shift_reg_hi = (self.bg_pattern_shift_reg_hi & 0xFF00) | self.bg_pattern_next_hi as u16;
-                                self.bg_pattern_shift_reg_lo = (self.bg_pattern_shift_reg_lo & 0xFF00) | self.bg_pattern_next_lo as u16;
-
-                                // Load the attribute shift registers with an expanded (8x1 slither) attribute value
-                                self.bg_attribute_shift_reg_hi = (self.bg_attribute_shift_reg_hi & 0xFF00) | if self.bg_attribute_next_hi & 1 == 1 { 0xFF } else { 0x00 };
-                                self.bg_attribute_shift_reg_lo = (self.bg_attribute_shift_reg_lo & 0xFF00) | if self.bg_attribute_next_lo & 1 == 1 { 0xFF } else { 0x00 };
-
-                                self.bg_next_tile = self.read(NESPpu::tile_attr_from_vram_addr(self.vram_v).0);
-                            }
-                            2 => {
-                                self.bg_next_attr = self.read(NESPpu::tile_attr_from_vram_addr(self.vram_v).1);
-                            }
-                            4 => {
-                                // Get the lsb bit plane from the pattern table for the next tile
-                                self.bg_pattern_next_lo = self.read(
-                                    (self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_TABLE_ADDR) as u16) << 12
-                                |   (self.bg_next_tile as u16) << 4
-                                |   ((self.vram_v & 0x7000) >> 12)); 
-                            }
-                            6 => {
-                                // Get the msb bit plane from the pattern table for the next tile (+8 offset from LSB)
-                                self.bg_pattern_next_hi = self.read(
-                                    (self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_TABLE_ADDR) as u16) << 12
-                                |   (self.bg_next_tile as u16) << 4
-                                |   ((self.vram_v & 0x7000) >> 12) + 8);
-                            }
-                            7 => {
-                                // This is only done when rendering is enabled
-                                if self.ppu_mask.contains(PPUMASK::RENDERING) {
-                                    // Scroll horizontally (algorithm taken from NESDEV)
-                                    if self.vram_v & 0x001F == 31 { // Are we at the end of a nametable?
-                                        self.vram_v &= !0x001F;     // Reset course X to 0
-                                        self.vram_v ^= 0x0400;      // Switch the horizontal nametable
-                                    } else {
-                                        self.vram_v += 1; // Increment as usual :-)
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
+                    if matches!(self.tick, 2..=257 | 321..=337) {
+                        self.background_dot(cart);
                     }
 
                     if self.tick == 256 {
                         // When we reach the end of a scanline, increment the fine Y-scroll, then course vertical scroll.
-                        // Again, this algorithm is lovingly taken from NESDEV.
                         // This is only done when rendering is enabled
-                        if self.ppu_mask.contains(PPUMASK::RENDERING) {
-                            if self.vram_v & 0x7000 != 0x7000 {
-                                self.vram_v += 0x1000; // Standard fine-Y increment
-                            } else {
-                                self.vram_v &= !0x7000;                       // Reset fine-Y to 0
-                                let mut y = (self.vram_v & 0x03E0) >> 5; // Fine-y = course-y
-                                if y == 29 {
-                                    y = 0;
-                                    self.vram_v ^= 0x0800;  // Switch the vertical nametable
-                                } else if y == 31 {
-                                    y = 0;                  // Reset course Y, but don't switch nametable
-                                } else {
-                                    y += 1;                 // Increment course-Y
-                                }
-                                self.vram_v = (self.vram_v & !0x03E0) | (y << 5);
-                            }
+                        if self.ppu_mask.intersects(PPUMASK::RENDERING) {
+                            self.increment_y();
                         }
                     }
 
                     if self.tick == 257 {
-                        // If rendering is enabled, transfer the X-affiliated parts of vram_t to vram_v.
-                        if self.ppu_mask.contains(PPUMASK::RENDERING) {
-                            self.vram_v = (self.vram_v & !0x41F) | (self.vram_t & 0x41F);
-                        }
+                        self.end_visible_dots(cart);
                     }
 
-                    if self.scanline == 261 && self.tick >= 280 && self.tick <= 304 {
-                        // End of the VBLANK period, copy the vertical bits from vram_t to vram_v.
-                        if self.ppu_mask.contains(PPUMASK::RENDERING) {
-                            self.vram_v = (self.vram_v & !0x7BE0) | (self.vram_t & 0x7BE0);
-                        }
+                    if self.scanline == pre_render && self.tick >= 280 && self.tick <= 304 {
+                        self.copy_vertical_scroll();
                     }
 
                     // Superfluous nametable reads at end of scanline
                     if self.tick == 338 || self.tick == 340 {
-                        self.bg_next_tile = self.read(0x2000 | (self.vram_v & 0x0FFF));
+                        self.bg_next_tile = self.read(cart, 0x2000 | (self.vram_v & 0x0FFF));
                     }
                 }
-                241..=260 => {
-                    if self.scanline == 241 && self.tick == 1 {
+                241.. => {
+                    if self.scanline == vblank && self.tick == 1 && !self.vblank_suppressed {
                         self.ppu_status.insert(PPUSTATUS::VBLANK);
+                        nes_log!(Trace, PPU, "Vblank begins, NMI {}",
+                            if self.ppu_ctrl.contains(PPUCTRL::NMI_ENABLED) { "enabled" } else { "disabled" });
                         if self.ppu_ctrl.contains(PPUCTRL::NMI_ENABLED) {
-                            self.cpu.borrow_mut().do_nmi = true;
+                            self.nmi_pending = true;
                         }
                     }
                 }
                 _ => {}
             }
 
-            let mut bg_pixel: u8 = 0;    /* An index into a palette */
-            let mut bg_palette: u8 = 0;  /* Which palette are we indexing? */
+            // Add this colour code to the pixel array, only if we are in the visible region.
+            // Note that on a real NES, the first pixel output is not produced until tick = 4
+            if self.scanline <= 239 && self.tick >= 1 && self.tick <= 256 {
+                self.output_pixel(self.tick - 1, 0);
+            }
+
+            self.next_dot();
+        }
+    }
 
-            if self.ppu_mask.contains(PPUMASK::BACKGROUND) {
-                // Retrieve the pattern information, indexing with fine_x
-                let lbp_pattern = ((self.bg_pattern_shift_reg_lo & (0x8000 >> self.vram_x)) > 0) as u8;
-                let hbp_pattern = ((self.bg_pattern_shift_reg_hi & (0x8000 >> self.vram_x)) > 0) as u8;
-                
-                bg_pixel = (hbp_pattern << 1) | lbp_pattern;
+    /* A whole rendering line from dot 0, doing just what ppu_tick would dot by dot,
+       without working out at each where it is. Returns the dots run, which is 340
+       if the last is skipped. */
+    fn render_line(&mut self, cart: &mut Cartridge) -> usize {
+        let pre_render = self.scanline == self.pre_render_scanline();
+        let visible = self.scanline <= 239;
+        let rendering = self.ppu_mask.intersects(PPUMASK::RENDERING);
 
-                // Now let's get the corresponding palette information
-                let lbp_attribute = ((self.bg_attribute_shift_reg_lo & (0x8000 >> self.vram_x)) > 0) as u8;
-                let hbp_attribute = ((self.bg_attribute_shift_reg_hi & (0x8000 >> self.vram_x)) > 0) as u8;
+        if pre_render {
+            self.ppu_status = PPUSTATUS::from_bits_truncate(0);
+            self.vblank_suppressed = false;
+        }
+        if visible {
+            self.output_pixel(0, 0);
+        }
 
-                bg_palette = (hbp_attribute << 1) | lbp_attribute;
+        // Dots 2-257 a tile at a time: the eight pixels, drawn from the shift registers
+        // as they'll be shifted, then the tile's fetches, in order, then the shift
+        for tile in 0..32 {
+            let first = tile * 8 + 2;
+            if visible {
+                for i in 0..8 {
+                    if first + i <= 256 {
+                        self.output_pixel(first + i - 1, i + 1);
+                    }
+                }
+            }
+            self.tick = first + 7;
+            self.fetch_tile(cart);
+            if tile == 31 && rendering {
+                self.increment_y();  /* At dot 256, after the coarse X increment */
+            }
+            if self.ppu_mask.contains(PPUMASK::BACKGROUND) {
+                self.bg_attribute_shift_reg_hi <<= 8;
+                self.bg_attribute_shift_reg_lo <<= 8;
+                self.bg_pattern_shift_reg_hi <<= 8;
+                self.bg_pattern_shift_reg_lo <<= 8;
             }
+            self.load_shift_registers(cart);
+        }
+        self.end_visible_dots(cart);
 
-            // Read palette RAM to determine which colour code this pixel is
-            let bg_pix_colour = self.read(0x3F00 | ((bg_palette as u16) << 2) | (bg_pixel as u16));
+        if pre_render {
+            self.copy_vertical_scroll();
+        }
+        for tick in 321..=337 {
+            self.tick = tick;
+            self.background_dot(cart);
+        }
 
-            // Add this colour code to the pixel array, only if we are in the visible region.
-            // Note that on a real NES, the first pixel output is not produced until tick = 4
-            if self.scanline >= 0 && self.scanline <= 239
-                && self.tick >= 1 && self.tick <= 256 {
-                    self.frame[self.scanline as usize * 256 + (self.tick as usize - 1)] = bg_pix_colour;
-            }
+        let skip = pre_render && self.skips_last_dot();
+        self.bg_next_tile = self.read(cart, 0x2000 | (self.vram_v & 0x0FFF));
+        if !skip {
+            self.bg_next_tile = self.read(cart, 0x2000 | (self.vram_v & 0x0FFF));
+        }
+        self.tick = 340;
+        self.next_dot();
+        if skip { 340 } else { 341 }
+    }
 
-            self.tick += 1;
-            if self.tick >= 341 {
-                self.tick = 0;
-                self.scanline += 1;
-                if self.scanline >= 262 {
-                    self.scanline = 0;
-                    self.frame_ready = true;
-                }
-            }
+    /* Shift the background along, and make the fetch due at this dot */
+    fn background_dot(&mut self, cart: &mut Cartridge) {
+        if self.ppu_mask.contains(PPUMASK::BACKGROUND) {
+            self.bg_attribute_shift_reg_hi <<= 1;
+            self.bg_attribute_shift_reg_lo <<= 1;
+
+            self.bg_pattern_shift_reg_hi <<= 1;
+            self.bg_pattern_shift_reg_lo <<= 1;
+        }
+
+        match (self.tick - 1) % 8 {
+            0 => self.load_shift_registers(cart),
+            2 => self.fetch_attribute(cart),
+            4 => self.fetch_pattern_lo(cart),
+            6 => self.fetch_pattern_hi(cart),
+            7 => self.increment_tile(),
+            _ => {}
+        }
+    }
+
+    /* The fetches of a tile after its nametable byte, in the order background_dot makes them */
+    fn fetch_tile(&mut self, cart: &mut Cartridge) {
+        self.fetch_attribute(cart);
+        self.fetch_pattern_lo(cart);
+        self.fetch_pattern_hi(cart);
+        self.increment_tile();
+    }
+
+    fn load_shift_registers(&mut self, cart: &mut Cartridge) {
+        // Load the background shift registers with pattern table data
+        self.bg_pattern_shift_reg_hi = (self.bg_pattern_shift_reg_hi & 0xFF00) | self.bg_pattern_next_hi as u16;
+        self.bg_pattern_shift_reg_lo = (self.bg_pattern_shift_reg_lo & 0xFF00) | self.bg_pattern_next_lo as u16;
+
+        // Load the attribute shift registers with an expanded (8x1 slither) attribute value
+        self.bg_attribute_shift_reg_hi = (self.bg_attribute_shift_reg_hi & 0xFF00) | if self.bg_attribute_next_hi & 1 == 1 { 0xFF } else { 0x00 };
+        self.bg_attribute_shift_reg_lo = (self.bg_attribute_shift_reg_lo & 0xFF00) | if self.bg_attribute_next_lo & 1 == 1 { 0xFF } else { 0x00 };
+
+        self.bg_next_tile = self.read(cart, NESPpu::tile_attr_from_vram_addr(self.vram_v).0);
+    }
+
+    fn fetch_attribute(&mut self, cart: &mut Cartridge) {
+        self.bg_next_attr = self.read(cart, NESPpu::tile_attr_from_vram_addr(self.vram_v).1);
+    }
+
+    fn fetch_pattern_lo(&mut self, cart: &mut Cartridge) {
+        // Get the lsb bit plane from the pattern table for the next tile
+        self.bg_pattern_next_lo = self.fetch_pattern(cart, 
+            (self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_TABLE_ADDR) as u16) << 12
+        |   (self.bg_next_tile as u16) << 4
+        |   ((self.vram_v & 0x7000) >> 12)); 
+    }
+
+    fn fetch_pattern_hi(&mut self, cart: &mut Cartridge) {
+        // Get the msb bit plane from the pattern table for the next tile (+8 offset from LSB)
+        self.bg_pattern_next_hi = self.fetch_pattern(cart, 
+            (self.ppu_ctrl.contains(PPUCTRL::BACKGROUND_TABLE_ADDR) as u16) << 12
+        |   (self.bg_next_tile as u16) << 4
+        |   ((self.vram_v & 0x7000) >> 12) + 8);
+    }
+
+    fn increment_tile(&mut self) {
+        // This is only done when rendering is enabled
+        if self.ppu_mask.intersects(PPUMASK::RENDERING) {
+            self.increment_coarse_x();
+        }
+    }
 
-            // println!("S: {}, T: {}, v: {:X}", self.scanline, self.tick, self.vram_v);
+    /* Dot 257: if rendering is enabled, transfer the X-affiliated parts of vram_t to vram_v,
+       and fetch the sprites for the next scanline. */
+    fn end_visible_dots(&mut self, cart: &mut Cartridge) {
+        if self.ppu_mask.intersects(PPUMASK::RENDERING) {
+            self.vram_v = (self.vram_v & !0x41F) | (self.vram_t & 0x41F);
+            let next = if self.scanline == self.pre_render_scanline() { 0 } else { self.scanline + 1 };
+            self.fetch_sprites(cart, next);
+        } else {
+            self.sprite_count = 0;
+            self.sprite_line = [0; 256];
         }
     }
-}
\ No newline at end of file
+
+    /* End of the VBLANK period (dots 280-304 of the pre-render line), copy the vertical bits from vram_t to vram_v. */
+    fn copy_vertical_scroll(&mut self) {
+        if self.ppu_mask.intersects(PPUMASK::RENDERING) {
+            self.vram_v = (self.vram_v & !0x7BE0) | (self.vram_t & 0x7BE0);
+        }
+    }
+
+    /* Draw the pixel at x on this scanline, from the background shift registers and the sprites -
+       as they will be, `ahead` shifts from now, for render_line */
+    fn output_pixel(&mut self, x: u16, ahead: u16) {
+        let mut bg_pixel: u8 = 0;    /* An index into a palette */
+        let mut bg_palette: u8 = 0;  /* Which palette are we indexing? */
+
+        // Either layer may be hidden in the leftmost 8 pixels, usually to mask scrolling artifacts
+        let show_background = self.ppu_mask.contains(PPUMASK::BACKGROUND)
+            && (x >= 8 || self.ppu_mask.contains(PPUMASK::LEFT_BACKGROUND));
+        let show_sprites = self.ppu_mask.contains(PPUMASK::SPRITES)
+            && (x >= 8 || self.ppu_mask.contains(PPUMASK::LEFT_SPRITES));
+
+        if show_background {
+            // Retrieve the pattern information, indexing with fine_x
+            let lbp_pattern = ((self.bg_pattern_shift_reg_lo & (0x8000 >> (self.vram_x + ahead))) > 0) as u8;
+            let hbp_pattern = ((self.bg_pattern_shift_reg_hi & (0x8000 >> (self.vram_x + ahead))) > 0) as u8;
+            
+            bg_pixel = (hbp_pattern << 1) | lbp_pattern;
+
+            // Now let's get the corresponding palette information
+            let lbp_attribute = ((self.bg_attribute_shift_reg_lo & (0x8000 >> (self.vram_x + ahead))) > 0) as u8;
+            let hbp_attribute = ((self.bg_attribute_shift_reg_hi & (0x8000 >> (self.vram_x + ahead))) > 0) as u8;
+
+            bg_palette = (hbp_attribute << 1) | lbp_attribute;
+        }
+
+        let sprite = if show_sprites { self.sprite_line[x as usize] } else { 0 };
+
+        // Sprite 0 hits when its opaque pixel meets an opaque background pixel, except at x=255
+        if sprite & SPRITE_ZERO != 0 && bg_pixel != 0 && x != 255 {
+            self.ppu_status.insert(PPUSTATUS::SPRITE_ZERO_HIT);
+        }
+
+        let palette_index = if sprite != 0 && (bg_pixel == 0 || sprite & SPRITE_BEHIND == 0) {
+            sprite & 0x1F
+        } else if bg_pixel == 0 {
+            0  /* The backdrop colour */
+        } else {
+            bg_palette << 2 | bg_pixel
+        };
+
+        // The colour code from palette RAM (masked if in greyscale mode), tagged with the colour
+        // emphasis bits, which the TV sees as a dimming of the other colours
+        self.frame[self.scanline as usize * 256 + x as usize] = self.colours[palette_index as usize];
+    }
+
+    /* On to the next dot, and at the end of the line, the next line, or frame */
+    fn next_dot(&mut self) {
+        self.tick += 1;
+
+        if self.scanline == self.pre_render_scanline() && self.tick == 340 && self.skips_last_dot() {
+            self.tick = 341;
+        }
+
+        if self.tick >= 341 {
+            self.tick = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_scanline() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                self.frame_ready = true;
+                self.decay_io_latch();
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Frame regression tests: run a ROM from power on for a number of frames, and
+//! compare the last frame with a known good one, to catch PPU regressions in
+//! scrolling, sprites, palettes and the like.
+//!
+//! Fixtures are listed in a text file, one per line:
+//!
+//! ```text
+//! # ROM                 frames  expected
+//! roms/nestest.nes      60      8a3c2e0f61d4b597
+//! roms/scroll.nes       120     scroll.png
+//! ```
+//!
+//! The expected frame is either its hash (as `Nes::frame_hash`, in hex) or a
+//! PNG of it in the built-in palette (as `png::encode_frame`). Paths are
+//! relative to the fixture file. When a frame doesn't match, the frame seen is
+//! saved beside the reference PNG (or the ROM) as e.g. `scroll-120.actual.png`,
+//! to be inspected, or to become the new reference.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Nes;
+use crate::nes::{FRAME_WIDTH, FRAME_HEIGHT};
+use crate::palette::Palette;
+use crate::png;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Hash(u64),
+    Png(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    pub rom: PathBuf,
+    pub frames: u32,
+    pub expected: Expected,
+    pub line: usize,  /* In the fixture file, for reporting */
+}
+
+/// Read a fixture file, resolving its paths relative to `base`
+pub fn parse_fixtures(text: &str, base: &Path) -> Result<Vec<Fixture>, String> {
+    let mut fixtures = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let bad = || format!("Bad fixture on line {}: {}", number + 1, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(bad());
+        }
+        let frames = fields[1].parse().map_err(|_| bad())?;
+        let expected = if fields[2].to_ascii_lowercase().ends_with(".png") {
+            Expected::Png(base.join(fields[2]))
+        } else {
+            Expected::Hash(u64::from_str_radix(fields[2], 16).map_err(|_| bad())?)
+        };
+
+        fixtures.push(Fixture { rom: base.join(fields[0]), frames, expected, line: number + 1 });
+    }
+    Ok(fixtures)
+}
+
+impl Fixture {
+    /// Run the ROM and compare its frame, saving the frame seen if it doesn't match
+    pub fn check(&self) -> Result<(), String> {
+        let rom = fs::read(&self.rom).map_err(|e| format!("Can't read {}: {}", self.rom.display(), e))?;
+        let mut nes = Nes::from_rom(&rom).map_err(|e| e.to_string())?;
+        for _ in 0..self.frames {
+            nes.run_frame().map_err(|e| e.to_string())?;
+        }
+
+        let palette = Palette::default();
+        let mismatch = match &self.expected {
+            Expected::Hash(hash) => (nes.frame_hash() != *hash)
+                .then(|| format!("frame hash {:016x}, expected {:016x}", nes.frame_hash(), hash)),
+            Expected::Png(path) => {
+                let reference = fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+                let (width, height, pixels) = png::decode(&reference).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let colours = palette.frame_colours();
+                let actual = nes.framebuffer().iter().map(|&pixel| colours[pixel as usize & 0x1FF]);
+
+                if (width, height) != (FRAME_WIDTH, FRAME_HEIGHT) {
+                    Some(format!("{} is {}x{}, not a whole frame", path.display(), width, height))
+                } else {
+                    let differing = actual.zip(pixels).filter(|(a, b)| a != b).count();
+                    (differing > 0).then(|| format!("{} pixels differ from {}", differing, path.display()))
+                }
+            }
+        };
+
+        match mismatch {
+            None => Ok(()),
+            Some(mismatch) => {
+                let actual = self.actual_path();
+                let saved = match fs::write(&actual, png::encode_frame(nes.framebuffer(), &palette)) {
+                    Ok(()) => format!("saved the frame to {}", actual.display()),
+                    Err(e) => format!("couldn't save the frame to {}: {}", actual.display(), e),
+                };
+                Err(format!("{} after {} frames: {}; {}", self.rom.display(), self.frames, mismatch, saved))
+            }
+        }
+    }
+
+    /* Where to save the frame seen when it doesn't match, e.g. scroll-120.actual.png */
+    fn actual_path(&self) -> PathBuf {
+        let stem = self.rom.file_stem().unwrap_or_default().to_string_lossy();
+        let dir = match &self.expected {
+            Expected::Png(path) => path.parent(),
+            Expected::Hash(_) => self.rom.parent(),
+        };
+        dir.unwrap_or(Path::new(".")).join(format!("{}-{}.actual.png", stem, self.frames))
+    }
+}
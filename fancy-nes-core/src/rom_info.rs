@@ -0,0 +1,158 @@
+//! What a ROM image is, for bug reports and matching against game databases:
+//! its header's description of the cartridge, and the CRC32, MD5 and SHA-1 of
+//! the whole file, its PRG and CHR ROM, and the two together (as No-Intro and
+//! the NES 2.0 database identify games, without the header). The report is
+//! plain text (see its Display impl).
+
+use core::fmt;
+
+use crate::prelude::*;
+use crate::{Mirroring, NESHeaderMetadata, Timing};
+use crate::cartridge::RomLayout;
+use crate::cpu::registry;
+use crate::movie::md5;
+use crate::png::crc32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hashes {
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+impl Hashes {
+    pub fn of(data: &[u8]) -> Self {
+        Self { crc32: crc32(data), md5: md5(data), sha1: sha1(data) }
+    }
+}
+
+pub struct RomInfo {
+    pub file_size: usize,
+    pub is_nes2: bool,
+    pub mapper_id: u16,
+    pub submapper_id: u8,
+    pub mapper_name: Option<&'static str>,  /* None if the mapper isn't supported */
+    pub mirroring: Mirroring,
+    pub prg_rom_size: u32,
+    pub chr_rom_size: u32,
+    pub prg_ram_size: u32,  /* Volatile and battery-backed together */
+    pub chr_ram_size: u32,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub timing: Timing,
+    pub truncated: bool,  /* Shorter than the header says, so the PRG or CHR hashes are of what there is */
+
+    pub file: Hashes,
+    pub prg_chr: Hashes,
+    pub prg: Hashes,
+    pub chr: Hashes,
+}
+
+impl RomInfo {
+    pub fn new(rom: &[u8]) -> Result<Self, String> {
+        let header = NESHeaderMetadata::parse_header(rom).map_err(|e| e.to_string())?;
+        let layout = RomLayout::of(&header);
+        let slice = |start: usize, end: usize| &rom[start.min(rom.len())..end.min(rom.len())];
+
+        Ok(Self {
+            file_size: rom.len(),
+            is_nes2: header.is_nes2,
+            mapper_id: header.mapper_id,
+            submapper_id: header.submapper_id,
+            mapper_name: registry::lookup(header.mapper_id).ok().map(|info| info.name),
+            mirroring: header.hardwired_mirroring,
+            prg_rom_size: header.prg_rom_size,
+            chr_rom_size: header.chr_rom_size,
+            prg_ram_size: header.prg_ram_size + header.prg_nvram_size,
+            chr_ram_size: header.chr_ram_size + header.chr_nvram_size,
+            has_battery: header.has_battery,
+            has_trainer: header.has_trainer,
+            timing: header.timing,
+            truncated: rom.len() < layout.chr.end,
+            file: Hashes::of(rom),
+            prg_chr: Hashes::of(slice(layout.prg.start, layout.chr.end)),
+            prg: Hashes::of(slice(layout.prg.start, layout.prg.end)),
+            chr: Hashes::of(slice(layout.chr.start, layout.chr.end)),
+        })
+    }
+
+    /// The report, a line at a time, e.g. for an overlay
+    pub fn lines(&self) -> Vec<String> {
+        self.to_string().lines().map(str::to_string).collect()
+    }
+}
+
+/* Sizes in whole KiB where they are, as they nearly always are */
+fn size(bytes: u32) -> String {
+    if bytes.is_multiple_of(1024) { format!("{} KiB", bytes / 1024) } else { format!("{} bytes", bytes) }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:0>2x}", b)).collect()
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} header, {} bytes{}", if self.is_nes2 { "NES 2.0" } else { "iNES" }, self.file_size,
+            if self.truncated { " (truncated)" } else { "" })?;
+        writeln!(f, "Mapper: {}.{} ({})", self.mapper_id, self.submapper_id, self.mapper_name.unwrap_or("unsupported"))?;
+        writeln!(f, "Mirroring: {:?}", self.mirroring)?;
+        writeln!(f, "PRG ROM: {}  CHR ROM: {}", size(self.prg_rom_size), size(self.chr_rom_size))?;
+        writeln!(f, "PRG RAM: {}  CHR RAM: {}", size(self.prg_ram_size), size(self.chr_ram_size))?;
+        writeln!(f, "Battery: {}  Trainer: {}", if self.has_battery { "yes" } else { "no" },
+            if self.has_trainer { "yes" } else { "no" })?;
+        writeln!(f, "Region: {:?}", self.timing)?;
+        for (name, hashes) in [("File", &self.file), ("PRG+CHR", &self.prg_chr), ("PRG", &self.prg), ("CHR", &self.chr)] {
+            writeln!(f, "{} CRC32: {:0>8X}", name, hashes.crc32)?;
+            writeln!(f, "{} MD5: {}", name, hex(&hashes.md5))?;
+            writeln!(f, "{} SHA-1: {}", name, hex(&hashes.sha1))?;
+        }
+        Ok(())
+    }
+}
+
+/* FIPS 180-4 */
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_be_bytes());
+
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5A827999),
+                1 => (b ^ c ^ d, 0x6ED9EBA1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, s) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
@@ -0,0 +1,147 @@
+//! Save states - a snapshot of the whole machine as a versioned binary blob.
+//!
+//! The layout is a simple, little-endian concatenation of each component's
+//! fields, in the order they are written by the `save_state` methods on
+//! NESCpu (including the APU), NESPpu and the cartridge. ROM contents are never
+//! stored, so a state is only valid for the ROM which produced it.
+
+use crate::prelude::*;
+use crate::cpu::NESCpu;
+
+pub const STATE_MAGIC: [u8; 4] = *b"FNSS";
+pub const STATE_VERSION: u16 = 18;
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, data: u8) {
+        self.buf.push(data);
+    }
+
+    pub fn write_bool(&mut self, data: bool) {
+        self.buf.push(data as u8);
+    }
+
+    pub fn write_u16(&mut self, data: u16) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, data: u32) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, data: u64) {
+        self.buf.extend_from_slice(&data.to_le_bytes());
+    }
+
+    /// Variable-length data is prefixed with its length
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.pos + count > self.data.len() {
+            return Err(format!("Save state truncated at offset {}", self.pos));
+        }
+        let slice = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Read a length-prefixed block into a fixed-size destination, which must match exactly
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), String> {
+        let data = self.read_bytes()?;
+        if data.len() != dest.len() {
+            return Err(format!("Save state block has length {}, expected {}", data.len(), dest.len()));
+        }
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Snapshot the CPU and everything on its bus - RAM, APU, PPU and cartridge
+pub fn save_state(cpu: &NESCpu) -> Vec<u8> {
+    let mut w = StateWriter::new();
+
+    w.buf.extend_from_slice(&STATE_MAGIC);
+    w.write_u16(STATE_VERSION);
+
+    cpu.save_state(&mut w);
+    cpu.bus.ppu.save_state(&mut w);
+
+    w.finish()
+}
+
+/// Restore a snapshot produced by `save_state`. On error, the machine may be left partially restored.
+pub fn load_state(cpu: &mut NESCpu, data: &[u8]) -> Result<(), String> {
+    let mut r = StateReader::new(data);
+
+    if r.take(4)? != STATE_MAGIC {
+        return Err("Not a fancy-nes save state".to_string());
+    }
+
+    let version = r.read_u16()?;
+    if version != STATE_VERSION {
+        return Err(format!("Unsupported save state version {} (expected {})", version, STATE_VERSION));
+    }
+
+    cpu.load_state(&mut r)?;
+    cpu.bus.ppu.load_state(&mut r)?;
+
+    if r.pos != data.len() {
+        return Err(format!("Save state has {} bytes of unexpected trailing data", data.len() - r.pos));
+    }
+
+    Ok(())
+}
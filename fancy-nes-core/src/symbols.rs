@@ -0,0 +1,244 @@
+//! Symbol files - the labels an assembler gives addresses, for the disassembler
+//! and debugger to show in place of raw addresses ("JSR reset_ppu" rather than
+//! "JSR $8123"). These formats are understood:
+//!   FCEUX .nl      "$C000#reset#comment", one file per 16KiB PRG ROM bank
+//!                  (game.nes.0.nl, game.nes.1.nl, ...) and game.nes.ram.nl
+//!   Mesen .mlb     "P:0123:reset", by PRG ROM offset, or R/W/S/G for RAM and registers
+//!   ca65 .dbg      The debug info ld65 writes with --dbgfile
+//!   VICE labels    "al 00C000 .reset", as ld65 writes with -Ln
+//!
+//! Labels within PRG ROM are kept by their offset into the ROM where the file
+//! says, so that the right label is shown for whichever bank is mapped.
+//! Without the std feature, files can't be loaded by name, but their text can
+//! still be parsed.
+
+use alloc::collections::BTreeMap;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+use crate::prelude::*;
+use crate::cartridge::Cartridge;
+
+const NL_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    cpu: BTreeMap<u16, String>,  /* By CPU address */
+    prg: BTreeMap<usize, String>,  /* By offset into PRG ROM */
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_empty() && self.prg.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg.len()
+    }
+
+    /// Add a label for a CPU address, replacing any already given
+    pub fn add(&mut self, addr: u16, name: &str) {
+        self.cpu.insert(addr, name.to_string());
+    }
+
+    /// Add a label for a byte of PRG ROM, wherever it's mapped
+    pub fn add_prg(&mut self, offset: usize, name: &str) {
+        self.prg.insert(offset, name.to_string());
+    }
+
+    /// Load a symbol file, in the format its name suggests. Returns the number of labels read.
+    #[cfg(feature = "std")]
+    pub fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = path.file_name().map(|name| name.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let before = self.len();
+
+        match path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase()).as_deref() {
+            Some("nl") => {
+                /* game.nes.ram.nl is RAM, game.nes.N.nl the Nth bank in hex */
+                let bank = name.trim_end_matches(".nl").rsplit('.').next().unwrap_or_default();
+                let bank = if bank == "ram" { None } else { usize::from_str_radix(bank, 16).ok() };
+                self.parse_nl(&text, bank)
+            }
+            Some("mlb") => self.parse_mlb(&text),
+            Some("dbg") => self.parse_dbg(&text),
+            _ => self.parse_vice(&text),
+        }.map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        Ok(self.len() - before)
+    }
+
+    /// Load whichever symbol files lie beside a ROM: game.dbg, game.mlb, game.nes.ram.nl
+    /// and game.nes.N.nl. Returns the files loaded.
+    #[cfg(feature = "std")]
+    pub fn load_beside(&mut self, rom: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut paths = vec![rom.with_extension("dbg"), rom.with_extension("mlb")];
+        let rom_name = rom.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        paths.push(rom.with_file_name(format!("{}.ram.nl", rom_name)));
+        for bank in 0..=0xFF {
+            paths.push(rom.with_file_name(format!("{}.{:X}.nl", rom_name, bank)));
+        }
+
+        let mut loaded = vec![];
+        for path in paths.into_iter().filter(|path| path.is_file()) {
+            self.load(&path)?;
+            loaded.push(path);
+        }
+        Ok(loaded)
+    }
+
+    /// FCEUX's "$ADDR#name#comment" lines. Arrays ("$ADDR/SIZE#name#") label their first byte.
+    /// Given a bank, addresses are in that 16KiB bank of PRG ROM; otherwise they're CPU addresses.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, '#');
+            let (addr, name) = match (fields.next(), fields.next()) {
+                (Some(addr), Some(name)) => (addr, name.trim()),
+                _ => return Err(format!("line {}: expected $ADDR#name#", number + 1)),
+            };
+            let addr = addr.split('/').next().unwrap_or_default().trim_start_matches('$');
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| format!("line {}: bad address {}", number + 1, addr))?;
+            if name.is_empty() {
+                continue;  /* A comment without a label */
+            }
+
+            match bank {
+                Some(bank) if addr >= 0x8000 => self.add_prg(bank * NL_BANK_SIZE + (addr as usize % NL_BANK_SIZE), name),
+                _ => self.add(addr, name),
+            }
+        }
+        Ok(())
+    }
+
+    /// Mesen's "TYPE:ADDR[-END]:name[:comment]" lines, where P is PRG ROM, R internal RAM,
+    /// W and S work and save RAM at $6000, and G registers
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.splitn(4, ':').collect();
+            if fields.len() < 3 {
+                return Err(format!("line {}: expected TYPE:ADDR:name", number + 1));
+            }
+            let addr = fields[1].split('-').next().unwrap_or_default();
+            let addr = usize::from_str_radix(addr, 16).map_err(|_| format!("line {}: bad address {}", number + 1, addr))?;
+            let name = fields[2].trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            match fields[0] {
+                "P" => self.add_prg(addr, name),
+                "R" | "G" if addr <= 0xFFFF => self.add(addr as u16, name),
+                "W" | "S" if addr < 0x2000 => self.add(0x6000 + addr as u16, name),
+                _ => {}  /* Other memory types (CHR, nametables...) aren't CPU addresses */
+            }
+        }
+        Ok(())
+    }
+
+    /// ca65's debug info. Only labels are taken, not equates (which are as often constants
+    /// as addresses). Labels in segments written to the ROM are kept by their ROM offset.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        /* Segment id => (start address, offset into the .nes file) */
+        let mut segments: BTreeMap<&str, (usize, Option<usize>)> = BTreeMap::new();
+        let mut labels = vec![];
+
+        for line in text.lines() {
+            let (kind, attributes) = match line.split_once(char::is_whitespace) {
+                Some(record) => record,
+                None => continue,
+            };
+            let attributes: BTreeMap<&str, &str> = attributes.trim().split(',')
+                .filter_map(|attribute| attribute.split_once('='))
+                .collect();
+            let number = |key: &str| attributes.get(key).and_then(|value| parse_dbg_number(value));
+
+            match kind {
+                "seg" => {
+                    if let (Some(id), Some(start)) = (attributes.get("id"), number("start")) {
+                        segments.insert(id, (start, number("ooffs")));
+                    }
+                }
+                "sym" if attributes.get("type") == Some(&"lab") => {
+                    if let (Some(name), Some(addr)) = (attributes.get("name"), number("val")) {
+                        labels.push((name.trim_matches('"'), addr, attributes.get("seg").copied()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (name, addr, segment) in labels {
+            let file_offset = segment.and_then(|segment| segments.get(segment))
+                .and_then(|&(start, ooffs)| Some(ooffs? + addr.checked_sub(start)?));
+            match file_offset {
+                /* Past the 16 byte iNES header */
+                Some(offset) if addr >= 0x8000 && offset >= 16 => self.add_prg(offset - 16, name),
+                _ if addr <= 0xFFFF => self.add(addr as u16, name),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// VICE's "al ADDR .name" lines, as written by ld65 -Ln
+    pub fn parse_vice(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["al", addr, name] => {
+                    let addr = usize::from_str_radix(addr, 16).ok().filter(|&addr| addr <= 0xFFFF)
+                        .ok_or_else(|| format!("line {}: bad address {}", number + 1, addr))?;
+                    self.add(addr as u16, name.trim_start_matches('.'));
+                }
+                _ => return Err(format!("line {}: expected al ADDR .name", number + 1)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The label for a CPU address, given the cartridge's banks as they're mapped now
+    pub fn label(&self, addr: u16, cartridge: &Cartridge) -> Option<&str> {
+        self.label_at(addr, cartridge.prg_rom_offset(addr))
+    }
+
+    /// The label for a CPU address, which reads from this offset into PRG ROM if any
+    pub fn label_at(&self, addr: u16, rom_offset: Option<usize>) -> Option<&str> {
+        rom_offset
+            .and_then(|offset| self.prg.get(&offset))
+            .or_else(|| self.cpu.get(&addr))
+            .map(String::as_str)
+    }
+
+    /// The CPU address a label is at, as the cartridge's banks are mapped now
+    pub fn address(&self, name: &str, cartridge: &Cartridge) -> Option<u16> {
+        let cpu = self.cpu.iter().find(|(_, label)| label.as_str() == name).map(|(&addr, _)| addr);
+        let offset = self.prg.iter().find(|(_, label)| label.as_str() == name).map(|(&offset, _)| offset);
+        match offset {
+            Some(offset) => (0x8000..=0xFFFF).find(|&addr| cartridge.prg_rom_offset(addr) == Some(offset)).or(cpu),
+            None => cpu,
+        }
+    }
+}
+
+/* ca65 writes numbers in hex with 0x, or otherwise decimal */
+fn parse_dbg_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,105 @@
+//! Running test ROMs headlessly, for accuracy suites.
+//!
+//! Most of blargg's test ROMs (and many written since) report through
+//! cartridge RAM: once $6001-$6003 hold the signature DE B0 61, $6000 is the
+//! status - $80 while the test runs, $81 when it needs the reset button
+//! pressed, and otherwise the result code, 0 for a pass. A zero-terminated
+//! description of the result is kept at $6004.
+
+use core::fmt;
+
+use crate::prelude::*;
+use crate::Nes;
+use crate::error::NesError;
+use crate::nes::MemorySpace;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+const SIGNATURE_BYTES: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+/* Tests asking for a reset want it at least 100ms later */
+const RESET_DELAY_FRAMES: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed(u8),  /* The test's result code */
+    TimedOut,    /* No result within the time allowed, including ROMs which never report one */
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub outcome: TestOutcome,
+    pub text: String,  /* What the test wrote at $6004, which usually explains a failure */
+    pub frames: u32,
+}
+
+impl fmt::Display for TestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.outcome {
+            TestOutcome::Passed => write!(f, "Passed")?,
+            TestOutcome::Failed(code) => write!(f, "Failed with code {}", code)?,
+            TestOutcome::TimedOut => write!(f, "Timed out")?,
+        }
+        write!(f, " after {} frames", self.frames)?;
+        if !self.text.trim().is_empty() {
+            write!(f, "\n{}", self.text.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Run a test ROM until it reports a result, or for at most `max_frames` frames
+pub fn run_test_rom(rom: &[u8], max_frames: u32) -> Result<TestResult, NesError> {
+    let mut nes = Nes::from_rom(rom)?;
+    let mut reset_at = None;
+
+    for frame in 1..=max_frames {
+        nes.run_frame()?;
+        if !has_signature(&nes) {
+            continue;
+        }
+
+        match peek(&nes, STATUS) {
+            STATUS_RUNNING => {}
+            STATUS_RESET => match reset_at {
+                None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                Some(at) if frame >= at => {
+                    nes.reset()?;
+                    reset_at = None;
+                }
+                Some(_) => {}
+            },
+            code => {
+                let outcome = if code == 0 { TestOutcome::Passed } else { TestOutcome::Failed(code) };
+                return Ok(TestResult { outcome, text: text(&nes), frames: frame });
+            }
+        }
+    }
+
+    Ok(TestResult { outcome: TestOutcome::TimedOut, text: text(&nes), frames: max_frames })
+}
+
+fn peek(nes: &Nes, addr: u16) -> u8 {
+    nes.peek(MemorySpace::Cpu, addr).unwrap_or(0)
+}
+
+fn has_signature(nes: &Nes) -> bool {
+    (0..3).all(|i| peek(nes, SIGNATURE + i) == SIGNATURE_BYTES[i as usize])
+}
+
+/* The result text, up to its terminator or the end of cartridge RAM */
+fn text(nes: &Nes) -> String {
+    if !has_signature(nes) {
+        return String::new();
+    }
+    (TEXT..0x8000)
+        .map(|addr| peek(nes, addr))
+        .take_while(|&c| c != 0)
+        .map(|c| c as char)
+        .collect()
+}
@@ -0,0 +1,37 @@
+// Short programs on an NROM cartridge built in memory, for the tests which
+// check the hardware at the edges nestest and the frame fixtures don't reach.
+
+#![allow(dead_code)]  /* Each test file uses its own share of these */
+
+use fancy_nes_core::Nes;
+
+/* Where the program is placed, and where the reset vector points */
+pub const START: u16 = 0xC000;
+
+/* The interrupt handlers, each just a JMP to itself */
+pub const NMI_HANDLER: u16 = 0xE000;
+pub const IRQ_HANDLER: u16 = 0xE100;
+
+/* A 16KiB NROM image, mirrored at $8000 and $C000, with the program at START */
+pub fn nrom(program: &[u8]) -> Nes {
+    let mut rom = vec![0; 16 + 0x4000 + 0x2000];
+    rom[..6].copy_from_slice(b"NES\x1A\x01\x01");
+    let prg = &mut rom[16..16 + 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    for (vector, handler) in [(0xFFFA, NMI_HANDLER), (0xFFFC, START), (0xFFFE, IRQ_HANDLER)] {
+        let at = (vector & 0x3FFF) as usize;
+        prg[at..at + 2].copy_from_slice(&handler.to_le_bytes());
+    }
+    for handler in [NMI_HANDLER, IRQ_HANDLER] {
+        let at = (handler & 0x3FFF) as usize;
+        prg[at] = 0x4C;
+        prg[at + 1..at + 3].copy_from_slice(&handler.to_le_bytes());
+    }
+    Nes::from_rom(&rom).unwrap()
+}
+
+pub fn run(nes: &mut Nes, instructions: usize) {
+    for _ in 0..instructions {
+        nes.step().unwrap();
+    }
+}
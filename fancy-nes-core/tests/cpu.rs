@@ -0,0 +1,76 @@
+// Check what the CPU does at the edges nestest doesn't reach: the stack
+// wrapping within page 1, and how long interrupts take to enter.
+
+mod common;
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::nes::MemorySpace;
+
+use common::{nrom, run, IRQ_HANDLER, NMI_HANDLER};
+
+/* Run until the handler's first instruction, returning the CPU cycles from the start of the
+   instruction the interrupt followed, and from the start of the interrupt sequence */
+fn enter(nes: &mut Nes, handler: u16) -> (u64, u64) {
+    let mut starts = vec![nes.cpu().cycle];
+    while nes.cpu().PC != handler {
+        assert!(starts.len() < 100_000, "the interrupt was never taken");
+        nes.step().unwrap();
+        starts.push(nes.cpu().cycle);
+    }
+    let n = starts.len();
+    (starts[n - 1] - starts[n - 3], starts[n - 1] - starts[n - 2])
+}
+
+#[test]
+fn pull_wraps_from_top_of_stack() {
+    /* LDX #$FF; TXS; PLA */
+    let mut nes = nrom(&[0xA2, 0xFF, 0x9A, 0x68]);
+    nes.poke(MemorySpace::Cpu, 0x0100, 0x42).unwrap();
+    run(&mut nes, 3);
+    assert_eq!((nes.cpu().SP, nes.cpu().A), (0x00, 0x42));
+}
+
+#[test]
+fn push_wraps_from_bottom_of_stack() {
+    /* LDA #$5A; LDX #0; TXS; PHA */
+    let mut nes = nrom(&[0xA9, 0x5A, 0xA2, 0x00, 0x9A, 0x48]);
+    run(&mut nes, 4);
+    assert_eq!(nes.cpu().SP, 0xFF);
+    assert_eq!(nes.peek(MemorySpace::Cpu, 0x0100), Some(0x5A));
+}
+
+#[test]
+fn subroutine_return_address_wraps() {
+    /* LDX #0; TXS; JSR $C008; NOP; RTS at $C008 */
+    let mut nes = nrom(&[0xA2, 0x00, 0x9A, 0x20, 0x08, 0xC0, 0xEA, 0xEA, 0x60]);
+    run(&mut nes, 3);
+    assert_eq!((nes.cpu().PC, nes.cpu().SP), (0xC008, 0xFE));
+    assert_eq!(nes.peek(MemorySpace::Cpu, 0x0100), Some(0xC0));
+    assert_eq!(nes.peek(MemorySpace::Cpu, 0x01FF), Some(0x05));
+    run(&mut nes, 1);
+    assert_eq!((nes.cpu().PC, nes.cpu().SP), (0xC006, 0x00));
+}
+
+#[test]
+fn reset_moves_stack_pointer() {
+    let mut nes = nrom(&[0x4C, 0x00, 0xC0]);
+    assert_eq!(nes.cpu().SP, 0xFD);
+    nes.reset().unwrap();
+    assert_eq!(nes.cpu().SP, 0xFA);
+}
+
+#[test]
+fn nmi_entry_takes_seven_cycles() {
+    /* LDA #$80; STA $2000; JMP * */
+    let mut nes = nrom(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0xC0]);
+    assert_eq!(enter(&mut nes, NMI_HANDLER), (3 + 7, 7));
+    assert_eq!(nes.cpu().SP, 0xFA);
+}
+
+#[test]
+fn irq_entry_takes_seven_cycles() {
+    /* LDA #0; STA $4017 (frame counter IRQ on); CLI; JMP * */
+    let mut nes = nrom(&[0xA9, 0x00, 0x8D, 0x17, 0x40, 0x58, 0x4C, 0x06, 0xC0]);
+    assert_eq!(enter(&mut nes, IRQ_HANDLER), (3 + 7, 7));
+    assert_eq!(nes.cpu().SP, 0xFA);
+}
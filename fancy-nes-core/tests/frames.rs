@@ -0,0 +1,19 @@
+// Run each ROM in tests/frames/fixtures.txt, comparing the frame it shows with
+// the one recorded there. See fancy_nes_core::regression for the format.
+
+use std::fs;
+use std::path::PathBuf;
+
+use fancy_nes_core::regression::parse_fixtures;
+
+#[test]
+fn frames_match_fixtures() {
+    let base = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/frames");
+    let text = fs::read_to_string(base.join("fixtures.txt")).expect("tests/frames/fixtures.txt is missing");
+    let fixtures = parse_fixtures(&text, &base).unwrap();
+
+    let failures: Vec<String> = fixtures.iter()
+        .filter_map(|fixture| fixture.check().err().map(|e| format!("line {}: {}", fixture.line, e)))
+        .collect();
+    assert!(failures.is_empty(), "{} of {} fixtures failed:\n{}", failures.len(), fixtures.len(), failures.join("\n"));
+}
@@ -0,0 +1,54 @@
+// Run nestest's automated mode, checking each instruction against nestest.log.
+//
+// nestest.log isn't distributed with fancy-nes, so download it (it is published
+// alongside nestest.nes) to tools/roms/nestest.log, or point NESTEST_LOG at it,
+// and run the ignored tests too (`cargo test -- --ignored`). Without it, the
+// ROM's own result codes are still checked.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::cpu::trace::verify_log;
+use fancy_nes_core::nes::MemorySpace;
+
+/* The automated mode's entry point, and the number of instructions nestest.log follows it for */
+const START: u16 = 0xC000;
+const INSTRUCTIONS: usize = 8991;
+
+fn roms() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tools/roms")
+}
+
+fn nestest() -> Nes {
+    let rom = fs::read(roms().join("nestest.nes")).expect("tools/roms/nestest.nes is missing");
+    Nes::from_rom(&rom).unwrap()
+}
+
+#[test]
+#[ignore = "needs nestest.log, which isn't distributed with fancy-nes"]
+fn matches_golden_log() {
+    let path = env::var_os("NESTEST_LOG").map(PathBuf::from).unwrap_or_else(|| roms().join("nestest.log"));
+    let golden = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Can't read the golden log at {}: {}", path.display(), e));
+
+    match verify_log(&mut nestest(), &golden) {
+        Ok(lines) => assert!(lines >= INSTRUCTIONS, "only {} lines in {}", lines, path.display()),
+        Err(divergence) => panic!("{}", divergence),
+    }
+}
+
+#[test]
+fn passes_own_checks() {
+    let mut nes = nestest();
+    nes.start_at(START);
+    for _ in 0..INSTRUCTIONS {
+        nes.step().unwrap();
+    }
+
+    /* nestest leaves the number of the first failing test in $02 (official opcodes) and $03 (the rest) */
+    let official = nes.peek(MemorySpace::Cpu, 0x02).unwrap();
+    let unofficial = nes.peek(MemorySpace::Cpu, 0x03).unwrap();
+    assert_eq!((official, unofficial), (0, 0), "nestest failed test ${:0>2X}/${:0>2X}", official, unofficial);
+}
@@ -0,0 +1,149 @@
+// Check the PPU's timing and quirks from the CPU's side, as games see them.
+
+mod common;
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::nes::MemorySpace;
+
+use common::{nrom, NMI_HANDLER};
+
+/* The CPU cycles from the end of one frame to the end of the frame `frames` later */
+fn frame_cycles(nes: &mut Nes, frames: usize) -> u64 {
+    while !nes.tick().unwrap() {}
+    let start = nes.cpu().cycle;
+    for _ in 0..frames {
+        while !nes.tick().unwrap() {}
+    }
+    nes.cpu().cycle - start
+}
+
+/* Run until the PPU is a few lines into vblank, then let the program past its wait for $10 */
+fn release_in_vblank(nes: &mut Nes) {
+    loop {
+        nes.tick().unwrap();
+        nes.catch_up();
+        if nes.ppu().scanline == 245 {
+            break;
+        }
+    }
+    nes.poke(MemorySpace::Cpu, 0x10, 1).unwrap();
+}
+
+#[test]
+fn odd_frames_are_a_dot_shorter_when_rendering() {
+    /* LDA #$1E; STA $2001; JMP * */
+    let mut nes = nrom(&[0xA9, 0x1E, 0x8D, 0x01, 0x20, 0x4C, 0x05, 0xC0]);
+    /* Six frames of 262 lines of 341 dots, three of them odd, at three dots to a cycle */
+    assert_eq!(frame_cycles(&mut nes, 6), (6 * 262 * 341 - 3) / 3);
+}
+
+#[test]
+fn frames_are_all_full_length_without_rendering() {
+    /* LDA #0; STA $2001; JMP * */
+    let mut nes = nrom(&[0xA9, 0x00, 0x8D, 0x01, 0x20, 0x4C, 0x05, 0xC0]);
+    assert_eq!(frame_cycles(&mut nes, 6), 6 * 262 * 341 / 3);
+}
+
+#[test]
+fn enabling_nmi_in_vblank_raises_one() {
+    /* C000: LDA $10; BEQ C000; LDA #$80; STA $2000; NOP; JMP * */
+    let mut nes = nrom(&[0xA5, 0x10, 0xF0, 0xFC, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0xEA, 0x4C, 0x0A, 0xC0]);
+    release_in_vblank(&mut nes);
+    for _ in 0..10 {
+        nes.step().unwrap();
+    }
+    assert_eq!(nes.cpu().PC, NMI_HANDLER);
+
+    /* The write raises NMI too late for the poll on its own last cycles, so the NOP runs first */
+    let stacked = |addr| nes.peek(MemorySpace::Cpu, addr).unwrap();
+    let returns_to = u16::from_le_bytes([stacked(0x01FC), stacked(0x01FD)]);
+    assert_eq!(returns_to, 0xC00A);
+}
+
+#[test]
+fn enabling_nmi_after_reading_vblank_raises_none() {
+    /* C000: LDA $10; BEQ C000; BIT $2002; LDA #$80; STA $2000; NOP; JMP * */
+    let mut nes = nrom(&[0xA5, 0x10, 0xF0, 0xFC, 0x2C, 0x02, 0x20, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0xEA, 0x4C, 0x0D, 0xC0]);
+    release_in_vblank(&mut nes);
+    while nes.ppu().scanline != 261 {
+        nes.step().unwrap();
+        nes.catch_up();
+        assert_ne!(nes.cpu().PC, NMI_HANDLER, "NMI raised after the vblank flag was read");
+    }
+}
+
+/* Show sprites of the given height, and run two frames, returning whether the program saw the
+   sprite overflow flag. OAM is filled with $FF (off screen) and then `sprites`, 4 bytes each. */
+fn sprite_overflow(height: u8, sprites: &[[u8; 4]]) -> bool {
+    let ctrl = if height == 16 { 0x20 } else { 0x00 };
+    /* C000: LDA #ctrl; STA $2000; LDA #$18; STA $2001
+       C00A: LDA $2002; AND #$20; BEQ C00A; STA $10; JMP * */
+    let mut nes = nrom(&[0xA9, ctrl, 0x8D, 0x00, 0x20, 0xA9, 0x18, 0x8D, 0x01, 0x20,
+        0xAD, 0x02, 0x20, 0x29, 0x20, 0xF0, 0xF9, 0x85, 0x10, 0x4C, 0x13, 0xC0]);
+    for addr in 0..0x100 {
+        nes.poke(MemorySpace::Oam, addr, 0xFF).unwrap();
+    }
+    for (addr, &byte) in sprites.iter().flatten().enumerate() {
+        nes.poke(MemorySpace::Oam, addr as u16, byte).unwrap();
+    }
+    for _ in 0..2 {
+        while !nes.tick().unwrap() {}
+    }
+    nes.peek(MemorySpace::Cpu, 0x10) == Some(0x20)
+}
+
+#[test]
+fn sprite_overflow_counts_8x16_sprites_lower_halves() {
+    /* Eight sprites on lines 33-40, and a ninth on lines 25-32, or 25-40 in 8x16 mode */
+    let mut sprites = vec![[0x20, 0, 0, 0]; 8];
+    sprites.push([0x18, 0, 0, 0]);
+    assert!(!sprite_overflow(8, &sprites));
+    assert!(sprite_overflow(16, &sprites));
+}
+
+#[test]
+fn sprite_overflow_checks_later_sprites_diagonally() {
+    /* Eight sprites on lines 33-40, an off screen ninth, and a tenth whose tile number is in range */
+    let mut sprites = vec![[0x20, 0, 0, 0]; 8];
+    sprites.push([0xFF, 0, 0, 0]);
+    sprites.push([0xFF, 0x20, 0, 0]);
+    assert!(sprite_overflow(8, &sprites));
+    sprites[9][1] = 0xFF;
+    assert!(!sprite_overflow(8, &sprites));
+}
+
+/* With rendering on or off, wait until partway along scanline 100, then read PPUDATA.
+   Returns the VRAM address before and after, and whether the line was still being drawn. */
+fn read_ppudata_on_line_100(mask: u8) -> (u16, u16, bool) {
+    /* C000: LDA #mask; STA $2001; C005: LDA $10; BEQ C005; LDA $2007; JMP * */
+    let mut nes = nrom(&[0xA9, mask, 0x8D, 0x01, 0x20, 0xA5, 0x10, 0xF0, 0xFC, 0xAD, 0x07, 0x20, 0x4C, 0x0C, 0xC0]);
+    loop {
+        nes.tick().unwrap();
+        nes.catch_up();
+        if nes.ppu().scanline == 100 && nes.ppu().tick >= 32 {
+            break;
+        }
+    }
+    let before = nes.ppu().vram_addresses().0;
+    nes.poke(MemorySpace::Cpu, 0x10, 1).unwrap();
+    while nes.cpu().PC != 0xC00C {
+        nes.step().unwrap();
+    }
+    nes.catch_up();
+    let drawing = nes.ppu().scanline == 100 && nes.ppu().tick < 256;
+    (before, nes.ppu().vram_addresses().0, drawing)
+}
+
+#[test]
+fn ppudata_access_while_rendering_increments_fine_y() {
+    /* The fine Y scroll in v's top bits moves to the next row, well before the end of the line */
+    let (before, after, drawing) = read_ppudata_on_line_100(0x18);
+    assert!(drawing);
+    assert_eq!((after >> 12) & 7, ((before >> 12) + 1) & 7);
+}
+
+#[test]
+fn ppudata_access_without_rendering_increments_by_one() {
+    let (before, after, _) = read_ppudata_on_line_100(0x00);
+    assert_eq!(after, before + 1);
+}
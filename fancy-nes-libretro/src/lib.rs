@@ -0,0 +1,360 @@
+//! A libretro core over fancy-nes-core, so that RetroArch and other libretro
+//! frontends can run fancy-nes, with their shaders, netplay and the rest.
+//!
+//! The frontend drives the core from one thread through the functions below:
+//! it hands over its callbacks, loads a game, and then calls retro_run once per
+//! frame, which polls input, runs the NES for a frame, and passes the frame and
+//! its audio back through the callbacks. Save states, battery-backed RAM and
+//! Game Genie codes are exposed as libretro expects them.
+
+// Each function's obligations on its caller are those set out by libretro.h
+#![allow(clippy::missing_safety_doc)]
+
+mod libretro;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+
+use fancy_nes_core::{Nes, Region};
+use fancy_nes_core::cheats::Cheats;
+use fancy_nes_core::cpu::controller::JoypadButton;
+use fancy_nes_core::nes::{FRAME_WIDTH, FRAME_HEIGHT};
+
+use libretro::*;
+
+const SAMPLE_RATE: u32 = 44100;
+
+/* Each libretro joypad button, and the NES button it presses */
+const BUTTONS: [(u32, JoypadButton); 8] = [
+    (DEVICE_ID_JOYPAD_A, JoypadButton::A),
+    (DEVICE_ID_JOYPAD_B, JoypadButton::B),
+    (DEVICE_ID_JOYPAD_SELECT, JoypadButton::SELECT),
+    (DEVICE_ID_JOYPAD_START, JoypadButton::START),
+    (DEVICE_ID_JOYPAD_UP, JoypadButton::UP),
+    (DEVICE_ID_JOYPAD_DOWN, JoypadButton::DOWN),
+    (DEVICE_ID_JOYPAD_LEFT, JoypadButton::LEFT),
+    (DEVICE_ID_JOYPAD_RIGHT, JoypadButton::RIGHT),
+];
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    nes: Nes,
+    colours: Vec<u32>,  /* XRGB8888 for each pixel value in a frame */
+    video: Vec<u32>,
+    audio: Vec<f32>,
+    samples: Vec<i16>,  /* Interleaved stereo, as the frontend takes it */
+    halted: bool,       /* After an emulation error, until the game is reset or a state loaded */
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> MutexGuard<'static, Callbacks> {
+    CALLBACKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> u32 {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *core() = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemInfo {
+            library_name: b"fancy-nes\0".as_ptr() as *const c_char,
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: b"nes\0".as_ptr() as *const c_char,
+            need_fullpath: false,
+            block_extract: false,
+        };
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let region = core().as_ref().map_or(Region::NTSC, |core| core.nes.region());
+    if let Some(info) = info.as_mut() {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: FRAME_WIDTH as u32,
+                base_height: FRAME_HEIGHT as u32,
+                max_width: FRAME_WIDTH as u32,
+                max_height: FRAME_HEIGHT as u32,
+                aspect_ratio: 4.0 / 3.0,
+            },
+            timing: SystemTiming {
+                fps: region.frame_rate(),
+                sample_rate: SAMPLE_RATE as f64,
+            },
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: EnvironmentFn) {
+    callbacks().environment = Some(environment);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: VideoRefreshFn) {
+    callbacks().video_refresh = Some(video_refresh);
+}
+
+/* Audio is only sent in batches */
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: AudioSampleBatchFn) {
+    callbacks().audio_sample_batch = Some(audio_sample_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: InputPollFn) {
+    callbacks().input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: InputStateFn) {
+    callbacks().input_state = Some(input_state);
+}
+
+/* Both ports always have a standard controller plugged in */
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: u32, _device: u32) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    let game = match game.as_ref() {
+        Some(game) if !game.data.is_null() => game,
+        _ => return false,
+    };
+
+    let rom = slice::from_raw_parts(game.data as *const u8, game.size);
+    let mut nes = match Nes::from_rom(rom) {
+        Ok(nes) => nes,
+        Err(e) => {
+            eprintln!("fancy-nes: {}", e);
+            return false;
+        }
+    };
+    nes.set_sample_rate(SAMPLE_RATE);
+
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    if let Some(environment) = callbacks().environment {
+        if !environment(ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut u32 as *mut c_void) {
+            eprintln!("fancy-nes: The frontend doesn't support XRGB8888");
+            return false;
+        }
+    }
+
+    let colours = nes.palette().frame_colours().iter()
+        .map(|&[r, g, b]| (r as u32) << 16 | (g as u32) << 8 | b as u32)
+        .collect();
+    *core() = Some(Core {
+        nes,
+        colours,
+        video: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+        audio: vec![0.0; 1024],
+        samples: Vec::new(),
+        halted: false,
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: u32, _info: *const GameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *core() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> u32 {
+    match core().as_ref().map(|core| core.nes.region()) {
+        Some(Region::PAL | Region::Dendy) => REGION_PAL,
+        _ => REGION_NTSC,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = core().as_mut() {
+        core.halted = core.nes.reset().map_err(|e| eprintln!("fancy-nes: {}", e)).is_err();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let (video_refresh, audio_sample_batch, input_poll, input_state) = {
+        let callbacks = callbacks();
+        (callbacks.video_refresh, callbacks.audio_sample_batch, callbacks.input_poll, callbacks.input_state)
+    };
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return,
+    };
+
+    if let (Some(input_poll), Some(input_state)) = (input_poll, input_state) {
+        input_poll();
+        for port in 0..2 {
+            let buttons = BUTTONS.iter()
+                .filter(|(id, _)| input_state(port as u32, DEVICE_JOYPAD, 0, *id) != 0)
+                .fold(0, |buttons, (_, button)| buttons | button.bits());
+            core.nes.set_controller(port, buttons);
+        }
+    }
+
+    /* A fault stops emulation, leaving the last frame on screen, as the SDL frontend's debugger would */
+    if !core.halted {
+        if let Err(e) = core.nes.run_frame() {
+            eprintln!("fancy-nes: {}", e);
+            core.halted = true;
+        }
+    }
+
+    if let Some(video_refresh) = video_refresh {
+        for (out, &pixel) in core.video.iter_mut().zip(core.nes.framebuffer().iter()) {
+            *out = core.colours[pixel as usize & 0x1FF];
+        }
+        video_refresh(core.video.as_ptr() as *const c_void, FRAME_WIDTH as u32, FRAME_HEIGHT as u32, FRAME_WIDTH * 4);
+    }
+
+    core.samples.clear();
+    loop {
+        let count = core.nes.take_samples(&mut core.audio);
+        if count == 0 {
+            break;
+        }
+        for &sample in &core.audio[..count] {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            core.samples.extend([sample, sample]);
+        }
+    }
+    if let Some(audio_sample_batch) = audio_sample_batch {
+        audio_sample_batch(core.samples.as_ptr(), core.samples.len() / 2);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    core().as_ref().map_or(0, |core| core.nes.save_state().len())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match core().as_ref() {
+        Some(core) => core.nes.save_state(),
+        None => return false,
+    };
+    if data.is_null() || state.len() > size {
+        return false;
+    }
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = core();
+    let core = match core.as_mut() {
+        Some(core) if !data.is_null() => core,
+        _ => return false,
+    };
+    match core.nes.load_state(slice::from_raw_parts(data as *const u8, size)) {
+        Ok(()) => {
+            core.halted = false;
+            true
+        }
+        Err(e) => {
+            eprintln!("fancy-nes: {}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(core) = core().as_mut() {
+        core.nes.cpu_mut().bus.cheats = Cheats::new();
+    }
+}
+
+/* Codes entered together are joined with + */
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: u32, enabled: bool, code: *const c_char) {
+    if !enabled || code.is_null() {
+        return;
+    }
+    if let Some(core) = core().as_mut() {
+        for code in CStr::from_ptr(code).to_string_lossy().split('+') {
+            if let Err(e) = core.nes.cpu_mut().bus.cheats.add(code.trim()) {
+                eprintln!("fancy-nes: {}", e);
+            }
+        }
+    }
+}
+
+/* The frontend reads and writes these directly: battery RAM to load and save
+   the game's .srm, and the console's own RAM for achievements and the like */
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: u32) -> *mut c_void {
+    let mut core = core();
+    let nes = match core.as_mut() {
+        Some(core) => &mut core.nes,
+        None => return ptr::null_mut(),
+    };
+    match id {
+        MEMORY_SAVE_RAM => nes.battery_ram().map_or(ptr::null_mut(), |ram| ram.as_mut_ptr() as *mut c_void),
+        MEMORY_SYSTEM_RAM => nes.cpu_mut().bus.internal_ram.as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: u32) -> usize {
+    let mut core = core();
+    let nes = match core.as_mut() {
+        Some(core) => &mut core.nes,
+        None => return 0,
+    };
+    match id {
+        MEMORY_SAVE_RAM => nes.battery_ram().map_or(0, |ram| ram.len()),
+        MEMORY_SYSTEM_RAM => nes.cpu().bus.internal_ram.len(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,72 @@
+// The parts of libretro.h which the core uses, transcribed by hand rather than
+// generated, to keep the crate free of a bindgen build step. Names follow the
+// header, less their RETRO_ prefixes.
+
+use std::os::raw::{c_char, c_void};
+
+pub const API_VERSION: u32 = 1;
+
+pub const DEVICE_JOYPAD: u32 = 1;
+
+pub const DEVICE_ID_JOYPAD_B: u32 = 0;
+pub const DEVICE_ID_JOYPAD_SELECT: u32 = 2;
+pub const DEVICE_ID_JOYPAD_START: u32 = 3;
+pub const DEVICE_ID_JOYPAD_UP: u32 = 4;
+pub const DEVICE_ID_JOYPAD_DOWN: u32 = 5;
+pub const DEVICE_ID_JOYPAD_LEFT: u32 = 6;
+pub const DEVICE_ID_JOYPAD_RIGHT: u32 = 7;
+pub const DEVICE_ID_JOYPAD_A: u32 = 8;
+
+pub const REGION_NTSC: u32 = 0;
+pub const REGION_PAL: u32 = 1;
+
+pub const MEMORY_SAVE_RAM: u32 = 0;
+pub const MEMORY_SYSTEM_RAM: u32 = 2;
+
+pub const ENVIRONMENT_SET_PIXEL_FORMAT: u32 = 10;
+pub const PIXEL_FORMAT_XRGB8888: u32 = 1;
+
+pub type EnvironmentFn = extern "C" fn(cmd: u32, data: *mut c_void) -> bool;
+pub type VideoRefreshFn = extern "C" fn(data: *const c_void, width: u32, height: u32, pitch: usize);
+pub type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+pub type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type InputPollFn = extern "C" fn();
+pub type InputStateFn = extern "C" fn(port: u32, device: u32, index: u32, id: u32) -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,  /* Separated by |, e.g. "nes|fds" */
+    pub need_fullpath: bool,              /* Whether load_game must be given a path rather than the data */
+    pub block_extract: bool,              /* Whether the frontend must leave archives to the core */
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    pub base_width: u32,
+    pub base_height: u32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub aspect_ratio: f32,  /* 0 for width/height */
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    pub geometry: GameGeometry,
+    pub timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
@@ -0,0 +1,160 @@
+use std::cell::RefMut;
+use std::collections::VecDeque;
+use fancy_nes_core::Nes;
+use fancy_nes_core::apu::{Channel, ChannelState};
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{Canvas, TextureCreator, TextureQuery};
+use sdl2::ttf::Sdl2TtfContext;
+use sdl2::video::{Window, WindowContext};
+
+use crate::{NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT};
+
+const TOP: i32 = 24;  /* below the title line */
+const ROW_HEIGHT: i32 = 64;
+const SCOPE_LEFT: i32 = 256;
+
+/* The piano roll, along the bottom: a column a frame, and the notes from C1 to C8 */
+const ROLL_FRAMES: usize = 256;
+const ROLL_LOWEST: u8 = 24;
+const ROLL_HIGHEST: u8 = 108;
+
+/* The channels the piano roll follows, and their colours there and in the scopes */
+const MELODIC: [Channel; 3] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle];
+const COLOURS: [Color; 5] = [
+    Color::RGB(255, 96, 96),
+    Color::RGB(255, 192, 64),
+    Color::RGB(96, 160, 255),
+    Color::RGB(192, 192, 192),
+    Color::RGB(128, 255, 128),
+];
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/* The highest level each channel puts out, to scale its scope to */
+const MAX_OUTPUT: [u8; 5] = [15, 15, 15, 15, 127];
+
+/// The APU's channels, drawn over the game screen: each one's registers and
+/// counters, the note it's playing and its output over the last few hundred
+/// samples, with a piano roll of the pulses' and triangle's notes beneath.
+/// Muted channels are greyed out, and the mixer's settings are shown in the title.
+pub struct ApuView<'a> {
+    font: sdl2::ttf::Font<'a, 'static>,
+    texture_creator: TextureCreator<WindowContext>,
+    roll: VecDeque<[Option<u8>; 3]>,  /* The MELODIC channels' notes, a frame at a time, the latest last */
+}
+
+impl<'a> ApuView<'a> {
+    pub fn new(texture_creator: TextureCreator<WindowContext>, ttf_context: &'a Sdl2TtfContext) -> Self {
+        Self {
+            font: ttf_context.load_font("debug.ttf", 12).unwrap(),
+            texture_creator,
+            roll: VecDeque::with_capacity(ROLL_FRAMES),
+        }
+    }
+
+    pub fn render(&mut self, mut canvas: RefMut<Canvas<Window>>, nes: &Nes) {
+        let apu = nes.apu();
+        let line = self.font.recommended_line_spacing();
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.fill_rect(Rect::new(0, 0, NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT)).unwrap();
+        let mixer = apu.mixer();
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let title = format!("APU - 1-6 mute, -/= volume {}%, H high-pass {}, L low-pass {}", (mixer.volume * 100.0).round(),
+            on_off(mixer.high_pass), on_off(mixer.low_pass));
+        self.draw_text(&mut canvas, &title, Color::RGB(255, 255, 255), 10, 4);
+
+        for (i, channel) in Channel::ALL.into_iter().enumerate() {
+            let top = TOP + i as i32 * ROW_HEIGHT;
+            let volume = mixer.channels[channel as usize];
+            let muted = if apu.muted(channel) { " (muted)".to_string() }
+                else if volume < 1.0 { format!(" ({}%)", (volume * 100.0).round()) }
+                else { String::new() };
+            let title = format!("{} {}{}", i + 1, channel.name(), muted);
+            let colour = if apu.muted(channel) { Color::RGB(128, 128, 128) } else { Color::RGB(255, 255, 255) };
+            self.draw_text(&mut canvas, &title, colour, 10, top);
+            let Some(state) = apu.channel_state(channel) else {
+                self.draw_text(&mut canvas, "Mixed in from the cartridge", Color::RGB(192, 192, 192), 18, top + line);
+                continue;
+            };
+            for (n, text) in describe(channel, &state).iter().enumerate() {
+                self.draw_text(&mut canvas, text, Color::RGB(192, 192, 192), 18, top + line * (n as i32 + 1));
+            }
+
+            let scope: Vec<u8> = apu.scope(channel).collect();
+            let area = Rect::new(SCOPE_LEFT, top + 4, NES_SCREEN_WIDTH - SCOPE_LEFT as u32 - 8, ROW_HEIGHT as u32 - 8);
+            canvas.set_draw_color(Color::RGB(32, 32, 32));
+            canvas.fill_rect(area).unwrap();
+            canvas.set_draw_color(COLOURS[i]);
+            let points: Vec<Point> = (0..area.width() as usize).map(|x| {
+                let level = scope[x * scope.len() / area.width() as usize] as i32;
+                Point::new(area.x() + x as i32, area.bottom() - 1 - level * (area.height() as i32 - 1) / MAX_OUTPUT[i] as i32)
+            }).collect();
+            canvas.draw_lines(&points[..]).unwrap();
+        }
+
+        // The piano roll moves on a column with each frame drawn
+        if self.roll.len() == ROLL_FRAMES {
+            self.roll.pop_front();
+        }
+        self.roll.push_back(MELODIC.map(|channel| apu.channel_state(channel).filter(|_| !apu.muted(channel)).and_then(|state| note(&state))));
+
+        let area = Rect::new(0, TOP + Channel::ALL.len() as i32 * ROW_HEIGHT, NES_SCREEN_WIDTH,
+            NES_SCREEN_HEIGHT - (TOP + Channel::ALL.len() as i32 * ROW_HEIGHT) as u32);
+        canvas.set_draw_color(Color::RGB(24, 24, 24));
+        canvas.fill_rect(area).unwrap();
+        let column = (area.width() / ROLL_FRAMES as u32).max(1);
+        let row = (area.height() / (ROLL_HIGHEST - ROLL_LOWEST) as u32).max(1);
+        for (x, notes) in self.roll.iter().enumerate() {
+            for (colour, note) in COLOURS.iter().zip(notes) {
+                let Some(note) = note.filter(|note| (ROLL_LOWEST..ROLL_HIGHEST).contains(note)) else { continue };
+                let y = area.bottom() - ((note - ROLL_LOWEST) as u32 * area.height() / (ROLL_HIGHEST - ROLL_LOWEST) as u32) as i32;
+                canvas.set_draw_color(*colour);
+                canvas.fill_rect(Rect::new(area.x() + x as i32 * column as i32, y - row as i32, column, row)).unwrap();
+            }
+        }
+    }
+
+    fn draw_text(&self, canvas: &mut Canvas<Window>, text: &str, colour: Color, x: i32, y: i32) {
+        let surface = self.font
+            .render(text)
+            .blended(colour)
+            .map_err(|e| e.to_string()).unwrap();
+        let texture = self.texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(x, y, width, height))).unwrap();
+    }
+}
+
+/* The MIDI number of the note a channel's playing, if it's audible and in tune with anything */
+fn note(state: &ChannelState) -> Option<u8> {
+    if state.length == 0 || state.volume == 0 || !(20.0..=20000.0).contains(&state.frequency) {
+        return None;
+    }
+    let note = 69.0 + 12.0 * (state.frequency / 440.0).log2();
+    Some(note.round() as u8)
+}
+
+fn note_name(note: u8) -> String {
+    format!("{}{}", NOTE_NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/* A channel's state, a couple of lines of it */
+fn describe(channel: Channel, state: &ChannelState) -> Vec<String> {
+    let enabled = if state.enabled { "on" } else { "off" };
+    let note = if MELODIC.contains(&channel) { note(state).map_or("-".to_string(), note_name) } else { "-".to_string() };
+    let first = format!("{} period ${:0>3X} {:.1}Hz {}", enabled, state.period, state.frequency, note);
+    let volume = match channel {
+        Channel::Triangle => format!("linear {}", state.volume),
+        Channel::Dmc => format!("level {}", state.volume),
+        _ if state.constant_volume => format!("volume {}", state.volume),
+        _ => format!("envelope {}", state.volume),
+    };
+    let duty = state.duty.map_or(String::new(), |duty| format!(" duty {}", ["12.5%", "25%", "50%", "75%"][duty as usize & 0x3]));
+    let length = if channel == Channel::Dmc { "bytes" } else { "length" };
+    let looping = if state.looping { " loop" } else { "" };
+    vec![first, format!("{}{} {} {}{}", volume, duty, length, state.length, looping)]
+}
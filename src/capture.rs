@@ -0,0 +1,168 @@
+//! Recording gameplay to disk: every frame the emulator completes, and the audio
+//! generated alongside it. Frames are piped to ffmpeg as raw video when it can be
+//! run, and otherwise saved as a numbered PNG sequence; either way the audio is
+//! written to a WAV file beside them, and muxed into the video once ffmpeg is done.
+//!
+//! Encoding happens on a thread of its own, fed over a channel like the emulator's,
+//! so that a slow encoder or disk doesn't hold up the UI.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+use fancy_nes_core::nes::{Frame, FRAME_WIDTH, FRAME_HEIGHT};
+use fancy_nes_core::palette::Palette;
+use fancy_nes_core::png;
+
+use crate::wav::Wav;
+
+pub struct Capture {
+    frames: Option<Sender<(Box<Frame>, Vec<f32>)>>,
+    writer: Option<JoinHandle<Result<String, String>>>,
+}
+
+impl Capture {
+    /// Start capturing to files named after `path`: path.mp4 by way of ffmpeg if it is
+    /// installed, or else path/frame-00001.png onwards, with the audio in path.wav
+    pub fn start(path: &Path, palette: Palette, frame_rate: f64, sample_rate: u32) -> Result<Self, String> {
+        let video = Video::start(path, frame_rate)?;
+        let audio = Wav::create(&with_suffix(path, ".wav"), sample_rate)?;
+        let (frames, frame_rx) = mpsc::channel::<(Box<Frame>, Vec<f32>)>();
+
+        let writer = thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || {
+                let mut video = video;
+                let mut audio = audio;
+                let colours = palette.frame_colours();
+
+                for (frame, samples) in frame_rx {
+                    let pixels: Vec<[u8; 3]> = frame.iter().map(|&pixel| colours[pixel as usize & 0x1FF]).collect();
+                    video.write(&pixels)?;
+                    audio.write(&samples)?;
+                }
+                audio.finish()?;
+                video.finish(audio.path())
+            })
+            .map_err(|e| format!("Can't start capture thread: {}", e))?;
+
+        Ok(Self { frames: Some(frames), writer: Some(writer) })
+    }
+
+    /// Add a frame, and the audio generated alongside it
+    pub fn frame(&self, frame: &Frame, audio: &[f32]) {
+        /* If the writer has stopped, it has an error to report from finish */
+        if let Some(frames) = &self.frames {
+            let _ = frames.send((Box::new(*frame), audio.to_vec()));
+        }
+    }
+
+    /// Stop capturing, waiting for everything to be written. Returns a description
+    /// of what was saved where.
+    pub fn finish(mut self) -> Result<String, String> {
+        self.frames = None;
+        match self.writer.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err("The capture thread panicked".to_string()),
+            None => Err("The capture was already finished".to_string()),
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        /* Let the writer see the channel close, and finish the files it has open */
+        self.frames = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Video {
+    Ffmpeg { ffmpeg: Child, stdin: ChildStdin, path: PathBuf },
+    Images { dir: PathBuf, count: u32 },
+}
+
+impl Video {
+    fn start(path: &Path, frame_rate: f64) -> Result<Self, String> {
+        /* ffmpeg encodes to a temporary file, which is muxed with the audio when it ends */
+        let video_path = with_suffix(path, ".video.mp4");
+        let ffmpeg = Command::new("ffmpeg")
+            .args(["-y", "-loglevel", "error", "-f", "rawvideo", "-pixel_format", "rgb24"])
+            .args(["-video_size", &format!("{}x{}", FRAME_WIDTH, FRAME_HEIGHT), "-framerate", &frame_rate.to_string()])
+            .args(["-i", "-", "-pix_fmt", "yuv420p"])
+            .arg(&video_path)
+            .stdin(Stdio::piped())
+            .spawn();
+
+        match ffmpeg {
+            Ok(mut ffmpeg) => {
+                let stdin = ffmpeg.stdin.take().ok_or("Can't write to ffmpeg")?;
+                Ok(Video::Ffmpeg { ffmpeg, stdin, path: path.to_path_buf() })
+            }
+            Err(_) => {
+                let dir = path.to_path_buf();
+                fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+                Ok(Video::Images { dir, count: 0 })
+            }
+        }
+    }
+
+    fn write(&mut self, pixels: &[[u8; 3]]) -> Result<(), String> {
+        match self {
+            Video::Ffmpeg { stdin, .. } => {
+                stdin.write_all(pixels.concat().as_slice()).map_err(|e| format!("Can't write to ffmpeg: {}", e))
+            }
+            Video::Images { dir, count } => {
+                *count += 1;
+                let path = dir.join(format!("frame-{:0>5}.png", count));
+                fs::write(&path, png::encode(FRAME_WIDTH, FRAME_HEIGHT, pixels))
+                    .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+            }
+        }
+    }
+
+    fn finish(self, audio: &Path) -> Result<String, String> {
+        match self {
+            Video::Ffmpeg { mut ffmpeg, stdin, path } => {
+                drop(stdin);
+                let video = with_suffix(&path, ".video.mp4");
+                let encoded = ffmpeg.wait().map_err(|e| format!("ffmpeg failed: {}", e))?;
+                if !encoded.success() {
+                    return Err(format!("ffmpeg failed ({}); the audio is in {}", encoded, audio.display()));
+                }
+
+                let output = with_suffix(&path, ".mp4");
+                let muxed = Command::new("ffmpeg")
+                    .args(["-y", "-loglevel", "error", "-i"]).arg(&video)
+                    .arg("-i").arg(audio)
+                    .args(["-c:v", "copy", "-c:a", "aac", "-shortest"])
+                    .arg(&output)
+                    .status();
+                match muxed {
+                    Ok(status) if status.success() => {
+                        let _ = fs::remove_file(&video);
+                        let _ = fs::remove_file(audio);
+                        Ok(format!("Saved the capture to {}", output.display()))
+                    }
+                    _ => Ok(format!("Saved the capture to {} and {}, which ffmpeg couldn't combine",
+                        video.display(), audio.display())),
+                }
+            }
+            Video::Images { dir, count } => {
+                Ok(format!("Saved {} frames to {}, and the audio to {}", count, dir.display(), audio.display()))
+            }
+        }
+    }
+}
+
+/// Name a file by adding to a path, as ROM names often contain dots of their own
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,378 @@
+//! Persistent frontend settings, kept in `$XDG_CONFIG_HOME/fancy-nes/config.toml`
+//! (or `~/.config/fancy-nes/config.toml`). Settings given on the command line
+//! take precedence for that run, but aren't saved.
+//!
+//!     scale = 2                 # window size, in multiples of the NES's 256x240
+//!     aspect_correction = false # widen pixels to the 8:7 shape they have on a TV
+//!     overscan = [8, 8]         # lines hidden at the top and bottom, up to 16 each
+//!     smooth = false            # scale the picture up bilinearly, rather than by nearest pixel
+//!     scanlines = 0.0           # how much to darken every other line, from 0 to 1
+//!     curvature = 0.0           # how much to curve the picture, as a CRT's glass, from 0 to 1
+//!     shader = "crt.glsl"       # a fragment shader to draw the picture with (see post_process.rs)
+//!     turbo_rate = 15           # turbo button presses per second, up to 30
+//!     fast_forward = 0          # speed while Tab is held, as a multiple, or 0 for flat out
+//!     slow_motion = 2           # how many times slower slow motion (`) runs
+//!     overclock = false         # add idle scanlines to each frame (Ctrl+O), for games which lag
+//!     overclock_lines = [100, 0] # how many, before vblank and after it, up to 1000 in all
+//!     palette = "data/palette/default.pal"
+//!     game_db = "nes20db.xml"   # NES 2.0 XML database to correct ROM headers by
+//!     recent_roms = ["smb.nes"]
+//!
+//!     [ntsc]                    # generate the palette instead (see NtscSettings)
+//!     hue = 0.0                 # in degrees
+//!     saturation = 1.0
+//!     brightness = 0.0
+//!     contrast = 1.0
+//!     gamma = 2.0
+//!
+//!     [debugger]
+//!     show = false              # show the debugger at startup
+//!     show_ppu_info = false
+//!
+//!     [input]                   # bindings, as for --input (see InputMap::from_toml)
+//!     port1 = { a = "Z", b = "X" }
+//!
+//!     [devices]                 # what's plugged in: "joypad", "paddle", "keyboard", "fourscore" or "none"
+//!     port1 = "joypad"          # a Four Score goes in both ports
+//!     port2 = "joypad"
+//!     expansion = "none"        # the Famicom's expansion port
+//!
+//!     [mixer]                   # (see MixerSettings)
+//!     volume = 1.0              # master volume, from 0 to 1
+//!     pulse1 = 1.0              # each channel's, likewise: pulse1, pulse2, triangle, noise, dmc, expansion
+//!     high_pass = false         # the NES's 90Hz and 440Hz high-pass filters
+//!     low_pass = false          # and its 14kHz low-pass filter
+//!     stems = false             # record each channel to a WAV of its own too (F9)
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fancy_nes_core::Overclock;
+use fancy_nes_core::apu::{Channel, MixerSettings};
+use fancy_nes_core::cpu::controller::{DeviceKind, PORTS};
+use fancy_nes_core::palette::NtscSettings;
+use toml::value::{Table, Value};
+
+const MAX_RECENT_ROMS: usize = 10;
+
+/* Turbo presses last at least a frame, and so do the releases between them */
+const MAX_TURBO_RATE: u32 = 30;
+
+const MAX_SPEED_FACTOR: u32 = 16;
+
+const MAX_OVERSCAN: u32 = 16;
+
+/* The names of the ports in [devices], controller::EXPANSION_PORT last */
+const PORT_NAMES: [&str; PORTS] = ["port1", "port2", "expansion"];
+
+/* Past this, the CPU's extra time is unlikely to help any game further */
+const MAX_OVERCLOCK_LINES: u16 = 1000;
+
+pub struct Config {
+    pub scale: u32,
+    pub aspect_correction: bool,
+    pub overscan: [u32; 2],  /* Lines hidden at the top and bottom */
+    pub smooth: bool,
+    pub scanlines: f32,
+    pub curvature: f32,
+    pub shader: Option<PathBuf>,  /* In place of data/shaders/crt.glsl */
+    pub turbo_rate: u32,  /* Presses per second */
+    pub fast_forward: u32,  /* Times normal speed, or 0 for as fast as possible */
+    pub slow_motion: u32,   /* Times slower than normal speed */
+    pub overclock: bool,
+    pub overclock_lines: Overclock,
+    pub palette: Option<PathBuf>,
+    pub game_db: Option<PathBuf>,
+    pub ntsc: Option<NtscSettings>,  /* Only used without a palette file */
+    pub recent_roms: Vec<PathBuf>,  /* Most recent first */
+    pub show_debugger: bool,
+    pub show_ppu_info: bool,
+    pub input: Option<Table>,
+    pub devices: [DeviceKind; PORTS],
+    pub mixer: MixerSettings,
+    pub stems: bool,  /* Whether audio recordings keep each channel too */
+
+    path: Option<PathBuf>,  /* None if there is nowhere to save to */
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scale: 2,
+            aspect_correction: false,
+            overscan: [0, 0],
+            smooth: false,
+            scanlines: 0.0,
+            curvature: 0.0,
+            shader: None,
+            turbo_rate: 15,
+            fast_forward: 0,
+            slow_motion: 2,
+            overclock: false,
+            overclock_lines: Overclock { pre_nmi: 100, post_nmi: 0 },
+            palette: None,
+            game_db: None,
+            ntsc: None,
+            recent_roms: Vec::new(),
+            show_debugger: false,
+            show_ppu_info: false,
+            input: None,
+            devices: [DeviceKind::Joypad, DeviceKind::Joypad, DeviceKind::None],
+            mixer: MixerSettings::default(),
+            stems: false,
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Where the config file lives, following the XDG base directory spec
+    pub fn default_path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(base.join("fancy-nes").join("config.toml"))
+    }
+
+    /// Load the config file, or the defaults if there isn't one yet. A config
+    /// file which can't be read is reported and left alone, rather than overwritten.
+    pub fn load() -> Self {
+        let path = match Config::default_path() {
+            Some(path) => path,
+            None => return Config::default(),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(text) => match Config::parse(&text) {
+                Ok(config) => Config { path: Some(path), ..config },
+                Err(e) => {
+                    println!("Ignoring config {}: {}", path.display(), e);
+                    Config::default()
+                }
+            },
+            Err(_) => Config { path: Some(path), ..Config::default() },
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table = match text.parse::<Value>().map_err(|e| e.to_string())? {
+            Value::Table(table) => table,
+            _ => return Err("config is not a table".to_string()),
+        };
+        let mut config = Config::default();
+
+        if let Some(scale) = table.get("scale") {
+            config.scale = scale.as_integer().filter(|s| (1..=8).contains(s))
+                .ok_or("scale should be a whole number from 1 to 8")? as u32;
+        }
+        if let Some(aspect_correction) = table.get("aspect_correction") {
+            config.aspect_correction = aspect_correction.as_bool().ok_or("aspect_correction should be true or false")?;
+        }
+        if let Some(overscan) = table.get("overscan") {
+            let lines: Vec<u32> = overscan.as_array().map_or(vec![], |lines| lines.iter()
+                .filter_map(Value::as_integer)
+                .filter(|l| (0..=MAX_OVERSCAN as i64).contains(l))
+                .map(|l| l as u32)
+                .collect());
+            config.overscan = match lines[..] {
+                [top, bottom] => [top, bottom],
+                _ => return Err(format!("overscan should be [top, bottom], with each from 0 to {} lines", MAX_OVERSCAN)),
+            };
+        }
+        if let Some(smooth) = table.get("smooth") {
+            config.smooth = smooth.as_bool().ok_or("smooth should be true or false")?;
+        }
+        if let Some(scanlines) = table.get("scanlines") {
+            config.scanlines = scanlines.as_float().or_else(|| scanlines.as_integer().map(|s| s as f64))
+                .filter(|s| (0.0..=1.0).contains(s))
+                .ok_or("scanlines should be a number from 0 to 1")? as f32;
+        }
+        if let Some(curvature) = table.get("curvature") {
+            config.curvature = curvature.as_float().or_else(|| curvature.as_integer().map(|c| c as f64))
+                .filter(|c| (0.0..=1.0).contains(c))
+                .ok_or("curvature should be a number from 0 to 1")? as f32;
+        }
+        if let Some(shader) = table.get("shader") {
+            config.shader = Some(PathBuf::from(shader.as_str().ok_or("shader should be a path")?));
+        }
+        if let Some(rate) = table.get("turbo_rate") {
+            config.turbo_rate = rate.as_integer().filter(|r| (1..=MAX_TURBO_RATE as i64).contains(r))
+                .ok_or_else(|| format!("turbo_rate should be a whole number from 1 to {}", MAX_TURBO_RATE))? as u32;
+        }
+        if let Some(fast_forward) = table.get("fast_forward") {
+            config.fast_forward = fast_forward.as_integer().filter(|f| (0..=MAX_SPEED_FACTOR as i64).contains(f))
+                .ok_or_else(|| format!("fast_forward should be a whole number from 0 to {}", MAX_SPEED_FACTOR))? as u32;
+        }
+        if let Some(slow_motion) = table.get("slow_motion") {
+            config.slow_motion = slow_motion.as_integer().filter(|s| (2..=MAX_SPEED_FACTOR as i64).contains(s))
+                .ok_or_else(|| format!("slow_motion should be a whole number from 2 to {}", MAX_SPEED_FACTOR))? as u32;
+        }
+        if let Some(overclock) = table.get("overclock") {
+            config.overclock = overclock.as_bool().ok_or("overclock should be true or false")?;
+        }
+        if let Some(lines) = table.get("overclock_lines") {
+            let lines: Vec<u16> = lines.as_array().map_or(vec![], |lines| lines.iter()
+                .filter_map(Value::as_integer)
+                .filter(|l| (0..=MAX_OVERCLOCK_LINES as i64).contains(l))
+                .map(|l| l as u16)
+                .collect());
+            config.overclock_lines = match lines[..] {
+                [pre_nmi, post_nmi] if pre_nmi + post_nmi <= MAX_OVERCLOCK_LINES => Overclock { pre_nmi, post_nmi },
+                _ => return Err(format!("overclock_lines should be [before vblank, after it], with up to {} lines in all", MAX_OVERCLOCK_LINES)),
+            };
+        }
+        if let Some(palette) = table.get("palette") {
+            config.palette = Some(PathBuf::from(palette.as_str().ok_or("palette should be a path")?));
+        }
+        if let Some(game_db) = table.get("game_db") {
+            config.game_db = Some(PathBuf::from(game_db.as_str().ok_or("game_db should be a path")?));
+        }
+        if let Some(ntsc) = table.get("ntsc") {
+            let ntsc = ntsc.as_table().ok_or("[ntsc] should be a table")?;
+            let mut settings = NtscSettings::default();
+            for (key, value) in ntsc {
+                let value = value.as_float().or_else(|| value.as_integer().map(|v| v as f64))
+                    .ok_or_else(|| format!("ntsc.{} should be a number", key))? as f32;
+                match key.as_str() {
+                    "hue" => settings.hue = value,
+                    "saturation" => settings.saturation = value,
+                    "brightness" => settings.brightness = value,
+                    "contrast" => settings.contrast = value,
+                    "gamma" if value > 0.0 => settings.gamma = value,
+                    "gamma" => return Err("ntsc.gamma should be positive".to_string()),
+                    _ => return Err(format!("Unknown NTSC setting {}", key)),
+                }
+            }
+            config.ntsc = Some(settings);
+        }
+        if let Some(recent) = table.get("recent_roms") {
+            config.recent_roms = recent.as_array().ok_or("recent_roms should be a list of paths")?
+                .iter().filter_map(|rom| rom.as_str()).map(PathBuf::from).collect();
+        }
+        if let Some(debugger) = table.get("debugger") {
+            let debugger = debugger.as_table().ok_or("[debugger] should be a table")?;
+            config.show_debugger = debugger.get("show").and_then(Value::as_bool).unwrap_or(false);
+            config.show_ppu_info = debugger.get("show_ppu_info").and_then(Value::as_bool).unwrap_or(false);
+        }
+        if let Some(input) = table.get("input") {
+            config.input = Some(input.as_table().ok_or("[input] should be a table")?.clone());
+        }
+        if let Some(devices) = table.get("devices") {
+            let devices = devices.as_table().ok_or("[devices] should be a table")?;
+            for (key, value) in devices {
+                let port = PORT_NAMES.iter().position(|name| name == key).ok_or_else(|| format!("Unknown port devices.{}", key))?;
+                let names: Vec<&str> = DeviceKind::ALL.iter().map(|kind| kind.name()).collect();
+                let kind = value.as_str().and_then(DeviceKind::from_name)
+                    .ok_or_else(|| format!("devices.{} should be one of {}", key, names.join(", ")))?;
+                kind.create(port)?;
+                config.devices[port] = kind;
+            }
+            if (config.devices[0] == DeviceKind::FourScore) != (config.devices[1] == DeviceKind::FourScore) {
+                return Err("A Four Score takes both ports - devices.port1 and devices.port2 should both be \"fourscore\"".to_string());
+            }
+        }
+        if let Some(mixer) = table.get("mixer") {
+            let mixer = mixer.as_table().ok_or("[mixer] should be a table")?;
+            for (key, value) in mixer {
+                let volume = || value.as_float().or_else(|| value.as_integer().map(|v| v as f64))
+                    .filter(|v| (0.0..=1.0).contains(v))
+                    .ok_or_else(|| format!("mixer.{} should be a volume from 0 to 1", key));
+                let flag = || value.as_bool().ok_or_else(|| format!("mixer.{} should be true or false", key));
+                match key.as_str() {
+                    "volume" => config.mixer.volume = volume()? as f32,
+                    "high_pass" => config.mixer.high_pass = flag()?,
+                    "low_pass" => config.mixer.low_pass = flag()?,
+                    "stems" => config.stems = flag()?,
+                    _ => {
+                        let channel = Channel::ALL.iter().find(|channel| channel.key() == key)
+                            .ok_or_else(|| format!("Unknown mixer setting {}", key))?;
+                        config.mixer.channels[*channel as usize] = volume()? as f32;
+                    }
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Write the config file, creating its directory if need be
+    pub fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut table = Table::new();
+        table.insert("scale".to_string(), Value::Integer(self.scale as i64));
+        table.insert("aspect_correction".to_string(), Value::Boolean(self.aspect_correction));
+        table.insert("overscan".to_string(), Value::Array(self.overscan.iter()
+            .map(|&lines| Value::Integer(lines as i64))
+            .collect()));
+        table.insert("smooth".to_string(), Value::Boolean(self.smooth));
+        table.insert("scanlines".to_string(), Value::Float(self.scanlines as f64));
+        table.insert("curvature".to_string(), Value::Float(self.curvature as f64));
+        if let Some(shader) = &self.shader {
+            table.insert("shader".to_string(), Value::String(shader.to_string_lossy().into_owned()));
+        }
+        table.insert("turbo_rate".to_string(), Value::Integer(self.turbo_rate as i64));
+        table.insert("fast_forward".to_string(), Value::Integer(self.fast_forward as i64));
+        table.insert("slow_motion".to_string(), Value::Integer(self.slow_motion as i64));
+        table.insert("overclock".to_string(), Value::Boolean(self.overclock));
+        table.insert("overclock_lines".to_string(), Value::Array(vec![
+            Value::Integer(self.overclock_lines.pre_nmi as i64),
+            Value::Integer(self.overclock_lines.post_nmi as i64),
+        ]));
+        if let Some(palette) = &self.palette {
+            table.insert("palette".to_string(), Value::String(palette.to_string_lossy().into_owned()));
+        }
+        if let Some(game_db) = &self.game_db {
+            table.insert("game_db".to_string(), Value::String(game_db.to_string_lossy().into_owned()));
+        }
+        if let Some(ntsc) = &self.ntsc {
+            let mut settings = Table::new();
+            for (key, value) in [("hue", ntsc.hue), ("saturation", ntsc.saturation), ("brightness", ntsc.brightness),
+                    ("contrast", ntsc.contrast), ("gamma", ntsc.gamma)] {
+                settings.insert(key.to_string(), Value::Float(value as f64));
+            }
+            table.insert("ntsc".to_string(), Value::Table(settings));
+        }
+        table.insert("recent_roms".to_string(), Value::Array(self.recent_roms.iter()
+            .map(|rom| Value::String(rom.to_string_lossy().into_owned()))
+            .collect()));
+
+        let mut debugger = Table::new();
+        debugger.insert("show".to_string(), Value::Boolean(self.show_debugger));
+        debugger.insert("show_ppu_info".to_string(), Value::Boolean(self.show_ppu_info));
+        table.insert("debugger".to_string(), Value::Table(debugger));
+        if let Some(input) = &self.input {
+            table.insert("input".to_string(), Value::Table(input.clone()));
+        }
+        table.insert("devices".to_string(), Value::Table(PORT_NAMES.iter().zip(self.devices)
+            .map(|(port, kind)| (port.to_string(), Value::String(kind.name().to_string())))
+            .collect()));
+
+        let mut mixer = Table::new();
+        mixer.insert("volume".to_string(), Value::Float(self.mixer.volume as f64));
+        for channel in Channel::ALL {
+            mixer.insert(channel.key().to_string(), Value::Float(self.mixer.channels[channel as usize] as f64));
+        }
+        mixer.insert("high_pass".to_string(), Value::Boolean(self.mixer.high_pass));
+        mixer.insert("low_pass".to_string(), Value::Boolean(self.mixer.low_pass));
+        mixer.insert("stems".to_string(), Value::Boolean(self.stems));
+        table.insert("mixer".to_string(), Value::Table(mixer));
+
+        let result = path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, Value::Table(table).to_string()));
+        if let Err(e) = result {
+            println!("Failed to save config to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Move a ROM to the front of the recently played list
+    pub fn add_recent_rom(&mut self, rom: &Path) {
+        let rom = fs::canonicalize(rom).unwrap_or_else(|_| rom.to_path_buf());
+        self.recent_roms.retain(|r| *r != rom);
+        self.recent_roms.insert(0, rom);
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+    }
+}
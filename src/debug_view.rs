@@ -1,18 +1,36 @@
 use std::ascii::AsciiExt;
-use std::cell::{RefCell, RefMut};
+use std::cell::RefMut;
 use std::collections::HashMap;
-use std::rc::Rc;
-use fancy_nes_core::cpu::{NESCpu, StatusRegister};
-use fancy_nes_core::cpu::debug::disasm_6502;
-use fancy_nes_core::ppu::NESPpu;
+use fancy_nes_core::Nes;
+use fancy_nes_core::bus::MemoryRead;
+use fancy_nes_core::cpu::StatusRegister;
+use fancy_nes_core::cpu::debug::disasm_6502_labelled;
+use fancy_nes_core::cpu::decode::LUT_6502;
+use fancy_nes_core::cpu::profile::Profiler;
+use fancy_nes_core::cpu::trace::{TraceFormat, TraceUnit};
+use fancy_nes_core::nes::MemorySpace;
+use fancy_nes_core::debugger::{BreakCondition, Watch, WatchEntry, WatchFormat};
+use fancy_nes_core::symbols::Symbols;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
-use sdl2::render::{Canvas, TextureCreator, TextureQuery};
+use sdl2::render::{Canvas, TextureCreator};
 use sdl2::surface;
 use sdl2::ttf::Sdl2TtfContext;
 use sdl2::pixels::Color;
 use sdl2::video::{Window, WindowContext};
 
-use crate::{NES_SCREEN_WIDTH, NES_DEBUGGER_WIDTH, NES_SCREEN_HEIGHT};
+use crate::logger;
+use crate::text_cache::TextCache;
+use crate::{NES_DEBUGGER_WIDTH, NES_SCREEN_HEIGHT};
+
+const BREAKPOINT_LIST_Y: i32 = 410;
+
+/* Index into DebugView::addresses of the line holding the PC, unless the view has been scrolled */
+const PC_LINE: usize = 10;
+
+/* The instruction history takes the disassembly's place, above the registers */
+const HISTORY_VIEW_HEIGHT: i32 = 350;
 
 pub struct DebugView<'a> {
     /* The address list here may seem redundant, as addresses are stored in disasm,,
@@ -20,99 +38,487 @@ pub struct DebugView<'a> {
     pub addresses: [u16; 21],             /* a list of the 20 addresses disassembled and visible */
 
     disasm: HashMap<u16, (String, u16)>, /* a map of memory addresses to a disasm entry */
-    cpu: Rc<RefCell<NESCpu<'a>>>,            /* we need to keep the whole CPU Rc alive, instead of trying to immutably
-                                        reference just cpu.memory */
-    ppu: Rc<RefCell<NESPpu<'a>>>,
+    selected: usize,                      /* the highlighted line, an index into addresses */
+    top: Option<u16>,                     /* the first line when scrolled away from the PC, otherwise the PC is on PC_LINE */
+    show_history: bool,                   /* show the last instructions executed instead of the disassembly */
+    symbols: Symbols,                     /* labels to show in place of addresses, and to take in commands */
+
+    text: TextCache<'a>,
+    small_text: TextCache<'a>,
 
-    font: sdl2::ttf::Font<'a, 'static>,
-    texture_creator: TextureCreator<WindowContext>,
+    /* Breakpoint command entry - see handle_event */
+    prompt: Option<String>,
+    swallow_text: bool,      /* the key which opened the prompt also produces a TextInput event */
+    message: String,         /* result of the last command */
 }
 
 
 impl<'a> DebugView<'a> {
     // Create a DebugView which renders onto the given canvas populates the disasm
     // HashMap with some useful initial entries
-    pub fn new(texture_creator: TextureCreator<WindowContext>, ttf_context: &'a Sdl2TtfContext, cpu: Rc<RefCell<NESCpu<'a>>>, ppu: Rc<RefCell<NESPpu<'a>>>) -> Self {        
+    pub fn new(texture_creator: &'a TextureCreator<WindowContext>, ttf_context: &'a Sdl2TtfContext, nes: &Nes) -> Self {
         let mut result = Self {
             addresses: [0; 21],
             disasm: HashMap::new(),
-            cpu: Rc::clone(&cpu),
-            ppu: Rc::clone(&ppu),
-            font: ttf_context.load_font("debug.ttf", 16).unwrap(),
-            texture_creator
+            selected: PC_LINE,
+            top: None,
+            show_history: false,
+            symbols: Symbols::new(),
+            text: TextCache::new(ttf_context.load_font("debug.ttf", 16).unwrap(), texture_creator, NES_DEBUGGER_WIDTH),
+            small_text: TextCache::new(ttf_context.load_font("debug.ttf", 12).unwrap(), texture_creator, NES_DEBUGGER_WIDTH),
+            prompt: None,
+            swallow_text: false,
+            message: String::new(),
         };
 
         // Insert a null disassembly
         result.disasm.insert(0, ("-".to_string(), 0));
 
-        DebugView::update_addresses(&mut result);
+        DebugView::update_addresses(&mut result, nes);
 
         result
     }
 
-    fn update_addresses(&mut self) {
-        // Update addresses in live address range
+    fn update_addresses(&mut self, nes: &Nes) {
+        // Either the PC is pinned to PC_LINE, or the view has been scrolled away and starts at `top`
+        let (anchor, anchor_line) = match self.top {
+            Some(top) => (top, 0),
+            None => (nes.cpu().PC, PC_LINE),
+        };
 
-        // Clear addresses
+        // Backwards pass. Lines we can't find an instruction for are left as the null disassembly.
         self.addresses = [0; 21];
-
-        let mut disasm_string: String;
-        let mut current_addr: u16 = self.cpu.borrow().PC;
-        let mut on_screen_index: usize = 10;
-        let mut initial_offset: u16;
-        let mut last_offset: u16;
+        let before = self.instructions_before(anchor, anchor_line, nes);
+        let first_line = anchor_line - before.len();
+        self.addresses[first_line..anchor_line].copy_from_slice(&before);
 
         // Forward pass
-        for i in 0..11 {
-            (disasm_string, last_offset) = self.disasm.get(&current_addr).unwrap_or(&disasm_6502(current_addr, &self.cpu.borrow().memory)).to_owned();
-            if i == 0 {
-                initial_offset = last_offset;
+        let mut current_addr = anchor;
+        for line in anchor_line..self.addresses.len() {
+            self.addresses[line] = current_addr;
+            current_addr = current_addr.wrapping_add(self.disassemble(current_addr, nes).1);
+        }
+    }
+
+    /* Look up the disassembly of an instruction, disassembling and caching it if necessary */
+    fn disassemble(&mut self, addr: u16, nes: &Nes) -> &(String, u16) {
+        let symbols = &self.symbols;
+        self.disasm.entry(addr).or_insert_with(|| disasm_6502_labelled(addr, &nes.cpu().bus, Some(symbols)))
+    }
+
+    /* Instructions can't be decoded backwards, so to find up to `count` instructions ending just
+       before `addr`, try starting a forward pass from each of the bytes before it. Misaligned
+       passes tend to fall into step within a few instructions, so of the passes which land
+       exactly on `addr`, prefer one which gives enough lines, then one with the fewest
+       undocumented opcodes (which are rare in real code), then the one starting furthest back. */
+    fn instructions_before(&mut self, addr: u16, count: usize, nes: &Nes) -> Vec<u16> {
+        let mut best: Vec<u16> = Vec::new();
+        let mut best_score = (false, usize::MAX);
+
+        for distance in 1..=(count as u16 * 3 + 8) {
+            let mut chain = Vec::new();
+            let mut current_addr = addr.wrapping_sub(distance);
+            while addr.wrapping_sub(current_addr) <= distance && current_addr != addr {
+                let len = self.disassemble(current_addr, nes).1;
+                if len == 0 {
+                    break;
+                }
+                chain.push(current_addr);
+                current_addr = current_addr.wrapping_add(len);
+            }
+            if current_addr != addr || chain.is_empty() {
+                continue;
+            }
+
+            let chain = chain.split_off(chain.len().saturating_sub(count));
+            let illegal = chain.iter().filter(|&&a| DebugView::is_illegal(a, nes)).count();
+            let score = (chain.len() == count, illegal);
+            if score.0 > best_score.0 || (score.0 == best_score.0 && score.1 <= best_score.1) {
+                best = chain;
+                best_score = score;
+            }
+        }
+        best
+    }
+
+    fn is_illegal(addr: u16, nes: &Nes) -> bool {
+        nes.cpu().bus.read(addr).map_or(false, |op| LUT_6502[op as usize].illegal)
+    }
+
+    /* Scroll the disassembly by a number of instructions, detaching it from the PC */
+    fn scroll(&mut self, lines: i32, nes: &Nes) {
+        let top = if lines < 0 {
+            let before = self.instructions_before(self.addresses[0], lines.unsigned_abs() as usize, nes);
+            before.first().copied().unwrap_or(self.addresses[0])
+        } else {
+            self.addresses[(lines as usize).min(self.addresses.len() - 1)]
+        };
+        self.top = Some(top);
+        self.update_addresses(nes);
+    }
+
+    /// Pin the disassembly to the PC again, e.g. after hitting a breakpoint
+    pub fn follow_pc(&mut self) {
+        self.top = None;
+        self.selected = PC_LINE;
+    }
+
+    /// Disassemble everything afresh, once another cartridge has been inserted
+    pub fn forget_disassembly(&mut self) {
+        self.disasm.clear();
+        self.disasm.insert(0, ("-".to_string(), 0));
+        self.follow_pc();
+    }
+
+    /// Show these labels in place of the addresses they name, and take them in commands
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+        self.forget_disassembly();
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Handle debugger hotkeys. Returns true if the event was consumed.
+    ///
+    /// B opens a command prompt for managing breakpoints:
+    ///   x ADDR - break when PC reaches ADDR      r ADDR - break on a read of ADDR
+    ///   w ADDR - break on a write to ADDR        s LINE - break at the start of a scanline
+    ///   d ID   - delete a breakpoint             t ID   - enable/disable a breakpoint
+    ///   c CODE - add a cheat (Game Genie, or AAAA:VV to freeze RAM)
+    ///   cd ID  - delete a cheat                  ct ID  - enable/disable a cheat
+    ///   wa ADDR [FORMAT] - watch a variable, shown as hex (the default), dec, bin or word (16 bits)
+    ///   wd ID  - delete a watch                  wf ID  - freeze/thaw a watch at its value
+    ///   tr FORMAT PATH - trace instructions to PATH as nestest, fceux or mesen,
+    ///                    with "+ppu" for scanline and dot columns, e.g. "tr mesen+ppu out.log"
+    ///   tr off - stop tracing
+    ///   pr PATH - profile the cycles spent in each subroutine and instruction, reporting to PATH
+    ///   pr off - stop profiling, and write the report
+    ///   lg LEVELS - set the levels of log messages by subsystem, e.g. "lg ppu=debug,dma=trace"
+    /// Addresses are in hex, or labels from a symbol file, scanlines and ids in decimal. Read and write
+    /// watchpoints also take a range, and a value to match, e.g. "w 0300-03FF 2A".
+    ///
+    /// Page Up/Down move the highlighted disassembly line, scrolling at the
+    /// edges, as does the mouse wheel. Home returns to the PC. F9 adds or removes
+    /// an execution breakpoint on the highlighted line, and F4 (handled by the caller) runs to it.
+    /// H swaps the disassembly for the last instructions executed, with the registers they saw.
+    pub fn handle_event(&mut self, event: &Event, nes: &mut Nes) -> bool {
+        if let Some(prompt) = self.prompt.as_mut() {
+            match event {
+                Event::TextInput { text, .. } => {
+                    if self.swallow_text {
+                        self.swallow_text = false;
+                    } else {
+                        prompt.push_str(text);
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    prompt.pop();
+                }
+                Event::KeyDown { keycode: Some(Keycode::Return), .. } => {
+                    let command = self.prompt.take().unwrap();
+                    self.message = self.run_command(command.trim(), nes);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    self.prompt = None;
+                }
+                Event::Quit { .. } => { return false; }
+                _ => {}
+            }
+            return true;
+        }
+
+        match event {
+            Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                self.prompt = Some(String::new());
+                self.swallow_text = true;
+            }
+            Event::KeyDown { keycode: Some(Keycode::PageUp), .. } => {
+                if self.selected == 0 {
+                    self.scroll(-1, nes);
+                } else {
+                    self.selected -= 1;
+                }
+            }
+            Event::KeyDown { keycode: Some(Keycode::PageDown), .. } => {
+                if self.selected == self.addresses.len() - 1 {
+                    self.scroll(1, nes);
+                } else {
+                    self.selected += 1;
+                }
+            }
+            Event::KeyDown { keycode: Some(Keycode::Home), .. } => {
+                self.follow_pc();
+            }
+            Event::MouseWheel { y, .. } if *y != 0 => {
+                self.scroll(-3 * y.signum(), nes);
+            }
+            Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                self.message = self.toggle_breakpoint(self.addresses[self.selected], nes);
+            }
+            Event::KeyDown { keycode: Some(Keycode::H), .. } => {
+                self.show_history = !self.show_history;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The address of the highlighted disassembly line, e.g. to run to
+    pub fn selected_address(&self) -> u16 {
+        self.addresses[self.selected]
+    }
+
+    fn toggle_breakpoint(&mut self, addr: u16, nes: &mut Nes) -> String {
+        let breakpoints = &mut nes.cpu_mut().bus.breakpoints;
+        let condition = BreakCondition::Execute(addr);
+
+        match breakpoints.find(condition) {
+            Some(id) => {
+                breakpoints.remove(id);
+                format!("Deleted #{}", id)
+            }
+            None => format!("Added #{}: {}", breakpoints.add(condition), condition),
+        }
+    }
+
+    fn run_command(&mut self, command: &str, nes: &mut Nes) -> String {
+        let mut parts = command.split_whitespace();
+        let (op, arg, value) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(op), Some(arg), value, None) => (op, arg, value),
+            _ => return format!("Bad command: {}", command),
+        };
+
+        if op == "tr" {
+            return DebugView::trace_command(arg, value, nes);
+        }
+        if op == "pr" && value.is_none() {
+            return self.profile_command(arg, nes);
+        }
+        if op == "wa" {
+            return self.add_watch(arg, value, nes);
+        }
+        if op == "lg" && value.is_none() {
+            return logger::set_levels(arg).unwrap_or_else(|e| e);
+        }
+
+        let dec = arg.parse::<u32>();
+        let cheats = &mut nes.cpu_mut().bus.cheats;
+        match (op, &dec) {
+            ("c", _) if value.is_none() => {
+                return match cheats.add(arg) {
+                    Ok(id) => format!("Cheat #{}: {}", id, arg),
+                    Err(e) => e,
+                };
+            }
+            ("cd", &Ok(id)) => {
+                return if cheats.remove(id) { format!("Deleted cheat #{}", id) } else { format!("No cheat #{}", id) };
+            }
+            ("ct", &Ok(id)) => {
+                return match cheats.toggle(id) {
+                    Some(true) => format!("Enabled cheat #{}", id),
+                    Some(false) => format!("Disabled cheat #{}", id),
+                    None => format!("No cheat #{}", id),
+                };
             }
-            self.disasm.insert(current_addr, (disasm_string, last_offset));
-            self.addresses[on_screen_index] = current_addr;
-            on_screen_index += 1;
-            current_addr += last_offset;
+            ("wd", &Ok(id)) => {
+                return if nes.cpu_mut().bus.watches.remove(id) { format!("Deleted watch #{}", id) } else { format!("No watch #{}", id) };
+            }
+            ("wf", &Ok(id)) => return DebugView::toggle_freeze(id, nes),
+            _ => {}
         }
 
-        // Figure out how to do a backwards pass
+        let addr = self.parse_address(arg, nes);
+        let watch = self.parse_watch(arg, value, nes);
+        let breakpoints = &mut nes.cpu_mut().bus.breakpoints;
+
+        let condition = match (op, addr, dec, watch) {
+            ("r", _, _, Some(watch)) => BreakCondition::Read(watch),
+            ("w", _, _, Some(watch)) => BreakCondition::Write(watch),
+            (_, _, _, _) if value.is_some() => return format!("Bad command: {}", command),
+            ("x", Some(addr), _, _) => BreakCondition::Execute(addr),
+            ("s", _, Ok(line), _) if line < 262 => BreakCondition::Scanline(line as u16),
+            ("d", _, Ok(id), _) => {
+                return if breakpoints.remove(id) { format!("Deleted #{}", id) } else { format!("No breakpoint #{}", id) };
+            }
+            ("t", _, Ok(id), _) => {
+                return match breakpoints.toggle(id) {
+                    Some(true) => format!("Enabled #{}", id),
+                    Some(false) => format!("Disabled #{}", id),
+                    None => format!("No breakpoint #{}", id),
+                };
+            }
+            _ => return format!("Bad command: {}", command),
+        };
+
+        let id = breakpoints.add(condition);
+        format!("Added #{}: {}", id, condition)
     }
 
-    pub fn render(&mut self, mut canvas: RefMut<Canvas<Window>>) {
-        self.update_addresses();
+    fn add_watch(&self, arg: &str, format: Option<&str>, nes: &mut Nes) -> String {
+        let addr = match self.parse_address(arg, nes) {
+            Some(addr) => addr,
+            None => return format!("Bad address: {}", arg),
+        };
+        let format = match format.map_or(Ok(WatchFormat::Hex), str::parse) {
+            Ok(format) => format,
+            Err(e) => return e,
+        };
+        let name = match self.symbols.label(addr, &nes.cpu().bus.cartridge) {
+            Some(label) => label.to_string(),
+            None => format!("${:0>4X}", addr),
+        };
+        let id = nes.cpu_mut().bus.watches.add(addr, &name, format);
+        format!("Watch #{}: {}", id, name)
+    }
+
+    /* Freeze a watch at the value it has now, or thaw it */
+    fn toggle_freeze(id: u32, nes: &mut Nes) -> String {
+        let watch = match nes.cpu().bus.watches.get(id) {
+            Some(watch) => watch.clone(),
+            None => return format!("No watch #{}", id),
+        };
+        if watch.frozen.is_some() {
+            nes.cpu_mut().bus.watches.freeze(id, None);
+            return format!("Thawed watch #{}", id);
+        }
+
+        let value = match DebugView::watch_value(&watch, nes) {
+            Some(value) => value,
+            None => return format!("Can't read {}", watch.name),
+        };
+        /* Writing the value it already has checks that it can be written at all */
+        if let Err(e) = nes.poke(MemorySpace::Cpu, watch.addr, value as u8) {
+            return e.to_string();
+        }
+        nes.cpu_mut().bus.watches.freeze(id, Some(value));
+        format!("Froze watch #{} at {}", id, watch.format.format(value))
+    }
+
+    /* A watch's value, read without side-effects */
+    fn watch_value(watch: &WatchEntry, nes: &Nes) -> Option<u16> {
+        (0..watch.format.width()).try_fold(0u16, |value, i| {
+            let byte = nes.peek(MemorySpace::Cpu, watch.addr.wrapping_add(i))?;
+            Some(value | (byte as u16) << (8 * i))
+        })
+    }
+
+    fn profile_command(&self, arg: &str, nes: &mut Nes) -> String {
+        if arg != "off" {
+            nes.start_profile(Profiler::new(arg.as_ref()));
+            return format!("Profiling to {}", arg);
+        }
+        match nes.stop_profile() {
+            Some(profile) => match profile.save(Some(&self.symbols)) {
+                Ok(()) => format!("Saved profile to {}", profile.path().display()),
+                Err(e) => e,
+            },
+            None => "Not profiling".to_string(),
+        }
+    }
+
+    fn trace_command(arg: &str, path: Option<&str>, nes: &mut Nes) -> String {
+        let path = match (arg, path) {
+            ("off", None) => {
+                return if nes.stop_trace().is_some() { "Stopped tracing".to_string() } else { "Not tracing".to_string() };
+            }
+            (_, Some(path)) => path,
+            (_, None) => return "Bad command: tr needs a format and a path".to_string(),
+        };
+
+        let (format, ppu_columns) = match arg.strip_suffix("+ppu") {
+            Some(format) => (format, true),
+            None => (arg, false),
+        };
+        match format.parse::<TraceFormat>().and_then(|format| TraceUnit::new(path.as_ref(), format, ppu_columns)) {
+            Ok(trace) => {
+                nes.start_trace(trace);
+                format!("Tracing to {}", path)
+            }
+            Err(e) => e,
+        }
+    }
+
+    /* An address in hex, or a label */
+    fn parse_address(&self, arg: &str, nes: &Nes) -> Option<u16> {
+        u16::from_str_radix(arg.trim_start_matches('$'), 16).ok()
+            .or_else(|| self.symbols.address(arg, &nes.cpu().bus.cartridge))
+    }
+
+    /* A watchpoint's "ADDR" or "START-END", and optional "VALUE", in hex (or labels for addresses) */
+    fn parse_watch(&self, range: &str, value: Option<&str>, nes: &Nes) -> Option<Watch> {
+        let watch = match range.split_once('-') {
+            Some((start, end)) => Watch::range(self.parse_address(start, nes)?, self.parse_address(end, nes)?),
+            None => Watch::addr(self.parse_address(range, nes)?),
+        };
+        match value {
+            Some(value) => Some(watch.with_value(u8::from_str_radix(value.trim_start_matches('$'), 16).ok()?)),
+            None => Some(watch),
+        }
+    }
+
+    fn render_disassembly(&mut self, canvas: &mut Canvas<Window>, nes: &Nes) {
+        self.update_addresses(nes);
 
         // Take a copy of the address disassemblies of interest and format appropriately.
+        // The PC is marked with '>', and enabled execution breakpoints with '*'.
+        let pc_line = match self.top {
+            Some(_) => self.addresses.iter().position(|&addr| addr == nes.cpu().PC),
+            None => Some(PC_LINE),
+        };
+        let breakpoints = &nes.cpu().bus.breakpoints;
         let disasm_vec = self.addresses.iter().enumerate()
-            .map(|i| { if i.0 == 10 {
-                format!("> ${:0>4X}: {}", i.1, self.disasm[i.1].0)
-             } else {
-                format!("  ${:0>4X}: {}", i.1, self.disasm[i.1].0)
-             } 
-        });
-        
-        // TODO - Integrate a better font rendering library so we are not constantly creating textures...
-
-        let surface = self.font
-            .render(
-                disasm_vec.collect::<Vec<String>>().join("\n").as_str()
-            )
-            .blended_wrapped(Color::RGBA(255, 255, 255, 255), NES_DEBUGGER_WIDTH)
-            .map_err(|e| e.to_string()).unwrap();
-
-        let texture = self.texture_creator
-            .create_texture_from_surface(&surface)
-            .map_err(|e| e.to_string()).unwrap();
-
-
-        let TextureQuery { width, height, .. } = texture.query();
-        let text_rect = Rect::new(NES_SCREEN_WIDTH as i32 + 10, 10, width, height);
-        
+            .map(|i| {
+                let pc_mark = if Some(i.0) == pc_line { '>' } else { ' ' };
+                let bp_mark = if breakpoints.iter().any(|b| b.enabled && b.condition == BreakCondition::Execute(*i.1)) { '*' } else { ' ' };
+                format!("{}{}${:0>4X}: {}", pc_mark, bp_mark, i.1, self.disasm[i.1].0)
+        }).collect::<Vec<String>>();
+
+        // Highlight the PC's line if it is in view, and the selected line
+        let line_height = self.text.line_spacing();
+        if let Some(line) = pc_line {
+            canvas.set_draw_color(Color::RGBA(40, 40, 200, 255));
+            canvas.fill_rect(Rect::new(0, 10 + line_height * line as i32,
+                NES_DEBUGGER_WIDTH, line_height as u32)).unwrap();
+        }
+        canvas.set_draw_color(Color::RGBA(80, 80, 255, 255));
+        canvas.fill_rect(Rect::new(0, 10 + line_height * self.selected as i32,
+            NES_DEBUGGER_WIDTH, line_height as u32)).unwrap();
+
+        self.text.draw(canvas, &disasm_vec, Color::RGBA(255, 255, 255, 255), 10, 10, NES_SCREEN_HEIGHT as i32).unwrap();
+    }
+
+    /* The last instructions executed, newest at the bottom, in place of the disassembly */
+    fn render_history(&mut self, canvas: &mut Canvas<Window>, nes: &Nes) {
+        let lines = (HISTORY_VIEW_HEIGHT / self.small_text.line_spacing()) as usize - 1;
+        let history = nes.cpu().history();
+
+        let mut history_lines = vec!["PC    Instruction      A  X  Y  P".to_string()];
+        history_lines.extend(history.iter().skip(history.len().saturating_sub(lines)).map(|entry| {
+            format!("{:0>4X}  {:<16} {:0>2X} {:0>2X} {:0>2X} {:0>2X}",
+                entry.pc, entry.disassemble(), entry.a, entry.x, entry.y, entry.status)
+        }));
+
+        self.small_text.draw(canvas, &history_lines, Color::RGBA(255, 255, 255, 255), 10, 10, NES_SCREEN_HEIGHT as i32).unwrap();
+    }
+
+    /// Drawn from the origin of the canvas's viewport, which the caller places (see Layout::debugger)
+    pub fn render(&mut self, mut canvas: RefMut<Canvas<Window>>, nes: &Nes) {
+        self.text.next_frame();
+        self.small_text.next_frame();
+
         canvas.set_draw_color(Color::RGBA(0, 0, 255, 180));
-        canvas.fill_rect(Rect::new(NES_SCREEN_WIDTH as i32, 0, NES_DEBUGGER_WIDTH, NES_SCREEN_HEIGHT)).unwrap();
+        canvas.fill_rect(Rect::new(0, 0, NES_DEBUGGER_WIDTH, NES_SCREEN_HEIGHT)).unwrap();
 
-        canvas.copy(&texture, None, Some(text_rect)).unwrap();
+        if self.show_history {
+            self.render_history(&mut canvas, nes);
+        } else {
+            self.render_disassembly(&mut canvas, nes);
+        }
 
-        let cpu = self.cpu.borrow();
-        let ppu = self.ppu.borrow();
+        let cpu = nes.cpu();
+        let ppu = nes.ppu();
         let mut status_string = String::new();
         status_string.push(if cpu.status.contains(StatusRegister::NEGATIVE) { 'N' } else { 'n' });
         status_string.push(if cpu.status.contains(StatusRegister::OVERFLOW) { 'V' } else { 'v' });
@@ -132,20 +538,26 @@ impl<'a> DebugView<'a> {
             ppu.tick,
         ).as_str());
 
-        let surface = self.font
-            .render(
-                status_string.as_str()
-            )
-            .blended_wrapped(Color::RGBA(255, 255, 255, 255), NES_DEBUGGER_WIDTH)
-            .map_err(|e| e.to_string()).unwrap();
-        
-        let texture = self.texture_creator
-            .create_texture_from_surface(&surface)
-            .map_err(|e| e.to_string()).unwrap();
-
-        let TextureQuery { width, height, .. } = texture.query();
-        let text_rect = Rect::new(NES_SCREEN_WIDTH as i32 + 10, 360, width, height);
-
-        canvas.copy(&texture, None, Some(text_rect)).unwrap();
+        let status_lines = status_string.split('\n').collect::<Vec<&str>>();
+        self.text.draw(&mut canvas, &status_lines, Color::RGBA(255, 255, 255, 255), 10, 360, BREAKPOINT_LIST_Y).unwrap();
+
+        // Watches, breakpoints and cheats, and the command prompt (or result of the last command)
+        let mut bp_lines = match &self.prompt {
+            Some(prompt) => vec![format!("bp> {}_", prompt)],
+            None => vec![if self.message.is_empty() { "B: breakpoints".to_string() } else { self.message.clone() }],
+        };
+        bp_lines.extend(cpu.bus.watches.iter().map(|w| {
+            let value = DebugView::watch_value(w, nes).map_or("--".to_string(), |value| w.format.format(value));
+            format!("W{} {} {} = {}", w.id, if w.frozen.is_some() { 'F' } else { ' ' }, w.name, value)
+        }));
+        bp_lines.extend(cpu.bus.breakpoints.iter().map(|b| {
+            format!("#{} {} {}", b.id, if b.enabled { ' ' } else { '-' }, b.condition)
+        }));
+        bp_lines.extend(cpu.bus.cheats.iter().map(|c| {
+            format!("C{} {} {} {}", c.id, if c.enabled { ' ' } else { '-' }, c.code, c.patch)
+        }));
+
+        self.small_text.draw(&mut canvas, &bp_lines, Color::RGBA(255, 255, 160, 255), 10, BREAKPOINT_LIST_Y,
+            NES_SCREEN_HEIGHT as i32).unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,100 @@
+//! Input for the devices plugged in besides controllers (see
+//! fancy_nes_core::cpu::expansion). The Family BASIC keyboard is typed on the
+//! computer's, by where its keys are rather than what's printed on them, and
+//! the Arkanoid paddle's knob is turned by moving the mouse left and right (a
+//! pixel a step), its button being the left mouse button.
+
+use fancy_nes_core::cpu::controller::{DeviceInput, DeviceKind, PORTS};
+use fancy_nes_core::cpu::expansion::{key_index, PADDLE_MAX, PADDLE_MIN};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+
+/* Keys which aren't called what the Family BASIC key they stand for is (as letters,
+   numbers and F1-F8 are), laid out as on a Japanese keyboard */
+const KEYS: [(Keycode, &str); 29] = [
+    (Keycode::Return, "Return"),
+    (Keycode::Space, "Space"),
+    (Keycode::Backspace, "Del"),
+    (Keycode::Delete, "Del"),
+    (Keycode::Insert, "Ins"),
+    (Keycode::Home, "ClrHome"),
+    (Keycode::End, "Stop"),
+    (Keycode::Tab, "Esc"),
+    (Keycode::LShift, "LShift"),
+    (Keycode::RShift, "RShift"),
+    (Keycode::LCtrl, "Ctr"),
+    (Keycode::RCtrl, "_"),
+    (Keycode::LAlt, "Grph"),
+    (Keycode::RAlt, "Kana"),
+    (Keycode::Up, "Up"),
+    (Keycode::Down, "Down"),
+    (Keycode::Left, "Left"),
+    (Keycode::Right, "Right"),
+    (Keycode::Minus, "-"),
+    (Keycode::Equals, "^"),
+    (Keycode::Backquote, "Yen"),
+    (Keycode::LeftBracket, "@"),
+    (Keycode::RightBracket, "["),
+    (Keycode::Semicolon, ";"),
+    (Keycode::Quote, ":"),
+    (Keycode::Backslash, "]"),
+    (Keycode::Comma, ","),
+    (Keycode::Period, "."),
+    (Keycode::Slash, "/"),
+];
+
+pub struct Devices {
+    ports: [DeviceKind; PORTS],
+    paddle: (i32, bool),  /* The knob's position and whether the button is held */
+}
+
+impl Devices {
+    pub fn new(ports: [DeviceKind; PORTS]) -> Self {
+        Self { ports, paddle: (((PADDLE_MIN as i32) + (PADDLE_MAX as i32)) / 2, false) }
+    }
+
+    /// Whether a keyboard is plugged in, in which case it's typed on ahead of hotkeys
+    pub fn has_keyboard(&self) -> bool {
+        self.ports.contains(&DeviceKind::FamilyKeyboard)
+    }
+
+    /// Turn an event into input for a device plugged in: the port it's in, and what to tell it
+    pub fn handle_event(&mut self, event: &Event) -> Option<(usize, DeviceInput)> {
+        match *event {
+            Event::KeyDown { keycode: Some(key), repeat: false, .. } => self.key(key, true),
+            Event::KeyUp { keycode: Some(key), .. } => self.key(key, false),
+            Event::MouseMotion { xrel, .. } => {
+                self.paddle.0 = (self.paddle.0 + xrel).clamp(PADDLE_MIN as i32, PADDLE_MAX as i32);
+                self.paddle_input()
+            }
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => {
+                self.paddle.1 = true;
+                self.paddle_input()
+            }
+            Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                self.paddle.1 = false;
+                self.paddle_input()
+            }
+            _ => None,
+        }
+    }
+
+    fn port(&self, kind: DeviceKind) -> Option<usize> {
+        self.ports.iter().position(|&port| port == kind)
+    }
+
+    fn key(&self, key: Keycode, down: bool) -> Option<(usize, DeviceInput)> {
+        let port = self.port(DeviceKind::FamilyKeyboard)?;
+        let name = match KEYS.iter().find(|&&(keycode, _)| keycode == key) {
+            Some(&(_, name)) => name.to_string(),
+            None => key.name(),
+        };
+        Some((port, DeviceInput::Key(key_index(&name)?, down)))
+    }
+
+    fn paddle_input(&self) -> Option<(usize, DeviceInput)> {
+        let (position, fire) = self.paddle;
+        Some((self.port(DeviceKind::ArkanoidPaddle)?, DeviceInput::Paddle { position: position as u8, fire }))
+    }
+}
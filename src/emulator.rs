@@ -0,0 +1,559 @@
+//! The emulation thread. The `Nes` is shared with the UI thread behind a mutex,
+//! so the debugger can inspect it, but only the worker thread runs it: a frame
+//! at a time, paced to the console's own frame rate rather than the display's
+//! vsync - or a multiple of it, to fast-forward or slow down. Completed frames
+//! and their audio are sent out over one channel, and input and run control
+//! come in over another.
+//!
+//! Controller input is only applied as each frame begins, so that it can be
+//! recorded to, or replayed from, a movie frame by frame. Input for other
+//! devices (the keyboard or paddle) is applied as it comes, and isn't recorded.
+//!
+//! A script, if one is loaded, runs on the worker thread too (see script.rs).
+//!
+//! In netplay, the input for each frame is swapped with the other player's as it
+//! begins, waiting for theirs if need be (see netplay.rs).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::apu::CHANNELS;
+use fancy_nes_core::cpu::controller::DeviceInput;
+use fancy_nes_core::cpu::debug::cpu_dump;
+use fancy_nes_core::movie::{Movie, MovieFrame, COMMAND_POWER, COMMAND_RESET};
+use fancy_nes_core::nes::Frame;
+
+use crate::input::PLAYERS;
+use crate::netplay::Netplay;
+use crate::script::{Script, Shape};
+
+/* If emulation falls further behind than this (e.g. the UI held the lock), give up catching up */
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+/// Requests from the UI thread
+pub enum Command {
+    SetController(usize, u8),
+    SetDeviceInput(usize, DeviceInput),  /* Input for a device other than a controller, applied straight away */
+    SetTurbo(usize, u8),  /* Buttons held with turbo, which are pressed and released frame by frame */
+    SetTurboRate(u32),    /* Turbo presses per second */
+    SetSpeed(Option<f64>),  /* A multiple of the console's frame rate (e.g. 4.0 or 0.5), or None to run flat out */
+    Run,    /* Continuous execution, stepping off any breakpoint at the PC */
+    Halt,
+    Pause(bool),   /* Stop (or carry on) between frames, without halting in the debugger */
+    FrameAdvance,  /* Run until the next frame completes, when paused or halted */
+    Reset,  /* Press the reset button, as the next frame begins */
+    PowerCycle,
+    LoadRom(Vec<u8>),  /* Swap the cartridge for this iNES image, ending any movie. Answered with Update::Loaded. */
+    LoadScript(PathBuf),  /* Run this script, in place of any running (or again, to reload it) */
+    Step,   /* Execute a single instruction, when halted */
+    StepOver,     /* Run until a JSR returns, or if not at a JSR, step. When halted. */
+    StepOut,      /* Run until the current subroutine returns, when halted */
+    RunTo(u16),   /* Run until PC reaches this address, when halted */
+    Quit,
+}
+
+/// What to do with a movie: record input to it, to be written out to a file
+/// when the emulator is dropped, or play it back in place of the controllers
+pub enum MovieMode {
+    Record(Movie, PathBuf),
+    Play(Movie),
+}
+
+/// Each of the APU's channels' audio on its own, by Channel (see NESApu::take_stems)
+pub type Stems = [Vec<f32>; CHANNELS];
+
+/// Notifications from the emulation thread
+pub enum Update {
+    Frame(Box<Frame>, Vec<f32>, Option<Box<Stems>>),  /* A completed frame, the audio generated alongside it, and each channel's if stems are kept */
+    Halted,                       /* Stopped at a breakpoint, or as asked */
+    Fault(String),                /* Stopped on an emulation error, for the UI to report */
+    Loaded(Result<(), String>),   /* The outcome of Command::LoadRom. On error, the old cartridge is still in. */
+    Overlay(Vec<Shape>),          /* What the script drew, to show over the frame which follows */
+}
+
+pub struct Emulator {
+    nes: Arc<Mutex<Nes>>,
+    commands: Sender<Command>,
+    updates: Receiver<Update>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Emulator {
+    /// Start emulating a freshly powered on machine, which a movie or netplay must begin from
+    pub fn spawn(nes: Nes, halted: bool, movie: Option<MovieMode>, netplay: Option<Netplay>) -> Self {
+        let nes = Arc::new(Mutex::new(nes));
+        let (commands, command_rx) = mpsc::channel();
+        let (update_tx, updates) = mpsc::channel();
+
+        let worker = Worker {
+            nes: Arc::clone(&nes),
+            commands: command_rx,
+            updates: update_tx,
+            running: !halted,
+            paused: false,
+            resuming: false,
+            last_scanline: 0,
+            buttons: [0; PLAYERS],
+            turbo: [0; PLAYERS],
+            turbo_rate: 15,
+            turbo_frame: 0,
+            speed: Some(1.0),
+            command: 0,
+            frame_start: true,
+            movie,
+            movie_frame: 0,
+            netplay,
+            script: None,
+        };
+        let thread = thread::Builder::new()
+            .name("emulation".to_string())
+            .spawn(move || worker.run())
+            .unwrap();
+
+        Self { nes, commands, updates, thread: Some(thread) }
+    }
+
+    /// Take the machine from the emulation thread, e.g. for the debugger.
+    /// The emulation thread waits until it is released.
+    pub fn lock(&self) -> MutexGuard<'_, Nes> {
+        self.nes.lock().unwrap()
+    }
+
+    /// Commands to a stopped emulation thread are dropped
+    pub fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+
+    pub fn try_recv(&self) -> Result<Update, TryRecvError> {
+        self.updates.try_recv()
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Worker {
+    nes: Arc<Mutex<Nes>>,
+    commands: Receiver<Command>,
+    updates: Sender<Update>,
+
+    running: bool,
+    paused: bool,    /* Between frames, by the user rather than the debugger (see Command::Pause) */
+    resuming: bool,  /* Skip the execution breakpoint check at the PC once, to step off it */
+    last_scanline: u16,
+
+    buttons: [u8; PLAYERS],  /* Controller state from the UI, applied at the start of the next frame */
+    turbo: [u8; PLAYERS],    /* Turbo buttons held, likewise */
+    turbo_rate: u32,     /* Turbo presses per second */
+    turbo_frame: u32,    /* Frames into the current turbo press */
+    speed: Option<f64>,  /* See Command::SetSpeed */
+    command: u8,         /* Reset or power cycle requested by the UI, likewise (see movie::COMMAND_*) */
+    frame_start: bool,   /* Nothing has run yet of the current frame */
+    movie: Option<MovieMode>,
+    movie_frame: usize,
+    netplay: Option<Netplay>,
+    script: Option<Script>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut next_frame = Instant::now();
+
+        loop {
+            /* When halted or paused, sleep until the UI asks for something */
+            let mut command = if self.running && !self.paused {
+                self.commands.try_recv()
+            } else {
+                self.commands.recv().map_err(|_| TryRecvError::Disconnected)
+            };
+
+            loop {
+                match command {
+                    Ok(Command::SetController(port, buttons)) => self.buttons[port] = buttons,
+                    Ok(Command::SetDeviceInput(port, input)) => self.nes.lock().unwrap().set_device_input(port, input),
+                    Ok(Command::SetTurbo(port, buttons)) => self.turbo[port] = buttons,
+                    Ok(Command::SetTurboRate(rate)) => self.turbo_rate = rate.max(1),
+                    Ok(Command::SetSpeed(speed)) => {
+                        // Pace from now at the new speed, rather than catching up (or waiting) at the old
+                        self.speed = speed;
+                        next_frame = Instant::now();
+                    }
+                    Ok(Command::Reset) => self.command |= COMMAND_RESET,
+                    Ok(Command::PowerCycle) => self.command |= COMMAND_POWER,
+                    Ok(Command::LoadRom(rom)) => self.load_rom(&rom),
+                    Ok(Command::LoadScript(path)) => self.load_script(&path),
+                    Ok(Command::Run) => self.resume(&mut next_frame),
+                    Ok(Command::Halt) => self.halt(),
+                    Ok(Command::Pause(paused)) => {
+                        self.paused = paused;
+                        next_frame = Instant::now();
+                    }
+                    Ok(Command::FrameAdvance) => if self.paused || !self.running {
+                        if !self.advance_frame() {
+                            self.save_movie();
+                            return;
+                        }
+                    },
+                    Ok(Command::Step) => if !self.running { self.step() },
+                    Ok(Command::StepOver) => if !self.running {
+                        let over_jsr = self.nes.lock().unwrap().cpu_mut().step_over();
+                        if let Ok(true) = over_jsr {
+                            self.resume(&mut next_frame);
+                        } else {
+                            self.step();
+                            let _ = self.updates.send(Update::Halted);
+                        }
+                    },
+                    Ok(Command::StepOut) => if !self.running {
+                        self.nes.lock().unwrap().cpu_mut().step_out();
+                        self.resume(&mut next_frame);
+                    },
+                    Ok(Command::RunTo(addr)) => if !self.running {
+                        self.nes.lock().unwrap().cpu_mut().run_to(addr);
+                        self.resume(&mut next_frame);
+                    },
+                    Ok(Command::Quit) | Err(TryRecvError::Disconnected) => {
+                        self.save_movie();
+                        return;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                }
+                command = self.commands.try_recv();
+            }
+
+            if !self.running || self.paused {
+                continue;
+            }
+
+            if !self.run_frame() {
+                self.save_movie();
+                return;
+            }
+
+            let speed = match self.speed {
+                Some(speed) => speed,
+                None => {
+                    next_frame = Instant::now();
+                    continue;
+                }
+            };
+            let period = Duration::from_secs_f64(1.0 / (self.nes.lock().unwrap().region().frame_rate() * speed));
+            next_frame += period;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else if now - next_frame > period * MAX_FRAMES_BEHIND {
+                next_frame = now;
+            }
+        }
+    }
+
+    fn resume(&mut self, next_frame: &mut Instant) {
+        self.running = true;
+        self.resuming = true;
+        *next_frame = Instant::now();
+    }
+
+    /* Stop running, abandoning any step over/out or run to cursor in progress */
+    fn halt(&mut self) {
+        self.running = false;
+        let mut nes = self.nes.lock().unwrap();
+        nes.cpu_mut().bus.breakpoints.temporary = None;
+        nes.catch_up();
+    }
+
+    /* Run until a frame completes, a breakpoint is hit or emulation faults. Returns false if the UI has gone. */
+    fn run_frame(&mut self) -> bool {
+        let shared = Arc::clone(&self.nes);
+        let mut nes = shared.lock().unwrap();
+
+        loop {
+            self.begin_frame(&mut nes);
+
+            // Execution breakpoints are checked before the instruction is fetched. When
+            // resuming from a breakpoint, skip the check once so we can step off it.
+            let at_boundary = nes.cpu().wait_cycles == 0;
+            let mut hit = if self.resuming { None } else { nes.cpu().breakpoint_at_pc() };
+            let reached = !self.resuming && hit.is_none() && nes.cpu_mut().temporary_break_at_pc();
+            if at_boundary {
+                self.resuming = false;
+            }
+
+            let mut fault = None;
+            let mut frame_done = false;
+            if hit.is_none() && !reached {
+                if at_boundary && self.script.as_ref().is_some_and(Script::wants_instructions) {
+                    let pc = nes.cpu().PC;
+                    nes = self.call_script(&shared, nes, |script| script.instruction(pc));
+                }
+                match tick_cpu(&mut nes) {
+                    Ok(done) => frame_done = done,
+                    Err(e) => fault = Some(e),
+                }
+
+                // Memory watchpoints, and scanline breakpoints
+                hit = nes.cpu_mut().bus.breakpoints.hit.take();
+                let scanline = nes.ppu().scanline;
+                if scanline != self.last_scanline {
+                    self.last_scanline = scanline;
+                    hit = hit.or(nes.cpu().bus.breakpoints.check_scanline(scanline));
+                }
+
+                if hit.is_some() && fault.is_none() {
+                    // Finish processing this instruction
+                    match flush_cpu(&mut nes) {
+                        Ok(done) => frame_done |= done,
+                        Err(e) => fault = Some(e),
+                    }
+                }
+            }
+
+            self.frame_start |= frame_done;
+            if frame_done {
+                nes = self.call_script(&shared, nes, Script::frame);
+                if !self.send_frame(&mut nes) {
+                    return false;
+                }
+            }
+
+            if let Some(id) = hit {
+                println!("Hit breakpoint #{} at ${:0>4X}", id, nes.cpu().PC);
+            }
+
+            // Rather than abort on an emulation error, dump the CPU state
+            // and stop in the debugger so it can be inspected.
+            if let Some(e) = &fault {
+                eprintln!("{}\nError: {}", cpu_dump(nes.cpu()), e);
+            }
+
+            if hit.is_some() || fault.is_some() || reached {
+                self.running = false;
+                nes.cpu_mut().bus.breakpoints.temporary = None;
+                nes.catch_up();
+                let update = match fault {
+                    Some(e) => Update::Fault(e),
+                    None => Update::Halted,
+                };
+                return self.updates.send(update).is_ok();
+            }
+
+            if frame_done {
+                return true;
+            }
+        }
+    }
+
+    /* Run a single frame from a pause or halt, leaving emulation stopped afterwards unless
+       paused (a breakpoint hit on the way halts it as usual). Returns false if the UI has gone. */
+    fn advance_frame(&mut self) -> bool {
+        let running = self.running;
+        self.running = true;
+        self.resuming = !running;
+        let connected = self.run_frame();
+        if self.running {
+            self.running = running;
+            if !running {
+                return self.updates.send(Update::Halted).is_ok();
+            }
+        }
+        connected
+    }
+
+    /* Execute a single instruction */
+    fn step(&mut self) {
+        let shared = Arc::clone(&self.nes);
+        let mut nes = shared.lock().unwrap();
+        self.begin_frame(&mut nes);
+
+        let result = tick_cpu(&mut nes)
+            .and_then(|done| Ok(flush_cpu(&mut nes)? || done));
+        nes.catch_up();
+        match result {
+            Ok(true) => {
+                self.frame_start = true;
+                self.send_frame(&mut nes);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("{}\nError: {}", cpu_dump(nes.cpu()), e);
+                let _ = self.updates.send(Update::Fault(e));
+            }
+        }
+    }
+
+    /* Set the controllers for a new frame, and reset if asked to, from the UI or the movie being played */
+    fn begin_frame(&mut self, nes: &mut Nes) {
+        if !self.frame_start {
+            return;
+        }
+        self.frame_start = false;
+
+        // Turbo buttons are pressed for the first half of each period, and released for the rest.
+        // This happens here rather than in the UI so the presses land on emulated frames.
+        let period = ((nes.region().frame_rate() / self.turbo_rate as f64).round() as u32).max(2);
+        let turbo = if self.turbo_frame % period < period / 2 { self.turbo } else { [0; PLAYERS] };
+        self.turbo_frame = (self.turbo_frame + 1) % period;
+
+        let held: [u8; PLAYERS] = std::array::from_fn(|player| self.buttons[player] | turbo[player]);
+        let mut buttons = [held[0], held[1]];
+        if let Some(script) = &mut self.script {
+            for (buttons, input) in buttons.iter_mut().zip(script.take_input()) {
+                *buttons = input.unwrap_or(*buttons);
+            }
+        }
+        let mut live = MovieFrame { command: self.command, buttons };
+        self.command = 0;
+        if let Some(netplay) = &mut self.netplay {
+            match netplay.next_frame(nes, live) {
+                Ok(frame) => live = frame,
+                Err(e) => {
+                    println!("Netplay ended: {}", e);
+                    self.netplay = None;
+                }
+            }
+        }
+        if let Some(frame) = self.netplay.as_mut().and_then(Netplay::take_desync) {
+            eprintln!("Netplay desynced: the two machines differed at frame {}", frame);
+        }
+        let frame = match &mut self.movie {
+            Some(MovieMode::Record(movie, _)) => {
+                movie.record(live);
+                live
+            }
+            Some(MovieMode::Play(movie)) => match movie.frame(self.movie_frame) {
+                Some(frame) => frame,
+                None => {
+                    println!("Movie finished after {} frames", movie.len());
+                    self.movie = None;
+                    live
+                }
+            },
+            None => live,
+        };
+        self.movie_frame += 1;
+
+        let result = if frame.command & COMMAND_POWER != 0 {
+            nes.power_cycle()
+        } else if frame.command & COMMAND_RESET != 0 {
+            nes.reset()
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to reset: {}", e);
+        }
+
+        for (port, &state) in frame.buttons.iter().enumerate() {
+            nes.set_controller(port, state);
+        }
+        /* Players 3 and 4 can't be recorded, there being no Four Score in movies */
+        for (player, &state) in held.iter().enumerate().skip(2) {
+            nes.set_controller(player, state);
+        }
+    }
+
+    /* Swap the cartridge. The new one starts from power on, so a movie of the old one
+       can't go on, and nor can netplay, the other player still having the old one. */
+    fn load_rom(&mut self, rom: &[u8]) {
+        let result = self.nes.lock().unwrap().load_rom(rom).map_err(|e| e.to_string());
+        if result.is_ok() {
+            self.save_movie();
+            self.movie = None;
+            if self.netplay.take().is_some() {
+                println!("Netplay ended, as the cartridge was swapped");
+            }
+            self.command = 0;
+            self.frame_start = true;
+        }
+        let _ = self.updates.send(Update::Loaded(result));
+    }
+
+    /* Run a script, replacing any already running. The machine mustn't be locked, as the script may get at it. */
+    fn load_script(&mut self, path: &Path) {
+        self.stop_script();
+        match Script::load(path, Arc::clone(&self.nes)) {
+            Ok(script) => {
+                println!("Running script {}", script.path().display());
+                self.script = Some(script);
+            }
+            Err(e) => eprintln!("Failed to load script {}", e),
+        }
+    }
+
+    fn stop_script(&mut self) {
+        if self.script.take().is_some() {
+            let _ = self.updates.send(Update::Overlay(vec![]));
+        }
+    }
+
+    /* Call into the script, letting go of the machine meanwhile so that the script can get at it.
+       A script which fails is stopped, as FCEUX does. */
+    fn call_script<'a>(&mut self, shared: &'a Mutex<Nes>, nes: MutexGuard<'a, Nes>,
+        call: impl FnOnce(&mut Script) -> Result<(), String>) -> MutexGuard<'a, Nes> {
+        let Some(script) = &mut self.script else {
+            return nes;
+        };
+        drop(nes);
+        if let Err(e) = call(script) {
+            eprintln!("Script stopped: {}", e);
+            self.stop_script();
+        }
+        shared.lock().unwrap()
+    }
+
+    fn save_movie(&mut self) {
+        if let Some(MovieMode::Record(movie, path)) = self.movie.take() {
+            match fs::write(&path, movie.to_fm2()) {
+                Ok(_) => println!("Recorded {} frames to {}", movie.len(), path.display()),
+                Err(e) => eprintln!("Failed to write movie to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    fn send_frame(&mut self, nes: &mut Nes) -> bool {
+        if let Some(script) = &mut self.script {
+            if self.updates.send(Update::Overlay(script.take_overlay())).is_err() {
+                return false;
+            }
+        }
+
+        let mut audio = vec![0f32; 2048];
+        let mut count = 0;
+        loop {
+            count += nes.take_samples(&mut audio[count..]);
+            if count < audio.len() {
+                break;
+            }
+            audio.resize(audio.len() * 2, 0.0);
+        }
+        audio.truncate(count);
+
+        let stems = nes.apu_mut().take_stems().map(Box::new);
+        self.updates.send(Update::Frame(Box::new(*nes.framebuffer()), audio, stems)).is_ok()
+    }
+}
+
+/* Flush the CPU's wait cycles. Returns whether a frame was completed meanwhile. */
+fn flush_cpu(nes: &mut Nes) -> Result<bool, String> {
+    let mut frame_done = false;
+    while nes.cpu().wait_cycles > 0 {
+        frame_done |= nes.tick().map_err(|e| e.to_string())?;
+    }
+    Ok(frame_done)
+}
+
+/* Tick the machine by one CPU cycle */
+fn tick_cpu(nes: &mut Nes) -> Result<bool, String> {
+    nes.tick().map_err(|e| e.to_string())
+}
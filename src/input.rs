@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+
+use fancy_nes_core::cpu::controller::JoypadButton;
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+/// Players with a Four Score plugged in, the last two only then
+pub const PLAYERS: usize = 4;
+
+/* Names of the NES buttons in a config file */
+const BUTTON_NAMES: [(&str, JoypadButton); 8] = [
+    ("a", JoypadButton::A),
+    ("b", JoypadButton::B),
+    ("select", JoypadButton::SELECT),
+    ("start", JoypadButton::START),
+    ("up", JoypadButton::UP),
+    ("down", JoypadButton::DOWN),
+    ("left", JoypadButton::LEFT),
+    ("right", JoypadButton::RIGHT),
+];
+
+/* Buttons which repeatedly press and release themselves while held (see Command::SetTurbo) */
+const TURBO_NAMES: [(&str, JoypadButton); 2] = [
+    ("turbo_a", JoypadButton::A),
+    ("turbo_b", JoypadButton::B),
+];
+
+/// Translates keyboard and game controller events into the button state of
+/// each player's controller. Bindings are set up with the builder methods,
+/// read from a config file (see `from_toml`), or the defaults can be used:
+///
+/// Port 1: Z (A), X (B), Right Shift (Select), Return (Start), arrow keys,
+///         C (turbo A), V (turbo B)
+/// Port 2: O (A), U (B), Y (Select), P (Start), I/K/J/L
+///
+/// Players 3 and 4, with a Four Score, have no keys unless bound. Game
+/// controllers are bound by button, and are assigned the first free player
+/// as they are attached (see `handle_device_event` and `set_players`).
+pub struct InputMap {
+    keys: HashMap<Keycode, (usize, JoypadButton)>,
+    buttons: HashMap<Button, JoypadButton>,
+    turbo_keys: HashMap<Keycode, (usize, JoypadButton)>,
+    turbo_buttons: HashMap<Button, JoypadButton>,
+    controllers: HashMap<u32, usize>,  /* SDL joystick instance id -> port */
+    players: usize,                    /* How many game controllers are given a port */
+    open: Vec<GameController>,         /* Controllers we opened, which close when dropped */
+
+    state: [JoypadButton; PLAYERS],
+    turbo: [JoypadButton; PLAYERS],  /* Turbo buttons held */
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self::new()
+            .bind_key(0, Keycode::Z, JoypadButton::A)
+            .bind_key(0, Keycode::X, JoypadButton::B)
+            .bind_key(0, Keycode::RShift, JoypadButton::SELECT)
+            .bind_key(0, Keycode::Return, JoypadButton::START)
+            .bind_key(0, Keycode::Up, JoypadButton::UP)
+            .bind_key(0, Keycode::Down, JoypadButton::DOWN)
+            .bind_key(0, Keycode::Left, JoypadButton::LEFT)
+            .bind_key(0, Keycode::Right, JoypadButton::RIGHT)
+            .bind_turbo_key(0, Keycode::C, JoypadButton::A)
+            .bind_turbo_key(0, Keycode::V, JoypadButton::B)
+            .bind_key(1, Keycode::O, JoypadButton::A)
+            .bind_key(1, Keycode::U, JoypadButton::B)
+            .bind_key(1, Keycode::Y, JoypadButton::SELECT)
+            .bind_key(1, Keycode::P, JoypadButton::START)
+            .bind_key(1, Keycode::I, JoypadButton::UP)
+            .bind_key(1, Keycode::K, JoypadButton::DOWN)
+            .bind_key(1, Keycode::J, JoypadButton::LEFT)
+            .bind_key(1, Keycode::L, JoypadButton::RIGHT)
+            .bind_button(Button::A, JoypadButton::A)
+            .bind_button(Button::X, JoypadButton::B)
+            .bind_button(Button::Back, JoypadButton::SELECT)
+            .bind_button(Button::Start, JoypadButton::START)
+            .bind_button(Button::DPadUp, JoypadButton::UP)
+            .bind_button(Button::DPadDown, JoypadButton::DOWN)
+            .bind_button(Button::DPadLeft, JoypadButton::LEFT)
+            .bind_button(Button::DPadRight, JoypadButton::RIGHT)
+            .bind_turbo_button(Button::B, JoypadButton::A)
+            .bind_turbo_button(Button::Y, JoypadButton::B)
+    }
+}
+
+impl InputMap {
+    /// An input map with no bindings at all
+    pub fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+            buttons: HashMap::new(),
+            turbo_keys: HashMap::new(),
+            turbo_buttons: HashMap::new(),
+            controllers: HashMap::new(),
+            open: Vec::new(),
+            players: 2,
+            state: [JoypadButton::empty(); PLAYERS],
+            turbo: [JoypadButton::empty(); PLAYERS],
+        }
+    }
+
+    /// Read bindings from a TOML config file. Each table replaces the default
+    /// bindings for a port's keyboard keys, or for game controllers, and maps
+    /// NES buttons to SDL key or controller button names, singly or in a list:
+    ///
+    ///     [port1]
+    ///     a = "Z"
+    ///     b = ["X", "Left Shift"]
+    ///     start = "Return"
+    ///     turbo_a = "C"
+    ///     ...
+    ///     [port2]
+    ///     ...
+    ///     [port3]             (and [port4], for a Four Score)
+    ///     ...
+    ///     [gamepad]
+    ///     a = "a"
+    ///     b = "x"
+    ///     up = "dpup"
+    ///     ...
+    pub fn from_toml(text: &str) -> Result<Self, String> {
+        let config: toml::Value = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        Self::from_table(config.as_table().ok_or("Input config is not a table")?)
+    }
+
+    /// As `from_toml`, for bindings already parsed, e.g. from the frontend's config
+    pub fn from_table(tables: &toml::value::Table) -> Result<Self, String> {
+        let mut map = Self::default();
+
+        for (section, bindings) in tables {
+            let bindings = bindings.as_table().ok_or_else(|| format!("[{}] is not a table", section))?;
+            let port = match section.as_str() {
+                "port1" => Some(0),
+                "port2" => Some(1),
+                "port3" => Some(2),
+                "port4" => Some(3),
+                "gamepad" => None,
+                _ => return Err(format!("Unknown input config section [{}]", section)),
+            };
+
+            match port {
+                Some(port) => {
+                    map.keys.retain(|_, &mut (p, _)| p != port);
+                    map.turbo_keys.retain(|_, &mut (p, _)| p != port);
+                }
+                None => {
+                    map.buttons.clear();
+                    map.turbo_buttons.clear();
+                }
+            }
+
+            for (name, value) in bindings {
+                let (button, turbo) = BUTTON_NAMES.iter().map(|&(n, b)| (n, b, false))
+                    .chain(TURBO_NAMES.iter().map(|&(n, b)| (n, b, true)))
+                    .find(|(n, _, _)| n == name)
+                    .map(|(_, b, turbo)| (b, turbo))
+                    .ok_or_else(|| format!("Unknown NES button \"{}\" in [{}]", name, section))?;
+
+                let inputs: Vec<&str> = match value {
+                    toml::Value::String(input) => vec![input.as_str()],
+                    toml::Value::Array(inputs) => inputs.iter().filter_map(|i| i.as_str()).collect(),
+                    _ => return Err(format!("Binding for {} in [{}] should be a name or list of names", name, section)),
+                };
+
+                for input in inputs {
+                    map = match port {
+                        Some(port) => {
+                            let key = Keycode::from_name(input).ok_or_else(|| format!("Unknown key \"{}\"", input))?;
+                            if turbo { map.bind_turbo_key(port, key, button) } else { map.bind_key(port, key, button) }
+                        }
+                        None => {
+                            let controller_button = Button::from_string(input).ok_or_else(|| format!("Unknown controller button \"{}\"", input))?;
+                            if turbo { map.bind_turbo_button(controller_button, button) } else { map.bind_button(controller_button, button) }
+                        }
+                    };
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    pub fn bind_key(mut self, port: usize, key: Keycode, button: JoypadButton) -> Self {
+        assert!(port < PLAYERS);
+        self.keys.insert(key, (port, button));
+        self
+    }
+
+    pub fn bind_button(mut self, controller_button: Button, button: JoypadButton) -> Self {
+        self.buttons.insert(controller_button, button);
+        self
+    }
+
+    /// Bind a key to hold a button with turbo
+    pub fn bind_turbo_key(mut self, port: usize, key: Keycode, button: JoypadButton) -> Self {
+        assert!(port < PLAYERS);
+        self.turbo_keys.insert(key, (port, button));
+        self
+    }
+
+    pub fn bind_turbo_button(mut self, controller_button: Button, button: JoypadButton) -> Self {
+        self.turbo_buttons.insert(controller_button, button);
+        self
+    }
+
+    /// How many players game controllers are given ports for as they're attached: 2,
+    /// or 4 with a Four Score
+    pub fn set_players(&mut self, players: usize) {
+        self.players = players.min(PLAYERS);
+    }
+
+    /// Route a game controller (by joystick instance id) to a port
+    pub fn attach_controller(&mut self, which: u32, port: usize) {
+        assert!(port < PLAYERS);
+        self.controllers.insert(which, port);
+    }
+
+    /// Open game controllers as they are plugged in (SDL also reports those
+    /// present at startup this way), giving each the first free port, and
+    /// release their port when they are unplugged. Returns whether the event
+    /// was a controller being added or removed.
+    pub fn handle_device_event(&mut self, event: &Event, subsystem: &GameControllerSubsystem) -> bool {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                let controller = match subsystem.open(which) {
+                    Ok(controller) => controller,
+                    Err(e) => {
+                        println!("Could not open controller {}: {}", which, e);
+                        return true;
+                    }
+                };
+                if self.controllers.contains_key(&controller.instance_id()) {
+                    return true;
+                }
+                match (0..self.players).find(|port| !self.controllers.values().any(|p| p == port)) {
+                    Some(port) => {
+                        println!("Controller \"{}\" attached to port {}", controller.name(), port + 1);
+                        self.attach_controller(controller.instance_id(), port);
+                        self.open.push(controller);
+                    }
+                    None => println!("Controller \"{}\" ignored - every port is taken", controller.name()),
+                }
+                true
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(port) = self.controllers.remove(&which) {
+                    println!("Controller detached from port {}", port + 1);
+                    self.open.retain(|c| c.instance_id() != which);
+                    /* Don't leave its buttons held */
+                    self.state[port] = JoypadButton::empty();
+                    self.turbo[port] = JoypadButton::empty();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether the event was an input event we are bound to
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match *event {
+            Event::KeyDown { keycode: Some(key), .. } => self.set_key(key, true),
+            Event::KeyUp { keycode: Some(key), .. } => self.set_key(key, false),
+            Event::ControllerButtonDown { which, button, .. } => self.set_button(which, button, true),
+            Event::ControllerButtonUp { which, button, .. } => self.set_button(which, button, false),
+            _ => false,
+        }
+    }
+
+    fn set_key(&mut self, key: Keycode, pressed: bool) -> bool {
+        if let Some(&(port, button)) = self.keys.get(&key) {
+            self.state[port].set(button, pressed);
+            true
+        } else if let Some(&(port, button)) = self.turbo_keys.get(&key) {
+            self.turbo[port].set(button, pressed);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn set_button(&mut self, which: u32, controller_button: Button, pressed: bool) -> bool {
+        let port = match self.controllers.get(&which) {
+            Some(&port) => port,
+            None => return false,
+        };
+        if let Some(&button) = self.buttons.get(&controller_button) {
+            self.state[port].set(button, pressed);
+            true
+        } else if let Some(&button) = self.turbo_buttons.get(&controller_button) {
+            self.turbo[port].set(button, pressed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The button state for a port, in the bit order the NES expects
+    pub fn state(&self, port: usize) -> u8 {
+        self.state[port].bits()
+    }
+
+    /// The buttons held with turbo on a port, likewise
+    pub fn turbo(&self, port: usize) -> u8 {
+        self.turbo[port].bits()
+    }
+}
@@ -5,9 +5,29 @@ pub const NES_SCREEN_HEIGHT: u32 = 240 * NES_SCREEN_SCALE;
 pub const NES_SCREEN_WIDTH: u32 = 256 * NES_SCREEN_SCALE;
 pub const NES_DEBUGGER_WIDTH: u32 = 260;
 pub const NES_PPU_INFO_HEIGHT: u32 = 280;
-pub const NES_PPU_INFO_WIDTH: u32 = 20; // Extra width needed to accommodate palettes.
+pub const NES_PPU_INFO_WIDTH: u32 = 280; // Extra width needed to accommodate palettes and the nametables.
 
+pub mod apu_view;
+pub mod capture;
+pub mod config;
 pub mod debug_view;
+pub mod devices;
+pub mod emulator;
+pub mod input;
+pub mod logger;
+pub mod memory_view;
+pub mod netplay;
+pub mod nsf_player;
+pub mod osd;
+pub mod post_process;
+pub mod rom_browser;
+pub mod rom_loader;
+pub mod rom_info_view;
+pub mod save_slots;
+pub mod script;
+pub mod sprite_view;
+pub mod text_cache;
+pub mod wav;
 
 use sdl2::pixels::Color;
 use sdl2::event::Event;
@@ -15,6 +35,7 @@ use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
 use sdl2::render::TextureQuery;
 use std::path::PathBuf;
+use fancy_nes_core::palette::{NtscSettings, Palette};
 use std::time::Duration;
 
 pub fn render_main() {
@@ -40,9 +61,9 @@ pub fn render_main() {
     disasm_strings.iter_mut().enumerate()
             .for_each(|i| {
                 if i.0 == disasm_sel {
-                    *i.1 = "> ".to_owned() + i.1;
+                    *i.1 = "> ".to_owned() + i.1.as_str();
                 } else {
-                    *i.1 = "  ".to_owned() + i.1;
+                    *i.1 = "  ".to_owned() + i.1.as_str();
                 }
              });
 
@@ -79,10 +100,64 @@ pub fn render_main() {
     }
 }
 
-pub fn load_palette(colors: PathBuf) -> Vec<Color> {
-    let mut color_vec: Vec<Color> = vec![];
+/// Where the game screen and the panels around it go. Everything is laid out in
+/// units of 1/NES_SCREEN_SCALE of an NES pixel, and scaled to fit the window.
+/// The debugger sits to the right of the screen, and the PPU info below both.
+#[derive(Clone, Copy, Default)]
+pub struct Layout {
+    pub aspect_correction: bool,  /* Show pixels 8:7 wide, as a TV does, rather than square */
+    pub overscan: [u32; 2],       /* Lines of the frame to hide at the top and bottom */
+}
+
+impl Layout {
+    /// The lines of the frame shown, in NES pixels
+    pub fn visible_lines(&self) -> Rect {
+        let [top, bottom] = self.overscan;
+        Rect::new(0, top as i32, 256, 240 - top - bottom)
+    }
+
+    pub fn screen(&self) -> Rect {
+        let width = if self.aspect_correction { NES_SCREEN_WIDTH * 8 / 7 } else { NES_SCREEN_WIDTH };
+        Rect::new(0, 0, width, self.visible_lines().height() * NES_SCREEN_SCALE)
+    }
+
+    pub fn debugger(&self) -> Rect {
+        Rect::new(self.screen().width() as i32, 0, NES_DEBUGGER_WIDTH, NES_SCREEN_HEIGHT)
+    }
+
+    pub fn ppu_info(&self, show_debugger: bool) -> Rect {
+        let top = if show_debugger { self.screen().height().max(NES_SCREEN_HEIGHT) } else { self.screen().height() };
+        Rect::new(0, top as i32, NES_SCREEN_WIDTH + NES_PPU_INFO_WIDTH, NES_PPU_INFO_HEIGHT)
+    }
+
+    /// The size of everything shown
+    pub fn size(&self, show_debugger: bool, show_ppu_info: bool) -> (u32, u32) {
+        let width = self.screen().width() + if show_debugger { NES_DEBUGGER_WIDTH } else { 0 }
+                                          + if show_ppu_info { NES_PPU_INFO_WIDTH } else { 0 };
+        let height = if show_ppu_info {
+            self.ppu_info(show_debugger).bottom() as u32
+        } else if show_debugger {
+            self.debugger().bottom() as u32
+        } else {
+            0
+        };
+        (width, height.max(self.screen().height()))
+    }
+}
 
-    let data: Vec<u8> = std::fs::read(colors).unwrap();
-    data.chunks(3).for_each(|c| { color_vec.push(Color::RGB(c[0], c[1], c[2])) });
-    color_vec
-}
\ No newline at end of file
+/// The palette from a .pal file, generated with NTSC settings, or the built-in
+/// NTSC palette, in that order of preference
+pub fn load_palette(colors: Option<PathBuf>, ntsc: Option<NtscSettings>) -> Result<Palette, String> {
+    Ok(match colors {
+        Some(path) => {
+            let data = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            Palette::from_pal(&data)?
+        }
+        None => ntsc.map_or_else(Palette::default, Palette::generate),
+    })
+}
+
+/// The SDL colours for each pixel value in a frame (i.e. including colour emphasis)
+pub fn sdl_colours(palette: &Palette) -> Vec<Color> {
+    palette.frame_colours().into_iter().map(|[r, g, b]| Color::RGB(r, g, b)).collect()
+}
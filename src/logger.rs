@@ -0,0 +1,135 @@
+//! Where the emulator's log messages go (see fancy_nes_core::logging): to stderr,
+//! and to a short history which F2 shows over the picture. Each subsystem has its
+//! own level, warn unless set otherwise by --log or the debugger's lg command, as
+//! a list such as "cpu=debug,mapper=trace", where a level alone sets them all.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+use fancy_nes_core::logging::TARGETS;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, TextureCreator, TextureQuery};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+/* How many of the latest messages are kept to show */
+const HISTORY_LINES: usize = 16;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger {
+    levels: Mutex<HashMap<&'static str, LevelFilter>>,
+    history: Mutex<VecDeque<(Level, String)>>,
+}
+
+impl Logger {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.levels.lock().unwrap().get(target).copied().unwrap_or(DEFAULT_LEVEL)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{} {}] {}", record.target(), record.level(), record.args());
+        eprintln!("{}", line);
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() == HISTORY_LINES {
+            history.pop_front();
+        }
+        history.push_back((record.level(), line));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, with levels as given to --log
+pub fn init(spec: Option<&str>) -> Result<(), String> {
+    let logger = LOGGER.get_or_init(|| Logger {
+        levels: Mutex::new(TARGETS.iter().map(|&target| (target, DEFAULT_LEVEL)).collect()),
+        history: Mutex::new(VecDeque::new()),
+    });
+    log::set_logger(logger).map_err(|e| e.to_string())?;
+    log::set_max_level(DEFAULT_LEVEL);
+    match spec {
+        Some(spec) => set_levels(spec).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Change the levels of the subsystems named, e.g. "ppu=trace,apu=off", or of all
+/// of them given a level alone. Returns every subsystem's level, as "cpu=debug, ...".
+pub fn set_levels(spec: &str) -> Result<String, String> {
+    let logger = LOGGER.get().ok_or("Logging isn't set up")?;
+
+    /* Check all of them before changing any */
+    let mut changes = vec![];
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let (targets, level) = match item.split_once('=') {
+            Some((target, level)) => match TARGETS.iter().find(|&&t| t == target) {
+                Some(target) => (vec![*target], level),
+                None => return Err(format!("No subsystem {} - there are {}", target, TARGETS.join(", "))),
+            },
+            None => (TARGETS.to_vec(), item),
+        };
+        let level: LevelFilter = level.parse()
+            .map_err(|_| format!("Bad level {} - use off, error, warn, info, debug or trace", level))?;
+        changes.extend(targets.into_iter().map(|target| (target, level)));
+    }
+
+    let mut levels = logger.levels.lock().unwrap();
+    levels.extend(changes);
+    log::set_max_level(levels.values().copied().max().unwrap_or(DEFAULT_LEVEL));
+    Ok(TARGETS.iter()
+        .map(|&target| format!("{}={}", target, levels[target].as_str().to_ascii_lowercase()))
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+/* The colour of a message, by how serious it is */
+fn colour(level: Level) -> Color {
+    match level {
+        Level::Error => Color::RGB(255, 96, 96),
+        Level::Warn => Color::RGB(255, 208, 96),
+        _ => Color::RGB(224, 224, 224),
+    }
+}
+
+/// Draw the latest messages over the bottom of the picture, on a darkened background
+pub fn draw_log_overlay(canvas: &mut Canvas<Window>, texture_creator: &TextureCreator<WindowContext>,
+    font: &Font, screen: Rect) -> Result<(), String> {
+    let mut lines: Vec<(String, Color)> = match LOGGER.get() {
+        Some(logger) => logger.history.lock().unwrap().iter().map(|(level, line)| (line.clone(), colour(*level))).collect(),
+        None => return Ok(()),
+    };
+    if lines.is_empty() {
+        lines.push(("No log messages".to_string(), Color::RGB(128, 128, 128)));
+    }
+    let line_height = font.recommended_line_spacing();
+    let height = line_height * lines.len() as i32 + 4;
+    let top = screen.height() as i32 - height;
+
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(0, top, screen.width(), height as u32))?;
+    canvas.set_blend_mode(BlendMode::None);
+
+    for (i, (line, colour)) in lines.iter().enumerate() {
+        let surface = font.render(line).blended(*colour).map_err(|e| e.to_string())?;
+        let texture = texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string())?;
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(4, top + 2 + i as i32 * line_height, width, height)))?;
+    }
+    Ok(())
+}
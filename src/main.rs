@@ -1,22 +1,58 @@
-use std::cell::{RefCell, Ref};
+use std::cell::RefCell;
 use std::fs;
 use std::ops::Index;
 use std::path::{PathBuf, Path};
 use std::rc::Rc;
-use clap::{ArgEnum, Parser};
-use fancy_nes_core::cpu::trace::TraceUnit;
-use fancy_nes_core::cpu::NESCpu;
-use fancy_nes_core::ppu::NESPpu;
-use fancy_nes_core::cpu::debug::{disasm_6502, cpu_dump};
+use std::sync::mpsc::TryRecvError;
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{ArgEnum, Parser, Subcommand};
+use fancy_nes_core::apu::Channel;
+use fancy_nes_core::cpu::controller::DeviceKind;
+use fancy_nes_core::cpu::profile::Profiler;
+use fancy_nes_core::cpu::trace::{verify_log, TraceFormat, TraceUnit};
+use fancy_nes_core::cpu::registry;
+use fancy_nes_core::{Mirroring, Nes, Overclock, Region};
+use fancy_nes_core::crash::CrashReport;
+use fancy_nes_core::game_db::{repair_header, GameDb, HeaderFix};
+use fancy_nes_core::bench::run_benchmark;
+use fancy_nes_core::test_rom::{run_test_rom, TestOutcome};
+use fancy_nes_core::movie::Movie;
+use fancy_nes_core::nes::{Frame, FRAME_WIDTH, FRAME_HEIGHT};
+use fancy_nes_core::palette::{draw_frame, PixelFormat};
+use fancy_nes_core::png;
+use fancy_nes_core::rom_info::RomInfo;
+use fancy_nes_core::symbols::Symbols;
+use fancy_nes::apu_view::ApuView;
+use fancy_nes::capture::Capture;
+use fancy_nes::emulator::{Command, Emulator, MovieMode, Update};
+use fancy_nes::config::Config;
 use fancy_nes::debug_view::DebugView;
-use fancy_nes::{load_palette, NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT, NES_DEBUGGER_WIDTH, NES_PPU_INFO_HEIGHT, NES_PPU_INFO_WIDTH};
+use fancy_nes::devices::Devices;
+use fancy_nes::memory_view::MemoryView;
+use fancy_nes::netplay::Netplay;
+use fancy_nes::nsf_player::play_nsf;
+use fancy_nes::osd::Osd;
+use fancy_nes::post_process::PostProcess;
+use fancy_nes::rom_browser::choose_rom;
+use fancy_nes::rom_loader::read_rom;
+use fancy_nes::rom_info_view::draw_rom_info;
+use fancy_nes::save_slots::{SaveSlot, SLOTS};
+use fancy_nes::script::{draw_overlay, Shape};
+use fancy_nes::sprite_view::SpriteView;
+use fancy_nes::wav::AudioRecording;
+use fancy_nes::input::{InputMap, PLAYERS};
+use fancy_nes::logger;
+use fancy_nes::{load_palette, sdl_colours, Layout, NES_SCREEN_SCALE};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::messagebox::{show_message_box, show_simple_message_box, ButtonData, ClickedButton, MessageBoxButtonFlag, MessageBoxFlag};
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Rect, Point};
-use sdl2::render::{TextureQuery, Texture};
+use sdl2::render::{BlendMode, Canvas, TextureQuery, Texture};
 use sdl2::render::TextureAccess::*;
 use sdl2::timer;
+use sdl2::video::{FullscreenType, Window};
 
 // For a reason unknown, the Mac CI build does not link against CoreHaptics for SDL_JOYSTICK
 // support. Create an empty extern block here to force a linkage.
@@ -24,19 +60,21 @@ use sdl2::timer;
 #[link(name = "CoreHaptics", kind = "framework")]
 extern { } 
 
-// Ensure that we aren't trying to use 2 different trace styles
-#[cfg(all(feature = "fceux-log", feature = "nestest-log"))]
-compile_error!("feature \"fceux-log\" and features \"nestest-log\" cannot be enabled at the same time");
+// Roughly 1/8th of a second of 32-bit mono audio at 44.1kHz
+const AUDIO_MAX_QUEUED_BYTES: usize = 4 * 44100 / 8;
 
-enum CPUMode {
-    SingleStep,
-    Continuous,
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
+enum RegionArg {
+    NTSC,
+    PAL,
+    Dendy,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum, Debug)]
-enum Region {
-    NTSC,
-    PAL
+enum MirroringArg {
+    Horizontal,
+    Vertical,
+    FourScreen,
 }
 #[derive(Default)]
 struct Margin {
@@ -47,133 +85,593 @@ struct Margin {
 }
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 /// fancy-nes Nintendo Entertainment System/Famicom Emulator
 struct Args {
-    /// Path to NES ROM image
-    #[clap(required = true, parse(from_os_str))]
-    rom: PathBuf,
+    #[clap(subcommand)]
+    tool: Option<Tool>,
+
+    /// Path to NES ROM image. Without one, a ROM browser is shown to pick one from
+    #[clap(parse(from_os_str))]
+    rom: Option<PathBuf>,
 
-    /// Path to a .pal (palette) file
-    #[clap(short, required = true, parse(from_os_str))]
-    palette: PathBuf,
+    /// Path to a .pal (palette) file, in place of the config file's or the built-in NTSC palette
+    #[clap(short, parse(from_os_str))]
+    palette: Option<PathBuf>,
 
     /// Start ROM with debugger halted
     #[clap(short)]
     halted_debug: bool,
 
-    /// Force a specific region
+    /// Force a specific region: NTSC, PAL, or Dendy (PAL famiclone) timing, rather than the header's
     #[clap(short, arg_enum)]
-    region: Option<Region>,
+    region: Option<RegionArg>,
+
+    /// Correct ROM headers from this game database (NES 2.0 XML, e.g. nes20db.xml), in place of the config file's
+    #[clap(long, parse(from_os_str))]
+    game_db: Option<PathBuf>,
+
+    /// Run the ROM with this mapper number, whatever its header says
+    #[clap(long, value_name = "N")]
+    force_mapper: Option<u16>,
+
+    /// Run the ROM with this hardwired mirroring, whatever its header says
+    #[clap(long, arg_enum)]
+    force_mirroring: Option<MirroringArg>,
+
+    /// Halt on undocumented opcodes, rather than executing them
+    #[clap(long)]
+    strict_opcodes: bool,
+
+    /// Record controller input to a movie file (.fm2), written on exit
+    #[clap(long, parse(from_os_str), conflicts_with = "play")]
+    record: Option<PathBuf>,
+
+    /// Play back a movie file (.fm2) in place of the controllers
+    #[clap(long, parse(from_os_str))]
+    play: Option<PathBuf>,
+
+    /// Wait for another player to connect on this UDP port, and play with them as player 2
+    #[clap(long, value_name = "PORT", conflicts_with_all = &["play", "connect"])]
+    host: Option<u16>,
+
+    /// Connect to a player hosting at this address (host:port), and play as player 2
+    #[clap(long, value_name = "ADDRESS", conflicts_with = "play")]
+    connect: Option<String>,
+
+    /// Frames between a button being pressed and the game seeing it, when hosting netplay.
+    /// More hides more network latency.
+    #[clap(long, default_value_t = 2)]
+    netplay_delay: u8,
+
+    /// Keyboard and game controller bindings (.toml), in place of the defaults
+    #[clap(long, parse(from_os_str))]
+    input: Option<PathBuf>,
+
+    /// Enable a cheat: a Game Genie code, or AAAA:VV to freeze RAM. May be repeated.
+    #[clap(long, multiple_occurrences(true))]
+    cheat: Vec<String>,
+
+    /// Log each instruction executed to this file from power on. F8 starts and stops tracing
+    /// (to <rom>.log if not given), as does the debugger's tr command.
+    #[clap(long, parse(from_os_str))]
+    trace: Option<PathBuf>,
+
+    /// Trace log format: nestest, fceux or mesen
+    #[clap(long, default_value = "nestest")]
+    trace_format: TraceFormat,
+
+    /// Add the PPU's scanline and dot to each line of the trace
+    #[clap(long)]
+    trace_ppu: bool,
+
+    /// Count the cycles spent in each subroutine and at each instruction from power on,
+    /// and report where the time went to this file on exit. The debugger's pr command does too.
+    #[clap(long, parse(from_os_str))]
+    profile: Option<PathBuf>,
+
+    /// Log which bytes of the ROM are run as code or read as data to this FCEUX .cdl file,
+    /// carrying on from it if it exists, and save it on exit
+    #[clap(long, parse(from_os_str))]
+    cdl: Option<PathBuf>,
+
+    /// Levels of the emulator's log messages by subsystem (cpu, ppu, mapper, apu and dma),
+    /// e.g. "cpu=debug,mapper=trace", or a level alone for all of them. Warnings by default.
+    #[clap(long, value_name = "LEVELS")]
+    log: Option<String>,
+
+    /// Start executing at this address (in hex) rather than the reset vector,
+    /// e.g. C000 for nestest's automated mode
+    #[clap(long, parse(try_from_str = parse_hex))]
+    start_at: Option<u16>,
+
+    /// Run without a window, checking each instruction against a golden trace in
+    /// nestest format (e.g. nestest.log), and report where emulation first differs
+    #[clap(long, parse(from_os_str))]
+    verify_log: Option<PathBuf>,
+
+    /// Run a Rhai script alongside the game, e.g. a bot or a practice hack. F3 reloads it.
+    #[clap(long, parse(from_os_str))]
+    script: Option<PathBuf>,
+
+    /// Labels for the debugger to show in place of addresses: FCEUX .nl, Mesen .mlb,
+    /// ca65 .dbg or ld65 -Ln files. May be repeated. Those beside the ROM are loaded anyway.
+    #[clap(long, parse(from_os_str), multiple_occurrences(true))]
+    symbols: Vec<PathBuf>,
+
+    /// Save each of the first N frames to DIR as a PNG (frame-0001.png, ...), to debug rendering
+    #[clap(long, number_of_values = 2, value_names = &["N", "DIR"])]
+    dump_frames: Vec<String>,
 }
 
-/* Flush the CPU's wait cycles. Invokes the appropriate number of PPU cycles */
-fn flush_cpu(cpu: Rc<RefCell<NESCpu>>, ppu: Rc<RefCell<NESPpu>>) {
-    while cpu.borrow().wait_cycles > 0 {
-        if let Err(e) = cpu.borrow_mut().tick() {
-            panic!("{}\nError: {}", cpu_dump(cpu.borrow()), e);
-        }
-        ppu.borrow_mut().ppu_tick(3); 
+#[derive(Subcommand, Debug)]
+enum Tool {
+    /// Run test ROMs without a window, reporting whether each passed by the
+    /// $6000 status protocol of blargg's tests
+    Test {
+        /// Test ROM images
+        #[clap(required = true, parse(from_os_str))]
+        roms: Vec<PathBuf>,
+
+        /// Give up on a test after this many frames
+        #[clap(long, default_value_t = 3600)]
+        frames: u32,
+    },
+
+    /// Run a ROM without a window as fast as possible, and report the frames
+    /// and CPU cycles emulated per second
+    Bench {
+        #[clap(parse(from_os_str))]
+        rom: PathBuf,
+
+        /// How many frames to run for, from power on
+        #[clap(long, default_value_t = 600)]
+        frames: u32,
+    },
+
+    /// List the mappers supported, and what each can do
+    Mappers,
+
+    /// Describe a ROM from its header, with checksums of the file and its PRG and CHR ROM,
+    /// e.g. for a bug report or to look it up in a game database
+    Info {
+        #[clap(parse(from_os_str))]
+        rom: PathBuf,
+    },
+
+    /// Play the songs of an NSF music file
+    Nsf {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+fn parse_hex(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s.trim_start_matches('$'), 16).map_err(|_| format!("{} is not a hex address", s))
+}
+
+/* Report an error we can't carry on from, in a dialog as well as on the console,
+   since there may be no console to see. SDL can show the dialog before it's initialised. */
+fn fatal(message: String) -> ! {
+    eprintln!("{}", message);
+    let _ = show_simple_message_box(MessageBoxFlag::ERROR, "fancy-nes", &message, None);
+    std::process::exit(1);
+}
+
+/* Ask which of an archive's ROMs to play, by name */
+fn choose_from_archive(names: &[String]) -> Option<usize> {
+    let buttons: Vec<ButtonData> = names.iter().enumerate()
+        .map(|(i, name)| ButtonData { flags: MessageBoxButtonFlag::NOTHING, button_id: i as i32, text: name })
+        .collect();
+    match show_message_box(MessageBoxFlag::INFORMATION, &buttons, "fancy-nes", "The archive holds more than one ROM. Which should be played?", None, None) {
+        Ok(ClickedButton::CustomButton(button)) => Some(button.button_id as usize),
+        _ => None,
     }
 }
 
-fn get_screen_size(show_debugger: bool, show_ppu_info: bool) -> (u32, u32) {
-    let width = NES_SCREEN_WIDTH + if show_debugger { NES_DEBUGGER_WIDTH } else { 0 }
-                                      + if show_ppu_info { NES_PPU_INFO_WIDTH } else { 0 }; 
+/* Correct a ROM's header by the game database, then any fix given on the command line */
+fn repair_rom(rom: Vec<u8>, game_db: &GameDb, forced: &HeaderFix) -> Result<Vec<u8>, String> {
+    let fix = match game_db.lookup(&rom) {
+        Some(known) => {
+            println!("Found the ROM in the game database - correcting its header");
+            forced.clone().or(known)
+        }
+        None => forced.clone(),
+    };
+    if fix.is_empty() { Ok(rom) } else { repair_header(&rom, &fix) }
+}
 
-    let height = NES_SCREEN_HEIGHT + if show_ppu_info { NES_PPU_INFO_HEIGHT } else { 0 };
+/* Traces started without a path go alongside the ROM, e.g. smb.nes -> smb.log */
+fn trace_path(rom: &Path) -> PathBuf {
+    rom.with_extension("log")
+}
 
-    (width, height)
+/* Crash reports go alongside the ROM too, stamped so they don't overwrite each other, e.g. smb-crash-1700000000.txt */
+fn crash_path(rom: &Path) -> PathBuf {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let stem = rom.file_stem().map_or("fancy-nes".into(), |s| s.to_string_lossy());
+    rom.with_file_name(format!("{}-crash-{}.txt", stem, secs))
 }
 
-fn main() {
-    let args: Args;
-    if cfg!(all(debug_assertions, feature = "nestest-log")) {
-        // Mock program arguments
-        args = Args {
-            region: Some(Region::NTSC),
-            rom: PathBuf::from("tools/roms/nestest.nes"),
-            palette: PathBuf::from("data/palette/default.pal"),
-            halted_debug: false,
-        };
-        
-    } else {
-        args = Args::parse();
+/* Screenshots too, stamped to the millisecond, e.g. smb-1700000000123.png */
+fn screenshot_path(rom: &Path) -> PathBuf {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let stem = rom.file_stem().map_or("fancy-nes".into(), |s| s.to_string_lossy());
+    rom.with_file_name(format!("{}-{}.png", stem, millis))
+}
+
+/* Captures go beside the ROM, e.g. smb-capture-1700000000.mp4, or smb-capture-1700000000/ and .wav */
+fn capture_path(rom: &Path) -> PathBuf {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let stem = rom.file_stem().map_or("fancy-nes".into(), |s| s.to_string_lossy());
+    rom.with_file_name(format!("{}-capture-{}", stem, secs))
+}
+
+/* Audio recordings go beside the ROM too, e.g. smb-audio-1700000000.wav, and .pulse1.wav onwards for stems */
+fn recording_path(rom: &Path) -> PathBuf {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let stem = rom.file_stem().map_or("fancy-nes".into(), |s| s.to_string_lossy());
+    rom.with_file_name(format!("{}-audio-{}", stem, secs))
+}
+
+/* Run test ROMs one after another, returning the exit status: 0 if they all passed */
+fn run_test_roms(roms: &[PathBuf], max_frames: u32) -> i32 {
+    let mut failures = 0;
+    for rom in roms {
+        let result = read_rom(rom, |_| Some(0))
+            .and_then(|data| run_test_rom(&data, max_frames).map_err(|e| e.to_string()));
+        match result {
+            Ok(result) => {
+                println!("{}: {}", rom.display(), result);
+                if result.outcome != TestOutcome::Passed {
+                    failures += 1;
+                }
+            }
+            Err(e) => {
+                println!("{}: {}", rom.display(), e);
+                failures += 1;
+            }
+        }
     }
 
-    let mut show_ppu_info = false;
-    let mut palette_selected = 0;
-    let mut show_debugger = args.halted_debug;
+    println!("{} of {} passed", roms.len() - failures, roms.len());
+    if failures == 0 { 0 } else { 1 }
+}
 
-    let mut cpu_mode = if args.halted_debug { CPUMode::SingleStep } else { CPUMode::Continuous };
-    let mut should_step = false;
+/* The symbol files beside a ROM, then those given on the command line */
+fn load_symbols(rom: &Path, extra: &[PathBuf]) -> Symbols {
+    let mut symbols = Symbols::new();
+    match symbols.load_beside(rom) {
+        Ok(paths) => paths.iter().for_each(|path| println!("Loaded symbols from {}", path.display())),
+        Err(e) => eprintln!("Failed to load symbols: {}", e),
+    }
+    for path in extra {
+        match symbols.load(path) {
+            Ok(count) => println!("Loaded {} symbols from {}", count, path.display()),
+            Err(e) => eprintln!("Failed to load symbols: {}", e),
+        }
+    }
+    symbols
+}
 
-    let nes_rom = fs::read(args.rom).unwrap();
+fn list_mappers() {
+    for info in registry::MAPPERS {
+        let ids: Vec<String> = info.ids.iter().map(|id| id.to_string()).collect();
+        println!("{:<14} {:<18} {}", ids.join(", "), info.name, info.capabilities.names().join(", "));
+    }
+}
 
-    let nes_rom_header = fancy_nes_core::NESHeaderMetadata::parse_header(&nes_rom).unwrap();
+/* The speed to run at, as a multiple of normal (see Command::SetSpeed) */
+fn emulation_speed(config: &Config, fast_forward: bool, slow_motion: bool) -> Option<f64> {
+    if fast_forward {
+        Some(config.fast_forward as f64).filter(|&speed| speed > 0.0)
+    } else if slow_motion {
+        Some(1.0 / config.slow_motion as f64)
+    } else {
+        Some(1.0)
+    }
+}
 
-    // Controller status
-    let mut joy1 = RefCell::new(0 as u8);
-    
-    // Load the PRG and CHR roms
-    let cpu_cell = Rc::new(RefCell::new(NESCpu::new(nes_rom_header.mapper_id as usize, &joy1)));
-    let mut ppu = Rc::new(RefCell::new(NESPpu::new(nes_rom_header.mapper_id as usize, Rc::clone(&cpu_cell), nes_rom_header.hardwired_mirroring)));
+/* Darken the lower half of each of the NES's lines, as the gaps between a CRT's scanlines */
+fn draw_scanlines(canvas: &mut Canvas<Window>, screen: Rect, intensity: f32) {
+    let lines: Vec<Rect> = (0..screen.height() / NES_SCREEN_SCALE)
+        .map(|line| Rect::new(0, (line * NES_SCREEN_SCALE + NES_SCREEN_SCALE / 2) as i32, screen.width(), NES_SCREEN_SCALE / 2))
+        .collect();
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, (intensity * 255.0) as u8));
+    canvas.fill_rects(&lines).unwrap();
+    canvas.set_blend_mode(BlendMode::None);
+}
 
-    let mut prg_rom_data = vec![0; nes_rom_header.prg_rom_size as usize];
-    let chr_rom_data: Vec<u8>;
+/* The window size for the panels shown. The layout is designed at NES_SCREEN_SCALE, and scaled to fit. */
+fn get_screen_size(layout: &Layout, show_debugger: bool, show_ppu_info: bool, scale: u32) -> (u32, u32) {
+    let (width, height) = layout.size(show_debugger, show_ppu_info);
+    (width * scale / NES_SCREEN_SCALE, height * scale / NES_SCREEN_SCALE)
+}
 
-    if nes_rom_header.has_trainer {
-        println!("ROM has trainer - ignoring.");
+/* Scale the layout to the window, returning where its origin lands. In a window that's by
+   the configured scale; fullscreen, it's the largest whole multiple of the NES's resolution
+   that fits, centred with black bars around it. */
+fn fit_layout(canvas: &mut Canvas<Window>, size: (u32, u32), scale: u32) -> Point {
+    let (output_width, output_height) = canvas.output_size().unwrap();
+    let scale = match canvas.window().fullscreen_state() {
+        FullscreenType::Off => scale,
+        _ => (output_width * NES_SCREEN_SCALE / size.0).min(output_height * NES_SCREEN_SCALE / size.1).max(1),
+    };
+    let render_scale = scale as f32 / NES_SCREEN_SCALE as f32;
+    canvas.set_scale(render_scale, render_scale).unwrap();
+    Point::new(((output_width as f32 / render_scale - size.0 as f32) / 2.0).max(0.0) as i32,
+               ((output_height as f32 / render_scale - size.1 as f32) / 2.0).max(0.0) as i32)
+}
 
-        let i = nes_rom_header.prg_rom_size as usize;
-        prg_rom_data.copy_from_slice(&nes_rom[528..(528 + i)]);
-        chr_rom_data = nes_rom[(528 + i)..(528 + i + nes_rom_header.chr_rom_size as usize)].to_vec();
-    } else {
-        let i = nes_rom_header.prg_rom_size as usize;
-        prg_rom_data.copy_from_slice(&nes_rom[16..(16+nes_rom_header.prg_rom_size as usize)]);
-        chr_rom_data = nes_rom[(16 + i)..(16 + i + nes_rom_header.chr_rom_size as usize)].to_vec();
+fn main() {
+    let args = Args::parse();
+    logger::init(args.log.as_deref()).unwrap_or_else(|e| fatal(format!("--log: {}", e)));
+    if let Some(Tool::Test { roms, frames }) = &args.tool {
+        std::process::exit(run_test_roms(roms, *frames));
     }
+    if let Some(Tool::Bench { rom, frames }) = &args.tool {
+        let result = read_rom(rom, |_| Some(0))
+            .and_then(|data| run_benchmark(&data, *frames).map_err(|e| e.to_string()));
+        match result {
+            Ok(result) => println!("{}: {}", rom.display(), result),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(Tool::Mappers) = &args.tool {
+        list_mappers();
+        return;
+    }
+    if let Some(Tool::Info { rom }) = &args.tool {
+        let result = read_rom(rom, |_| Some(0))
+            .and_then(|data| RomInfo::new(&data).map_err(|e| format!("{}: {}", rom.display(), e)));
+        match result {
+            Ok(info) => print!("{}", info),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(Tool::Nsf { file }) = &args.tool {
+        play_nsf(file).unwrap_or_else(|e| fatal(e));
+        return;
+    }
+    let mut config = Config::load();
+
+    let mut rom = match args.rom.clone() {
+        Some(rom) => rom,
+        None => {
+            /* Start where the last ROM was played from, as the likeliest place for the next */
+            let dir = config.recent_roms.first().and_then(|rom| rom.parent()).filter(|dir| dir.is_dir())
+                .map_or_else(|| std::env::current_dir().unwrap_or_default(), Path::to_path_buf);
+            match choose_rom(&dir, &config.recent_roms) {
+                Ok(Some(rom)) => rom,
+                Ok(None) => return,
+                Err(e) => fatal(format!("Failed to open the ROM browser: {}", e)),
+            }
+        }
+    };
 
-    cpu_cell.borrow_mut().memory.mapper.load_rom(&prg_rom_data);
-    ppu.borrow_mut().mapper.load_rom(&chr_rom_data);
+    let mut show_ppu_info = config.show_ppu_info;
+    let mut palette_selected = 0;
+    let mut show_debugger = args.halted_debug || config.show_debugger;
+    let mut show_memory = false;
+    let mut show_sprites = false;
+    let mut show_apu = false;
+    let mut show_log = false;
+    let mut rom_info: Option<RomInfo> = None;  /* Shown over the picture while Some */
+
+    let mut running = !args.halted_debug;
+    let mut paused = false;  /* By the Pause key, between frames - see emulator::Command::Pause */
+    let mut state_slot: u8 = 0;
+
+    // Headers known to be wrong are put right, by the game database or by hand
+    let mut game_db = GameDb::new();
+    if let Some(path) = args.game_db.as_ref().or(config.game_db.as_ref()) {
+        match game_db.load(path) {
+            Ok(count) => println!("Loaded {} games from {}", count, path.display()),
+            Err(e) => println!("Failed to load the game database: {}", e),
+        }
+    }
+    let forced = HeaderFix {
+        mapper_id: args.force_mapper,
+        mirroring: args.force_mirroring.map(|mirroring| match mirroring {
+            MirroringArg::Horizontal => Mirroring::Horizontal,
+            MirroringArg::Vertical => Mirroring::Vertical,
+            MirroringArg::FourScreen => Mirroring::FourScreen,
+        }),
+        ..HeaderFix::default()
+    };
+
+    // The image as read, before any repair, for the ROM info panel
+    let mut rom_image = read_rom(&rom, choose_from_archive).unwrap_or_else(|e| fatal(e));
+    let nes_rom = repair_rom(rom_image.clone(), &game_db, &forced)
+        .unwrap_or_else(|e| fatal(format!("Failed to load {}: {}", rom.display(), e)));
+
+    let nes_rom_header = fancy_nes_core::NESHeaderMetadata::parse_header(&nes_rom)
+        .unwrap_or_else(|e| fatal(format!("Failed to load {}: {}", rom.display(), e)));
+    println!("{} ROM, mapper {}.{}, {:?} timing", if nes_rom_header.is_nes2 { "NES 2.0" } else { "iNES" },
+        nes_rom_header.mapper_id, nes_rom_header.submapper_id, nes_rom_header.timing);
 
-    let palette = load_palette(args.palette);
-    let mut trace_unit: Option<TraceUnit> = None;
+    if nes_rom_header.has_trainer {
+        println!("ROM has a trainer - loading it to $7000.");
+    }
 
-    cpu_cell.borrow_mut().reset();
-    #[cfg(all(debug_assertions, feature = "nestest-log"))] 
-    {
-        let mut cpu = cpu_cell.borrow_mut();
+    let mut nes = Nes::from_rom(&nes_rom).unwrap_or_else(|e| fatal(format!("Failed to load {}: {}", rom.display(), e)));
+    nes.set_strict_opcodes(args.strict_opcodes);
 
-        // In debug mode, we are loading nestest.nes ROM without PPU support.
-        // Also, the trace unit is attached to provide instruction + cycle logs.
-        cpu.PC = 0xC000;
+    for code in &args.cheat {
+        match nes.cpu_mut().bus.cheats.add(code) {
+            Ok(id) => println!("Cheat #{}: {}", id, code),
+            Err(e) => fatal(e),
+        }
+    }
 
-        // Start with some dummy address on the stack (0x0800)
-        cpu.SP = 0xFF;
-        cpu.A = 0x00;
-        cpu.op_stack_push(false);
-        cpu.A = 0x08;
-        cpu.op_stack_push(false);
-        cpu.A = 0x00;
+    // Unless forced on the command line, the cartridge runs in the region it was made for
+    let forced_region = args.region.map(|region| match region {
+        RegionArg::NTSC => Region::NTSC,
+        RegionArg::PAL => Region::PAL,
+        RegionArg::Dendy => Region::Dendy,
+    });
+    if let Some(region) = forced_region {
+        nes.set_region(region);
+    }
 
-        // nestest.log starts with 7 cycles
-        cpu.cycle = 7;
+    // Netplay is in the host's region, with both machines from power on
+    let netplay = if let Some(port) = args.host {
+        Some(Netplay::host(port, &nes_rom, nes.region(), args.netplay_delay as u32).unwrap_or_else(|e| fatal(e)))
+    } else {
+        args.connect.as_ref().map(|addr| {
+            let netplay = Netplay::connect(addr, &nes_rom).unwrap_or_else(|e| fatal(e));
+            nes.set_region(netplay.region());
+            netplay
+        })
+    };
+    if let Some(netplay) = &netplay {
+        println!("Playing netplay as player {}", netplay.local_port() + 1);
+    }
 
-        trace_unit = Some(TraceUnit::new(Path::new("out.log")));
+    // A movie is replayed in the region it was recorded in
+    let movie = if let Some(path) = &args.play {
+        let movie = fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|text| Movie::from_fm2(&text))
+            .and_then(|movie| movie.check_rom(&nes_rom).map(|_| movie))
+            .unwrap_or_else(|e| fatal(format!("Failed to load movie {}: {}", path.display(), e)));
+        println!("Playing {} frames from {}", movie.len(), path.display());
+        nes.set_region(movie.region);
+        Some(MovieMode::Play(movie))
+    } else {
+        let rom_filename = rom.file_name().unwrap_or_default().to_string_lossy();
+        args.record.as_ref().map(|path| MovieMode::Record(Movie::new(&nes_rom, &rom_filename, nes.region()), path.clone()))
+    };
+    println!("Running with {:?} timing", nes.region());
+
+    // Movies and netplay only carry what's pressed on controllers
+    let devices = if (movie.is_some() || netplay.is_some()) && config.devices != Config::default().devices {
+        println!("Movies and netplay only support controllers - plugging them in, rather than [devices]");
+        Config::default().devices
+    } else {
+        config.devices
+    };
+    for (port, kind) in devices.into_iter().enumerate() {
+        nes.set_device(port, kind.create(port).unwrap_or_else(|e| fatal(e)));
     }
-    #[cfg(all(debug_assertions, feature = "fceux-log"))]
-    {
-        // We can just start trace_unit without any hacks
-        trace_unit = Some(TraceUnit::new(Path::new("out.log")));
+    let mut device_input = Devices::new(devices);
+
+    if let Some(pc) = args.start_at {
+        nes.start_at(pc);
     }
+    if let Some(path) = &args.verify_log {
+        let golden = fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            std::process::exit(2);
+        });
+        match verify_log(&mut nes, &golden) {
+            Ok(lines) => println!("All {} lines of {} matched", lines, path.display()),
+            Err(divergence) => {
+                eprintln!("{}", divergence);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Only ROMs actually played are remembered
+    config.add_recent_rom(&rom);
+    config.save();
+
+    if let Some(path) = &args.trace {
+        let trace = TraceUnit::new(path, args.trace_format, args.trace_ppu).unwrap_or_else(|e| fatal(e));
+        nes.start_trace(trace);
+        println!("Tracing to {} ({})", path.display(), args.trace_format);
+    }
+    if let Some(path) = &args.profile {
+        nes.start_profile(Profiler::new(path));
+    }
+    if let Some(path) = &args.cdl {
+        let saved = if path.exists() {
+            Some(fs::read(path).unwrap_or_else(|e| fatal(format!("Failed to read {}: {}", path.display(), e))))
+        } else {
+            None
+        };
+        nes.start_cdl(saved.as_deref()).unwrap_or_else(|e| fatal(format!("{}: {}", path.display(), e)));
+    }
+
+    let nes_palette = load_palette(args.palette.clone().or_else(|| config.palette.clone()), config.ntsc)
+        .unwrap_or_else(|e| fatal(format!("Failed to load palette: {}", e)));
+    let palette = sdl_colours(&nes_palette);
+    let frame_rgb = nes_palette.frame_colours();  /* The RGB bytes of each pixel value, for the screen texture */
+    nes.set_palette(nes_palette.clone());
+    if config.overclock {
+        nes.set_overclock(config.overclock_lines);
+    }
+
+    // Frames to save as they arrive, and where to
+    let mut dump_frames = match args.dump_frames.as_slice() {
+        [count, dir] => {
+            let count: u32 = count.parse().unwrap_or_else(|_| fatal(format!("--dump-frames: {} is not a number of frames", count)));
+            fs::create_dir_all(dir).unwrap_or_else(|e| fatal(format!("Failed to create {}: {}", dir, e)));
+            Some((count, PathBuf::from(dir)))
+        }
+        _ => None,
+    };
+    let mut frames_received: u32 = 0;
+    let mut capture: Option<Capture> = None;
+    let mut recording: Option<AudioRecording> = None;
+    let mut dropped_rom: Option<(PathBuf, Vec<u8>)> = None;  /* A ROM dropped on the window and its image, until the emulation thread has loaded it */
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let timer_subsystem = sdl_context.timer().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+
+    // Game controllers are opened as SDL reports them, including any present at startup
+    let mut input_map = match (&args.input, &config.input) {
+        (Some(path), _) => fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|text| InputMap::from_toml(&text))
+            .unwrap_or_else(|e| fatal(format!("Failed to load input config {}: {}", path.display(), e))),
+        (None, Some(bindings)) => InputMap::from_table(bindings).unwrap_or_else(|e| {
+            println!("Ignoring [input] in config: {}", e);
+            InputMap::default()
+        }),
+        (None, None) => InputMap::default(),
+    };
+    if devices[0] == DeviceKind::FourScore {
+        input_map.set_players(PLAYERS);
+    }
 
-    let mut window = video_subsystem.window("fancy-nes v0.1.0", 
-        NES_SCREEN_WIDTH + (if args.halted_debug { NES_DEBUGGER_WIDTH } else { 0 } ), 
-        NES_SCREEN_HEIGHT)
+    // Audio is pushed to a queue once per frame, rather than pulled by a callback,
+    // so the emulator remains in control of timing.
+    let audio_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &audio_spec).unwrap();
+    nes.set_sample_rate(audio_queue.spec().freq as u32);
+    nes.apu_mut().set_mixer(config.mixer);
+    audio_queue.resume();
+
+    // Changing overclocking mid-game would put the players out of sync
+    let netplay_active = netplay.is_some();
+
+    // From here on, the NES belongs to the emulation thread
+    let emulator = Emulator::spawn(nes, args.halted_debug, movie, netplay);
+    emulator.send(Command::SetTurboRate(config.turbo_rate));
+    if let Some(script) = &args.script {
+        emulator.send(Command::LoadScript(script.clone()));
+    }
+
+    let layout = Layout { aspect_correction: config.aspect_correction, overscan: config.overscan };
+    let window_size = get_screen_size(&layout, show_debugger, show_ppu_info, config.scale);
+    let mut window = video_subsystem.window("fancy-nes v0.1.0", window_size.0, window_size.1)
         .opengl()
         .position_centered()
         .build()
@@ -181,337 +679,724 @@ fn main() {
 
     let pixel_format = window.window_pixel_format();
 
+    // The game screen is drawn through a shader in the renderer's GL context, so ask for
+    // OpenGL rather than whatever SDL would pick. Without it, the picture is copied flat.
+    sdl2::hint::set("SDL_RENDER_DRIVER", "opengl");
     let canvas_cell = Rc::new(RefCell::new(window.into_canvas()
         .accelerated()
         .present_vsync()
         .build().unwrap()));
+    let mut post_process = match PostProcess::new(&video_subsystem, &canvas_cell.borrow(), config.shader.as_deref()) {
+        Ok(post_process) => Some(post_process),
+        Err(e) => {
+            if config.shader.is_some() || config.curvature > 0.0 {
+                eprintln!("Drawing the picture without shaders: {}", e);
+            }
+            None
+        }
+    };
 
     let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string()).unwrap();
-    let mut debug_view = DebugView::new(canvas_cell.borrow().texture_creator(), &ttf_context, Rc::clone(&cpu_cell), Rc::clone(&ppu));
-
-    // Create the texture and buffer which we will write RGB data into
+    let debug_texture_creator = canvas_cell.borrow().texture_creator();
+    let mut debug_view = DebugView::new(&debug_texture_creator, &ttf_context, &emulator.lock());
+    debug_view.set_symbols(load_symbols(&rom, &args.symbols));
+    let mut memory_view = MemoryView::new(canvas_cell.borrow().texture_creator(), &ttf_context);
+    let mut sprite_view = SpriteView::new(canvas_cell.borrow().texture_creator(), &ttf_context);
+    let mut apu_view = ApuView::new(canvas_cell.borrow().texture_creator(), &ttf_context);
+    let overlay_font = ttf_context.load_font("debug.ttf", 12).unwrap();
+    let mut overlay: Vec<Shape> = vec![];  /* Drawn by the script over the latest frame */
+
+    // Create the texture and buffer which we will write RGB data into. Only the game
+    // screen may be smoothed as it's scaled up; SDL takes the filter as each texture is created.
     let nes_texture_creator = canvas_cell.clone().borrow().texture_creator();
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", if config.smooth { "linear" } else { "nearest" });
     let mut nes_texture: Texture = nes_texture_creator
         .create_texture_streaming(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "nearest");
+    let mut osd = Osd::new(ttf_context.load_font("debug.ttf", 12).unwrap(), &nes_texture_creator);
 
-    let mut palette_texture = nes_texture_creator
+    // The pattern tables are only redrawn when CHR or the colours they're drawn in change
+    let mut pattern_textures = [(); 2].map(|_| nes_texture_creator
         .create_texture_streaming(PixelFormatEnum::RGB24, 128, 128)
+        .unwrap());
+    let mut pattern_key: Option<(u32, [u8; 4])> = None;
+
+    let mut nametable_texture = nes_texture_creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, 512, 480)
         .unwrap();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    // Connect the PPU's registers to the CPU's address space
-    cpu_cell.borrow_mut().memory.ppu_registers = Some(ppu.clone());
-
     // Illustrate the contents of the four background, and four sprite palettes
     let palette_view_margin = Margin { top: 3, left: 3, ..Margin::default() };
     let palette_margin = Margin { left: 5, ..Margin::default() };
 
-    // A thread handles emulating the CPU and PPU
-    // and removes the overhead of SDL from the mix.
-    // This allows us to determine shortfalls in emulator
-    // performance separately from those incurred by SDL2.
+    // The emulation thread paces itself, so SDL's vsync only governs how often we present.
+    // Frames which arrive faster than we can present are skipped.
+    let mut frame: Box<Frame> = Box::new([0; 256 * 240]);
+    let mut buttons = [0u8; PLAYERS];
+    let mut turbo = [0u8; PLAYERS];
+
+    // Fast-forward while Tab is held, and slow motion while toggled on with `.
+    // Audio is only played at normal speed.
+    let mut fast_forward = false;
+    let mut slow_motion = false;
 
     // Last update time
     let mut last_time: u64 = timer_subsystem.performance_counter();
 
     'running: loop {
-        match &cpu_mode {
-            CPUMode::SingleStep => { 
-                if should_step { 
-                    // In single-step mode, we need to fast-forward the CPU and
-                    // PPU to the next instruction in order to provide "step-over"-like
-                    // functionality in the debugger view.
-
-                    // Perform a single tick anyways
-                    if let Some(ref mut tu) = trace_unit {
-                        if cpu_cell.borrow().wait_cycles == 0 {
-                            tu.dump(&cpu_cell.borrow());
-                        }
+        let mut halt = false;
+
+        loop {
+            match emulator.try_recv() {
+                Ok(Update::Frame(f, audio, stems)) => {
+                    frame = f;
+                    frames_received += 1;
+                    if let Some(capture) = &capture {
+                        capture.frame(&frame, &audio);
                     }
-                    if let Err(e) = cpu_cell.borrow_mut().tick() {
-                        panic!("{}\nError: {}", cpu_dump(cpu_cell.borrow()), e);
+                    if let Some(Err(e)) = recording.as_mut().map(|recording| recording.write(&audio, stems.as_deref())) {
+                        println!("Recording failed: {}", e);
+                        osd.show("Recording failed");
+                        recording = None;
+                        emulator.lock().apu_mut().set_stems(false);
                     }
-                    ppu.borrow_mut().ppu_tick(3);
 
-                    // Flush the pipeline
-                    flush_cpu(Rc::clone(&cpu_cell), Rc::clone(&ppu));
-                    should_step = false; 
-                } 
-            }
-            CPUMode::Continuous => { 
-                {
-                    if let Some(ref mut tu) = trace_unit {
-                        if cpu_cell.borrow().wait_cycles == 0 {
-                            tu.dump(&cpu_cell.borrow());
+                    if let Some((count, dir)) = &dump_frames {
+                        let path = dir.join(format!("frame-{:0>4}.png", frames_received));
+                        if let Err(e) = fs::write(&path, png::encode_frame(&frame, &nes_palette)) {
+                            println!("Failed to save {}: {}", path.display(), e);
                         }
+                        if frames_received >= *count {
+                            println!("Saved {} frames to {}", count, dir.display());
+                            dump_frames = None;
+                        }
+                    }
+
+                    // If we have fallen too far behind (e.g. the debugger was halted),
+                    // drop the backlog rather than playing it late.
+                    if audio_queue.size() as usize > AUDIO_MAX_QUEUED_BYTES {
+                        audio_queue.clear();
                     }
-                    let mut cpu = cpu_cell.borrow_mut();
-                    if let Err(e) = cpu.tick() {
-                        panic!("{}\nError: {}", cpu_dump(cpu), e);
+                    if !fast_forward && !slow_motion {
+                        audio_queue.queue_audio(&audio).unwrap();
                     }
                 }
-
-                ppu.borrow_mut().ppu_tick(3); 
-
-                // Simple breakpoint mechanism (make this programmable)
-                if cpu_cell.borrow().PC & 0xFFFF == 0xC293 {
-                    // Finish processing this instruction
-                    flush_cpu(Rc::clone(&cpu_cell), Rc::clone(&ppu));
-
-                    cpu_mode = CPUMode::SingleStep;
-                    should_step = false;
-
-                    show_debugger = true;
-                    let size = get_screen_size(show_debugger, show_ppu_info);
-                    canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+                Ok(Update::Overlay(shapes)) => overlay = shapes,
+                Ok(Update::Halted) => halt = true,
+                Ok(Update::Fault(message)) => {
+                    halt = true;
+                    let report = CrashReport::capture(&emulator.lock()).with_error(&message);
+                    let path = crash_path(&rom);
+                    let message = match fs::write(&path, report.to_string()) {
+                        Ok(()) => format!("{}\n\nA crash report was saved to {}", message, path.display()),
+                        Err(e) => format!("{}\n\nFailed to save a crash report to {}: {}", message, path.display(), e),
+                    };
+                    eprintln!("{}", message);
+                    let _ = show_simple_message_box(MessageBoxFlag::ERROR, "Emulation stopped", &message,
+                        canvas_cell.borrow().window());
                 }
+                Ok(Update::Loaded(result)) => {
+                    let (path, image) = dropped_rom.take().unwrap_or_else(|| (rom.clone(), rom_image.clone()));
+                    match result {
+                        Ok(()) => {
+                            println!("Loaded {}", path.display());
+                            if let Some(region) = forced_region {
+                                emulator.lock().set_region(region);
+                            }
+                            debug_view.set_symbols(load_symbols(&path, &[]));
+                            config.add_recent_rom(&path);
+                            config.save();
+                            rom = path;
+                            rom_image = image;
+                            pattern_key = None;
+                            rom_info = None;
+                        }
+                        Err(e) => {
+                            let message = format!("Failed to load {}: {}", path.display(), e);
+                            eprintln!("{}", message);
+                            let _ = show_simple_message_box(MessageBoxFlag::ERROR, "Failed to load ROM", &message,
+                                canvas_cell.borrow().window());
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'running,
             }
         }
 
+        if halt {
+            running = false;
+            debug_view.follow_pc();
+
+            show_debugger = true;
+            let size = get_screen_size(&layout, show_debugger, show_ppu_info, config.scale);
+            canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+        }
+
         let fps = (timer_subsystem.performance_frequency()) / (timer_subsystem.performance_counter() - last_time);
 
-        // Place a minimum render rate of 30 FPS for when in single-step execution mode.
-        if ppu.borrow().frame_ready || fps < 30 {
-            // Set window title to be the FPS
-            canvas_cell.borrow_mut().window_mut().set_title(format!("fancy-nes v0.1.0 - FPS: {}", fps).as_str()).unwrap();
+        // Set window title to be the FPS, and the speed if it isn't normal
+        let speed = if paused { " (paused)" } else if fast_forward { " (fast-forward)" } else if slow_motion { " (slow motion)" } else { "" };
+        canvas_cell.borrow_mut().window_mut().set_title(format!("fancy-nes v0.1.0 - FPS: {}{}", fps, speed).as_str()).unwrap();
 
-            last_time = timer_subsystem.performance_counter();
+        last_time = timer_subsystem.performance_counter();
 
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit {..} |
-                    Event::KeyDown { keycode: Some(Keycode::Escape), ..} => {
-                        break 'running
-                    },
-                    Event::KeyDown { keycode: Some(Keycode::Hash), ..} => {
-                        show_ppu_info = !show_ppu_info;
+        for event in event_pump.poll_iter() {
+            if show_memory && memory_view.handle_event(&event, &mut emulator.lock()) {
+                continue;
+            }
+            if show_debugger && debug_view.handle_event(&event, &mut emulator.lock()) {
+                continue;
+            }
 
-                        let size = get_screen_size(show_debugger, show_ppu_info);
-                        canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+            match event {
+                Event::Quit {..} |
+                Event::KeyDown { keycode: Some(Keycode::Escape), ..} => {
+                    break 'running
+                },
+                // While the keyboard is plugged in, everything but Escape is typed on it
+                ref e @ (Event::KeyDown {..} | Event::KeyUp {..}) if device_input.has_keyboard() => {
+                    if let Some((port, input)) = device_input.handle_event(e) {
+                        emulator.send(Command::SetDeviceInput(port, input));
                     }
-                    Event::KeyDown { keycode: Some(Keycode::Quote), keymod: sdl2::keyboard::Mod::NOMOD, ..} => {
-                        show_debugger = !show_debugger;
+                }
+                Event::KeyDown { keycode: Some(Keycode::Hash), ..} => {
+                    show_ppu_info = !show_ppu_info;
+                    config.show_ppu_info = show_ppu_info;
+                    config.save();
+
+                    let size = get_screen_size(&layout, show_debugger, show_ppu_info, config.scale);
+                    canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+                }
+                Event::KeyDown { keycode: Some(Keycode::Quote), keymod: sdl2::keyboard::Mod::NOMOD, ..} => {
+                    show_debugger = !show_debugger;
+                    config.show_debugger = show_debugger;
+                    config.save();
 
-                        let size = get_screen_size(show_debugger, show_ppu_info);
-                        canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+                    let size = get_screen_size(&layout, show_debugger, show_ppu_info, config.scale);
+                    canvas_cell.borrow_mut().window_mut().set_size(size.0, size.1).unwrap();
+                }
+                // The memory, sprite and APU viewers take the place of the game screen
+                Event::KeyDown { keycode: Some(Keycode::M), ..} => {
+                    show_memory = !show_memory;
+                    show_sprites = false;
+                    show_apu = false;
+                }
+                Event::KeyDown { keycode: Some(Keycode::S), ..} => {
+                    show_sprites = !show_sprites;
+                    show_memory = false;
+                    show_apu = false;
+                }
+                Event::KeyDown { keycode: Some(Keycode::A), ..} => {
+                    show_apu = !show_apu;
+                    show_memory = false;
+                    show_sprites = false;
+                }
+                // While the APU viewer is up, 1-6 mute and unmute its channels in place of picking a slot
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4 |
+                    Keycode::Num5 | Keycode::Num6)), ..} if show_apu => {
+                    let channel = Channel::ALL[(keycode as i32 - Keycode::Num1 as i32) as usize];
+                    let mut nes = emulator.lock();
+                    let muted = !nes.apu().muted(channel);
+                    nes.apu_mut().set_muted(channel, muted);
+                    osd.show(format!("{} {}", channel.name(), if muted { "muted" } else { "unmuted" }));
+                }
+                // and - and = turn the volume down and up, and H and L switch the high- and low-pass filters
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::Minus | Keycode::Equals | Keycode::H | Keycode::L)), ..} if show_apu => {
+                    let mixer = &mut config.mixer;
+                    match keycode {
+                        Keycode::Minus => mixer.volume = ((mixer.volume - 0.1).max(0.0) * 10.0).round() / 10.0,
+                        Keycode::Equals => mixer.volume = ((mixer.volume + 0.1).min(1.0) * 10.0).round() / 10.0,
+                        Keycode::H => mixer.high_pass = !mixer.high_pass,
+                        _ => mixer.low_pass = !mixer.low_pass,
                     }
-                    Event::KeyDown { keycode: Some(Keycode::Quote), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
-                        cpu_mode = match cpu_mode {
-                            CPUMode::SingleStep => CPUMode::Continuous,
-                            CPUMode::Continuous => CPUMode::SingleStep,
-                        }
+                    osd.show(match keycode {
+                        Keycode::H => format!("High-pass filter {}", if mixer.high_pass { "on" } else { "off" }),
+                        Keycode::L => format!("Low-pass filter {}", if mixer.low_pass { "on" } else { "off" }),
+                        _ => format!("Volume {}%", (mixer.volume * 100.0).round()),
+                    });
+                    emulator.lock().apu_mut().set_mixer(config.mixer);
+                    config.save();
+                }
+                Event::KeyDown { keycode: Some(Keycode::Quote), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
+                    running = !running;
+                    emulator.send(if running { Command::Run } else { Command::Halt });
+                }
+                Event::KeyDown { keycode: Some(Keycode::Right), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
+                    if palette_selected < 7 {
+                        palette_selected +=  1;
                     }
-                    Event::KeyDown { keycode: Some(Keycode::Right), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
-                        if palette_selected < 7 {
-                            palette_selected +=  1;
-                        }
+                }
+                Event::KeyDown { keycode: Some(Keycode::Left), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
+                    if palette_selected > 0 {
+                        palette_selected -=  1;
                     }
-                    Event::KeyDown { keycode: Some(Keycode::Left), keymod: sdl2::keyboard::Mod::LALTMOD, ..} => {
-                        if palette_selected > 0 {
-                            palette_selected -=  1;
-                        }
+                }
+                // Reset and power buttons
+                Event::KeyDown { keycode: Some(Keycode::R), keymod: sdl2::keyboard::Mod::LCTRLMOD, ..} => {
+                    emulator.send(Command::Reset);
+                }
+                Event::KeyDown { keycode: Some(Keycode::R), keymod, ..} if keymod == sdl2::keyboard::Mod::LCTRLMOD | sdl2::keyboard::Mod::LSHIFTMOD => {
+                    emulator.send(Command::PowerCycle);
+                }
+                Event::KeyDown { keycode: Some(Keycode::N), ..} => {
+                    emulator.send(Command::Step);
+                }
+                // Pause, and advance a frame at a time while paused (the first press pauses)
+                Event::KeyDown { keycode: Some(Keycode::Pause), ..} => {
+                    paused = !paused;
+                    emulator.send(Command::Pause(paused));
+                }
+                Event::KeyDown { keycode: Some(Keycode::Backslash), ..} => {
+                    if paused {
+                        emulator.send(Command::FrameAdvance);
+                    } else {
+                        paused = true;
+                        emulator.send(Command::Pause(true));
                     }
-                    Event::KeyDown { keycode: Some(Keycode::N), ..} => {
-                        should_step = true;
+                }
+                // A ROM dropped on the window replaces the one playing
+                Event::DropFile { filename, .. } => {
+                    let path = PathBuf::from(filename);
+                    let result = read_rom(&path, choose_from_archive)
+                        .and_then(|image| repair_rom(image.clone(), &game_db, &HeaderFix::default()).map(|data| (image, data)));
+                    match result {
+                        Ok((image, data)) => {
+                            emulator.send(Command::LoadRom(data));
+                            dropped_rom = Some((path, image));
+                        }
+                        Err(e) => println!("{}", e),
                     }
+                }
+                // Step over a JSR, step out of a subroutine, and run to the line selected in the debugger
+                Event::KeyDown { keycode: Some(Keycode::F10), ..} if !running => {
+                    running = true;
+                    emulator.send(Command::StepOver);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F11), ..} if !running => {
+                    running = true;
+                    emulator.send(Command::StepOut);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F4), ..} if !running && show_debugger => {
+                    running = true;
+                    emulator.send(Command::RunTo(debug_view.selected_address()));
+                }
 
-                    // Controller Port 1 BEGIN
-                    /* A */
-                    Event::KeyDown { keycode: Some(Keycode::Z), ..} => {
-                        *joy1.borrow_mut() |= 1 << 0;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::Z), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 0);
+                // Save states, in slots picked with F6 (Shift+F6 goes back) or 0-9
+                Event::KeyDown { keycode: Some(Keycode::F5), ..} => {
+                    let slot = SaveSlot::capture(&emulator.lock());
+                    let path = SaveSlot::path(&rom, state_slot);
+                    match slot.save(&path) {
+                        Ok(()) => {
+                            println!("Saved state to slot {} ({})", state_slot, path.display());
+                            osd.show_slot(format!("Saved slot {}", state_slot), Some(&slot));
+                        }
+                        Err(e) => {
+                            println!("Failed to save state: {}", e);
+                            osd.show(format!("Failed to save slot {}", state_slot));
+                        }
                     }
-
-                    /* B */
-                    Event::KeyDown { keycode: Some(Keycode::X), ..} => {
-                        *joy1.borrow_mut() |= 1 << 1;
+                }
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::F6 | Keycode::Num0 | Keycode::Num1 | Keycode::Num2 |
+                    Keycode::Num3 | Keycode::Num4 | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 | Keycode::Num9)), keymod, ..} => {
+                    state_slot = match keycode {
+                        Keycode::F6 if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) => (state_slot + SLOTS - 1) % SLOTS,
+                        Keycode::F6 => (state_slot + 1) % SLOTS,
+                        _ => (keycode as i32 - Keycode::Num0 as i32) as u8,
+                    };
+                    println!("Selected save state slot {}", state_slot);
+                    let path = SaveSlot::path(&rom, state_slot);
+                    match SaveSlot::load(&path) {
+                        Ok(slot) => osd.show_slot(format!("Slot {}: saved {}", state_slot, slot.age()), Some(&slot)),
+                        Err(_) if !path.exists() => osd.show(format!("Slot {}: empty", state_slot)),
+                        Err(e) => osd.show(format!("Slot {}: {}", state_slot, e)),
                     }
-                    Event::KeyUp { keycode: Some(Keycode::X), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 1);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F7), ..} => {
+                    let path = SaveSlot::path(&rom, state_slot);
+                    let result = SaveSlot::load(&path)
+                        .and_then(|slot| emulator.lock().load_state(&slot.state).map_err(|e| e.to_string()).map(|_| slot));
+                    match result {
+                        Ok(slot) => {
+                            println!("Loaded state from slot {}", state_slot);
+                            osd.show_slot(format!("Loaded slot {}", state_slot), Some(&slot));
+                        }
+                        Err(e) => {
+                            println!("Failed to load state: {}", e);
+                            osd.show(format!("Failed to load slot {}", state_slot));
+                        }
                     }
+                }
 
-                    /* Select */
-                    Event::KeyDown { keycode: Some(Keycode::RShift), ..} => {
-                        *joy1.borrow_mut() |= 1 << 2;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::RShift), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 2);
-                    }
+                // Reload the script, e.g. after editing it
+                Event::KeyDown { keycode: Some(Keycode::F3), ..} => match &args.script {
+                    Some(script) => emulator.send(Command::LoadScript(script.clone())),
+                    None => println!("No script to reload - start one with --script"),
+                },
 
-                    /* Start */
-                    Event::KeyDown { keycode: Some(Keycode::Return), ..} => {
-                        *joy1.borrow_mut() |= 1 << 3;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::Return), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 3);
-                    }
+                Event::KeyDown { keycode: Some(Keycode::F2), ..} => {
+                    show_log = !show_log;
+                }
 
-                    /* Up */
-                    Event::KeyDown { keycode: Some(Keycode::Up), ..} => {
-                        *joy1.borrow_mut() |= 1 << 4;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::Up), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 4);
-                    }
+                // Show what the ROM file is, as read from disk
+                Event::KeyDown { keycode: Some(Keycode::F1), ..} => {
+                    rom_info = match rom_info {
+                        Some(_) => None,
+                        None => match RomInfo::new(&rom_image) {
+                            Ok(info) => Some(info),
+                            Err(e) => {
+                                osd.show(format!("Failed to read the ROM's info: {}", e));
+                                None
+                            }
+                        },
+                    };
+                }
 
-                    /* Down */
-                    Event::KeyDown { keycode: Some(Keycode::Down), ..} => {
-                        *joy1.borrow_mut() |= 1 << 5;
-                    }
-                    Event::KeyUp { keycode: Some(Keycode::Down), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 5);
+                // Start or stop tracing
+                Event::KeyDown { keycode: Some(Keycode::F8), ..} => {
+                    let mut nes = emulator.lock();
+                    if nes.stop_trace().is_some() {
+                        println!("Stopped tracing");
+                    } else {
+                        let path = args.trace.clone().unwrap_or_else(|| trace_path(&rom));
+                        match TraceUnit::new(&path, args.trace_format, args.trace_ppu) {
+                            Ok(trace) => {
+                                nes.start_trace(trace);
+                                println!("Tracing to {} ({})", path.display(), args.trace_format);
+                            }
+                            Err(e) => println!("{}", e),
+                        }
                     }
+                }
 
-                    /* Left */
-                    Event::KeyDown { keycode: Some(Keycode::Left), keymod: sdl2::keyboard::Mod::NOMOD, ..} => {
-                        *joy1.borrow_mut() |= 1 << 6;
+                // Start or stop capturing video and audio beside the ROM
+                Event::KeyDown { keycode: Some(Keycode::F12), keymod, ..} if keymod.intersects(sdl2::keyboard::Mod::LSHIFTMOD | sdl2::keyboard::Mod::RSHIFTMOD) => {
+                    match capture.take() {
+                        Some(capture) => match capture.finish() {
+                            Ok(saved) => println!("{}", saved),
+                            Err(e) => println!("Capture failed: {}", e),
+                        },
+                        None => {
+                            let path = capture_path(&rom);
+                            let frame_rate = emulator.lock().region().frame_rate();
+                            match Capture::start(&path, nes_palette.clone(), frame_rate, audio_queue.spec().freq as u32) {
+                                Ok(started) => {
+                                    println!("Capturing to {}", path.display());
+                                    capture = Some(started);
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                    }
+                }
+                // Start or stop recording the audio, and with stems on, each channel's, beside the ROM
+                Event::KeyDown { keycode: Some(Keycode::F9), ..} => {
+                    match recording.take() {
+                        Some(finished) => {
+                            emulator.lock().apu_mut().set_stems(false);
+                            match finished.finish() {
+                                Ok(saved) => {
+                                    println!("{}", saved);
+                                    osd.show("Stopped recording audio");
+                                }
+                                Err(e) => println!("Recording failed: {}", e),
+                            }
+                        }
+                        None => {
+                            let path = recording_path(&rom);
+                            match AudioRecording::start(&path, audio_queue.spec().freq as u32, config.stems) {
+                                Ok(started) => {
+                                    println!("Recording audio to {}.wav", path.display());
+                                    osd.show("Recording audio");
+                                    emulator.lock().apu_mut().set_stems(config.stems);
+                                    recording = Some(started);
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
                     }
-                    Event::KeyUp { keycode: Some(Keycode::Left), keymod: sdl2::keyboard::Mod::NOMOD, ..} => {
-                        *joy1.borrow_mut() &= !(1 << 6);
+                }
+                // Save a screenshot beside the ROM
+                Event::KeyDown { keycode: Some(Keycode::F12), ..} => {
+                    let pixels = emulator.lock().screenshot();
+                    let path = screenshot_path(&rom);
+                    match fs::write(&path, png::encode(FRAME_WIDTH, FRAME_HEIGHT, &pixels)) {
+                        Ok(()) => println!("Saved screenshot to {}", path.display()),
+                        Err(e) => println!("Failed to save screenshot to {}: {}", path.display(), e),
                     }
+                }
 
-                    /* Right */
-                    Event::KeyDown { keycode: Some(Keycode::Right), ..} => {
-                        *joy1.borrow_mut() |= 1 << 4;
+                Event::KeyDown { keycode: Some(Keycode::Return), keymod, ..} if keymod.intersects(sdl2::keyboard::Mod::LALTMOD | sdl2::keyboard::Mod::RALTMOD) => {
+                    let mut canvas = canvas_cell.borrow_mut();
+                    let window = canvas.window_mut();
+                    if window.fullscreen_state() == FullscreenType::Off {
+                        window.set_fullscreen(FullscreenType::Desktop).unwrap();
+                    } else {
+                        window.set_fullscreen(FullscreenType::Off).unwrap();
+                        let size = get_screen_size(&layout, show_debugger, show_ppu_info, config.scale);
+                        window.set_size(size.0, size.1).unwrap();
                     }
-                    Event::KeyUp { keycode: Some(Keycode::Right), ..} => {
-                        *joy1.borrow_mut() &= !(1 << 4);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, ..} => {
+                    fast_forward = true;
+                    emulator.send(Command::SetSpeed(emulation_speed(&config, fast_forward, slow_motion)));
+                }
+                Event::KeyUp { keycode: Some(Keycode::Tab), ..} => {
+                    fast_forward = false;
+                    emulator.send(Command::SetSpeed(emulation_speed(&config, fast_forward, slow_motion)));
+                }
+                Event::KeyDown { keycode: Some(Keycode::Backquote), ..} => {
+                    slow_motion = !slow_motion;
+                    emulator.send(Command::SetSpeed(emulation_speed(&config, fast_forward, slow_motion)));
+                }
+                Event::KeyDown { keycode: Some(Keycode::O), keymod, ..} if keymod.intersects(sdl2::keyboard::Mod::LCTRLMOD | sdl2::keyboard::Mod::RCTRLMOD) => {
+                    if netplay_active {
+                        osd.show("Overclocking can't change during netplay");
+                        continue;
                     }
-                    // Controller Port 1 END
-                    _ => {}
+                    config.overclock = !config.overclock;
+                    config.save();
+                    let lines = config.overclock_lines;
+                    emulator.lock().set_overclock(if config.overclock { lines } else { Overclock::default() });
+                    osd.show(if config.overclock {
+                        format!("Overclocking: {} lines before vblank, {} after", lines.pre_nmi, lines.post_nmi)
+                    } else {
+                        "Overclocking off".to_string()
+                    });
                 }
+
+                ref e if input_map.handle_device_event(e, &controller_subsystem) => {}
+                ref e => match device_input.handle_event(e) {
+                    Some((port, input)) => emulator.send(Command::SetDeviceInput(port, input)),
+                    None => { input_map.handle_event(e); }
+                },
             }
+        }
 
-            // Render the complete image
-            nes_texture.with_lock(None, |r, p| {
-                for y in 0..240 {
-                    for x in 0..256 {
-                        let offset = y * p + x * 3;
-                        let color = palette[ppu.borrow().frame[(y * 256 + x) as usize] as usize];
-                        r[offset + 0] = color.r;  // R
-                        r[offset + 1] = color.g;  // G
-                        r[offset + 2] = color.b;  // B
-                    }
-                }
-            }).unwrap();
+        for port in 0..PLAYERS {
+            if input_map.state(port) != buttons[port] {
+                buttons[port] = input_map.state(port);
+                emulator.send(Command::SetController(port, buttons[port]));
+            }
+            if input_map.turbo(port) != turbo[port] {
+                turbo[port] = input_map.turbo(port);
+                emulator.send(Command::SetTurbo(port, turbo[port]));
+            }
+        }
+
+        // Render the latest complete image
+        nes_texture.with_lock(None, |r, p| draw_frame(&frame, &frame_rgb, r, p, PixelFormat::Rgb24)).unwrap();
 
+        // Each panel is drawn in its own viewport, placed by the layout
+        let origin = {
+            let mut canvas = canvas_cell.borrow_mut();
+            canvas.set_viewport(None);
+            canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+            canvas.clear();
+            fit_layout(&mut canvas, layout.size(show_debugger, show_ppu_info), config.scale)
+        };
+        let viewport = |rect: Rect| Some(Rect::new(origin.x() + rect.x(), origin.y() + rect.y(), rect.width(), rect.height()));
+
+        if show_debugger {
             {
                 let mut canvas = canvas_cell.borrow_mut();
-                canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
-                canvas.clear();
+                canvas.set_viewport(viewport(layout.debugger()));
+                debug_view.render(canvas, &emulator.lock());
             }
+        }
 
-            if show_debugger {
-                {
-                    let canvas = canvas_cell.borrow_mut();
-                    debug_view.render(canvas);
-                }
-            }
-    
-            if show_ppu_info {
-                {
-                    let mut canvas = canvas_cell.borrow_mut();
-    
-                    canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
-                    canvas.draw_rects(&(0..8).into_iter().map(|v| {
-                        Rect::new(palette_view_margin.left as i32 + 48 * v + palette_margin.left as i32 * v,
-                            (NES_SCREEN_HEIGHT + palette_view_margin.top) as i32, 50, 14)
-                    }).collect::<Vec<Rect>>()).unwrap();
-    
-                    // Show the currently selected palette.
-                    canvas.draw_rect(Rect::new(palette_view_margin.left as i32 - 1
-                        + palette_selected * 48 + palette_selected * palette_margin.left as i32,
-                    (NES_SCREEN_HEIGHT + palette_view_margin.top) as i32 - 1, 52, 16)).unwrap();
-    
-                    // Actually populate the palette information
-                    ppu.borrow().palette.chunks(4).enumerate().for_each(|i| {
-                        let palette_idx = i.0;
-                        let mut color_idx = 0;
-    
-                        for color in i.1 {
-                            let color_rgb = palette[*color as usize];
-    
-                            canvas.set_draw_color(color_rgb);
-                            canvas.fill_rect(Rect::new(palette_view_margin.left as i32 + 1
-                                + palette_idx as i32 * 48 + palette_idx as i32 * palette_margin.left as i32
-                                + color_idx * 12,
-                            (NES_SCREEN_HEIGHT + palette_view_margin.top) as i32 + 1, 12, 12)).unwrap();
-    
-                            color_idx += 1;
-                        }
-                    });
+        if show_ppu_info {
+            {
+                let mut canvas = canvas_cell.borrow_mut();
+                canvas.set_viewport(viewport(layout.ppu_info(show_debugger)));
 
-                    // Draw the two pattern tables
-                    canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
-                    canvas.draw_rects(&(0..2).into_iter().map(|v| {
-                        Rect::new(palette_view_margin.left as i32 + 256 * v + palette_margin.left as i32 * v,
-                            (NES_SCREEN_HEIGHT + palette_view_margin.top * 2 + 14) as i32, 258, 258)
-                    }).collect::<Vec<Rect>>()).unwrap();
+                canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
+                canvas.draw_rects(&(0..8).into_iter().map(|v| {
+                    Rect::new(palette_view_margin.left as i32 + 48 * v + palette_margin.left as i32 * v,
+                        palette_view_margin.top as i32, 50, 14)
+                }).collect::<Vec<Rect>>()).unwrap();
 
-                    {
-                        let p_ppu = ppu.borrow();
+                // Show the currently selected palette.
+                canvas.draw_rect(Rect::new(palette_view_margin.left as i32 - 1
+                    + palette_selected * 48 + palette_selected * palette_margin.left as i32,
+                palette_view_margin.top as i32 - 1, 52, 16)).unwrap();
 
-                        let mut palette_raw = [0 as u8; 3*128*128];
+                let nes = emulator.lock();
 
-                        for table in 0..2 {
-                            for tile_row in 0..16 {
-                                for tile_col in 0..16 {
-                                    for fine_y in 0..8 {
-                                        let lsb_addr: u16 = ((table << 12) | (tile_row << 8) | (tile_col << 4) | fine_y) as u16;
+                // Actually populate the palette information
+                nes.ppu().palette_ram().chunks(4).enumerate().for_each(|i| {
+                    let palette_idx = i.0;
+                    let mut color_idx = 0;
 
-                                        let px_color_lsb = p_ppu.read(lsb_addr);
-                                        let px_color_msb = p_ppu.read(lsb_addr + 8);
+                    for color in i.1 {
+                        let color_rgb = palette[*color as usize];
 
-                                        for pxidx in 0..8 {
-                                            let px_color = (((px_color_msb & (0x80 >> pxidx) > 1) as u8) << 1) | ((px_color_lsb & (0x80 >> pxidx) > 1) as u8);
-                                            let px_color_pal = p_ppu.read(0x3F00 + px_color as u16);
-                                            let px_color_rgb = palette[px_color_pal as usize];
+                        canvas.set_draw_color(color_rgb);
+                        canvas.fill_rect(Rect::new(palette_view_margin.left as i32 + 1
+                            + palette_idx as i32 * 48 + palette_idx as i32 * palette_margin.left as i32
+                            + color_idx * 12,
+                        palette_view_margin.top as i32 + 1, 12, 12)).unwrap();
+
+                        color_idx += 1;
+                    }
+                });
 
-                                            let draw_x = pxidx as i32 + 8 * tile_col as i32;
-                                            let draw_y = fine_y as i32 + 8 * tile_row as i32;
+                // Draw the two pattern tables
+                canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
+                canvas.draw_rects(&(0..2).into_iter().map(|v| {
+                    Rect::new(palette_view_margin.left as i32 + 256 * v + palette_margin.left as i32 * v,
+                        (palette_view_margin.top * 2 + 14) as i32, 258, 258)
+                }).collect::<Vec<Rect>>()).unwrap();
 
-                                            palette_raw[(draw_x * 3 + draw_y * 3 * 128 + 0) as usize] = px_color_rgb.r;
-                                            palette_raw[(draw_x * 3 + draw_y * 3 * 128 + 1) as usize] = px_color_rgb.g;
-                                            palette_raw[(draw_x * 3 + draw_y * 3 * 128 + 2) as usize] = px_color_rgb.b;
+                {
+                    let p_ppu = nes.ppu();
+                    let cartridge = nes.cartridge();
+
+                    let colours = [0, 1, 2, 3].map(|i| p_ppu.read(cartridge, 0x3F00 + i));
+                    let key = Some((cartridge.chr_changes(), colours));
+                    if key != pattern_key {
+                        pattern_key = key;
+                        for (table, texture) in pattern_textures.iter_mut().enumerate() {
+                            texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                                for tile_row in 0..16 {
+                                    for tile_col in 0..16 {
+                                        for fine_y in 0..8 {
+                                            let lsb_addr: u16 = ((table << 12) | (tile_row << 8) | (tile_col << 4) | fine_y) as u16;
+
+                                            let px_color_lsb = p_ppu.read(cartridge, lsb_addr);
+                                            let px_color_msb = p_ppu.read(cartridge, lsb_addr + 8);
+
+                                            for pxidx in 0..8 {
+                                                let px_color = (((px_color_msb & (0x80 >> pxidx) > 1) as u8) << 1) | ((px_color_lsb & (0x80 >> pxidx) > 1) as u8);
+                                                let px_color_rgb = palette[colours[px_color as usize] as usize];
+
+                                                let offset = (fine_y + 8 * tile_row) * pitch + (pxidx + 8 * tile_col) * 3;
+                                                buffer[offset] = px_color_rgb.r;
+                                                buffer[offset + 1] = px_color_rgb.g;
+                                                buffer[offset + 2] = px_color_rgb.b;
+                                            }
                                         }
                                     }
                                 }
-                            }
-                            palette_texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                                for y in 0..128 {
-                                    for x in 0..128 {
-                                        let offset = y * pitch + x * 3;
-                                        buffer[offset] = palette_raw[x * 3 + y * 3 * 128 + 0];
-                                        buffer[offset + 1] = palette_raw[x * 3 + y * 3 * 128 + 1];
-                                        buffer[offset + 2] = palette_raw[x * 3 + y * 3 * 128 + 2];
-                                    }
-                                }
                             }).unwrap();
-                            canvas.copy(&palette_texture, None, Some(Rect::new(
-                                palette_view_margin.left as i32 + 256i32 * (table as i32) + palette_margin.left as i32 * (table as i32) + 1,
-                                (NES_SCREEN_HEIGHT + palette_view_margin.top) as i32 + palette_margin.top as i32 + 18i32,
-                                256, 256))).unwrap();
-                        }   
+                        }
+                    }
+                    for (table, texture) in pattern_textures.iter().enumerate() {
+                        canvas.copy(texture, None, Some(Rect::new(
+                            palette_view_margin.left as i32 + 256i32 * (table as i32) + palette_margin.left as i32 * (table as i32) + 1,
+                            palette_view_margin.top as i32 + palette_margin.top as i32 + 18i32,
+                            256, 256))).unwrap();
                     }
                 }
-            }
 
-            ppu.borrow_mut().frame_ready = false;
-
-            canvas_cell.borrow_mut().copy(&nes_texture, None, Some(Rect::new(0, 0, NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT))).unwrap();
-            canvas_cell.borrow_mut().present();
+                // Draw the four nametables at half size, outlining the scroll window. The
+                // window wraps around at the edges, so may need drawing in up to four pieces.
+                {
+                    let nametable_rect = Rect::new(
+                        palette_view_margin.left as i32 + 512 + palette_margin.left as i32 * 2 + 6,
+                        palette_view_margin.top as i32 + palette_margin.top as i32 + 18i32,
+                        256, 240);
+
+                    let mut nametable_raw = vec![0u8; 512 * 480];
+                    nes.ppu().render_nametables(nes.cartridge(), &mut nametable_raw);
+                    nametable_texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                        for y in 0..480 {
+                            for x in 0..512 {
+                                let offset = y * pitch + x * 3;
+                                let color = palette[nametable_raw[y * 512 + x] as usize];
+                                buffer[offset] = color.r;
+                                buffer[offset + 1] = color.g;
+                                buffer[offset + 2] = color.b;
+                            }
+                        }
+                    }).unwrap();
+                    canvas.copy(&nametable_texture, None, Some(nametable_rect)).unwrap();
+
+                    let (scroll_x, scroll_y) = nes.ppu().scroll();
+                    canvas.set_clip_rect(nametable_rect);
+                    canvas.set_draw_color(Color::RGBA(255, 0, 0, 255));
+                    for (wrap_x, wrap_y) in [(0, 0), (256, 0), (0, 240), (256, 240)] {
+                        canvas.draw_rect(Rect::new(
+                            nametable_rect.x() + (scroll_x as i32 - wrap_x * 2) / 2,
+                            nametable_rect.y() + (scroll_y as i32 - wrap_y * 2) / 2,
+                            128, 120)).unwrap();
+                    }
+                    canvas.set_clip_rect(None);
+                }
+            }
+        }
 
-            // Abort if > 1 million cycles have been traced.
-            #[cfg(all(debug_assertions, feature = "fceux-log"))]
-            {
-                if cpu_cell.borrow().cycle > 1_000_000 {
-                    break 'running;
+        canvas_cell.borrow_mut().set_viewport(viewport(layout.screen()));
+        if show_memory {
+            memory_view.render(canvas_cell.borrow_mut(), &emulator.lock());
+        } else if show_sprites {
+            sprite_view.render(canvas_cell.borrow_mut(), &emulator.lock(), &palette);
+        } else if show_apu {
+            apu_view.render(canvas_cell.borrow_mut(), &emulator.lock());
+        } else {
+            let mut canvas = canvas_cell.borrow_mut();
+            let drawn = post_process.as_ref().map(|post_process| post_process.draw(&mut canvas, &mut nes_texture,
+                layout.visible_lines(), layout.screen(), config.curvature, config.scanlines));
+            if drawn != Some(Ok(())) {
+                if let Some(Err(e)) = drawn {
+                    println!("Failed to draw the picture through its shader: {}", e);
+                    post_process = None;
+                }
+                canvas.copy(&nes_texture, layout.visible_lines(), layout.screen()).unwrap();
+                if config.scanlines > 0.0 {
+                    draw_scanlines(&mut canvas, layout.screen(), config.scanlines);
                 }
             }
+            if let Err(e) = draw_overlay(&mut canvas, &nes_texture_creator, &overlay_font, &layout, &overlay) {
+                println!("Failed to draw the script's overlay: {}", e);
+                overlay.clear();
+            }
+            if let Some(info) = &rom_info {
+                if let Err(e) = draw_rom_info(&mut canvas, &nes_texture_creator, &overlay_font, layout.screen(), info) {
+                    println!("Failed to draw the ROM's info: {}", e);
+                    rom_info = None;
+                }
+            }
+            if show_log {
+                if let Err(e) = logger::draw_log_overlay(&mut canvas, &nes_texture_creator, &overlay_font, layout.screen()) {
+                    println!("Failed to draw the log: {}", e);
+                    show_log = false;
+                }
+            }
+            if let Err(e) = osd.draw(&mut canvas) {
+                println!("Failed to draw the on-screen display: {}", e);
+            }
+        }
+        canvas_cell.borrow_mut().present();
+    }
+
+    if let Some(capture) = capture {
+        match capture.finish() {
+            Ok(saved) => println!("{}", saved),
+            Err(e) => println!("Capture failed: {}", e),
+        }
+    }
+    if let Some(recording) = recording {
+        match recording.finish() {
+            Ok(saved) => println!("{}", saved),
+            Err(e) => println!("Recording failed: {}", e),
+        }
+    }
+    let profile = emulator.lock().stop_profile();
+    if let Some(profile) = profile {
+        match profile.save(Some(debug_view.symbols())) {
+            Ok(()) => println!("Saved profile to {}", profile.path().display()),
+            Err(e) => println!("{}", e),
+        }
+    }
+    let cdl = emulator.lock().stop_cdl();
+    if let (Some(cdl), Some(path)) = (cdl, &args.cdl) {
+        match fs::write(path, cdl.to_bytes()) {
+            Ok(()) => println!("Saved code/data log to {} ({})", path.display(), cdl.summary()),
+            Err(e) => println!("Can't write code/data log {}: {}", path.display(), e),
         }
     }
 }
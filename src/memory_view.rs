@@ -0,0 +1,196 @@
+use std::cell::RefMut;
+use fancy_nes_core::Nes;
+use fancy_nes_core::nes::MemorySpace;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, TextureCreator, TextureQuery};
+use sdl2::ttf::Sdl2TtfContext;
+use sdl2::pixels::Color;
+use sdl2::video::{Window, WindowContext};
+
+use crate::{NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT};
+
+const BYTES_PER_ROW: usize = 16;
+const ROWS: usize = 28;
+
+/* Offset, in characters, of the first byte on a row: "$0000: " */
+const HEX_COLUMN: i32 = 7;
+
+/// A hex and ASCII dump of CPU, PPU or OAM memory, drawn over the game screen.
+/// It reads memory afresh each frame, so it stays live while the game runs.
+pub struct MemoryView<'a> {
+    space: MemorySpace,
+    top: u16,              /* address of the first row */
+    cursor: u16,           /* the byte being edited */
+    nibble: Option<u8>,    /* the high nibble typed so far at the cursor */
+
+    font: sdl2::ttf::Font<'a, 'static>,
+    texture_creator: TextureCreator<WindowContext>,
+
+    prompt: Option<String>,  /* address being typed - see handle_event */
+    message: String,         /* result of the last edit or jump */
+}
+
+impl<'a> MemoryView<'a> {
+    pub fn new(texture_creator: TextureCreator<WindowContext>, ttf_context: &'a Sdl2TtfContext) -> Self {
+        Self {
+            space: MemorySpace::Cpu,
+            top: 0,
+            cursor: 0,
+            nibble: None,
+            font: ttf_context.load_font("debug.ttf", 12).unwrap(),
+            texture_creator,
+            prompt: None,
+            message: String::new(),
+        }
+    }
+
+    /// Handle memory viewer hotkeys. Returns true if the event was consumed.
+    ///
+    /// Arrow keys move the cursor, and Page Up/Down scroll by a screen. Typing
+    /// two hex digits pokes a byte at the cursor. G jumps to an address typed in
+    /// hex, and Tab switches between CPU, PPU and OAM memory.
+    pub fn handle_event(&mut self, event: &Event, nes: &mut Nes) -> bool {
+        let keycode = match event {
+            Event::KeyDown { keycode: Some(keycode), .. } => *keycode,
+            _ => return false,
+        };
+
+        if let Some(prompt) = self.prompt.as_mut() {
+            match keycode {
+                Keycode::Backspace => { prompt.pop(); }
+                Keycode::Return => {
+                    let prompt = self.prompt.take().unwrap();
+                    match u16::from_str_radix(&prompt, 16) {
+                        Ok(addr) if (addr as usize) < self.space.size() => {
+                            self.cursor = addr;
+                            self.top = addr & !(BYTES_PER_ROW as u16 - 1);
+                            self.message.clear();
+                        }
+                        _ => self.message = format!("Bad address: {}", prompt),
+                    }
+                }
+                Keycode::Escape => self.prompt = None,
+                _ => match hex_digit(keycode) {
+                    Some(digit) if prompt.len() < 4 => prompt.push(char::from_digit(digit as u32, 16).unwrap()),
+                    _ => {}
+                },
+            }
+            return true;
+        }
+
+        match keycode {
+            Keycode::Tab => {
+                self.space = match self.space {
+                    MemorySpace::Cpu => MemorySpace::Ppu,
+                    MemorySpace::Ppu => MemorySpace::Oam,
+                    MemorySpace::Oam => MemorySpace::Cpu,
+                };
+                self.top = 0;
+                self.cursor = 0;
+                self.nibble = None;
+            }
+            Keycode::G => {
+                self.prompt = Some(String::new());
+                self.nibble = None;
+            }
+            Keycode::Left => self.move_cursor(-1),
+            Keycode::Right => self.move_cursor(1),
+            Keycode::Up => self.move_cursor(-(BYTES_PER_ROW as i32)),
+            Keycode::Down => self.move_cursor(BYTES_PER_ROW as i32),
+            Keycode::PageUp => self.move_cursor(-((BYTES_PER_ROW * ROWS) as i32)),
+            Keycode::PageDown => self.move_cursor((BYTES_PER_ROW * ROWS) as i32),
+            _ => match hex_digit(keycode) {
+                Some(digit) => match self.nibble.take() {
+                    None => self.nibble = Some(digit),
+                    Some(high) => {
+                        match nes.poke(self.space, self.cursor, high << 4 | digit) {
+                            Ok(_) => {
+                                self.message.clear();
+                                self.move_cursor(1);
+                            }
+                            Err(e) => self.message = e.to_string(),
+                        }
+                    }
+                },
+                None => return false,
+            },
+        }
+        true
+    }
+
+    /* Move the cursor, wrapping around the address space, and scroll to keep it in view */
+    fn move_cursor(&mut self, offset: i32) {
+        let size = self.space.size() as i32;
+        self.cursor = (self.cursor as i32 + offset).rem_euclid(size) as u16;
+        self.nibble = None;
+
+        let row_start = self.cursor & !(BYTES_PER_ROW as u16 - 1);
+        let screen = (BYTES_PER_ROW * ROWS) as i32;
+        if row_start < self.top {
+            self.top = row_start;
+        } else if row_start as i32 >= self.top as i32 + screen {
+            self.top = (row_start as i32 - screen + BYTES_PER_ROW as i32) as u16;
+        }
+    }
+
+    pub fn render(&mut self, mut canvas: RefMut<Canvas<Window>>, nes: &Nes) {
+        let size = self.space.size();
+        let mut lines = vec![match &self.prompt {
+            Some(prompt) => format!("{:?} goto> {}_", self.space, prompt),
+            None if !self.message.is_empty() => format!("{:?}  {}", self.space, self.message),
+            None => format!("{:?}  G: goto, Tab: CPU/PPU/OAM, hex digits: poke", self.space),
+        }];
+
+        // Bytes which can't be read without side-effects are shown as "--"
+        for row in 0..ROWS {
+            let row_addr = self.top as usize + row * BYTES_PER_ROW;
+            if row_addr >= size {
+                break;
+            }
+            let bytes: Vec<Option<u8>> = (0..BYTES_PER_ROW)
+                .map(|i| nes.peek(self.space, (row_addr + i) as u16))
+                .collect();
+            let hex: Vec<String> = bytes.iter()
+                .map(|b| b.map_or("--".to_string(), |b| format!("{:0>2X}", b)))
+                .collect();
+            let ascii: String = bytes.iter()
+                .map(|b| match b { Some(b @ 0x20..=0x7E) => *b as char, _ => '.' })
+                .collect();
+            lines.push(format!("${:0>4X}: {}  {}", row_addr, hex.join(" "), ascii));
+        }
+
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+        canvas.fill_rect(Rect::new(0, 0, NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT)).unwrap();
+
+        // Highlight the byte under the cursor
+        let (char_width, _) = self.font.size_of_char('0').unwrap();
+        let line_height = self.font.recommended_line_spacing();
+        let row = (self.cursor - self.top) as i32 / BYTES_PER_ROW as i32;
+        let column = HEX_COLUMN + 3 * (self.cursor as i32 % BYTES_PER_ROW as i32);
+        canvas.set_draw_color(if self.nibble.is_some() { Color::RGBA(160, 80, 0, 255) } else { Color::RGBA(80, 80, 255, 255) });
+        canvas.fill_rect(Rect::new(10 + column * char_width as i32, 10 + line_height * (row + 1),
+            char_width * 2, line_height as u32)).unwrap();
+
+        let surface = self.font
+            .render(lines.join("\n").as_str())
+            .blended_wrapped(Color::RGBA(255, 255, 255, 255), NES_SCREEN_WIDTH)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let texture = self.texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(10, 10, width, height))).unwrap();
+    }
+}
+
+fn hex_digit(keycode: Keycode) -> Option<u8> {
+    let name = keycode.name();
+    match name.len() {
+        1 => u8::from_str_radix(&name, 16).ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,339 @@
+//! Netplay: two copies of the emulator, on different machines, playing the same
+//! game together. Since the core is deterministic, only controller input needs
+//! to cross the network - each side runs the whole machine, applying its own
+//! player's input and the other's on the same frames.
+//!
+//! Play is in lockstep, over UDP. Input is sent a few frames ahead of when it
+//! is applied (the input delay), so that it usually arrives before it's needed;
+//! when it hasn't, the frame waits for it. Every packet repeats all the input
+//! the peer hasn't acknowledged yet, so a lost packet is made up by the next.
+//!
+//! The host is player 1 and the player who connects is player 2. Both must have
+//! the same ROM, and start from power on in the host's region. Every so often
+//! each side also sends a checksum of its save state, so that a desync (e.g.
+//! from loading a save state on one side only) is noticed rather than played on.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use fancy_nes_core::{Nes, Region};
+use fancy_nes_core::movie::{rom_checksum, MovieFrame};
+use fancy_nes_core::state::{StateReader, StateWriter};
+
+const MAGIC: [u8; 4] = *b"FNNP";
+const VERSION: u8 = 1;
+
+/* Packet types */
+const HELLO: u8 = 0;    /* From the player connecting: the ROM's checksum */
+const WELCOME: u8 = 1;  /* The host's reply: the region and input delay to play with */
+const REFUSE: u8 = 2;   /* The host's reply to a player it won't play with: why not */
+const INPUT: u8 = 3;    /* Input the peer hasn't acknowledged, and the latest state checksum */
+const BYE: u8 = 4;      /* The sender has stopped playing */
+
+/* Compare save states this often, in frames */
+const CHECKSUM_INTERVAL: u32 = 60;
+/* Resend while waiting for the peer's input this often */
+const RESEND_INTERVAL: Duration = Duration::from_millis(16);
+/* Give up on a peer who has sent nothing for this long */
+const TIMEOUT: Duration = Duration::from_secs(10);
+/* Give up on a host who hasn't answered for this long */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/* Input sent in one packet, at most */
+const MAX_INPUTS: usize = 120;
+
+pub struct Netplay {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    local_port: usize,  /* The controller our player holds: 0 for the host, 1 for the other */
+    delay: u32,         /* Frames between input being read and applied */
+    region: Region,
+    frame: u32,         /* The frame about to run, from power on */
+
+    local: BTreeMap<u32, MovieFrame>,   /* Our input the peer hasn't acknowledged, by frame */
+    remote: BTreeMap<u32, MovieFrame>,  /* The peer's input for frames yet to run */
+    remote_next: u32,                   /* The first frame we don't have the peer's input for */
+
+    checksums: BTreeMap<u32, u32>,       /* Ours, until the peer's for the same frame arrives */
+    peer_checksums: BTreeMap<u32, u32>,  /* The peer's, until ours is taken */
+    desync: Option<u32>,  /* The first frame found to differ, until taken */
+    desynced: bool,       /* Only the first desync is reported */
+}
+
+impl Netplay {
+    /// Wait on `port` for a player with the same ROM to connect, and play with
+    /// them as player 2, in `region` with `delay` frames of input delay
+    pub fn host(port: u16, rom: &[u8], region: Region, delay: u32) -> Result<Self, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("Can't listen on port {}: {}", port, e))?;
+        let checksum = rom_checksum(rom);
+        let mut buf = [0u8; 1500];
+
+        println!("Waiting for a player to connect on port {}", port);
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).map_err(|e| format!("Can't receive: {}", e))?;
+            let Some((HELLO, mut r)) = parse(&buf[..len]) else {
+                continue;
+            };
+            if r.read_bytes().ok() != Some(&checksum[..]) {
+                let mut w = packet(REFUSE);
+                w.write_bytes(b"The host is playing a different ROM");
+                let _ = socket.send_to(&w.finish(), from);
+                continue;
+            }
+
+            println!("Playing with {}", from);
+            let netplay = Self::new(socket, from, 0, delay, region);
+            netplay.send(&netplay.welcome())?;
+            return Ok(netplay);
+        }
+    }
+
+    /// Connect to a host at `addr` (host:port), to play as player 2 in the region it says
+    pub fn connect(addr: &str, rom: &[u8]) -> Result<Self, String> {
+        let peer = addr.to_socket_addrs().map_err(|e| format!("Can't find {}: {}", addr, e))?
+            .next().ok_or_else(|| format!("Can't find {}", addr))?;
+        let socket = UdpSocket::bind(if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+            .map_err(|e| format!("Can't open a socket: {}", e))?;
+        socket.set_read_timeout(Some(Duration::from_millis(500))).map_err(|e| e.to_string())?;
+
+        let mut hello = packet(HELLO);
+        hello.write_bytes(&rom_checksum(rom));
+        let hello = hello.finish();
+        let mut buf = [0u8; 1500];
+
+        println!("Connecting to {}", peer);
+        let start = Instant::now();
+        while start.elapsed() < CONNECT_TIMEOUT {
+            socket.send_to(&hello, peer).map_err(|e| format!("Can't send to {}: {}", peer, e))?;
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                continue;
+            };
+            if from != peer {
+                continue;
+            }
+            match parse(&buf[..len]) {
+                Some((WELCOME, mut r)) => {
+                    let (Ok(region), Ok(delay)) = (r.read_u8(), r.read_u8()) else {
+                        continue;
+                    };
+                    let region = match region {
+                        1 => Region::PAL,
+                        2 => Region::Dendy,
+                        _ => Region::NTSC,
+                    };
+                    println!("Playing with {}", peer);
+                    return Ok(Self::new(socket, peer, 1, delay as u32, region));
+                }
+                Some((REFUSE, mut r)) => {
+                    let reason = r.read_bytes().map(String::from_utf8_lossy).unwrap_or_default();
+                    return Err(format!("{} refused to play: {}", peer, reason));
+                }
+                _ => {}
+            }
+        }
+        Err(format!("{} didn't answer", peer))
+    }
+
+    fn new(socket: UdpSocket, peer: SocketAddr, local_port: usize, delay: u32, region: Region) -> Self {
+        Self {
+            socket,
+            peer,
+            local_port,
+            delay,
+            region,
+            frame: 0,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            remote_next: delay,
+            checksums: BTreeMap::new(),
+            peer_checksums: BTreeMap::new(),
+            desync: None,
+            desynced: false,
+        }
+    }
+
+    /// The region both sides play in, which the machine must be set to
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// The controller our player holds
+    pub fn local_port(&self) -> usize {
+        self.local_port
+    }
+
+    /// Take the input for the frame about to run: our player's buttons (on the first
+    /// controller of `local`) and commands are sent on, to be applied after the input
+    /// delay, and the input for this frame is returned, waiting for the peer's if it
+    /// hasn't arrived. On error, the peer has gone and netplay is over.
+    pub fn next_frame(&mut self, nes: &Nes, local: MovieFrame) -> Result<MovieFrame, String> {
+        if self.frame.is_multiple_of(CHECKSUM_INTERVAL) {
+            self.checksums.insert(self.frame, state_checksum(&nes.save_state()));
+        }
+        self.local.insert(self.frame + self.delay, MovieFrame { command: local.command, buttons: [local.buttons[0], 0] });
+
+        self.send_input()?;
+        let mut last_sent = Instant::now();
+        let mut last_heard = Instant::now();
+        while self.frame >= self.remote_next {
+            let wait = RESEND_INTERVAL.saturating_sub(last_sent.elapsed()).max(Duration::from_millis(1));
+            self.socket.set_read_timeout(Some(wait)).map_err(|e| e.to_string())?;
+            if self.receive()? {
+                last_heard = Instant::now();
+            } else if last_heard.elapsed() > TIMEOUT {
+                return Err(format!("Lost contact with {}", self.peer));
+            }
+            if last_sent.elapsed() >= RESEND_INTERVAL {
+                self.send_input()?;
+                last_sent = Instant::now();
+            }
+        }
+
+        /* Catch up on anything else the peer has sent, without waiting */
+        self.socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        while self.receive()? {}
+        self.socket.set_nonblocking(false).map_err(|e| e.to_string())?;
+        self.compare_checksums();
+
+        let ours = self.local.get(&self.frame).copied().unwrap_or_default();
+        let theirs = self.remote.remove(&self.frame).unwrap_or_default();
+        let mut frame = MovieFrame { command: ours.command | theirs.command, buttons: [0; 2] };
+        frame.buttons[self.local_port] = ours.buttons[0];
+        frame.buttons[1 - self.local_port] = theirs.buttons[0];
+        self.frame += 1;
+        Ok(frame)
+    }
+
+    /// The first frame at which the two machines were found to differ, if they have since last asked
+    pub fn take_desync(&mut self) -> Option<u32> {
+        self.desync.take()
+    }
+
+    fn welcome(&self) -> Vec<u8> {
+        let mut w = packet(WELCOME);
+        w.write_u8(match self.region {
+            Region::NTSC => 0,
+            Region::PAL => 1,
+            Region::Dendy => 2,
+        });
+        w.write_u8(self.delay as u8);
+        w.finish()
+    }
+
+    /* Send the input the peer hasn't acknowledged, acknowledging theirs, with our latest checksum */
+    fn send_input(&self) -> Result<(), String> {
+        let first = self.local.keys().next().copied().unwrap_or(self.frame + self.delay);
+        let mut w = packet(INPUT);
+        w.write_u32(self.remote_next);
+        w.write_u32(first);
+        let inputs: Vec<u8> = self.local.values().take(MAX_INPUTS)
+            .flat_map(|input| [input.command, input.buttons[0]])
+            .collect();
+        w.write_bytes(&inputs);
+        let (frame, checksum) = self.checksums.iter().next_back().map_or((u32::MAX, 0), |(&f, &c)| (f, c));
+        w.write_u32(frame);
+        w.write_u32(checksum);
+        self.send(&w.finish())
+    }
+
+    fn send(&self, data: &[u8]) -> Result<(), String> {
+        self.socket.send_to(data, self.peer).map(|_| ()).map_err(|e| format!("Can't send to {}: {}", self.peer, e))
+    }
+
+    /* Handle a packet from the peer, if one comes. Returns whether one did. */
+    fn receive(&mut self) -> Result<bool, String> {
+        let mut buf = [0u8; 1500];
+        let (len, from) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => return Ok(false),
+            Err(e) => return Err(format!("Can't receive from {}: {}", self.peer, e)),
+        };
+        if from != self.peer {
+            return Ok(false);
+        }
+
+        match parse(&buf[..len]) {
+            /* Our welcome was lost, so the peer is still asking */
+            Some((HELLO, _)) => self.send(&self.welcome())?,
+            Some((INPUT, mut r)) => {
+                let _ = self.read_input(&mut r);
+            }
+            Some((BYE, _)) => return Err(format!("{} stopped playing", self.peer)),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn read_input(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let acknowledged = r.read_u32()?;
+        let first = r.read_u32()?;
+        let inputs = r.read_bytes()?;
+        let checksum_frame = r.read_u32()?;
+        let checksum = r.read_u32()?;
+
+        /* Ours for the frame about to run is kept until it has run */
+        self.local.retain(|&frame, _| frame >= acknowledged.min(self.frame));
+        for (frame, input) in (first..).zip(inputs.chunks_exact(2)) {
+            if frame >= self.frame {
+                self.remote.entry(frame).or_insert(MovieFrame { command: input[0], buttons: [input[1], 0] });
+            }
+        }
+        while self.remote.contains_key(&self.remote_next) {
+            self.remote_next += 1;
+        }
+
+        if checksum_frame != u32::MAX {
+            self.peer_checksums.insert(checksum_frame, checksum);
+        }
+        Ok(())
+    }
+
+    /* Check each frame both sides have a checksum for, forgetting those the peer has moved past */
+    fn compare_checksums(&mut self) {
+        let Some(&latest) = self.peer_checksums.keys().next_back() else {
+            return;
+        };
+        for (frame, theirs) in std::mem::take(&mut self.peer_checksums) {
+            match self.checksums.remove(&frame) {
+                Some(ours) if ours != theirs && !self.desynced => {
+                    self.desynced = true;
+                    self.desync = Some(frame);
+                }
+                Some(_) => {}
+                /* Not run by us yet */
+                None if frame > self.frame => {
+                    self.peer_checksums.insert(frame, theirs);
+                }
+                None => {}
+            }
+        }
+        self.checksums.retain(|&frame, _| frame >= latest);
+    }
+}
+
+impl Drop for Netplay {
+    fn drop(&mut self) {
+        let _ = self.send(&packet(BYE).finish());
+    }
+}
+
+fn packet(kind: u8) -> StateWriter {
+    let mut w = StateWriter::new();
+    MAGIC.iter().for_each(|&b| w.write_u8(b));
+    w.write_u8(VERSION);
+    w.write_u8(kind);
+    w
+}
+
+/* A packet's type, and a reader for the rest, if it's one of ours */
+fn parse(data: &[u8]) -> Option<(u8, StateReader<'_>)> {
+    if data.len() < 6 || data[..4] != MAGIC || data[4] != VERSION {
+        return None;
+    }
+    Some((data[5], StateReader::new(&data[6..])))
+}
+
+/* 32-bit FNV-1a, which is the same on both sides whatever they were built with */
+fn state_checksum(state: &[u8]) -> u32 {
+    state.iter().fold(0x811c9dc5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
@@ -0,0 +1,109 @@
+//! The `fancy-nes nsf` window: plays an NSF's songs, showing what's playing.
+//! Left and Right (or Page Up/Down) change song, Space pauses and Escape quits.
+//!
+//! There's no picture to keep in time with, so the audio queue sets the pace:
+//! the player is run on whenever less than a few frames' worth of audio is queued.
+
+use std::fs;
+use std::path::Path;
+
+use fancy_nes_core::nsf::NsfPlayer;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::TextureQuery;
+
+/* Keep roughly this much audio queued ahead, in bytes of f32 samples */
+const AUDIO_TARGET_BYTES: u32 = 4 * 44100 / 15;
+
+pub fn play_nsf(path: &Path) -> Result<(), String> {
+    let nsf = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut player = NsfPlayer::new(&nsf).map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+    let chips = player.header().unemulated_chips();
+    if !chips.is_empty() {
+        println!("{} sound isn't emulated - only the NES's own channels will play", chips.join(", "));
+    }
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let audio_subsystem = sdl_context.audio()?;
+    let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
+    let font = ttf_context.load_font("debug.ttf", 16)?;
+
+    let audio_spec = AudioSpecDesired {
+        freq: Some(44100),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &audio_spec)?;
+    player.set_sample_rate(audio_queue.spec().freq as u32);
+    audio_queue.resume();
+
+    let window = video_subsystem.window("fancy-nes v0.1.0 - NSF", 512, 160)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut event_pump = sdl_context.event_pump()?;
+
+    /* Run the player a frame's worth of cycles at a time */
+    let cycles = (player.region().cpu_clock() / player.region().frame_rate()) as u32;
+    let mut audio = vec![0f32; 4096];
+    let mut paused = false;
+
+    loop {
+        let songs = player.header().songs;
+        let mut song = None;
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(()),
+                Event::KeyDown { keycode: Some(Keycode::Left | Keycode::PageUp), .. } => {
+                    song = Some(player.song().checked_sub(1).unwrap_or(songs - 1));
+                }
+                Event::KeyDown { keycode: Some(Keycode::Right | Keycode::PageDown), .. } => {
+                    song = Some((player.song() + 1) % songs);
+                }
+                Event::KeyDown { keycode: Some(Keycode::Space), .. } => paused = !paused,
+                _ => {}
+            }
+        }
+
+        if let Some(song) = song {
+            audio_queue.clear();
+            player.start_song(song).map_err(|e| e.to_string())?;
+            paused = false;
+        }
+
+        while !paused && audio_queue.size() < AUDIO_TARGET_BYTES {
+            player.run(cycles).map_err(|e| e.to_string())?;
+            let count = player.take_samples(&mut audio);
+            audio_queue.queue_audio(&audio[..count])?;
+        }
+
+        let header = player.header();
+        let lines = [
+            header.title.clone(),
+            header.artist.clone(),
+            header.copyright.clone(),
+            String::new(),
+            format!("Song {} of {}{}", player.song() + 1, songs, if paused { " (paused)" } else { "" }),
+            "Left/Right: change song  Space: pause  Esc: quit".to_string(),
+        ];
+
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+        canvas.clear();
+        let surface = font
+            .render(lines.join("\n").as_str())
+            .blended_wrapped(Color::RGBA(255, 255, 255, 255), 492)
+            .map_err(|e| e.to_string())?;
+        let texture = texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string())?;
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(10, 10, width, height)))?;
+        canvas.present();
+    }
+}
@@ -0,0 +1,88 @@
+//! Messages shown briefly over the top of the game - which save state slot is
+//! picked, or that one was saved or loaded - with a slot's thumbnail beneath.
+
+use std::time::{Duration, Instant};
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator, TextureQuery};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+use crate::NES_SCREEN_SCALE;
+use crate::save_slots::{SaveSlot, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT};
+
+/* How long a message stays up */
+const SHOW_FOR: Duration = Duration::from_secs(3);
+
+pub struct Osd<'a> {
+    font: Font<'a, 'static>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+
+    message: String,
+    thumbnail: Option<Texture<'a>>,
+    shown_at: Option<Instant>,
+}
+
+impl<'a> Osd<'a> {
+    pub fn new(font: Font<'a, 'static>, texture_creator: &'a TextureCreator<WindowContext>) -> Self {
+        Self {
+            font,
+            texture_creator,
+            message: String::new(),
+            thumbnail: None,
+            shown_at: None,
+        }
+    }
+
+    /// Show a message, in place of any already up
+    pub fn show(&mut self, message: impl Into<String>) {
+        self.message = message.into();
+        self.thumbnail = None;
+        self.shown_at = Some(Instant::now());
+    }
+
+    /// Show a message about a slot, with its thumbnail if it has one
+    pub fn show_slot(&mut self, message: impl Into<String>, slot: Option<&SaveSlot>) {
+        self.show(message);
+        let Some(slot) = slot.filter(|slot| slot.thumbnail.len() == THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3) else {
+            return;
+        };
+        match self.texture_creator.create_texture_static(PixelFormatEnum::RGB24, THUMBNAIL_WIDTH as u32, THUMBNAIL_HEIGHT as u32) {
+            Ok(mut texture) => {
+                if texture.update(None, &slot.thumbnail, THUMBNAIL_WIDTH * 3).is_ok() {
+                    self.thumbnail = Some(texture);
+                }
+            }
+            Err(e) => println!("Failed to show the slot's thumbnail: {}", e),
+        }
+    }
+
+    /// Draw the message in the top left of the screen, on a darkened background, until it's been up a while
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>) -> Result<(), String> {
+        if self.shown_at.is_none_or(|at| at.elapsed() > SHOW_FOR) {
+            self.shown_at = None;
+            self.thumbnail = None;
+            return Ok(());
+        }
+
+        let surface = self.font.render(&self.message).blended(Color::RGB(224, 224, 224)).map_err(|e| e.to_string())?;
+        let text = self.texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string())?;
+        let TextureQuery { width, height, .. } = text.query();
+        let thumbnail = self.thumbnail.as_ref().map(|texture| (texture,
+            Rect::new(8, 8 + height as i32 + 4, THUMBNAIL_WIDTH as u32 * NES_SCREEN_SCALE, THUMBNAIL_HEIGHT as u32 * NES_SCREEN_SCALE)));
+        let bottom = thumbnail.map_or(8 + height as i32, |(_, rect)| rect.bottom());
+        let right = thumbnail.map_or(0, |(_, rect)| rect.right()).max(8 + width as i32);
+
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+        canvas.fill_rect(Rect::new(4, 4, right as u32, bottom as u32))?;
+        canvas.set_blend_mode(BlendMode::None);
+
+        canvas.copy(&text, None, Some(Rect::new(8, 8, width, height)))?;
+        if let Some((texture, rect)) = thumbnail {
+            canvas.copy(texture, None, Some(rect))?;
+        }
+        Ok(())
+    }
+}
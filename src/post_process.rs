@@ -0,0 +1,287 @@
+//! Post-processing of the game screen. Rather than copied to the window by
+//! SDL's renderer, the picture is drawn by a fragment shader through the
+//! renderer's own OpenGL context, for a CRT's curvature and scanlines, or a
+//! shader of the user's own. The debug panels and overlays are still drawn by
+//! SDL, over the top.
+//!
+//! Shaders are GLSL 1.10 fragment shaders, and may use any of these:
+//!
+//!     uniform sampler2D source;  // the NES's frame
+//!     uniform vec2 source_origin;  // the visible lines' top left in source, in texture coordinates
+//!     uniform vec2 source_extent;  // their size, likewise
+//!     uniform vec2 source_size;    // their size in NES pixels
+//!     uniform vec2 output_size;    // the picture's size in the window, in pixels
+//!     uniform float curvature;     // as configured, from 0 to 1
+//!     uniform float scanlines;     // likewise
+//!     varying vec2 tex_coord;      // 0 to 1 across the picture, from its top left
+//!
+//! data/shaders/crt.glsl, which is used unless the config names another, is a
+//! place to start. Where SDL can't render with OpenGL, the picture is copied to
+//! the window as before, flat, with scanlines drawn over it.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int, c_uchar, c_uint};
+use std::path::Path;
+
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureQuery};
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+
+const CRT_SHADER: &str = include_str!("../data/shaders/crt.glsl");
+
+/* Passes the picture's corners through as they are, with the texture coordinates beside them */
+const VERTEX_SHADER: &str = "#version 110
+varying vec2 tex_coord;
+void main() {
+    gl_Position = gl_Vertex;
+    tex_coord = gl_MultiTexCoord0.xy;
+}
+";
+
+const GL_TRIANGLE_STRIP: c_uint = 0x0005;
+const GL_BLEND: c_uint = 0x0BE2;
+const GL_SCISSOR_TEST: c_uint = 0x0C11;
+const GL_VIEWPORT: c_uint = 0x0BA2;
+const GL_FRAGMENT_SHADER: c_uint = 0x8B30;
+const GL_VERTEX_SHADER: c_uint = 0x8B31;
+const GL_COMPILE_STATUS: c_uint = 0x8B81;
+const GL_LINK_STATUS: c_uint = 0x8B82;
+const GL_INFO_LOG_LENGTH: c_uint = 0x8B84;
+const GL_CURRENT_PROGRAM: c_uint = 0x8B8D;
+
+/* The GL functions used, loaded from the driver by name */
+macro_rules! gl_functions {
+    ($($field:ident = $name:literal: fn($($arg:ty),*) $(-> $ret:ty)?;)*) => {
+        struct Gl {
+            $($field: unsafe extern "system" fn($($arg),*) $(-> $ret)?,)*
+        }
+
+        impl Gl {
+            fn load(video: &VideoSubsystem) -> Result<Self, String> {
+                Ok(Self {
+                    $($field: {
+                        let function = video.gl_get_proc_address($name);
+                        if function.is_null() {
+                            return Err(format!("the OpenGL driver has no {}", $name));
+                        }
+                        unsafe { std::mem::transmute::<*const (), unsafe extern "system" fn($($arg),*) $(-> $ret)?>(function) }
+                    },)*
+                })
+            }
+        }
+    };
+}
+
+gl_functions! {
+    create_shader = "glCreateShader": fn(c_uint) -> c_uint;
+    shader_source = "glShaderSource": fn(c_uint, c_int, *const *const c_char, *const c_int);
+    compile_shader = "glCompileShader": fn(c_uint);
+    get_shader_iv = "glGetShaderiv": fn(c_uint, c_uint, *mut c_int);
+    get_shader_info_log = "glGetShaderInfoLog": fn(c_uint, c_int, *mut c_int, *mut c_char);
+    delete_shader = "glDeleteShader": fn(c_uint);
+    create_program = "glCreateProgram": fn() -> c_uint;
+    attach_shader = "glAttachShader": fn(c_uint, c_uint);
+    link_program = "glLinkProgram": fn(c_uint);
+    get_program_iv = "glGetProgramiv": fn(c_uint, c_uint, *mut c_int);
+    get_program_info_log = "glGetProgramInfoLog": fn(c_uint, c_int, *mut c_int, *mut c_char);
+    delete_program = "glDeleteProgram": fn(c_uint);
+    use_program = "glUseProgram": fn(c_uint);
+    get_uniform_location = "glGetUniformLocation": fn(c_uint, *const c_char) -> c_int;
+    uniform_1i = "glUniform1i": fn(c_int, c_int);
+    uniform_1f = "glUniform1f": fn(c_int, f32);
+    uniform_2f = "glUniform2f": fn(c_int, f32, f32);
+    get_integer_v = "glGetIntegerv": fn(c_uint, *mut c_int);
+    is_enabled = "glIsEnabled": fn(c_uint) -> c_uchar;
+    enable = "glEnable": fn(c_uint);
+    disable = "glDisable": fn(c_uint);
+    viewport = "glViewport": fn(c_int, c_int, c_int, c_int);
+    begin = "glBegin": fn(c_uint);
+    end = "glEnd": fn();
+    tex_coord_2f = "glTexCoord2f": fn(f32, f32);
+    vertex_2f = "glVertex2f": fn(f32, f32);
+}
+
+/* Where the shader's uniforms are, or -1 for those it doesn't use */
+struct Uniforms {
+    source: c_int,
+    source_origin: c_int,
+    source_extent: c_int,
+    source_size: c_int,
+    output_size: c_int,
+    curvature: c_int,
+    scanlines: c_int,
+}
+
+/// A fragment shader, compiled for the GL context a canvas renders with, to
+/// draw the game screen through
+pub struct PostProcess {
+    gl: Gl,
+    program: c_uint,
+    uniforms: Uniforms,
+}
+
+impl PostProcess {
+    /// Compile a shader from a file, or the built-in CRT shader. Fails unless
+    /// the canvas renders with OpenGL, or if the shader doesn't compile.
+    pub fn new(video: &VideoSubsystem, canvas: &Canvas<Window>, shader: Option<&Path>) -> Result<Self, String> {
+        let renderer = canvas.info().name;
+        if renderer != "opengl" {
+            return Err(format!("SDL is rendering with {}, rather than OpenGL", renderer));
+        }
+        let source = match shader {
+            Some(path) => fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?,
+            None => CRT_SHADER.to_string(),
+        };
+
+        let gl = Gl::load(video)?;
+        let program = unsafe { link(&gl, &source) }
+            .map_err(|e| format!("{}: {}", shader.map_or("The CRT shader".into(), |path| path.to_string_lossy()), e))?;
+        let uniform = |name: &str| {
+            let name = CString::new(name).unwrap();
+            unsafe { (gl.get_uniform_location)(program, name.as_ptr()) }
+        };
+        let uniforms = Uniforms {
+            source: uniform("source"),
+            source_origin: uniform("source_origin"),
+            source_extent: uniform("source_extent"),
+            source_size: uniform("source_size"),
+            output_size: uniform("output_size"),
+            curvature: uniform("curvature"),
+            scanlines: uniform("scanlines"),
+        };
+        Ok(Self { gl, program, uniforms })
+    }
+
+    /// Draw the lines of the frame in `texture` that `source` covers (in NES
+    /// pixels) to `screen` in the canvas's viewport, as `Canvas::copy` would,
+    /// but through the shader. The canvas is left as SDL expects to find it.
+    pub fn draw(&self, canvas: &mut Canvas<Window>, texture: &mut Texture, source: Rect, screen: Rect,
+                curvature: f32, scanlines: f32) -> Result<(), String> {
+        /* The screen in the window's pixels, with GL's origin at the bottom left */
+        let (_, output_height) = canvas.output_size()?;
+        let (scale_x, scale_y) = canvas.scale();
+        let viewport = canvas.viewport();
+        let x = ((viewport.x() + screen.x()) as f32 * scale_x) as i32;
+        let y = ((viewport.y() + screen.y()) as f32 * scale_y) as i32;
+        let width = (screen.width() as f32 * scale_x) as i32;
+        let height = (screen.height() as f32 * scale_y) as i32;
+        let TextureQuery { width: texture_width, height: texture_height, .. } = texture.query();
+
+        let gl = &self.gl;
+        let uniforms = &self.uniforms;
+        unsafe {
+            /* SDL batches its drawing, so have what's queued drawn first */
+            sdl2::sys::SDL_RenderFlush(canvas.raw());
+
+            /* Binding through SDL keeps track of the texture for it, and says how much of it the frame fills */
+            let (extent_x, extent_y) = texture.gl_bind_texture();
+            if extent_x > 1.0 {
+                texture.gl_unbind_texture();
+                return Err("the OpenGL driver only has rectangle textures".to_string());
+            }
+
+            /* SDL doesn't expect the rest of its state to change under it, so save it to put back */
+            let mut program = 0;
+            (gl.get_integer_v)(GL_CURRENT_PROGRAM, &mut program);
+            let mut saved_viewport = [0; 4];
+            (gl.get_integer_v)(GL_VIEWPORT, saved_viewport.as_mut_ptr());
+            let blend = (gl.is_enabled)(GL_BLEND) != 0;
+            let scissor = (gl.is_enabled)(GL_SCISSOR_TEST) != 0;
+
+            (gl.disable)(GL_BLEND);
+            (gl.disable)(GL_SCISSOR_TEST);
+            (gl.viewport)(x, output_height as i32 - y - height, width, height);
+            (gl.use_program)(self.program);
+            (gl.uniform_1i)(uniforms.source, 0);
+            let (per_x, per_y) = (extent_x / texture_width as f32, extent_y / texture_height as f32);
+            (gl.uniform_2f)(uniforms.source_origin, source.x() as f32 * per_x, source.y() as f32 * per_y);
+            (gl.uniform_2f)(uniforms.source_extent, source.width() as f32 * per_x, source.height() as f32 * per_y);
+            (gl.uniform_2f)(uniforms.source_size, source.width() as f32, source.height() as f32);
+            (gl.uniform_2f)(uniforms.output_size, width as f32, height as f32);
+            (gl.uniform_1f)(uniforms.curvature, curvature);
+            (gl.uniform_1f)(uniforms.scanlines, scanlines);
+
+            (gl.begin)(GL_TRIANGLE_STRIP);
+            for (u, v) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+                (gl.tex_coord_2f)(u, v);
+                (gl.vertex_2f)(u * 2.0 - 1.0, 1.0 - v * 2.0);
+            }
+            (gl.end)();
+
+            (gl.use_program)(program as c_uint);
+            (gl.viewport)(saved_viewport[0], saved_viewport[1], saved_viewport[2], saved_viewport[3]);
+            if blend {
+                (gl.enable)(GL_BLEND);
+            }
+            if scissor {
+                (gl.enable)(GL_SCISSOR_TEST);
+            }
+            texture.gl_unbind_texture();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PostProcess {
+    fn drop(&mut self) {
+        unsafe { (self.gl.delete_program)(self.program) };
+    }
+}
+
+/* Compile and link the fragment shader with VERTEX_SHADER, or say what the driver didn't like */
+unsafe fn link(gl: &Gl, fragment: &str) -> Result<c_uint, String> {
+    let vertex = compile(gl, GL_VERTEX_SHADER, VERTEX_SHADER)?;
+    let fragment = match compile(gl, GL_FRAGMENT_SHADER, fragment) {
+        Ok(fragment) => fragment,
+        Err(e) => {
+            (gl.delete_shader)(vertex);
+            return Err(e);
+        }
+    };
+
+    let program = (gl.create_program)();
+    (gl.attach_shader)(program, vertex);
+    (gl.attach_shader)(program, fragment);
+    (gl.link_program)(program);
+    /* The program keeps them, for as long as it's around */
+    (gl.delete_shader)(vertex);
+    (gl.delete_shader)(fragment);
+
+    let mut linked = 0;
+    (gl.get_program_iv)(program, GL_LINK_STATUS, &mut linked);
+    if linked == 0 {
+        let log = info_log(|length| (gl.get_program_iv)(program, GL_INFO_LOG_LENGTH, length),
+                           |size, buf| (gl.get_program_info_log)(program, size, std::ptr::null_mut(), buf));
+        (gl.delete_program)(program);
+        return Err(log);
+    }
+    Ok(program)
+}
+
+unsafe fn compile(gl: &Gl, kind: c_uint, source: &str) -> Result<c_uint, String> {
+    let source = CString::new(source).map_err(|_| "shaders can't contain NUL".to_string())?;
+    let shader = (gl.create_shader)(kind);
+    (gl.shader_source)(shader, 1, &source.as_ptr(), std::ptr::null());
+    (gl.compile_shader)(shader);
+
+    let mut compiled = 0;
+    (gl.get_shader_iv)(shader, GL_COMPILE_STATUS, &mut compiled);
+    if compiled == 0 {
+        let log = info_log(|length| (gl.get_shader_iv)(shader, GL_INFO_LOG_LENGTH, length),
+                           |size, buf| (gl.get_shader_info_log)(shader, size, std::ptr::null_mut(), buf));
+        (gl.delete_shader)(shader);
+        return Err(log);
+    }
+    Ok(shader)
+}
+
+/* A shader's or program's info log, which is where the driver explains errors */
+unsafe fn info_log(length: impl Fn(*mut c_int), read: impl Fn(c_int, *mut c_char)) -> String {
+    let mut size = 0;
+    length(&mut size);
+    let mut buf = vec![0 as c_char; size.max(1) as usize];
+    read(buf.len() as c_int, buf.as_mut_ptr());
+    CStr::from_ptr(buf.as_ptr()).to_string_lossy().trim().to_string()
+}
@@ -0,0 +1,145 @@
+//! A minimal ROM browser, for when fancy-nes is started without a ROM (e.g. by
+//! double-clicking it): the recently played ROMs, then the subdirectories and
+//! ROMs (and archives of them) of a directory, in the debugger's font. It runs
+//! in a window of its own before emulation starts, and is closed again once a
+//! ROM is chosen.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::TextureQuery;
+
+use crate::{NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT};
+use crate::rom_loader::is_rom_file;
+
+const ROWS: usize = 26;
+
+enum Entry {
+    Recent(PathBuf),
+    Parent(PathBuf),
+    Directory(PathBuf),
+    Rom(PathBuf),
+}
+
+impl Entry {
+    fn label(&self) -> String {
+        let name = |path: &Path| path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+        match self {
+            Entry::Recent(path) => format!("* {}  ({})", name(path), path.parent().map_or(String::new(), |dir| dir.display().to_string())),
+            Entry::Parent(_) => "../".to_string(),
+            Entry::Directory(path) => format!("{}/", name(path)),
+            Entry::Rom(path) => name(path),
+        }
+    }
+}
+
+/* The recent ROMs which are still there, then the directory's parent, subdirectories and
+   ROMs, each sorted by name. Hidden files are left out. */
+fn list(dir: &Path, recent: &[PathBuf]) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = recent.iter().filter(|rom| rom.is_file()).cloned().map(Entry::Recent).collect();
+    if let Some(parent) = dir.parent() {
+        entries.push(Entry::Parent(parent.to_path_buf()));
+    }
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).map_or(vec![], |listing| listing
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| !path.file_name().map_or(true, |name| name.to_string_lossy().starts_with('.')))
+        .collect());
+    paths.sort();
+    entries.extend(paths.iter().filter(|path| path.is_dir()).cloned().map(Entry::Directory));
+    entries.extend(paths.iter()
+        .filter(|path| is_rom_file(path))
+        .cloned()
+        .map(Entry::Rom));
+    entries
+}
+
+/// Let the user pick a ROM, starting in `dir`. Arrow keys and Page Up/Down (or
+/// the mouse wheel) move the selection, Return opens it, and Backspace goes up
+/// a directory. Gives None if the window is closed or Escape pressed.
+pub fn choose_rom(dir: &Path, recent: &[PathBuf]) -> Result<Option<PathBuf>, String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
+    let font = ttf_context.load_font("debug.ttf", 12)?;
+
+    let window = video_subsystem.window("fancy-nes v0.1.0 - Open a ROM", NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().present_vsync().build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let mut dir = dir.to_path_buf();
+    let mut entries = list(&dir, recent);
+    let (mut selected, mut top) = (0usize, 0usize);
+
+    loop {
+        let mut open = None;
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return Ok(None),
+                Event::KeyDown { keycode: Some(Keycode::Up), .. } => selected = selected.saturating_sub(1),
+                Event::KeyDown { keycode: Some(Keycode::Down), .. } => selected += 1,
+                Event::KeyDown { keycode: Some(Keycode::PageUp), .. } => selected = selected.saturating_sub(ROWS),
+                Event::KeyDown { keycode: Some(Keycode::PageDown), .. } => selected += ROWS,
+                Event::MouseWheel { y, .. } => selected = (selected as i32 - y).max(0) as usize,
+                Event::KeyDown { keycode: Some(Keycode::Return), .. } => open = entries.get(selected),
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    open = entries.iter().find(|entry| matches!(entry, Entry::Parent(_)));
+                }
+                _ => {}
+            }
+            selected = selected.min(entries.len().saturating_sub(1));
+        }
+
+        match open {
+            Some(Entry::Recent(rom) | Entry::Rom(rom)) => return Ok(Some(rom.clone())),
+            Some(Entry::Parent(path) | Entry::Directory(path)) => {
+                dir = path.clone();
+                entries = list(&dir, recent);
+                selected = 0;
+            }
+            None => {}
+        }
+
+        // Scroll to keep the selection in view
+        if selected < top {
+            top = selected;
+        } else if selected >= top + ROWS {
+            top = selected + 1 - ROWS;
+        }
+
+        let mut lines = vec![format!("Open a ROM - {}", dir.display())];
+        lines.extend(entries.iter().skip(top).take(ROWS).map(Entry::label));
+        if entries.is_empty() {
+            lines.push("(no ROMs here)".to_string());
+        }
+
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+        canvas.clear();
+
+        let line_height = font.recommended_line_spacing();
+        if !entries.is_empty() {
+            canvas.set_draw_color(Color::RGBA(80, 80, 255, 255));
+            canvas.fill_rect(Rect::new(0, 10 + line_height * (selected - top + 1) as i32,
+                NES_SCREEN_WIDTH, line_height as u32))?;
+        }
+
+        let surface = font
+            .render(lines.join("\n").as_str())
+            .blended_wrapped(Color::RGBA(255, 255, 255, 255), NES_SCREEN_WIDTH - 20)
+            .map_err(|e| e.to_string())?;
+        let texture = texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string())?;
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(10, 10, width, height)))?;
+        canvas.present();
+    }
+}
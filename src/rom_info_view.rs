@@ -0,0 +1,32 @@
+//! The ROM info panel (F1): what the ROM being played is and its checksums,
+//! over the top of the picture, to copy into a bug report or look up in a
+//! game database. `fancy-nes info <rom>` prints the same.
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, TextureCreator, TextureQuery};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+use fancy_nes_core::rom_info::RomInfo;
+
+/// Draw the ROM's info over the top of the picture, on a darkened background
+pub fn draw_rom_info(canvas: &mut Canvas<Window>, texture_creator: &TextureCreator<WindowContext>,
+    font: &Font, screen: Rect, info: &RomInfo) -> Result<(), String> {
+    let lines = info.lines();
+    let line_height = font.recommended_line_spacing();
+    let height = line_height * lines.len() as i32 + 4;
+
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 208));
+    canvas.fill_rect(Rect::new(0, 0, screen.width(), height as u32))?;
+    canvas.set_blend_mode(BlendMode::None);
+
+    for (i, line) in lines.iter().enumerate() {
+        let surface = font.render(line).blended(Color::RGB(224, 224, 224)).map_err(|e| e.to_string())?;
+        let texture = texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string())?;
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(4, 2 + i as i32 * line_height, width, height)))?;
+    }
+    Ok(())
+}
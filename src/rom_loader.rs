@@ -0,0 +1,86 @@
+//! Reading ROM images from disk. Besides bare .nes files, a ROM can be read
+//! from inside a .zip or .7z archive, as ROM sets are often shared: the
+//! archive's .nes files are found, the caller asked which to play if there's
+//! more than one, and that one decompressed in memory. Nothing is extracted to
+//! disk. Archives are told apart by their contents rather than their name.
+
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use sevenz_rust::{Password, SevenZReader};
+use zip::ZipArchive;
+
+/// The extensions of files which may hold a ROM, for file browsers to list
+pub const EXTENSIONS: &[&str] = &["nes", "zip", "7z"];
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+/// Whether a file looks like it holds a ROM, by its extension
+pub fn is_rom_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| EXTENSIONS.iter().any(|rom_ext| ext.eq_ignore_ascii_case(rom_ext)))
+}
+
+/// Read a ROM image, from inside an archive if the file is one. `choose` picks which of
+/// an archive's .nes files to read, given their names, when there's more than one.
+pub fn read_rom(path: &Path, choose: impl FnOnce(&[String]) -> Option<usize>) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let result = if data.starts_with(ZIP_MAGIC) {
+        read_zip(&data, choose)
+    } else if data.starts_with(SEVEN_ZIP_MAGIC) {
+        read_7z(&data, choose)
+    } else {
+        return Ok(data);
+    };
+    result.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/* Which of an archive's entries to read, by name */
+fn pick(mut names: Vec<String>, choose: impl FnOnce(&[String]) -> Option<usize>) -> Result<String, String> {
+    names.sort();
+    let index = match names.len() {
+        0 => return Err("The archive has no .nes file in it".to_string()),
+        1 => 0,
+        _ => choose(&names).ok_or("No ROM was chosen from the archive")?,
+    };
+    names.get(index).cloned().ok_or_else(|| "No such ROM in the archive".to_string())
+}
+
+fn is_nes(name: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".nes")
+}
+
+fn read_zip(data: &[u8], choose: impl FnOnce(&[String]) -> Option<usize>) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let names = archive.file_names().filter(|name| is_nes(name)).map(str::to_string).collect();
+    let name = pick(names, choose)?;
+
+    let mut file = archive.by_name(&name).map_err(|e| e.to_string())?;
+    let mut rom = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut rom).map_err(|e| format!("{}: {}", name, e))?;
+    Ok(rom)
+}
+
+fn read_7z(data: &[u8], choose: impl FnOnce(&[String]) -> Option<usize>) -> Result<Vec<u8>, String> {
+    let mut archive = SevenZReader::new(Cursor::new(data), data.len() as u64, Password::empty()).map_err(|e| e.to_string())?;
+    let names = archive.archive().files.iter()
+        .filter(|entry| !entry.is_directory() && is_nes(entry.name()))
+        .map(|entry| entry.name().to_string())
+        .collect();
+    let name = pick(names, choose)?;
+
+    /* Solid archives decompress in one stream, so the entries before it are read through */
+    let mut rom = None;
+    archive.for_each_entries(|entry, reader| {
+        if entry.name() == name {
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            reader.read_to_end(&mut data)?;
+            rom = Some(data);
+            return Ok(false);
+        }
+        io::copy(reader, &mut io::sink())?;
+        Ok(true)
+    }).map_err(|e| e.to_string())?;
+    rom.ok_or_else(|| format!("{} could not be read from the archive", name))
+}
@@ -0,0 +1,117 @@
+//! Save state slots: ten for each ROM, kept beside it (smb.nes -> smb.ss0 to
+//! smb.ss9). Ahead of the state itself, a slot holds when it was saved and a
+//! thumbnail of the screen at the time, so that the slot being picked can be
+//! shown over the game (see osd.rs). Slots saved before thumbnails were added
+//! hold the bare state, and still load.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::nes::{FRAME_WIDTH, FRAME_HEIGHT};
+use fancy_nes_core::state::{StateReader, StateWriter, STATE_MAGIC};
+
+pub const SLOTS: u8 = 10;
+
+/* Thumbnails are the screen shrunk by this much each way */
+const THUMBNAIL_SCALE: usize = 4;
+pub const THUMBNAIL_WIDTH: usize = FRAME_WIDTH / THUMBNAIL_SCALE;
+pub const THUMBNAIL_HEIGHT: usize = FRAME_HEIGHT / THUMBNAIL_SCALE;
+
+const SLOT_MAGIC: [u8; 4] = *b"FNSL";
+const SLOT_VERSION: u16 = 1;
+
+pub struct SaveSlot {
+    pub saved_at: u64,       /* Seconds since the Unix epoch, or 0 if not known */
+    pub thumbnail: Vec<u8>,  /* RGB24, THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT, or empty if there's none */
+    pub state: Vec<u8>,
+}
+
+impl SaveSlot {
+    /// Snapshot the machine now, with its last frame in its palette's colours as the thumbnail
+    pub fn capture(nes: &Nes) -> Self {
+        Self {
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            thumbnail: thumbnail(&nes.screenshot()),
+            state: nes.save_state(),
+        }
+    }
+
+    /// Where a ROM's slot is kept, e.g. smb.nes -> smb.ss0
+    pub fn path(rom: &Path, slot: u8) -> PathBuf {
+        rom.with_extension(format!("ss{}", slot))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_bytes(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_bytes()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.starts_with(&STATE_MAGIC) {
+            return Ok(Self { saved_at: 0, thumbnail: vec![], state: data.to_vec() });
+        }
+        if !data.starts_with(&SLOT_MAGIC) {
+            return Err("Not a fancy-nes save state".to_string());
+        }
+
+        let mut r = StateReader::new(&data[SLOT_MAGIC.len()..]);
+        let version = r.read_u16()?;
+        if version != SLOT_VERSION {
+            return Err(format!("Unsupported save slot version {} (expected {})", version, SLOT_VERSION));
+        }
+        let saved_at = (r.read_u32()? as u64) << 32 | r.read_u32()? as u64;
+        let thumbnail = r.read_bytes()?.to_vec();
+        let state = r.read_bytes()?.to_vec();
+        Ok(Self { saved_at, thumbnail, state })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        SLOT_MAGIC.iter().for_each(|&b| w.write_u8(b));
+        w.write_u16(SLOT_VERSION);
+        w.write_u32((self.saved_at >> 32) as u32);
+        w.write_u32(self.saved_at as u32);
+        w.write_bytes(&self.thumbnail);
+        w.write_bytes(&self.state);
+        w.finish()
+    }
+
+    /// How long ago the slot was saved, e.g. "5 minutes ago"
+    pub fn age(&self) -> String {
+        if self.saved_at == 0 {
+            return "at an unknown time".to_string();
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (count, unit) = match now.saturating_sub(self.saved_at) {
+            secs if secs < 60 => return "just now".to_string(),
+            secs if secs < 60 * 60 => (secs / 60, "minute"),
+            secs if secs < 24 * 60 * 60 => (secs / (60 * 60), "hour"),
+            secs => (secs / (24 * 60 * 60), "day"),
+        };
+        format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+    }
+}
+
+/* Shrink a screenshot, averaging each block of pixels */
+fn thumbnail(screenshot: &[[u8; 3]]) -> Vec<u8> {
+    let mut thumbnail = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT * 3);
+    for y in 0..THUMBNAIL_HEIGHT {
+        for x in 0..THUMBNAIL_WIDTH {
+            let mut sum = [0u32; 3];
+            for row in 0..THUMBNAIL_SCALE {
+                let start = (y * THUMBNAIL_SCALE + row) * FRAME_WIDTH + x * THUMBNAIL_SCALE;
+                for pixel in &screenshot[start..start + THUMBNAIL_SCALE] {
+                    sum.iter_mut().zip(pixel).for_each(|(sum, &c)| *sum += c as u32);
+                }
+            }
+            thumbnail.extend(sum.map(|sum| (sum / (THUMBNAIL_SCALE * THUMBNAIL_SCALE) as u32) as u8));
+        }
+    }
+    thumbnail
+}
@@ -0,0 +1,201 @@
+//! Scripts, in Rhai, for bots, practice hacks and automated testing, in the way
+//! of FCEUX's Lua scripts. A script is run once as it's loaded, and registers
+//! closures to be called as the machine runs:
+//!   on_frame(|| ...)          As each frame completes
+//!   on_instruction(|pc| ...)  Before each instruction (which slows emulation down a lot)
+//! Anywhere in a script, these are available:
+//!   peek(addr), poke(addr, value)  Read and write CPU memory, without side-effects (see Nes::peek)
+//!   set_input(port, buttons)       Hold buttons for the next frame in place of the player's,
+//!                                  e.g. set_input(0, button::A | button::RIGHT)
+//!   text(x, y, message[, colour])  Draw over the next frame shown, in NES pixels,
+//!   rect(x, y, w, h, colour)       with colours as 0xRRGGBB
+//!   fill(x, y, w, h, colour)
+//!
+//! Scripts run on the emulation thread, which lets go of the machine while they do,
+//! so they get at it as the UI does: through the mutex.
+
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use fancy_nes_core::Nes;
+use fancy_nes_core::cpu::controller::JoypadButton;
+use fancy_nes_core::nes::MemorySpace;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, Module, AST, INT};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, TextureCreator, TextureQuery};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+use crate::Layout;
+
+const WHITE: INT = 0xFFFFFF;
+
+/// Something a script has drawn over the frame, in NES pixels
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Text { x: i32, y: i32, text: String, colour: u32 },
+    Rect { x: i32, y: i32, width: u32, height: u32, colour: u32, filled: bool },
+}
+
+/* What the script's functions share with the worker */
+#[derive(Default)]
+struct Shared {
+    on_frame: Option<FnPtr>,
+    on_instruction: Option<FnPtr>,
+    input: [Option<u8>; 2],
+    overlay: Vec<Shape>,
+}
+
+pub struct Script {
+    path: PathBuf,
+    engine: Engine,
+    ast: AST,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Script {
+    /// Compile and run a script, which can then get at the machine
+    pub fn load(path: &Path, nes: Arc<Mutex<Nes>>) -> Result<Self, String> {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let engine = engine(nes, Arc::clone(&shared));
+        let ast = engine.compile_file(path.to_path_buf()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        engine.run_ast(&ast).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self { path: path.to_path_buf(), engine, ast, shared })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the script wants calling before each instruction
+    pub fn wants_instructions(&self) -> bool {
+        self.shared.lock().unwrap().on_instruction.is_some()
+    }
+
+    /// Call the script's frame callback, if it registered one
+    pub fn frame(&mut self) -> Result<(), String> {
+        let callback = self.shared.lock().unwrap().on_frame.clone();
+        match callback {
+            Some(callback) => self.call(&callback, ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Call the script's instruction callback, if it registered one
+    pub fn instruction(&mut self, pc: u16) -> Result<(), String> {
+        let callback = self.shared.lock().unwrap().on_instruction.clone();
+        match callback {
+            Some(callback) => self.call(&callback, (pc as INT,)),
+            None => Ok(()),
+        }
+    }
+
+    /// The buttons the script is holding for the next frame, by port
+    pub fn take_input(&mut self) -> [Option<u8>; 2] {
+        mem::take(&mut self.shared.lock().unwrap().input)
+    }
+
+    /// What the script has drawn since the last frame
+    pub fn take_overlay(&mut self) -> Vec<Shape> {
+        mem::take(&mut self.shared.lock().unwrap().overlay)
+    }
+
+    fn call(&self, callback: &FnPtr, args: impl rhai::FuncArgs) -> Result<(), String> {
+        callback.call::<Dynamic>(&self.engine, &self.ast, args)
+            .map(|_| ())
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+fn engine(nes: Arc<Mutex<Nes>>, shared: Arc<Mutex<Shared>>) -> Engine {
+    let mut engine = Engine::new();
+
+    let mut buttons = Module::new();
+    for (name, button) in [("A", JoypadButton::A), ("B", JoypadButton::B), ("SELECT", JoypadButton::SELECT),
+        ("START", JoypadButton::START), ("UP", JoypadButton::UP), ("DOWN", JoypadButton::DOWN),
+        ("LEFT", JoypadButton::LEFT), ("RIGHT", JoypadButton::RIGHT)] {
+        buttons.set_var(name, button.bits() as INT);
+    }
+    engine.register_static_module("button", buttons.into());
+
+    let machine = Arc::clone(&nes);
+    engine.register_fn("peek", move |addr: INT| -> Result<INT, Box<EvalAltResult>> {
+        machine.lock().unwrap().peek(MemorySpace::Cpu, address(addr)?)
+            .map(|data| data as INT)
+            .ok_or_else(|| format!("Can't peek ${:0>4X}", addr).into())
+    });
+    let machine = nes;
+    engine.register_fn("poke", move |addr: INT, data: INT| -> Result<(), Box<EvalAltResult>> {
+        machine.lock().unwrap().poke(MemorySpace::Cpu, address(addr)?, data as u8)
+            .map_err(|e| e.to_string().into())
+    });
+
+    let state = Arc::clone(&shared);
+    engine.register_fn("on_frame", move |callback: FnPtr| state.lock().unwrap().on_frame = Some(callback));
+    let state = Arc::clone(&shared);
+    engine.register_fn("on_instruction", move |callback: FnPtr| state.lock().unwrap().on_instruction = Some(callback));
+    let state = Arc::clone(&shared);
+    engine.register_fn("set_input", move |port: INT, buttons: INT| -> Result<(), Box<EvalAltResult>> {
+        let mut state = state.lock().unwrap();
+        let input = state.input.get_mut(port as usize).ok_or_else(|| format!("No controller port {}", port))?;
+        *input = Some(buttons as u8);
+        Ok(())
+    });
+
+    let state = Arc::clone(&shared);
+    let text = move |x: INT, y: INT, text: &str, colour: INT| {
+        state.lock().unwrap().overlay.push(Shape::Text { x: x as i32, y: y as i32, text: text.to_string(), colour: colour as u32 });
+    };
+    let white_text = text.clone();
+    engine.register_fn("text", text);
+    engine.register_fn("text", move |x: INT, y: INT, text: &str| white_text(x, y, text, WHITE));
+    for (name, filled) in [("rect", false), ("fill", true)] {
+        let state = Arc::clone(&shared);
+        engine.register_fn(name, move |x: INT, y: INT, width: INT, height: INT, colour: INT| {
+            state.lock().unwrap().overlay.push(Shape::Rect { x: x as i32, y: y as i32,
+                width: width.max(0) as u32, height: height.max(0) as u32, colour: colour as u32, filled });
+        });
+    }
+
+    engine
+}
+
+fn address(addr: INT) -> Result<u16, Box<EvalAltResult>> {
+    u16::try_from(addr).map_err(|_| format!("${:X} is not a CPU address", addr).into())
+}
+
+/// Draw a script's overlay over the game screen, scaled as the frame is by the layout.
+/// The canvas's viewport should be the screen's.
+pub fn draw_overlay(canvas: &mut Canvas<Window>, texture_creator: &TextureCreator<WindowContext>, font: &Font,
+    layout: &Layout, overlay: &[Shape]) -> Result<(), String> {
+    let lines = layout.visible_lines();
+    let screen = layout.screen();
+    let scale = |x: i32, y: i32| (x * screen.width() as i32 / lines.width() as i32,
+        (y - lines.y()) * screen.height() as i32 / lines.height() as i32);
+    let colour = |rgb: u32| Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+
+    for shape in overlay {
+        match shape {
+            Shape::Text { x, y, text, colour: rgb } => {
+                if text.is_empty() {
+                    continue;
+                }
+                let surface = font.render(text).blended(colour(*rgb)).map_err(|e| e.to_string())?;
+                let texture = texture_creator.create_texture_from_surface(&surface).map_err(|e| e.to_string())?;
+                let TextureQuery { width, height, .. } = texture.query();
+                let (x, y) = scale(*x, *y);
+                canvas.copy(&texture, None, Some(Rect::new(x, y, width, height)))?;
+            }
+            Shape::Rect { x, y, width, height, colour: rgb, filled } => {
+                let (left, top) = scale(*x, *y);
+                let (right, bottom) = scale(x + *width as i32, y + *height as i32);
+                let rect = Rect::new(left, top, (right - left).max(1) as u32, (bottom - top).max(1) as u32);
+                canvas.set_draw_color(colour(*rgb));
+                if *filled { canvas.fill_rect(rect)? } else { canvas.draw_rect(rect)? }
+            }
+        }
+    }
+    Ok(())
+}
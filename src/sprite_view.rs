@@ -0,0 +1,105 @@
+use std::cell::RefMut;
+use fancy_nes_core::Nes;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, TextureCreator, TextureQuery};
+use sdl2::surface::Surface;
+use sdl2::ttf::Sdl2TtfContext;
+use sdl2::video::{Window, WindowContext};
+
+use crate::{NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT};
+
+const ROWS: usize = 16;
+const CELL_WIDTH: i32 = 128;
+const CELL_HEIGHT: i32 = 28;
+const TOP: i32 = 24;  /* below the title line */
+
+/// The 64 sprites in OAM, drawn over the game screen: each sprite's pattern
+/// in its palette, and its Y, tile, attribute and X bytes. The (up to) eight
+/// sprites on the PPU's current scanline are highlighted.
+pub struct SpriteView<'a> {
+    font: sdl2::ttf::Font<'a, 'static>,
+    texture_creator: TextureCreator<WindowContext>,
+}
+
+impl<'a> SpriteView<'a> {
+    pub fn new(texture_creator: TextureCreator<WindowContext>, ttf_context: &'a Sdl2TtfContext) -> Self {
+        Self {
+            font: ttf_context.load_font("debug.ttf", 12).unwrap(),
+            texture_creator,
+        }
+    }
+
+    pub fn render(&mut self, mut canvas: RefMut<Canvas<Window>>, nes: &Nes, palette: &[Color]) {
+        let ppu = nes.ppu();
+        let oam = ppu.oam();
+        let height = ppu.sprite_height();
+        let on_scanline = if ppu.scanline < 240 { ppu.sprites_on_scanline(ppu.scanline) } else { vec![] };
+
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+        canvas.fill_rect(Rect::new(0, 0, NES_SCREEN_WIDTH, NES_SCREEN_HEIGHT)).unwrap();
+
+        // Highlight the sprites selected for the current scanline
+        canvas.set_draw_color(Color::RGBA(80, 80, 255, 255));
+        for &i in &on_scanline {
+            canvas.fill_rect(self.cell(i)).unwrap();
+        }
+
+        // All 64 patterns side by side on one surface, scaled to fit a cell as they're copied
+        let mut surface = Surface::new(64 * 8, height as u32, PixelFormatEnum::RGB24).unwrap();
+        let pitch = surface.pitch() as usize;
+        let mut pixels = vec![0u8; 8 * height as usize];
+        surface.with_lock_mut(|buffer: &mut [u8]| {
+            for i in 0..64 {
+                ppu.render_sprite(nes.cartridge(), i, &mut pixels);
+                for y in 0..height as usize {
+                    for x in 0..8 {
+                        let color = palette[pixels[y * 8 + x] as usize];
+                        let offset = y * pitch + (i * 8 + x) * 3;
+                        buffer[offset] = color.r;
+                        buffer[offset + 1] = color.g;
+                        buffer[offset + 2] = color.b;
+                    }
+                }
+            }
+        });
+        let texture = self.texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let scale = if height == 8 { 2 } else { 1 };  /* 8x8 sprites at double size, 8x16 at their own */
+        for i in 0..64 {
+            let cell = self.cell(i);
+            canvas.copy(&texture, Rect::new(i as i32 * 8, 0, 8, height as u32),
+                Rect::new(cell.x() + 4, cell.y() + 6, 8 * scale, height as u32 * scale)).unwrap();
+        }
+
+        // Title, and each sprite's bytes beside its pattern
+        let title = format!("OAM - scanline {}: {} sprites", ppu.scanline, on_scanline.len());
+        self.draw_text(&mut canvas, &title, 10, 4);
+        for i in 0..64 {
+            let cell = self.cell(i);
+            let entry = format!("{:0>2} {:0>2X} {:0>2X} {:0>2X} {:0>2X}", i, oam[i * 4], oam[i * 4 + 1], oam[i * 4 + 2], oam[i * 4 + 3]);
+            self.draw_text(&mut canvas, &entry, cell.x() + 24, cell.y() + 7);
+        }
+    }
+
+    fn draw_text(&self, canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32) {
+        let surface = self.font
+            .render(text)
+            .blended(Color::RGBA(255, 255, 255, 255))
+            .map_err(|e| e.to_string()).unwrap();
+        let texture = self.texture_creator
+            .create_texture_from_surface(&surface)
+            .map_err(|e| e.to_string()).unwrap();
+
+        let TextureQuery { width, height, .. } = texture.query();
+        canvas.copy(&texture, None, Some(Rect::new(x, y, width, height))).unwrap();
+    }
+
+    /* The area of the screen given to a sprite. Sprites run down the columns. */
+    fn cell(&self, index: usize) -> Rect {
+        Rect::new((index / ROWS) as i32 * CELL_WIDTH, TOP + (index % ROWS) as i32 * CELL_HEIGHT,
+            CELL_WIDTH as u32, CELL_HEIGHT as u32)
+    }
+}
@@ -0,0 +1,89 @@
+//! Text for the debugger panels, drawn a line at a time from textures kept between
+//! frames. Rendering text with SDL_ttf and uploading it is slow, but from one frame
+//! to the next most lines of a panel don't change - and while the game runs, the
+//! few which do (the registers, the disassembly around the PC) tend to come round
+//! again - so each line's texture is kept, keyed by its text and colour, until it
+//! hasn't been drawn for a while.
+
+use std::collections::HashMap;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator, TextureQuery};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+/* How many frames a line's texture is kept without being drawn */
+const KEEP_FRAMES: u32 = 60;
+
+struct Line<'a> {
+    texture: Texture<'a>,
+    last_drawn: u32,  /* The frame it was last drawn in */
+}
+
+pub struct TextCache<'a> {
+    font: Font<'a, 'static>,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    wrap_width: u32,  /* Lines longer than this wrap onto the next */
+
+    lines: HashMap<(String, Color), Line<'a>>,
+    frame: u32,
+}
+
+impl<'a> TextCache<'a> {
+    pub fn new(font: Font<'a, 'static>, texture_creator: &'a TextureCreator<WindowContext>, wrap_width: u32) -> Self {
+        Self {
+            font,
+            texture_creator,
+            wrap_width,
+            lines: HashMap::new(),
+            frame: 0,
+        }
+    }
+
+    pub fn line_spacing(&self) -> i32 {
+        self.font.recommended_line_spacing()
+    }
+
+    /// Start drawing a new frame, letting go of lines which haven't been drawn in a while
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+        let frame = self.frame;
+        self.lines.retain(|_, line| frame.wrapping_sub(line.last_drawn) <= KEEP_FRAMES);
+    }
+
+    /// Draw lines of text down from (x, y), cut off at `bottom`. Returns the y below the last line.
+    pub fn draw<S: AsRef<str>>(&mut self, canvas: &mut Canvas<Window>, lines: &[S], colour: Color,
+        x: i32, mut y: i32, bottom: i32) -> Result<i32, String> {
+        for line in lines {
+            if y >= bottom {
+                break;
+            }
+            let line = line.as_ref();
+            if line.is_empty() {
+                /* SDL_ttf won't render nothing */
+                y += self.line_spacing();
+                continue;
+            }
+
+            let key = (line.to_string(), colour);
+            let cached = match self.lines.get_mut(&key) {
+                Some(cached) => cached,
+                None => {
+                    let surface = self.font.render(line).blended_wrapped(colour, self.wrap_width)
+                        .map_err(|e| e.to_string())?;
+                    let texture = self.texture_creator.create_texture_from_surface(&surface)
+                        .map_err(|e| e.to_string())?;
+                    self.lines.entry(key).or_insert(Line { texture, last_drawn: 0 })
+                }
+            };
+            cached.last_drawn = self.frame;
+
+            let TextureQuery { width, height, .. } = cached.texture.query();
+            let height = height.min((bottom - y) as u32);
+            canvas.copy(&cached.texture, Rect::new(0, 0, width, height), Rect::new(x, y, width, height))?;
+            y += height as i32;
+        }
+        Ok(y)
+    }
+}
@@ -0,0 +1,119 @@
+//! Writing audio to WAV files: the audio of a capture (see capture.rs), and
+//! recordings of the audio alone, which can also keep each of the APU's
+//! channels in a WAV of its own (a stem), as for remixing a game's music.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use fancy_nes_core::apu::{Channel, CHANNELS};
+
+use crate::capture::with_suffix;
+
+/* 16-bit mono PCM, the lowest common denominator of WAV readers */
+pub struct Wav {
+    out_file: BufWriter<File>,
+    path: PathBuf,
+    samples: u32,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl Wav {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Can't create {}: {}", path.display(), e))?;
+        let mut wav = Self { out_file: BufWriter::new(file), path: path.to_path_buf(), samples: 0 };
+        wav.write_header(sample_rate).map_err(|e| wav.error(e))?;
+        Ok(wav)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /* The sizes are left at zero until finish fills them in */
+    fn write_header(&mut self, sample_rate: u32) -> std::io::Result<()> {
+        let w = &mut self.out_file;
+        w.write_all(b"RIFF")?;
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?;  /* PCM */
+        w.write_all(&1u16.to_le_bytes())?;  /* Mono */
+        w.write_all(&sample_rate.to_le_bytes())?;
+        w.write_all(&(sample_rate * 2).to_le_bytes())?;  /* Bytes per second */
+        w.write_all(&2u16.to_le_bytes())?;  /* Bytes per sample */
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&0u32.to_le_bytes())
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out_file.write_all(&sample.to_le_bytes()).map_err(|e| self.error(e))?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        let data_size = self.samples * 2;
+        let result = (|| {
+            let w = &mut self.out_file;
+            w.seek(SeekFrom::Start(4))?;
+            w.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+            w.seek(SeekFrom::Start(WAV_HEADER_SIZE as u64 - 4))?;
+            w.write_all(&data_size.to_le_bytes())?;
+            w.flush()
+        })();
+        result.map_err(|e| self.error(e))
+    }
+
+    fn error(&self, e: std::io::Error) -> String {
+        format!("Can't write {}: {}", self.path.display(), e)
+    }
+}
+
+/// A recording of the audio the emulator generates, as it's played: the mix to
+/// path.wav, and with stems, each channel to e.g. path.pulse1.wav
+pub struct AudioRecording {
+    mix: Wav,
+    stems: Vec<Wav>,  /* By Channel, or empty without stems */
+}
+
+impl AudioRecording {
+    pub fn start(path: &Path, sample_rate: u32, stems: bool) -> Result<Self, String> {
+        let mix = Wav::create(&with_suffix(path, ".wav"), sample_rate)?;
+        let stems = match stems {
+            true => Channel::ALL.iter()
+                .map(|channel| Wav::create(&with_suffix(path, &format!(".{}.wav", channel.key())), sample_rate))
+                .collect::<Result<_, _>>()?,
+            false => vec![],
+        };
+        Ok(Self { mix, stems })
+    }
+
+    /// Add the audio of a frame, and each channel's if stems are being recorded
+    pub fn write(&mut self, mix: &[f32], stems: Option<&[Vec<f32>; CHANNELS]>) -> Result<(), String> {
+        self.mix.write(mix)?;
+        if let Some(stems) = stems {
+            for (wav, samples) in self.stems.iter_mut().zip(stems) {
+                wav.write(samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop recording, returning a description of what was saved where
+    pub fn finish(mut self) -> Result<String, String> {
+        self.mix.finish()?;
+        for wav in &mut self.stems {
+            wav.finish()?;
+        }
+        Ok(match self.stems.len() {
+            0 => format!("Saved the audio to {}", self.mix.path().display()),
+            count => format!("Saved the audio to {}, and {} channels beside it", self.mix.path().display(), count),
+        })
+    }
+}
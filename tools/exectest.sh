@@ -10,7 +10,7 @@ fceux_diff() {
     test_len=`grep -c ^ "${script_args[0]}"`
     sed -i "${test_len}"',$d' "${script_args[1]}"
 
-    test_=`sed 's/^.*[ '"${TAB}"']CYC:\(.*\)$/\1/' "${script_args[0]}"`
+    test_=`sed 's/^c\([0-9]*\).*$/\1/' "${script_args[0]}"`
     correct=`sed 's/^c\([0-9]*\).*$/\1/' "${script_args[1]}"`
 
     sdiff -l <(echo "$test_") <(echo "$correct") | cat -n | grep -v -e '($' 1>difftest.diff